@@ -0,0 +1,74 @@
+// Arithmetic semantics shared by the VM and the compile-time folding passes, so that folded
+// expressions always evaluate to exactly what the VM would have computed.
+//
+// Integers (32 and 64 bit) wrap around on overflow (also in debug builds).
+// Division and modulo by zero have no result, for both integers and floats, which the VM reports as a runtime
+// error and the folding passes leave to the VM.
+
+use std::ops::{Div, Rem};
+
+pub trait WrappingInteger: Copy + PartialEq + Default {
+    fn wrapping_add(self, right: Self) -> Self;
+    fn wrapping_sub(self, right: Self) -> Self;
+    fn wrapping_mul(self, right: Self) -> Self;
+    fn wrapping_div(self, right: Self) -> Self;
+    fn wrapping_rem(self, right: Self) -> Self;
+}
+
+macro_rules! impl_wrapping_integer {
+    ($($t:ty),*) => {
+        $(impl WrappingInteger for $t {
+            fn wrapping_add(self, right: Self) -> Self {
+                <$t>::wrapping_add(self, right)
+            }
+            fn wrapping_sub(self, right: Self) -> Self {
+                <$t>::wrapping_sub(self, right)
+            }
+            fn wrapping_mul(self, right: Self) -> Self {
+                <$t>::wrapping_mul(self, right)
+            }
+            fn wrapping_div(self, right: Self) -> Self {
+                <$t>::wrapping_div(self, right)
+            }
+            fn wrapping_rem(self, right: Self) -> Self {
+                <$t>::wrapping_rem(self, right)
+            }
+        })*
+    };
+}
+
+impl_wrapping_integer!(i32, i64);
+
+pub fn integer_addition<T: WrappingInteger>(left: T, right: T) -> T {
+    left.wrapping_add(right)
+}
+
+pub fn integer_subtraction<T: WrappingInteger>(left: T, right: T) -> T {
+    left.wrapping_sub(right)
+}
+
+pub fn integer_multiplication<T: WrappingInteger>(left: T, right: T) -> T {
+    left.wrapping_mul(right)
+}
+
+pub fn integer_division<T: WrappingInteger>(left: T, right: T) -> Option<T> {
+    (right != T::default()).then(|| left.wrapping_div(right))
+}
+
+pub fn integer_modulo<T: WrappingInteger>(left: T, right: T) -> Option<T> {
+    (right != T::default()).then(|| left.wrapping_rem(right))
+}
+
+pub fn float_division<T: Copy + PartialEq + Default + Div<Output = T>>(
+    left: T,
+    right: T,
+) -> Option<T> {
+    (right != T::default()).then(|| left / right)
+}
+
+pub fn float_modulo<T: Copy + PartialEq + Default + Rem<Output = T>>(
+    left: T,
+    right: T,
+) -> Option<T> {
+    (right != T::default()).then(|| left % right)
+}
@@ -0,0 +1,603 @@
+use std::collections::HashMap;
+
+use elements::{FieldLayout, world::ComponentEvent};
+
+use crate::bytecode_file::{
+    BytecodeFile, ComponentEntry, ComponentNames, DebugInfo, FORMAT_VERSION, ForeignFunctionEntry,
+    FunctionEntry, HandlerEntry, ResourceEntry,
+};
+use crate::bytecodes::ByteCode;
+use crate::disassembler::binary_operation_name;
+use crate::instructions::{ConstantPool, Instruction, Label, encode};
+use crate::optimizer::is_binary_operation;
+use crate::vm::Value;
+
+// Turns the text produced by the disassembler back into a bytecode file, e.g.
+//
+//   Format Version: 4
+//   Debug Info: included
+//   Component Count: 0
+//   function signature (name: double) (local count: 1) (function position: L0)
+//
+//   === function chunk: main ===
+//   function (function index: 0) (params: 1) (line: 1)
+//   push_integer (value: 21) (line: 2)
+//   call double (is_global: 0) (arg count: 1, function index: 0)
+//   ret
+//
+//   L0:
+//   === function chunk: double ===
+//   get_value_and_operate (index: 0, op: int_add) (line: 1)
+//   ret
+//
+// The byte offsets printed in front of the disassembled instructions are optional and ignored, and the constant
+// pool is rebuilt from the strings of the instructions.
+// Names of components, fields and called functions and the source lines are only kept with `Debug Info: included`.
+// The bytecode is not verified, so hand-written programs can also be used to test the verifier.
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut assembler = Assembler::default();
+    for (line_index, line) in source.lines().enumerate() {
+        assembler
+            .assemble_line(line)
+            .map_err(|error| format!("Line {}: {error}", line_index + 1))?;
+    }
+    assembler.finish()
+}
+
+#[derive(Default)]
+struct Assembler {
+    components: Vec<ComponentEntry>,
+    functions: Vec<(FunctionEntry, String)>, // function entries and the label of their position
+    foreign_functions: Vec<ForeignFunctionEntry>,
+    handlers: Vec<HandlerEntry>,
+    resources: Vec<ResourceEntry>,
+    instructions: Vec<Instruction>,
+    labels: HashMap<String, Label>,
+    defined_labels: Vec<String>,
+    debug_info: DebugInfo,
+    include_debug_info: bool,
+}
+
+impl Assembler {
+    fn assemble_line(&mut self, line: &str) -> Result<(), String> {
+        let text = strip_offset(line.trim());
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        // The header comes before the first instruction
+        let in_header = self.instructions.is_empty();
+        if let Some(version) = text.strip_prefix("Format Version: ") {
+            if parse_number::<u16>(version)? != FORMAT_VERSION {
+                return Err(format!(
+                    "Unsupported format version {version} (expected {FORMAT_VERSION})"
+                ));
+            }
+        } else if in_header && let Some(debug_info) = text.strip_prefix("Debug Info: ") {
+            self.include_debug_info = match debug_info {
+                "included" => true,
+                "stripped" => false,
+                _ => return Err(format!("Invalid debug info '{debug_info}'")),
+            };
+        } else if in_header
+            && (text.starts_with("Component Count: ") || text.starts_with("Constant Count: "))
+        {
+            // The counts follow from the components and the instructions themselves
+        } else if in_header && let Some(component) = text.strip_prefix("Component ID: ") {
+            let (id, fields) = match component.split_once(" (") {
+                Some((id, operands)) => (id, fields(operands)?),
+                None => (component, HashMap::new()),
+            };
+            let name = fields.get("name").cloned();
+            let is_child_of = match fields.get("relationship").map(String::as_str) {
+                Some("child of") => true,
+                Some(relationship) => return Err(format!("Unknown relationship '{relationship}'")),
+                None => false,
+            };
+            let id = parse_number(id)?;
+            self.components.push(ComponentEntry {
+                id,
+                is_child_of,
+                fields: Vec::new(),
+            });
+            if let Some(name) = name {
+                self.debug_info.components.push(ComponentNames {
+                    id,
+                    name,
+                    field_names: Vec::new(),
+                });
+            }
+        } else if in_header && let Some((name, layout)) = field_layout(text) {
+            let (type_id, size) = layout
+                .split_once(", size: ")
+                .ok_or_else(|| format!("Invalid field '{text}'"))?;
+            let (size, type_parameter) = match size.split_once(", type parameter: ") {
+                Some((size, type_parameter)) => (size, parse_number(type_parameter)?),
+                None => (size, 0),
+            };
+            let component = self
+                .components
+                .last_mut()
+                .ok_or_else(|| format!("Field '{text}' does not belong to a component"))?;
+            component.fields.push(FieldLayout {
+                name: String::new(),
+                type_id: parse_number(type_id)?,
+                size: parse_number(size)?,
+                type_parameter,
+            });
+            let names = self
+                .debug_info
+                .components
+                .last_mut()
+                .filter(|names| names.id == component.id);
+            match (name, names) {
+                (Some(name), Some(names)) => names.field_names.push(name.to_string()),
+                (None, None) => (),
+                _ => return Err(format!("Field '{text}' must be named like its component")),
+            }
+        } else if in_header && let Some(signature) = text.strip_prefix("function signature ") {
+            let fields = fields(signature)?;
+            let entry = FunctionEntry {
+                name: field(&fields, "name")?.to_string(),
+                local_count: parse_number(field(&fields, "local count")?)?,
+                position: 0, // resolved once all instructions are known
+            };
+            let label = field(&fields, "function position")?.to_string();
+            self.label(&label); // so that the position is checked to be defined, like jump targets
+            self.functions.push((entry, label));
+        } else if in_header && let Some(foreign) = text.strip_prefix("foreign function ") {
+            let fields = fields(foreign)?;
+            self.foreign_functions.push(ForeignFunctionEntry {
+                name: field(&fields, "name")?.to_string(),
+                arity: parse_number(field(&fields, "arity")?)?,
+            });
+        } else if in_header && let Some(handler) = text.strip_prefix("handler ") {
+            let fields = fields(handler)?;
+            let event = match field(&fields, "event")? {
+                "add" => ComponentEvent::Add,
+                "remove" => ComponentEvent::Remove,
+                "destroy" => ComponentEvent::Destroy,
+                event => return Err(format!("Unknown handler event '{event}'")),
+            };
+            self.handlers.push(HandlerEntry {
+                event,
+                component: parse_number(field(&fields, "component")?)?,
+                function: parse_number(field(&fields, "function index")?)?,
+            });
+        } else if in_header && let Some(resource) = text.strip_prefix("resource ") {
+            // A string default may contain anything, so it is read from between the outermost quotes
+            let (operands, string) = match (resource.find('\''), resource.rfind('\'')) {
+                (Some(start), Some(end)) if start < end => (
+                    resource[..start].trim_end().trim_end_matches("(default:"),
+                    Some(unescape(&resource[start + 1..end])?),
+                ),
+                _ => (resource, None),
+            };
+            let fields = fields(operands)?;
+            let default = match (field(&fields, "type")?, string) {
+                ("i32", None) => Value::Integer(parse_number(field(&fields, "default")?)?),
+                ("i64", None) => Value::Integer64(parse_number(field(&fields, "default")?)?),
+                ("f32", None) => Value::Float(parse_number(field(&fields, "default")?)?),
+                ("f64", None) => Value::Float64(parse_number(field(&fields, "default")?)?),
+                ("str", Some(string)) => Value::String(string),
+                (type_name, _) => {
+                    return Err(format!("Invalid resource default of type '{type_name}'"));
+                }
+            };
+            self.resources.push(ResourceEntry {
+                name: field(&fields, "name")?.to_string(),
+                default,
+            });
+        } else if let Some(label) = text.strip_suffix(':')
+            && !label.contains(' ')
+        {
+            if self.defined_labels.iter().any(|defined| defined == label) {
+                return Err(format!("Label '{label}' is defined more than once"));
+            }
+            self.defined_labels.push(label.to_string());
+            let label = self.label(label);
+            self.instructions.push(Instruction::Label(label));
+        } else {
+            let (text, line) = strip_line(text)?;
+            if let Some(line) = line {
+                self.instructions.push(Instruction::Line(line));
+            }
+            let instruction = self.instruction(text)?;
+            self.instructions.push(instruction);
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, text: &str) -> Result<Instruction, String> {
+        // The names of functions are part of the function tables
+        if text.starts_with("=== function chunk: ") && text.ends_with(" ===") {
+            return Ok(Instruction::FunctionChunk);
+        }
+        if let Some(query) = text.strip_prefix("query components: ") {
+            return self.query(query);
+        }
+        if let Some(call) = text.strip_prefix("call foreign function ") {
+            let (_, operands) = call
+                .split_once(" (")
+                .ok_or_else(|| format!("Invalid call '{text}'"))?;
+            let fields = fields(operands)?;
+            return Ok(Instruction::CallForeign {
+                foreign_index: parse_number(field(&fields, "foreign_index")?)?,
+                arg_count: parse_number(field(&fields, "arg count")?)?,
+            });
+        }
+        if let Some(call) = text.strip_prefix("call ") {
+            // The name of the called function is left out without debug info
+            let (name, operands) = match call.strip_prefix('(') {
+                Some(operands) => (None, operands),
+                None => call
+                    .split_once(" (")
+                    .map(|(name, operands)| (Some(name.to_string()), operands))
+                    .ok_or_else(|| format!("Invalid call '{text}'"))?,
+            };
+            let fields = fields(operands)?;
+            return Ok(Instruction::Call {
+                arg_count: parse_number(field(&fields, "arg count")?)?,
+                is_global: parse_number::<u8>(field(&fields, "is_global")?)? == 1,
+                index: parse_number(field(&fields, "function index")?)?,
+                name,
+            });
+        }
+
+        let (mnemonic, operands) = match text.split_once(" (") {
+            Some((mnemonic, operands)) => (mnemonic, operands),
+            None => (text, ""),
+        };
+        // Strings may contain anything, so they are read from between the outermost quotes
+        let string_operand = || -> Result<String, String> {
+            match (text.find('\''), text.rfind('\'')) {
+                (Some(start), Some(end)) if start < end => unescape(&text[start + 1..end]),
+                _ => Err(format!("Missing string value in '{text}'")),
+            }
+        };
+        let fields = if string_operand().is_ok() {
+            HashMap::new()
+        } else {
+            fields(operands)?
+        };
+        let number = |key: &str| -> Result<u32, String> { parse_number(field(&fields, key)?) };
+        let name = |key: &str| -> Result<String, String> { Ok(field(&fields, key)?.to_string()) };
+
+        let instruction = match mnemonic {
+            "push_true" => Instruction::PushTrue,
+            "push_false" => Instruction::PushFalse,
+            "push_integer" => Instruction::PushInteger(parse_number(field(&fields, "value")?)?),
+            "push_integer64" => Instruction::PushInteger64(parse_number(field(&fields, "value")?)?),
+            "push_float" => Instruction::PushFloat(parse_number(field(&fields, "value")?)?),
+            "push_float64" => Instruction::PushFloat64(parse_number(field(&fields, "value")?)?),
+            "push_string" => Instruction::PushString(string_operand()?),
+            "push_simple_tag" => Instruction::PushSimpleTag {
+                name: string_operand()?,
+            },
+            "push_tag" => Instruction::PushTag {
+                name: string_operand()?,
+            },
+            "push_list" => Instruction::PushList {
+                length: number("length")?,
+            },
+            "push_component" => Instruction::PushComponent {
+                id: number("id")?,
+                property_count: number("properties")?,
+            },
+            "set_next_component_column_or_jump" => Instruction::SetNextComponentColumnOrJump,
+            "get_tag_name" => Instruction::GetTagName,
+            "get_tag_payload" => Instruction::GetTagPayload,
+            "neg" => Instruction::Negation,
+            "not" => Instruction::Not,
+            "get_value" => Instruction::GetLocalValue {
+                index: number("index")?,
+            },
+            "set_value" => Instruction::SetLocalValue {
+                index: number("index")?,
+            },
+            "get_value_and_operate" => Instruction::GetLocalValueAndOperate {
+                index: number("index")?,
+                operation: binary_operation(field(&fields, "op")?)?,
+            },
+            "get_foreign_value" => Instruction::GetForeignValue {
+                name: name("name")?,
+            },
+            "get_field_value" => Instruction::GetFieldValue {
+                index: number("index")?,
+                component: number("component")?,
+                field_path: field_path(field(&fields, "field_path")?)?,
+            },
+            "set_field_value" => Instruction::SetFieldValue {
+                index: number("index")?,
+                component: number("component")?,
+                field_path: field_path(field(&fields, "field_path")?)?,
+            },
+            "get_context_value" => Instruction::GetContextValue {
+                name: name("name")?,
+            },
+            "set_context_value" => Instruction::SetContextValue {
+                name: name("name")?,
+            },
+            "get_list_element_at_index" => Instruction::GetListElementAtIndex,
+            "get_array_length" => Instruction::GetArrayLength,
+            "append" => Instruction::ArrayAppend,
+            "log" => Instruction::Log,
+            "function" => Instruction::Function {
+                function_index: number("function index")?,
+                param_count: number("params")?,
+            },
+            "ret" => Instruction::Return,
+            "jump" => Instruction::Jump(self.label(field(&fields, "to")?)),
+            "jump if true" => Instruction::JumpIfTrue(self.label(field(&fields, "to")?)),
+            "jump if false" => Instruction::JumpIfFalse(self.label(field(&fields, "to")?)),
+            "create entity" => Instruction::Create,
+            "destroy entity" => Instruction::Destroy,
+            "add component" => Instruction::AddComponent,
+            "remove component" => Instruction::RemoveComponent { id: number("id")? },
+            _ => match binary_operation(mnemonic) {
+                Ok(operation) => binary_instruction(operation),
+                Err(_) => return Err(format!("Unknown instruction '{mnemonic}'")),
+            },
+        };
+        Ok(instruction)
+    }
+
+    // E.g. `+include 0 (Position), include 1 (Velocity) ?optional 3 (Target) -exclude 2 (Frozen) > ~changed 0
+    // (Position) (local index: 2, parent: 0, end: L1)`, where the component names are optional and ignored
+    fn query(&mut self, text: &str) -> Result<Instruction, String> {
+        let invalid_query = || format!("Invalid query '{text}'");
+        let (components, operands) = text
+            .rsplit_once(" (local index: ")
+            .ok_or_else(invalid_query)?;
+        let (local_index, end) = operands
+            .strip_suffix(')')
+            .and_then(|operands| operands.split_once(", end: "))
+            .ok_or_else(invalid_query)?;
+        let (local_index, has_parent) = local_index
+            .split_once(", parent: ")
+            .ok_or_else(invalid_query)?;
+        let (include, optional) = components
+            .strip_prefix('+')
+            .and_then(|components| components.split_once(" ?"))
+            .ok_or_else(invalid_query)?;
+        let (optional, exclude) = optional.split_once(" -").ok_or_else(invalid_query)?;
+        let (exclude, added) = exclude.split_once(" >").ok_or_else(invalid_query)?;
+        let (added, changed) = added.split_once(" ~").ok_or_else(invalid_query)?;
+        Ok(Instruction::ContextQuery {
+            end: self.label(end),
+            local_index: parse_number(local_index)?,
+            has_parent: parse_number::<u8>(has_parent)? == 1,
+            include_components: query_components(include, "include ")?,
+            optional_components: query_components(optional, "optional ")?,
+            exclude_components: query_components(exclude, "exclude ")?,
+            added_components: query_components(added, "added ")?,
+            changed_components: query_components(changed, "changed ")?,
+        })
+    }
+
+    fn label(&mut self, name: &str) -> Label {
+        let next_label = Label(self.labels.len() as u32);
+        *self.labels.entry(name.to_string()).or_insert(next_label)
+    }
+
+    fn finish(self) -> Result<Vec<u8>, String> {
+        if let Some(undefined) = self
+            .labels
+            .keys()
+            .find(|label| !self.defined_labels.contains(label))
+        {
+            return Err(format!("Label '{undefined}' is not defined"));
+        }
+
+        let mut constants = ConstantPool::default();
+        let mut code = Vec::new();
+        let mut debug_info = self.debug_info;
+        encode(
+            &self.instructions,
+            &mut code,
+            &mut constants,
+            &mut debug_info,
+        );
+
+        let mut label_positions = HashMap::new();
+        let mut position = 0;
+        for instruction in &self.instructions {
+            if let Instruction::Label(label) = instruction {
+                label_positions.insert(*label, position as u32);
+            }
+            position += instruction.size(&constants);
+        }
+
+        let functions = self
+            .functions
+            .into_iter()
+            .map(|(mut function, label)| {
+                function.position = label_positions[&self.labels[&label]];
+                function
+            })
+            .collect();
+
+        Ok(BytecodeFile {
+            components: self.components,
+            functions,
+            foreign_functions: self.foreign_functions,
+            handlers: self.handlers,
+            resources: self.resources,
+            constants: constants.into_constants(),
+            code,
+            debug_info: self.include_debug_info.then_some(debug_info),
+        }
+        .to_bytes())
+    }
+}
+
+// Removes the byte offset that the disassembler prints in front of each instruction
+fn strip_offset(text: &str) -> &str {
+    match text.split_once(char::is_whitespace) {
+        Some((offset, rest)) if offset.chars().all(|char| char.is_ascii_digit()) => rest.trim(),
+        _ => text,
+    }
+}
+
+// Removes the source line that the disassembler appends to the first instruction of each line, e.g. `(line: 3)`
+fn strip_line(text: &str) -> Result<(&str, Option<u32>), String> {
+    match text
+        .strip_suffix(')')
+        .and_then(|text| text.rsplit_once(" (line: "))
+    {
+        Some((instruction, line)) if line.chars().all(|char| char.is_ascii_digit()) => {
+            Ok((instruction, Some(parse_number(line)?)))
+        }
+        _ => Ok((text, None)),
+    }
+}
+
+// Splits a field of a component into its (optional) name and its layout, e.g. `x, type id: 2, size: 4`
+fn field_layout(text: &str) -> Option<(Option<&str>, &str)> {
+    match text.strip_prefix("type id: ") {
+        Some(layout) => Some((None, layout)),
+        None => text
+            .split_once(", type id: ")
+            .map(|(name, layout)| (Some(name), layout)),
+    }
+}
+
+// The field indices of a path through nested components, e.g. `1.0`
+fn field_path(text: &str) -> Result<Vec<u32>, String> {
+    text.split('.').map(parse_number).collect()
+}
+
+// Collects the `key: value` pairs of the parenthesized groups, e.g. `(arg count: 1, function index: 0)`
+fn fields(text: &str) -> Result<HashMap<String, String>, String> {
+    let mut fields = HashMap::new();
+    for group in text.split('(') {
+        let group = group.trim().trim_end_matches(')');
+        for pair in group.split(", ").filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once(": ")
+                .ok_or_else(|| format!("Invalid operand '{pair}'"))?;
+            fields.insert(key.to_string(), value.to_string());
+        }
+    }
+    Ok(fields)
+}
+
+fn field<'a>(fields: &'a HashMap<String, String>, key: &str) -> Result<&'a str, String> {
+    fields
+        .get(key)
+        .map(String::as_str)
+        .ok_or_else(|| format!("Missing operand '{key}'"))
+}
+
+fn parse_number<T: std::str::FromStr>(text: &str) -> Result<T, String> {
+    text.parse().map_err(|_| format!("Invalid number '{text}'"))
+}
+
+fn query_components(text: &str, prefix: &str) -> Result<Vec<u32>, String> {
+    text.split(", ")
+        .filter(|component| !component.is_empty())
+        .map(|component| {
+            let id = component
+                .strip_prefix(prefix)
+                .map(|component| match component.split_once(" (") {
+                    Some((id, _)) => id,
+                    None => component,
+                })
+                .ok_or_else(|| format!("Invalid query component '{component}'"))?;
+            parse_number(id)
+        })
+        .collect()
+}
+
+fn binary_operation(name: &str) -> Result<ByteCode, String> {
+    (0..=u8::MAX)
+        .filter_map(|opcode| ByteCode::try_from(opcode).ok())
+        .find(|operation| {
+            is_binary_operation(*operation) && binary_operation_name(*operation) == name
+        })
+        .ok_or_else(|| format!("Unknown binary operation '{name}'"))
+}
+
+fn binary_instruction(operation: ByteCode) -> Instruction {
+    match operation {
+        ByteCode::IntegerAddition => Instruction::IntegerAddition,
+        ByteCode::IntegerSubtraction => Instruction::IntegerSubtraction,
+        ByteCode::IntegerDivision => Instruction::IntegerDivision,
+        ByteCode::IntegerMultiplication => Instruction::IntegerMultiplication,
+        ByteCode::IntegerModulo => Instruction::IntegerModulo,
+        ByteCode::IntegerLessThan => Instruction::IntegerLessThan,
+        ByteCode::IntegerLessThanEquals => Instruction::IntegerLessThanEquals,
+        ByteCode::FloatAddition => Instruction::FloatAddition,
+        ByteCode::FloatSubtraction => Instruction::FloatSubtraction,
+        ByteCode::FloatDivision => Instruction::FloatDivision,
+        ByteCode::FloatMultiplication => Instruction::FloatMultiplication,
+        ByteCode::FloatModulo => Instruction::FloatModulo,
+        ByteCode::FloatLessThan => Instruction::FloatLessThan,
+        ByteCode::FloatLessThanEquals => Instruction::FloatLessThanEquals,
+        ByteCode::StringConcat => Instruction::StringConcat,
+        ByteCode::BooleanAnd => Instruction::BooleanAnd,
+        ByteCode::BooleanOr => Instruction::BooleanOr,
+        ByteCode::Equals => Instruction::Equals,
+        _ => unreachable!("{operation:?} is not a binary operation"),
+    }
+}
+
+// Reverses the escaping done by the disassembler
+fn unescape(text: &str) -> Result<String, String> {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(char) = chars.next() {
+        if char != '\\' {
+            unescaped.push(char);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('\'') => unescaped.push('\''),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            other => {
+                return Err(format!(
+                    "Invalid escape sequence '\\{}'",
+                    other.unwrap_or(' ')
+                ));
+            }
+        }
+    }
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble_listing(function_position: &str) -> Result<BytecodeFile, String> {
+        let listing = format!(
+            "\
+Format Version: {FORMAT_VERSION}
+function signature (name: double) (local count: 1) (function position: {function_position})
+=== function chunk: main ===
+ret
+L0:
+=== function chunk: double ===
+ret
+"
+        );
+        assemble(&listing).and_then(|bytes| BytecodeFile::from_bytes(&bytes))
+    }
+
+    #[test]
+    fn resolves_function_positions() {
+        let file = assemble_listing("L0").unwrap();
+        assert_eq!(file.functions[0].position, 2);
+    }
+
+    #[test]
+    fn rejects_undefined_function_position() {
+        assert_eq!(
+            assemble_listing("L9").err(),
+            Some("Label 'L9' is not defined".to_string())
+        );
+    }
+}
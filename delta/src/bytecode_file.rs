@@ -0,0 +1,566 @@
+use std::collections::BTreeMap;
+
+use elements::{FieldLayout, world::ComponentEvent};
+
+use crate::instructions::add_varint;
+use crate::vm::Value;
+
+// On-disk format of a compiled program (a `.∆c` file):
+//
+//   magic           4 bytes: "∆c" in UTF-8
+//   format version  u16
+//   section count   u8
+//   section table   per section: kind (u8), offset (u32) and length (u32).
+//                   Offsets are relative to the start of the file.
+//   section data
+//
+// Multi-byte integers are big-endian, counts and string lengths are varints.
+// Strings used by the code are stored once in the constants section and referenced by their (varint) index.
+// Names that are only needed for debugging (components, fields and called functions) and the source line table
+// are kept in the optional debug info section, which release builds leave out.
+// Sections with an unknown kind are skipped, so optional sections can be added without breaking older runtimes.
+
+pub const MAGIC: [u8; 4] = *b"\xE2\x88\x86c";
+pub const FORMAT_VERSION: u16 = 14;
+
+const SECTION_TABLE_ENTRY_SIZE: usize = 1 + 4 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    Components = 1,
+    Functions = 2,
+    Code = 3,
+    DebugInfo = 4,
+    Constants = 5,
+    ForeignFunctions = 6,
+    Handlers = 7,  // only written when the program has handlers
+    Resources = 8, // only written when the program declares resources
+}
+
+impl TryFrom<u8> for SectionKind {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            value if value == SectionKind::Components as u8 => Ok(Self::Components),
+            value if value == SectionKind::Functions as u8 => Ok(Self::Functions),
+            value if value == SectionKind::Code as u8 => Ok(Self::Code),
+            value if value == SectionKind::DebugInfo as u8 => Ok(Self::DebugInfo),
+            value if value == SectionKind::Constants as u8 => Ok(Self::Constants),
+            value if value == SectionKind::ForeignFunctions as u8 => Ok(Self::ForeignFunctions),
+            value if value == SectionKind::Handlers as u8 => Ok(Self::Handlers),
+            value if value == SectionKind::Resources as u8 => Ok(Self::Resources),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ComponentEntry {
+    pub id: u32,
+    pub is_child_of: bool, // the built-in `ChildOf` relationship, which the world keeps a hierarchy of
+    pub fields: Vec<FieldLayout>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionEntry {
+    pub name: String,
+    pub local_count: u32,
+    pub position: u32, // relative to the start of the code section
+}
+
+// A host function called by the code. It is looked up by name in the host context when the file is loaded.
+#[derive(Debug, Clone)]
+pub struct ForeignFunctionEntry {
+    pub name: String,
+    pub arity: u32,
+}
+
+// A function that the VM calls with the entity and the component when the event happens to the component
+#[derive(Debug, Clone)]
+pub struct HandlerEntry {
+    pub event: ComponentEvent,
+    pub component: u32,
+    pub function: u32, // index into the function table
+}
+
+// A `∆` value declared by the program. The VM sets it to the default value unless it already has a value of the type.
+#[derive(Debug, Clone)]
+pub struct ResourceEntry {
+    pub name: String,
+    pub default: Value, // an integer, float or string
+}
+
+#[derive(Debug, Clone)]
+pub struct ComponentNames {
+    pub id: u32,
+    pub name: String,
+    pub field_names: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct DebugInfo {
+    pub components: Vec<ComponentNames>,
+    pub call_names: BTreeMap<u32, String>, // code position of a call => name of the called function
+    pub lines: BTreeMap<u32, u32>,         // code position => source line of the code from there on
+}
+
+impl DebugInfo {
+    pub fn component(&self, id: u32) -> Option<&ComponentNames> {
+        self.components.iter().find(|component| component.id == id)
+    }
+
+    // The source line of the instruction at the code position
+    pub fn line(&self, position: u32) -> Option<u32> {
+        self.lines
+            .range(..=position)
+            .next_back()
+            .map(|(_, line)| *line)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BytecodeFile {
+    pub components: Vec<ComponentEntry>,
+    pub functions: Vec<FunctionEntry>,
+    pub foreign_functions: Vec<ForeignFunctionEntry>,
+    pub handlers: Vec<HandlerEntry>,
+    pub resources: Vec<ResourceEntry>,
+    pub constants: Vec<String>,
+    pub code: Vec<u8>, // the main chunk followed by the function chunks
+    pub debug_info: Option<DebugInfo>, // left out of release builds
+}
+
+pub fn is_bytecode_file(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+impl BytecodeFile {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut components = Vec::new();
+        add_varint(&mut components, self.components.len() as u32);
+        for component in &self.components {
+            add_varint(&mut components, component.id);
+            components.push(component.is_child_of as u8);
+            add_varint(&mut components, component.fields.len() as u32);
+            for field in &component.fields {
+                components.push(field.type_id);
+                components.extend_from_slice(&field.size.to_be_bytes());
+                add_varint(&mut components, field.type_parameter);
+            }
+        }
+
+        let mut functions = Vec::new();
+        add_varint(&mut functions, self.functions.len() as u32);
+        for function in &self.functions {
+            add_string(&mut functions, &function.name);
+            add_varint(&mut functions, function.local_count);
+            functions.extend_from_slice(&function.position.to_be_bytes());
+        }
+
+        let mut foreign_functions = Vec::new();
+        add_varint(&mut foreign_functions, self.foreign_functions.len() as u32);
+        for foreign_function in &self.foreign_functions {
+            add_string(&mut foreign_functions, &foreign_function.name);
+            add_varint(&mut foreign_functions, foreign_function.arity);
+        }
+
+        let mut handlers = Vec::new();
+        add_varint(&mut handlers, self.handlers.len() as u32);
+        for handler in &self.handlers {
+            handlers.push(handler.event as u8);
+            add_varint(&mut handlers, handler.component);
+            add_varint(&mut handlers, handler.function);
+        }
+
+        let mut resources = Vec::new();
+        add_varint(&mut resources, self.resources.len() as u32);
+        for resource in &self.resources {
+            add_string(&mut resources, &resource.name);
+            add_resource_value(&mut resources, &resource.default);
+        }
+
+        let mut constants = Vec::new();
+        add_varint(&mut constants, self.constants.len() as u32);
+        for constant in &self.constants {
+            add_string(&mut constants, constant);
+        }
+
+        let mut sections = vec![
+            (SectionKind::Components, components.as_slice()),
+            (SectionKind::Functions, functions.as_slice()),
+            (SectionKind::ForeignFunctions, foreign_functions.as_slice()),
+            (SectionKind::Constants, constants.as_slice()),
+            (SectionKind::Code, self.code.as_slice()),
+        ];
+        if !self.handlers.is_empty() {
+            sections.push((SectionKind::Handlers, handlers.as_slice()));
+        }
+        if !self.resources.is_empty() {
+            sections.push((SectionKind::Resources, resources.as_slice()));
+        }
+        let debug_info = self.debug_info.as_ref().map(write_debug_info);
+        if let Some(debug_info) = &debug_info {
+            sections.push((SectionKind::DebugInfo, debug_info.as_slice()));
+        }
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        bytes.push(sections.len() as u8);
+
+        let mut offset = bytes.len() + sections.len() * SECTION_TABLE_ENTRY_SIZE;
+        for (kind, data) in &sections {
+            bytes.push(*kind as u8);
+            bytes.extend_from_slice(&(offset as u32).to_be_bytes());
+            bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            offset += data.len();
+        }
+        for (_, data) in &sections {
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if !is_bytecode_file(bytes) {
+            return Err("Not a bytecode file (missing magic bytes)".to_string());
+        }
+
+        let mut reader = ByteReader::new(&bytes[MAGIC.len()..]);
+        let version = reader.read_u16()?;
+        if version != FORMAT_VERSION {
+            return Err(format!(
+                "Unsupported bytecode format version {version} (expected {FORMAT_VERSION})"
+            ));
+        }
+
+        let mut file = BytecodeFile::default();
+        let mut has_components = false;
+        let mut has_functions = false;
+        let mut has_foreign_functions = false;
+        let mut has_constants = false;
+        let mut has_code = false;
+        let section_count = reader.read_byte()?;
+        for _ in 0..section_count {
+            let kind = reader.read_byte()?;
+            let offset = reader.read_u32()? as usize;
+            let length = reader.read_u32()? as usize;
+            let data = offset
+                .checked_add(length)
+                .and_then(|end| bytes.get(offset..end))
+                .ok_or_else(|| format!("Section {kind} is out of bounds"))?;
+
+            match SectionKind::try_from(kind) {
+                Ok(SectionKind::Components) => {
+                    file.components = read_components(data)?;
+                    has_components = true;
+                }
+                Ok(SectionKind::Functions) => {
+                    file.functions = read_functions(data)?;
+                    has_functions = true;
+                }
+                Ok(SectionKind::ForeignFunctions) => {
+                    file.foreign_functions = read_foreign_functions(data)?;
+                    has_foreign_functions = true;
+                }
+                Ok(SectionKind::Constants) => {
+                    file.constants = read_constants(data)?;
+                    has_constants = true;
+                }
+                Ok(SectionKind::Code) => {
+                    file.code = data.to_vec();
+                    has_code = true;
+                }
+                Ok(SectionKind::Handlers) => file.handlers = read_handlers(data)?,
+                Ok(SectionKind::Resources) => file.resources = read_resources(data)?,
+                Ok(SectionKind::DebugInfo) => file.debug_info = Some(read_debug_info(data)?),
+                Err(()) => (), // unknown, optional section
+            }
+        }
+
+        if !has_components
+            || !has_functions
+            || !has_foreign_functions
+            || !has_constants
+            || !has_code
+        {
+            return Err("Missing required section".to_string());
+        }
+
+        Ok(file)
+    }
+}
+
+fn read_components(data: &[u8]) -> Result<Vec<ComponentEntry>, String> {
+    let mut reader = ByteReader::new(data);
+    let component_count = reader.read_varint()?;
+    let mut components = Vec::new();
+    for _ in 0..component_count {
+        let id = reader.read_varint()?;
+        let is_child_of = match reader.read_byte()? {
+            0 => false,
+            1 => true,
+            flag => {
+                return Err(format!(
+                    "Invalid relationship flag {flag} of component {id}"
+                ));
+            }
+        };
+        let field_count = reader.read_varint()?;
+        let mut fields = Vec::new();
+        for _ in 0..field_count {
+            let type_id = reader.read_byte()?;
+            let size = reader.read_u16()?;
+            let type_parameter = reader.read_varint()?;
+            fields.push(FieldLayout {
+                name: String::new(), // field names are part of the debug info
+                type_id,
+                size,
+                type_parameter,
+            });
+        }
+        components.push(ComponentEntry {
+            id,
+            is_child_of,
+            fields,
+        });
+    }
+    Ok(components)
+}
+
+fn read_functions(data: &[u8]) -> Result<Vec<FunctionEntry>, String> {
+    let mut reader = ByteReader::new(data);
+    let function_count = reader.read_varint()?;
+    let mut functions = Vec::new();
+    for _ in 0..function_count {
+        let name = reader.read_string()?;
+        let local_count = reader.read_varint()?;
+        let position = reader.read_u32()?;
+        functions.push(FunctionEntry {
+            name,
+            local_count,
+            position,
+        });
+    }
+    Ok(functions)
+}
+
+fn read_handlers(data: &[u8]) -> Result<Vec<HandlerEntry>, String> {
+    let mut reader = ByteReader::new(data);
+    let handler_count = reader.read_varint()?;
+    let mut handlers = Vec::new();
+    for _ in 0..handler_count {
+        let event = match reader.read_byte()? {
+            event if event == ComponentEvent::Add as u8 => ComponentEvent::Add,
+            event if event == ComponentEvent::Remove as u8 => ComponentEvent::Remove,
+            event if event == ComponentEvent::Destroy as u8 => ComponentEvent::Destroy,
+            event => return Err(format!("Unknown handler event {event}")),
+        };
+        let component = reader.read_varint()?;
+        let function = reader.read_varint()?;
+        handlers.push(HandlerEntry {
+            event,
+            component,
+            function,
+        });
+    }
+    Ok(handlers)
+}
+
+fn read_resources(data: &[u8]) -> Result<Vec<ResourceEntry>, String> {
+    let mut reader = ByteReader::new(data);
+    let resource_count = reader.read_varint()?;
+    let mut resources = Vec::new();
+    for _ in 0..resource_count {
+        let name = reader.read_string()?;
+        let default = read_resource_value(&mut reader)?;
+        resources.push(ResourceEntry { name, default });
+    }
+    Ok(resources)
+}
+
+// Resource values are stored as their primitive type id (as in field layouts) followed by the value
+fn add_resource_value(bytes: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Integer(value) => {
+            bytes.push(1);
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        Value::Float(value) => {
+            bytes.push(2);
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        Value::String(value) => {
+            bytes.push(3);
+            add_string(bytes, value);
+        }
+        Value::Integer64(value) => {
+            bytes.push(4);
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        Value::Float64(value) => {
+            bytes.push(5);
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        _ => panic!("Unsupported resource value {value:?}"),
+    }
+}
+
+fn read_resource_value(reader: &mut ByteReader) -> Result<Value, String> {
+    match reader.read_byte()? {
+        1 => Ok(Value::Integer(reader.read_u32()? as i32)),
+        2 => Ok(Value::Float(f32::from_bits(reader.read_u32()?))),
+        3 => Ok(Value::String(reader.read_string()?)),
+        4 => Ok(Value::Integer64(i64::from_be_bytes(
+            reader.read_bytes(8)?.try_into().unwrap(),
+        ))),
+        5 => Ok(Value::Float64(f64::from_be_bytes(
+            reader.read_bytes(8)?.try_into().unwrap(),
+        ))),
+        type_id => Err(format!("Unsupported resource type id {type_id}")),
+    }
+}
+
+fn read_foreign_functions(data: &[u8]) -> Result<Vec<ForeignFunctionEntry>, String> {
+    let mut reader = ByteReader::new(data);
+    let foreign_function_count = reader.read_varint()?;
+    let mut foreign_functions = Vec::new();
+    for _ in 0..foreign_function_count {
+        let name = reader.read_string()?;
+        let arity = reader.read_varint()?;
+        foreign_functions.push(ForeignFunctionEntry { name, arity });
+    }
+    Ok(foreign_functions)
+}
+
+fn read_constants(data: &[u8]) -> Result<Vec<String>, String> {
+    let mut reader = ByteReader::new(data);
+    let constant_count = reader.read_varint()?;
+    let mut constants = Vec::new();
+    for _ in 0..constant_count {
+        constants.push(reader.read_string()?);
+    }
+    Ok(constants)
+}
+
+fn write_debug_info(debug_info: &DebugInfo) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    add_varint(&mut bytes, debug_info.components.len() as u32);
+    for component in &debug_info.components {
+        add_varint(&mut bytes, component.id);
+        add_string(&mut bytes, &component.name);
+        add_varint(&mut bytes, component.field_names.len() as u32);
+        for field_name in &component.field_names {
+            add_string(&mut bytes, field_name);
+        }
+    }
+
+    add_varint(&mut bytes, debug_info.call_names.len() as u32);
+    for (position, name) in &debug_info.call_names {
+        add_varint(&mut bytes, *position);
+        add_string(&mut bytes, name);
+    }
+
+    add_varint(&mut bytes, debug_info.lines.len() as u32);
+    for (position, line) in &debug_info.lines {
+        add_varint(&mut bytes, *position);
+        add_varint(&mut bytes, *line);
+    }
+    bytes
+}
+
+fn read_debug_info(data: &[u8]) -> Result<DebugInfo, String> {
+    let mut reader = ByteReader::new(data);
+    let mut debug_info = DebugInfo::default();
+    let component_count = reader.read_varint()?;
+    for _ in 0..component_count {
+        let id = reader.read_varint()?;
+        let name = reader.read_string()?;
+        let field_count = reader.read_varint()?;
+        let mut field_names = Vec::new();
+        for _ in 0..field_count {
+            field_names.push(reader.read_string()?);
+        }
+        debug_info.components.push(ComponentNames {
+            id,
+            name,
+            field_names,
+        });
+    }
+
+    let call_name_count = reader.read_varint()?;
+    for _ in 0..call_name_count {
+        let position = reader.read_varint()?;
+        let name = reader.read_string()?;
+        debug_info.call_names.insert(position, name);
+    }
+
+    let line_count = reader.read_varint()?;
+    for _ in 0..line_count {
+        let position = reader.read_varint()?;
+        let line = reader.read_varint()?;
+        debug_info.lines.insert(position, line);
+    }
+    Ok(debug_info)
+}
+
+pub fn add_string(bytes: &mut Vec<u8>, value: &str) {
+    add_varint(bytes, value.len() as u32);
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+// Bounds-checked reading of the header sections and, in the verifier, of the code section
+pub struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn read_bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + count)
+            .ok_or_else(|| "Unexpected end of bytecode".to_string())?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8, String> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_varint(&mut self) -> Result<u32, String> {
+        let mut value: u32 = 0;
+        for shift in (0..32).step_by(7) {
+            let byte = self.read_byte()?;
+            value |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("Malformed varint".to_string())
+    }
+
+    pub fn read_string(&mut self) -> Result<String, String> {
+        let length = self.read_varint()? as usize;
+        let bytes = self.read_bytes(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| "Invalid UTF-8 in string".to_string())
+    }
+}
@@ -1,6 +1,4 @@
-// TODO(anissen): Bytecode should be in a higher level format w. values to allow for an simple optimization phase. The format should know how to translate to/from actual bytes.
-// TODO: Serialized types ought to be serialized by index, but also include a name in debug mode
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ByteCode {
     IntegerAddition,
     IntegerSubtraction,
@@ -30,6 +28,7 @@ pub enum ByteCode {
 
     GetLocalValue,
     SetLocalValue,
+    GetLocalValueAndOperate,
 
     GetFieldValue,
     SetFieldValue,
@@ -40,7 +39,9 @@ pub enum ByteCode {
     PushTrue,
     PushFalse,
     PushFloat,
+    PushFloat64,
     PushInteger,
+    PushInteger64,
     PushString,
     PushList,
     PushComponent,
@@ -50,7 +51,6 @@ pub enum ByteCode {
     GetTagName,
     GetTagPayload,
 
-    FunctionSignature,
     FunctionChunk,
     Function,
     Return,
@@ -73,6 +73,8 @@ pub enum ByteCode {
     SetNextComponentColumnOrJump,
     Create,
     Destroy,
+    AddComponent,
+    RemoveComponent,
 }
 
 impl From<ByteCode> for u8 {
@@ -117,6 +119,9 @@ impl TryFrom<u8> for ByteCode {
 
             value if value == ByteCode::GetLocalValue as u8 => Ok(Self::GetLocalValue),
             value if value == ByteCode::SetLocalValue as u8 => Ok(Self::SetLocalValue),
+            value if value == ByteCode::GetLocalValueAndOperate as u8 => {
+                Ok(Self::GetLocalValueAndOperate)
+            }
 
             value if value == ByteCode::GetFieldValue as u8 => Ok(Self::GetFieldValue),
             value if value == ByteCode::SetFieldValue as u8 => Ok(Self::SetFieldValue),
@@ -127,7 +132,9 @@ impl TryFrom<u8> for ByteCode {
             value if value == ByteCode::PushTrue as u8 => Ok(Self::PushTrue),
             value if value == ByteCode::PushFalse as u8 => Ok(Self::PushFalse),
             value if value == ByteCode::PushFloat as u8 => Ok(Self::PushFloat),
+            value if value == ByteCode::PushFloat64 as u8 => Ok(Self::PushFloat64),
             value if value == ByteCode::PushInteger as u8 => Ok(Self::PushInteger),
+            value if value == ByteCode::PushInteger64 as u8 => Ok(Self::PushInteger64),
             value if value == ByteCode::PushString as u8 => Ok(Self::PushString),
             value if value == ByteCode::PushList as u8 => Ok(Self::PushList),
             value if value == ByteCode::PushComponent as u8 => Ok(Self::PushComponent),
@@ -142,7 +149,6 @@ impl TryFrom<u8> for ByteCode {
             value if value == ByteCode::GetArrayLength as u8 => Ok(Self::GetArrayLength),
             value if value == ByteCode::ArrayAppend as u8 => Ok(Self::ArrayAppend),
 
-            value if value == ByteCode::FunctionSignature as u8 => Ok(Self::FunctionSignature),
             value if value == ByteCode::FunctionChunk as u8 => Ok(Self::FunctionChunk),
             value if value == ByteCode::Function as u8 => Ok(Self::Function),
             value if value == ByteCode::Return as u8 => Ok(Self::Return),
@@ -163,11 +169,10 @@ impl TryFrom<u8> for ByteCode {
             }
             value if value == ByteCode::Create as u8 => Ok(Self::Create),
             value if value == ByteCode::Destroy as u8 => Ok(Self::Destroy),
+            value if value == ByteCode::AddComponent as u8 => Ok(Self::AddComponent),
+            value if value == ByteCode::RemoveComponent as u8 => Ok(Self::RemoveComponent),
 
-            _ => {
-                dbg!(value);
-                Err(())
-            }
+            _ => Err(()),
         }
     }
 }
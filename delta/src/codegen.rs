@@ -1,28 +1,36 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::bytecodes::ByteCode;
+use elements::{COMPONENT_TYPE_ID, FieldLayout, LIST_TYPE_ID, TAG_TYPE_ID, world::ComponentEvent};
+
+use crate::bytecode_file::{
+    BytecodeFile, ComponentEntry, ComponentNames, DebugInfo, ForeignFunctionEntry, FunctionEntry,
+    HandlerEntry, ResourceEntry,
+};
 use crate::diagnostics::Diagnostics;
-use crate::errors::Error;
 use crate::expressions::{
     ArithmeticOperations, BinaryOperator, BooleanOperations, Comparisons, EqualityOperations, Expr,
-    IsArm, IsArmPattern, MaybeNamedType, StringOperations, UnaryOperator, ValueType,
+    IsArm, IsArmPattern, MaybeNamedType, PropertyDefinition, PropertyType, StringOperations,
+    UnaryOperator, ValueType,
 };
+use crate::instructions::{ConstantPool, Instruction, Label, encode};
+use crate::optimizer::optimize;
 use crate::program::Context;
 use crate::tokens::{Position, Token};
 use crate::unification::Type;
+use crate::vm::Value;
 
 #[derive(Debug, Clone)]
 struct FunctionChunk<'a> {
-    local_count: u8,
-    bytes: Vec<u8>,
+    local_count: u32,
+    instructions: Vec<Instruction>,
     _position: &'a Position,
     function_name: String,
 }
 
 #[derive(Debug)]
 pub struct Scope {
-    bytecode: BytecodeBuilder,
-    environment: HashMap<String, u8>,
+    instructions: InstructionBuilder,
+    environment: HashMap<String, u32>,
     locals: HashSet<String>,
 
     /// Mapping local component variables to their component types (e.g. "p" => "Position")
@@ -32,7 +40,7 @@ pub struct Scope {
 impl Scope {
     fn new() -> Self {
         Self {
-            bytecode: BytecodeBuilder::new(),
+            instructions: InstructionBuilder::new(),
             environment: HashMap::new(),
             locals: HashSet::new(),
             local_component_mapping: HashMap::new(),
@@ -41,7 +49,7 @@ impl Scope {
 
     fn function(&mut self) -> Self {
         Self {
-            bytecode: BytecodeBuilder::new(),
+            instructions: InstructionBuilder::new(),
             environment: self.environment.clone(),
             locals: HashSet::new(),
             local_component_mapping: self.local_component_mapping.clone(),
@@ -51,34 +59,65 @@ impl Scope {
 
 #[derive(Debug)]
 struct ComponentMetadata<'a> {
-    id: u8,
+    id: u32,
     properties: &'a Vec<crate::expressions::PropertyDefinition>,
 }
 
 pub struct Codegen<'a> {
     function_chunks: Vec<FunctionChunk<'a>>,
     context: &'a Context<'a>,
+    foreign_functions: Vec<ForeignFunctionEntry>, // the host functions called by the program
+    handlers: Vec<HandlerEntry>,
+    resources: Vec<ResourceEntry>,
     components: HashMap<String, ComponentMetadata<'a>>, // TODO(anissen): Move into Environment
+    release: bool,                                      // leave out the debug info
     diagnostics: Diagnostics,
 }
 
-pub fn codegen<'a>(expression: &'a Expr, context: &'a Context<'a>) -> Result<Vec<u8>, Diagnostics> {
-    Codegen::new(context).emit(expression)
+// Release builds only contain what the VM needs to run the code. Debug builds also contain the names of
+// components, fields and called functions and a table of source lines.
+pub fn codegen<'a>(
+    expression: &'a Expr,
+    context: &'a Context<'a>,
+    release: bool,
+) -> Result<Vec<u8>, Diagnostics> {
+    Codegen::new(context, release).emit(expression)
 }
 
 // TODO(anissen): Add a function overview mapping for each scope containing { name, arity, starting IP, source line number  }.
 // This will be used directly in the VM as well as for debug logging.
 
 impl<'a> Codegen<'a> {
-    fn new(context: &'a Context<'a>) -> Self {
+    fn new(context: &'a Context<'a>, release: bool) -> Self {
         Self {
             function_chunks: vec![],
             context,
+            foreign_functions: Vec::new(),
+            handlers: Vec::new(),
+            resources: Vec::new(),
             components: HashMap::new(),
+            release,
             diagnostics: Diagnostics::new(),
         }
     }
 
+    // Index of the host function in the foreign function table of the bytecode
+    fn foreign_function_index(&mut self, name: &String) -> u32 {
+        if let Some(index) = self
+            .foreign_functions
+            .iter()
+            .position(|foreign| &foreign.name == name)
+        {
+            return index as u32;
+        }
+        let context_index = self.context.get_index(name).unwrap();
+        self.foreign_functions.push(ForeignFunctionEntry {
+            name: name.clone(),
+            arity: self.context.get_arity(context_index),
+        });
+        self.foreign_functions.len() as u32 - 1
+    }
+
     fn emit_exprs(&mut self, expressions: &'a Vec<Expr>, scope: &mut Scope) {
         for expr in expressions {
             self.emit_expr(expr, scope);
@@ -94,7 +133,14 @@ impl<'a> Codegen<'a> {
                 // Emit block with its own environment and locals
                 let locals = scope.locals.clone();
                 let environment = scope.environment.clone();
-                self.emit_exprs(exprs, scope);
+                // Lines are only marked at the start of statements, so they never split the instruction
+                // patterns that the optimizer looks for
+                for expr in exprs {
+                    if let Some(line) = source_line(expr) {
+                        scope.instructions.add_line(line);
+                    }
+                    self.emit_expr(expr, scope);
+                }
                 scope.locals = locals;
                 scope.environment = environment;
             }
@@ -103,17 +149,11 @@ impl<'a> Codegen<'a> {
                 let lexeme = &name.lexeme;
                 if self.context.has_value(lexeme) {
                     // TODO(anissen): Should (also) output index
-                    if lexeme.len() > 255 {
-                        self.diagnostics.add_error(Error::FunctionNameTooLong {
-                            token: name.clone(),
-                        });
-                    }
-                    scope
-                        .bytecode
-                        .add_op(ByteCode::GetForeignValue)
-                        .add_string(lexeme);
+                    scope.instructions.add(Instruction::GetForeignValue {
+                        name: lexeme.clone(),
+                    });
                 } else if let Some(index) = scope.environment.get(lexeme) {
-                    scope.bytecode.add_get_local_value(*index);
+                    scope.instructions.add_get_local_value(*index);
                 } else {
                     // TODO(anissen): Move into Resolver
                     panic!("Name not found in scope");
@@ -121,20 +161,20 @@ impl<'a> Codegen<'a> {
             }
 
             Expr::ContextIdentifier { context: _, name } => {
-                scope.bytecode.add_get_context_value(&name.lexeme);
+                scope.instructions.add_get_context_value(&name.lexeme);
             }
 
             Expr::FieldAccess {
                 identifier,
-                field_name,
+                field_names,
             } => {
                 let lexeme = &identifier.lexeme;
                 if let Some(index) = scope.environment.get(lexeme) {
-                    let field_access_index =
-                        self.get_component_field_index(scope, identifier, field_name);
+                    let (component, field_path) =
+                        self.get_component_field_path(scope, identifier, field_names);
                     scope
-                        .bytecode
-                        .add_get_field_value(*index, field_access_index as u8);
+                        .instructions
+                        .add_get_field_value(*index, component, field_path);
                 } else {
                     // TODO(anissen): Move into Resolver
                     dbg!(&identifier);
@@ -150,12 +190,34 @@ impl<'a> Codegen<'a> {
                 self.components.insert(
                     name.lexeme.clone(),
                     ComponentMetadata {
-                        id: self.components.len() as u8,
+                        id: self.components.len() as u32,
                         properties,
                     },
                 );
             }
 
+            Expr::ResourceDefinition {
+                name,
+                type_: _,
+                default,
+            } => {
+                let default = match **default {
+                    Expr::Value { ref value, .. } => resource_value(value),
+                    _ => panic!("Resource default must be a literal"),
+                };
+                self.resources.push(ResourceEntry {
+                    name: name.lexeme.clone(),
+                    default,
+                });
+            }
+
+            Expr::Handler {
+                event,
+                entity,
+                component,
+                expr,
+            } => self.emit_handler(event, entity.as_ref(), component, expr, scope),
+
             Expr::Call { name, args } => {
                 let lexeme = &name.lexeme;
                 let arg_count = args.len();
@@ -163,34 +225,30 @@ impl<'a> Codegen<'a> {
 
                 // TODO(anissen): Hack
                 if lexeme == "get_list_element_at_index" {
-                    scope.bytecode.add_op(ByteCode::GetListElementAtIndex);
+                    scope.instructions.add(Instruction::GetListElementAtIndex);
                 } else if lexeme == "get_array_length" {
-                    scope.bytecode.add_op(ByteCode::GetArrayLength);
+                    scope.instructions.add(Instruction::GetArrayLength);
                 } else if lexeme == "append" {
-                    scope.bytecode.add_op(ByteCode::ArrayAppend);
+                    scope.instructions.add(Instruction::ArrayAppend);
                 } else if lexeme == "log" {
-                    scope.bytecode.add_op(ByteCode::Log);
+                    scope.instructions.add(Instruction::Log);
                 } else {
                     if self.context.has_function(lexeme) {
                         // TODO(anissen): Maybe this should be its own Expr instead?
-                        scope
-                            .bytecode
-                            .add_op(ByteCode::CallForeign)
-                            .add_byte(self.context.get_index(lexeme))
-                            .add_byte(arg_count as u8);
+                        let foreign_index = self.foreign_function_index(lexeme);
+                        scope.instructions.add(Instruction::CallForeign {
+                            foreign_index,
+                            arg_count: arg_count as u32,
+                        });
                     } else {
                         match scope.environment.get(lexeme) {
                             Some(index) => {
-                                scope
-                                    .bytecode
-                                    .add_op(ByteCode::Call)
-                                    .add_byte(arg_count as u8);
-                                if scope.locals.contains(lexeme) {
-                                    scope.bytecode.add_byte(0);
-                                } else {
-                                    scope.bytecode.add_byte(1);
-                                }
-                                scope.bytecode.add_byte(*index);
+                                scope.instructions.add(Instruction::Call {
+                                    arg_count: arg_count as u32,
+                                    is_global: !scope.locals.contains(lexeme),
+                                    index: *index,
+                                    name: Some(lexeme.clone()),
+                                });
                             }
                             None => {
                                 // TODO(anissen): Move into Resolver
@@ -198,12 +256,6 @@ impl<'a> Codegen<'a> {
                             }
                         }
                     };
-
-                    if lexeme.len() > 255 {
-                        panic!("function name too long!");
-                        // let msg = Message::new(format!("Function name too long: {}", name), ;
-                    }
-                    scope.bytecode.add_string(lexeme);
                 };
             }
 
@@ -218,20 +270,21 @@ impl<'a> Codegen<'a> {
                     ref name,
                 } => {
                     self.emit_expr(expr, scope);
-                    scope.bytecode.add_set_context_value(&name.lexeme);
+                    scope.instructions.add_set_context_value(&name.lexeme);
                 }
                 Expr::FieldAccess {
                     ref identifier,
-                    ref field_name,
+                    ref field_names,
                 } => {
                     self.emit_expr(expr, scope);
                     let index = scope.environment.get(&identifier.lexeme).unwrap();
-                    let field_index = self.get_component_field_index(scope, identifier, field_name);
-                    scope
-                        .bytecode
-                        .add_op(ByteCode::SetFieldValue)
-                        .add_byte(*index)
-                        .add_byte(field_index as u8);
+                    let (component, field_path) =
+                        self.get_component_field_path(scope, identifier, field_names);
+                    scope.instructions.add(Instruction::SetFieldValue {
+                        index: *index,
+                        component,
+                        field_path,
+                    });
                 }
 
                 _ => panic!("Invalid assignment target"),
@@ -244,11 +297,11 @@ impl<'a> Codegen<'a> {
             } => match operator {
                 UnaryOperator::Negation => {
                     self.emit_expr(expr, scope);
-                    scope.bytecode.add_op(ByteCode::Negation);
+                    scope.instructions.add(Instruction::Negation);
                 }
                 UnaryOperator::Not => {
                     self.emit_expr(expr, scope);
-                    scope.bytecode.add_op(ByteCode::Not);
+                    scope.instructions.add(Instruction::Not);
                 }
             },
 
@@ -259,82 +312,117 @@ impl<'a> Codegen<'a> {
                 right,
             } => self.emit_binary(left, operator, right, scope),
 
-            Expr::Is { token: _, expr, arms } => self.emit_is(expr, arms, scope),
-
-            Expr::Query {
-                include_components: include_compoments,
-                exclude_components: exclude_compoments,
+            Expr::Is {
+                token: _,
                 expr,
-            } => self.emit_query(include_compoments, exclude_compoments, expr, scope),
+                arms,
+            } => self.emit_is(expr, arms, scope),
+
+            Expr::Query { .. } => self.emit_query(expr, scope),
 
             Expr::Create {
                 token: _,
                 arguments,
             } => {
                 self.emit_expr(arguments, scope);
-                scope.bytecode.add_op(ByteCode::Create);
+                scope.instructions.add(Instruction::Create);
             }
 
             Expr::Destroy { token: _, argument } => {
                 self.emit_expr(argument, scope);
-                scope.bytecode.add_op(ByteCode::Destroy);
+                scope.instructions.add(Instruction::Destroy);
+            }
+
+            Expr::AddComponent {
+                token: _,
+                entity,
+                component,
+            } => {
+                self.emit_expr(entity, scope);
+                self.emit_expr(component, scope);
+                scope.instructions.add(Instruction::AddComponent);
+            }
+
+            Expr::RemoveComponent {
+                token: _,
+                entity,
+                component,
+            } => {
+                self.emit_expr(entity, scope);
+                let id = self.components.get(&component.lexeme).unwrap().id;
+                scope.instructions.add(Instruction::RemoveComponent { id });
             }
         };
     }
 
-    fn get_component_field_index(
+    // The id of the bound component and the indices of the fields along the path, each field being a nested component
+    // of the previous one
+    fn get_component_field_path(
         &mut self,
         scope: &Scope,
         component_identifier: &Token,
-        field_name: &Token,
-    ) -> usize {
-        let component_name = scope
+        field_names: &[Token],
+    ) -> (u32, Vec<u32>) {
+        let mut component_name = scope
             .local_component_mapping
             .get(&component_identifier.lexeme)
             .unwrap();
-        let component_properties = self.components.get(&component_name.lexeme).unwrap();
+        let component = self.components.get(&component_name.lexeme).unwrap().id;
 
-        component_properties
-            .properties
+        let field_path = field_names
             .iter()
-            .enumerate()
-            .find(|(_, field)| field.name.lexeme == field_name.lexeme)
-            .map(|field_index| field_index.0)
-            .unwrap()
+            .map(|field_name| {
+                let component_properties = self.components.get(&component_name.lexeme).unwrap();
+                let (field_index, field) = component_properties
+                    .properties
+                    .iter()
+                    .enumerate()
+                    .find(|(_, field)| field.name.lexeme == field_name.lexeme)
+                    .unwrap();
+                if let PropertyType::Component(nested_name) = &field.type_ {
+                    component_name = nested_name;
+                }
+                field_index as u32
+            })
+            .collect();
+        (component, field_path)
     }
 
     fn emit_value(&mut self, value: &'a ValueType, token: &'a Token, scope: &mut Scope) {
         match value {
             ValueType::Boolean(true) => {
-                scope.bytecode.add_op(ByteCode::PushTrue);
+                scope.instructions.add(Instruction::PushTrue);
             }
 
             ValueType::Boolean(false) => {
-                scope.bytecode.add_op(ByteCode::PushFalse);
+                scope.instructions.add(Instruction::PushFalse);
             }
 
             ValueType::Integer(i) => {
-                scope.bytecode.add_op(ByteCode::PushInteger).add_i32(i);
+                scope.instructions.add(Instruction::PushInteger(*i));
+            }
+
+            ValueType::Integer64(i) => {
+                scope.instructions.add(Instruction::PushInteger64(*i));
+            }
+
+            ValueType::Float64(f) => {
+                scope.instructions.add(Instruction::PushFloat64(*f));
             }
 
             ValueType::Float(f) => {
-                scope.bytecode.add_op(ByteCode::PushFloat).add_f32(f);
+                scope.instructions.add(Instruction::PushFloat(*f));
             }
 
             ValueType::List(exprs) => {
                 self.emit_exprs(exprs, scope);
-                scope
-                    .bytecode
-                    .add_op(ByteCode::PushList)
-                    .add_i32(&(exprs.len() as i32));
+                scope.instructions.add(Instruction::PushList {
+                    length: exprs.len() as u32,
+                });
             }
 
             ValueType::String(str) => {
-                if str.len() > 255 {
-                    // TODO(anissen): Should add error to a error reporter instead
-                    panic!("string too long!");
-                }
-                scope.bytecode.add_op(ByteCode::PushString).add_string(str);
+                scope.instructions.add(Instruction::PushString(str.clone()));
             }
 
             ValueType::Function { params, expr } => {
@@ -342,20 +430,15 @@ impl<'a> Codegen<'a> {
             }
 
             ValueType::Tag { name, payload } => {
-                if name.lexeme.len() > 255 {
-                    panic!("string too long!");
-                }
                 if let Some(payload) = payload {
                     self.emit_expr(payload, scope);
-                    scope
-                        .bytecode
-                        .add_op(ByteCode::PushTag)
-                        .add_string(&name.lexeme);
+                    scope.instructions.add(Instruction::PushTag {
+                        name: name.lexeme.clone(),
+                    });
                 } else {
-                    scope
-                        .bytecode
-                        .add_op(ByteCode::PushSimpleTag)
-                        .add_string(&name.lexeme);
+                    scope.instructions.add(Instruction::PushSimpleTag {
+                        name: name.lexeme.clone(),
+                    });
                 };
             }
 
@@ -374,11 +457,10 @@ impl<'a> Codegen<'a> {
                     });
 
                 let component_id = self.components.get(&name.lexeme).unwrap().id;
-                scope
-                    .bytecode
-                    .add_op(ByteCode::PushComponent)
-                    .add_byte(component_id)
-                    .add_byte(properties.len() as u8);
+                scope.instructions.add(Instruction::PushComponent {
+                    id: component_id,
+                    property_count: properties.len() as u32,
+                });
             }
         }
     }
@@ -394,71 +476,81 @@ impl<'a> Codegen<'a> {
         self.emit_expr(right, scope);
         match operator {
             BinaryOperator::IntegerOperation(integer_operation) => match integer_operation {
-                ArithmeticOperations::Addition => scope.bytecode.add_op(ByteCode::IntegerAddition),
+                ArithmeticOperations::Addition => {
+                    scope.instructions.add(Instruction::IntegerAddition)
+                }
                 ArithmeticOperations::Subtraction => {
-                    scope.bytecode.add_op(ByteCode::IntegerSubtraction)
+                    scope.instructions.add(Instruction::IntegerSubtraction)
                 }
                 ArithmeticOperations::Multiplication => {
-                    scope.bytecode.add_op(ByteCode::IntegerMultiplication)
+                    scope.instructions.add(Instruction::IntegerMultiplication)
                 }
-                ArithmeticOperations::Division => scope.bytecode.add_op(ByteCode::IntegerDivision),
-                ArithmeticOperations::Modulus => scope.bytecode.add_op(ByteCode::IntegerModulo),
+                ArithmeticOperations::Division => {
+                    scope.instructions.add(Instruction::IntegerDivision)
+                }
+                ArithmeticOperations::Modulus => scope.instructions.add(Instruction::IntegerModulo),
             },
             BinaryOperator::FloatOperation(float_operation) => match float_operation {
-                ArithmeticOperations::Addition => scope.bytecode.add_op(ByteCode::FloatAddition),
+                ArithmeticOperations::Addition => {
+                    scope.instructions.add(Instruction::FloatAddition)
+                }
                 ArithmeticOperations::Subtraction => {
-                    scope.bytecode.add_op(ByteCode::FloatSubtraction)
+                    scope.instructions.add(Instruction::FloatSubtraction)
                 }
                 ArithmeticOperations::Multiplication => {
-                    scope.bytecode.add_op(ByteCode::FloatMultiplication)
+                    scope.instructions.add(Instruction::FloatMultiplication)
+                }
+                ArithmeticOperations::Division => {
+                    scope.instructions.add(Instruction::FloatDivision)
                 }
-                ArithmeticOperations::Division => scope.bytecode.add_op(ByteCode::FloatDivision),
-                ArithmeticOperations::Modulus => scope.bytecode.add_op(ByteCode::FloatModulo),
+                ArithmeticOperations::Modulus => scope.instructions.add(Instruction::FloatModulo),
             },
             BinaryOperator::BooleanOperation(boolean_operation) => match boolean_operation {
-                BooleanOperations::And => scope.bytecode.add_op(ByteCode::BooleanAnd),
-                BooleanOperations::Or => scope.bytecode.add_op(ByteCode::BooleanOr),
+                BooleanOperations::And => scope.instructions.add(Instruction::BooleanAnd),
+                BooleanOperations::Or => scope.instructions.add(Instruction::BooleanOr),
             },
             BinaryOperator::StringOperation(string_operation) => match string_operation {
-                StringOperations::StringConcat => scope.bytecode.add_op(ByteCode::StringConcat),
+                StringOperations::StringConcat => scope.instructions.add(Instruction::StringConcat),
             },
             BinaryOperator::IntegerComparison(integer_comparison) => match integer_comparison {
-                Comparisons::LessThan => scope.bytecode.add_op(ByteCode::IntegerLessThan),
+                Comparisons::LessThan => scope.instructions.add(Instruction::IntegerLessThan),
                 Comparisons::LessThanEqual => {
-                    scope.bytecode.add_op(ByteCode::IntegerLessThanEquals)
+                    scope.instructions.add(Instruction::IntegerLessThanEquals)
                 }
                 Comparisons::GreaterThan => scope
-                    .bytecode
-                    .add_op(ByteCode::IntegerLessThanEquals)
-                    .add_op(ByteCode::Not),
+                    .instructions
+                    .add(Instruction::IntegerLessThanEquals)
+                    .add(Instruction::Not),
                 Comparisons::GreaterThanEqual => scope
-                    .bytecode
-                    .add_op(ByteCode::IntegerLessThan)
-                    .add_op(ByteCode::Not),
+                    .instructions
+                    .add(Instruction::IntegerLessThan)
+                    .add(Instruction::Not),
             },
             BinaryOperator::FloatComparison(float_comparison) => match float_comparison {
-                Comparisons::LessThan => scope.bytecode.add_op(ByteCode::FloatLessThan),
-                Comparisons::LessThanEqual => scope.bytecode.add_op(ByteCode::FloatLessThanEquals),
+                Comparisons::LessThan => scope.instructions.add(Instruction::FloatLessThan),
+                Comparisons::LessThanEqual => {
+                    scope.instructions.add(Instruction::FloatLessThanEquals)
+                }
                 Comparisons::GreaterThan => scope
-                    .bytecode
-                    .add_op(ByteCode::FloatLessThanEquals)
-                    .add_op(ByteCode::Not),
+                    .instructions
+                    .add(Instruction::FloatLessThanEquals)
+                    .add(Instruction::Not),
                 Comparisons::GreaterThanEqual => scope
-                    .bytecode
-                    .add_op(ByteCode::FloatLessThan)
-                    .add_op(ByteCode::Not),
+                    .instructions
+                    .add(Instruction::FloatLessThan)
+                    .add(Instruction::Not),
             },
             BinaryOperator::Equality(equality) => match equality {
-                EqualityOperations::Equal => scope.bytecode.add_op(ByteCode::Equals),
+                EqualityOperations::Equal => scope.instructions.add(Instruction::Equals),
                 EqualityOperations::NotEqual => scope
-                    .bytecode
-                    .add_op(ByteCode::Equals)
-                    .add_op(ByteCode::Not),
+                    .instructions
+                    .add(Instruction::Equals)
+                    .add(Instruction::Not),
             },
         };
     }
 
-    fn emit_is(&mut self, expr: &'a Expr, arms: &'a Vec<IsArm>, scope: &mut Scope) {
+    fn emit_is(&mut self, expr: &'a Expr, arms: &'a [IsArm], scope: &mut Scope) {
         let index = match *expr {
             Expr::Identifier { ref name } => {
                 // If the value is already in the environment, use its index
@@ -469,53 +561,61 @@ impl<'a> Codegen<'a> {
                 // Otherwise, emit the expression and add it to the locals
                 // to avoid emitting the same value multiple times
                 self.emit_expr(expr, scope);
-                let index = scope.locals.len() as u8;
-                scope.bytecode.add_set_local_value(index);
+                let index = scope.locals.len() as u32;
+                scope.instructions.add_set_local_value(index);
                 index
             }
         };
 
-        let locals_count = scope.locals.len() as u8;
+        let locals_count = scope.locals.len() as u32;
 
-        let mut jump_to_end_offsets = vec![];
+        let mut jump_to_end_labels = vec![];
 
         for (arm_index, arm) in arms.iter().enumerate() {
             let is_last_arm = arm_index == arms.len() - 1;
 
             // Handle pattern matching logic
-            let mut pattern_jump_offsets = vec![];
+            let mut pattern_jump_labels = vec![];
 
             match &arm.pattern {
                 IsArmPattern::Expression(pattern) => {
                     // Emit expression and pattern and compare
-                    scope.bytecode.add_get_local_value(index);
+                    scope.instructions.add_get_local_value(index);
                     self.emit_expr(pattern, scope);
-                    scope.bytecode.add_op(ByteCode::Equals);
+                    scope.instructions.add(Instruction::Equals);
 
                     // Jump to next arm if not equal
-                    let next_arm_offset = scope.bytecode.add_jump_if_false();
-                    pattern_jump_offsets.push(next_arm_offset);
+                    let next_arm_label = scope.instructions.add_jump_if_false();
+                    pattern_jump_labels.push(next_arm_label);
                 }
                 IsArmPattern::Capture { identifier } => {
                     scope.environment.insert(identifier.lexeme.clone(), index);
                     scope.locals.insert(identifier.lexeme.clone());
+                    // A capture of a (narrowed) optional component is bound to the same component
+                    if let Expr::Identifier { ref name } = *expr
+                        && let Some(component) = scope.local_component_mapping.get(&name.lexeme)
+                    {
+                        let component = component.clone();
+                        scope
+                            .local_component_mapping
+                            .insert(identifier.lexeme.clone(), component);
+                    }
                 }
                 IsArmPattern::CaptureTagPayload {
                     tag_name,
                     identifier,
                 } => {
                     scope
-                        .bytecode
-                        .add_op(ByteCode::GetTagName)
-                        .add_op(ByteCode::PushString)
-                        .add_string(&tag_name.lexeme)
-                        .add_op(ByteCode::Equals);
+                        .instructions
+                        .add(Instruction::GetTagName)
+                        .add(Instruction::PushString(tag_name.lexeme.clone()))
+                        .add(Instruction::Equals);
 
                     // Jump to next arm if not equal
-                    let next_arm_offset = scope.bytecode.add_jump_if_false();
-                    pattern_jump_offsets.push(next_arm_offset);
+                    let next_arm_label = scope.instructions.add_jump_if_false();
+                    pattern_jump_labels.push(next_arm_label);
 
-                    scope.bytecode.add_op(ByteCode::GetTagPayload);
+                    scope.instructions.add(Instruction::GetTagPayload);
 
                     // TODO: Is this right?!?
                     scope
@@ -532,8 +632,8 @@ impl<'a> Codegen<'a> {
             if let Some(guard) = &arm.guard {
                 // Check if-guard
                 self.emit_expr(&guard.condition, scope);
-                let guard_jump_offset = scope.bytecode.add_jump_if_false();
-                pattern_jump_offsets.push(guard_jump_offset);
+                let guard_jump_label = scope.instructions.add_jump_if_false();
+                pattern_jump_labels.push(guard_jump_label);
             }
 
             // Execute arm block
@@ -541,45 +641,42 @@ impl<'a> Codegen<'a> {
 
             if !is_last_arm {
                 // Jump to end of `is` block
-                let end_offset = scope.bytecode.add_unconditional_jump();
-                jump_to_end_offsets.push(end_offset);
+                let end_label = scope.instructions.add_unconditional_jump();
+                jump_to_end_labels.push(end_label);
             }
 
             // Patch all jumps to next arm now that we know the position
-            for offset in pattern_jump_offsets {
-                scope.bytecode.patch_jump_to_current_byte(offset);
+            for label in pattern_jump_labels {
+                scope.instructions.add_label(label);
             }
         }
 
         // Patch all jumps to end of `is` block now that we know where it ends
-        for offset in jump_to_end_offsets {
-            scope.bytecode.patch_jump_to_current_byte(offset);
+        for label in jump_to_end_labels {
+            scope.instructions.add_label(label);
         }
     }
 
-    fn emit_query(
-        &mut self,
-        include_components: &Vec<MaybeNamedType>,
-        exclude_components: &Vec<Token>,
-        expr: &'a Expr,
-        scope: &mut Scope,
-    ) {
-        let query_end_offset = scope
-            .bytecode
-            .add_op(ByteCode::ContextQuery)
-            .get_patchable_i16_offset();
-
+    fn emit_query(&mut self, query: &'a Expr, scope: &mut Scope) {
+        let Expr::Query {
+            include_components,
+            optional_components,
+            exclude_components,
+            added_components,
+            changed_components,
+            parent,
+            expr,
+        } = query
+        else {
+            panic!("Expected a query");
+        };
         let (entity_components, include_components): (Vec<&MaybeNamedType>, Vec<&MaybeNamedType>) =
             include_components
                 .iter()
                 .partition(|component| component.type_.lexeme == "Entity");
 
-        scope.bytecode.add_byte(include_components.len() as u8);
-        scope.bytecode.add_byte(exclude_components.len() as u8);
-
         let old_environment = scope.environment.clone();
         let old_locals = scope.locals.clone();
-        scope.locals.clear();
         let old_local_component_mapping = scope.local_component_mapping.clone();
 
         let mut sorted_includes = include_components.iter().collect::<Vec<_>>();
@@ -590,42 +687,42 @@ impl<'a> Codegen<'a> {
             component_id_a.cmp(&component_id_b)
         });
 
-        let mut component_variable_index = 1; // Entity always exists at index 0
+        let mut sorted_optionals = optional_components.iter().collect::<Vec<_>>();
+        sorted_optionals
+            .sort_by_key(|component| self.components.get(&component.type_.lexeme).unwrap().id);
 
-        if let Some(entity_component) = entity_components.first()
-            && let Some(ref name) = entity_component.name
-        {
-            let lexeme = name.lexeme.clone();
-            scope.environment.insert(lexeme.clone(), 0);
-            scope.locals.insert(lexeme.clone());
-            scope
-                .local_component_mapping
-                .insert(lexeme.clone(), entity_component.type_.clone());
-        }
+        let mut query_include_components = Vec::new();
+        let mut query_optional_components = Vec::new();
+        let mut query_exclude_components = Vec::new();
+
+        // The entity and its components are bound to the next locals of the enclosing frame, so the query body
+        // (and nested queries) can still access the locals and bindings around it
+        let local_index = scope.locals.len() as u32;
+        let entity_component = entity_components.first().unwrap(); // added by the parser if missing
+        bind_query_local(
+            scope,
+            entity_component.name.as_ref(),
+            &entity_component.type_,
+        );
 
         sorted_includes.iter().for_each(|component| {
-            let component_type_name = component.type_.lexeme.clone();
-
-            let component_id = self.components.get(&component_type_name).unwrap().id;
-            scope
-                .bytecode
-                .add_byte(component_id)
-                .add_string(&component_type_name);
-
-            if let Some(ref name) = component.name {
-                let lexeme = name.lexeme.clone();
-                scope
-                    .environment
-                    .insert(lexeme.clone(), component_variable_index);
-                component_variable_index += 1;
-                scope.locals.insert(lexeme.clone());
-                scope
-                    .local_component_mapping
-                    .insert(lexeme.clone(), component.type_.clone());
+            let metadata = self.components.get(&component.type_.lexeme).unwrap();
+            query_include_components.push(metadata.id);
+
+            // Markers have no values to bind
+            if !metadata.properties.is_empty() {
+                bind_query_local(scope, component.name.as_ref(), &component.type_);
             }
         });
 
-        let mut sorted_excludes = exclude_components.clone();
+        // Optional components are bound after the included components
+        sorted_optionals.iter().for_each(|component| {
+            let component_id = self.components.get(&component.type_.lexeme).unwrap().id;
+            query_optional_components.push(component_id);
+            bind_query_local(scope, component.name.as_ref(), &component.type_);
+        });
+
+        let mut sorted_excludes = exclude_components.to_vec();
         sorted_excludes.sort_by(|a, b| {
             let component_id_a = self.components.get(&a.lexeme).unwrap().id;
             let component_id_b = self.components.get(&b.lexeme).unwrap().id;
@@ -634,29 +731,53 @@ impl<'a> Codegen<'a> {
         });
 
         sorted_excludes.iter().for_each(|component| {
-            let component_type_name = component.lexeme.clone();
-
-            let component_id = self.components.get(&component_type_name).unwrap().id;
-            scope
-                .bytecode
-                .add_byte(component_id)
-                .add_string(&component_type_name);
+            let component_id = self.components.get(&component.lexeme).unwrap().id;
+            query_exclude_components.push(component_id);
         });
 
+        // The filtered components are also included, and thereby bound, above
+        let filter_ids = |components: &[Token]| {
+            let mut ids = components
+                .iter()
+                .map(|component| self.components.get(&component.lexeme).unwrap().id)
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        let query_added_components = filter_ids(added_components);
+        let query_changed_components = filter_ids(changed_components);
+
         /*
-        context_query (queries components and jumps to :end if empty, pushes query frame otherwise)
+        context_query (queries components and jumps to :end if empty, pushes query state otherwise)
         :start
-        set_next_component_column_or_jump (set/replaces components or pops query frame when done)
+        set_next_component_column_or_jump (sets the bindings or pops query state and jumps to :end when done)
         [expr]
         jump_to_label start
         :end
         */
 
-        let start_label = scope.bytecode.bytes.len() as i16;
+        // The parent is evaluated before the query, which pops it
+        if let Some(parent) = parent {
+            self.emit_expr(parent, scope);
+        }
+
+        let start_label = scope.instructions.new_label();
+        let end_label = scope.instructions.new_label();
 
         scope
-            .bytecode
-            .add_op(ByteCode::SetNextComponentColumnOrJump);
+            .instructions
+            .add(Instruction::ContextQuery {
+                end: end_label,
+                local_index,
+                has_parent: parent.is_some(),
+                include_components: query_include_components,
+                optional_components: query_optional_components,
+                exclude_components: query_exclude_components,
+                added_components: query_added_components,
+                changed_components: query_changed_components,
+            })
+            .add_label(start_label)
+            .add(Instruction::SetNextComponentColumnOrJump);
 
         self.emit_expr(expr, scope);
         scope.locals = old_locals;
@@ -664,12 +785,10 @@ impl<'a> Codegen<'a> {
         scope.local_component_mapping = old_local_component_mapping;
 
         // Unconditional jump to start label
-        scope.bytecode.add_op(ByteCode::Jump);
-        let start_offset =
-            start_label - (scope.bytecode.bytes.len() as i16 + 2/* jump offset bytes */);
-        scope.bytecode.add_i16(&start_offset);
-
-        scope.bytecode.patch_jump_to_current_byte(query_end_offset);
+        scope
+            .instructions
+            .add(Instruction::Jump(start_label))
+            .add_label(end_label);
     }
 
     fn emit_assignment(&mut self, name: &Token, expr: &'a Expr, scope: &mut Scope) {
@@ -679,21 +798,21 @@ impl<'a> Codegen<'a> {
                 token,
             } => {
                 // save function name to environment before entering function definition
-                let index = scope.locals.len() as u8;
+                let index = scope.locals.len() as u32;
                 scope.environment.insert(name.lexeme.clone(), index);
                 scope.locals.insert(name.lexeme.clone());
 
                 self.emit_function(token, Some(name), params, expr, scope);
-                scope.bytecode.add_set_local_value(index);
+                scope.instructions.add_set_local_value(index);
             }
 
             _ => {
                 self.emit_expr(expr, scope);
 
-                let index = scope.locals.len() as u8;
+                let index = scope.locals.len() as u32;
                 scope.environment.insert(name.lexeme.clone(), index);
                 scope.locals.insert(name.lexeme.clone());
-                scope.bytecode.add_set_local_value(index);
+                scope.instructions.add_set_local_value(index);
             }
         }
     }
@@ -706,19 +825,66 @@ impl<'a> Codegen<'a> {
         body: &'a Expr,
         scope: &mut Scope,
     ) {
-        if params.len() > u8::MAX.into() {
-            panic!("Too many parameters");
-        }
-
-        scope
-            .bytecode
-            .add_op(ByteCode::Function)
-            .add_byte(self.function_chunks.len() as u8)
-            .add_byte(params.len() as u8);
+        scope.instructions.add(Instruction::Function {
+            function_index: self.function_chunks.len() as u32,
+            param_count: params.len() as u32,
+        });
 
         self.create_function_chunk(name, &slash.position, params, body, &mut scope.function());
     }
 
+    // Handlers are compiled to functions of the entity and the component, which are called by the VM rather than by
+    // the code
+    fn emit_handler(
+        &mut self,
+        event: &'a Token,
+        entity: Option<&Token>,
+        component: &MaybeNamedType,
+        body: &'a Expr,
+        scope: &mut Scope,
+    ) {
+        let event_kind = match event.lexeme.as_str() {
+            "add" => ComponentEvent::Add,
+            "remove" => ComponentEvent::Remove,
+            _ => ComponentEvent::Destroy,
+        };
+        self.handlers.push(HandlerEntry {
+            event: event_kind,
+            component: self.components.get(&component.type_.lexeme).unwrap().id,
+            function: self.function_chunks.len() as u32,
+        });
+
+        // Unnamed parameters still take up a local, which is reserved with a name that cannot clash with identifiers
+        let param = |name: Option<&Token>, placeholder: &str| match name {
+            Some(name) => name.clone(),
+            None => Token {
+                lexeme: placeholder.to_string(),
+                ..event.clone()
+            },
+        };
+        let params = [
+            param(entity, "<handler entity>"),
+            param(component.name.as_ref(), "<handler component>"),
+        ];
+        let mut handler_scope = scope.function();
+        if let Some(name) = &component.name {
+            handler_scope
+                .local_component_mapping
+                .insert(name.lexeme.clone(), component.type_.clone());
+        }
+        let name = Token {
+            lexeme: format!("on {} {}", event.lexeme, component.type_.lexeme),
+            ..event.clone()
+        };
+        self.create_function_chunk(
+            Some(&name),
+            &event.position,
+            &params,
+            body,
+            &mut handler_scope,
+        );
+    }
+
     fn create_function_chunk(
         &mut self,
         name: Option<&Token>,
@@ -727,14 +893,6 @@ impl<'a> Codegen<'a> {
         body: &'a Expr,
         scope: &mut Scope,
     ) {
-        if params.len() > u8::MAX.into() {
-            panic!("Too many parameters");
-        }
-
-        if self.function_chunks.len() >= u8::MAX.into() {
-            panic!("Too many functions");
-        }
-
         let lexeme = match name {
             Some(name) => name.lexeme.clone(),
             None => "(unnamed)".to_string(),
@@ -742,40 +900,35 @@ impl<'a> Codegen<'a> {
 
         let function_chunk_index = self.function_chunks.len();
         let function_chunk = FunctionChunk {
-            function_name: lexeme.clone(),
+            function_name: lexeme,
             _position: position,
-            local_count: params.len() as u8,
-            bytes: vec![],
+            local_count: params.len() as u32,
+            instructions: vec![],
         };
         self.function_chunks.push(function_chunk);
 
-        scope
-            .bytecode
-            .add_op(ByteCode::FunctionChunk)
-            .add_string(&lexeme);
+        scope.instructions.add(Instruction::FunctionChunk);
 
         for (index, param) in params.iter().enumerate() {
-            scope.environment.insert(param.lexeme.clone(), index as u8);
+            scope.environment.insert(param.lexeme.clone(), index as u32);
             scope.locals.insert(param.lexeme.clone());
         }
 
         // TODO(anissen): Expr is already a block, so we shouldn't need to create new environment and locals
         self.emit_expr(body, scope);
 
-        scope.bytecode.add_op(ByteCode::Return);
+        scope.instructions.add(Instruction::Return);
 
-        self.function_chunks[function_chunk_index].bytes = scope.bytecode.bytes.clone();
+        self.function_chunks[function_chunk_index].instructions =
+            scope.instructions.instructions.clone();
     }
 
     pub fn emit(&mut self, expression: &'a Expr) -> Result<Vec<u8>, Diagnostics> {
         let mut scope = Scope::new();
-        scope
-            .bytecode
-            .add_op(ByteCode::FunctionChunk)
-            .add_string("main");
+        scope.instructions.add(Instruction::FunctionChunk);
 
         self.emit_expr(expression, &mut scope);
-        scope.bytecode.add_op(ByteCode::Return); // TODO(anissen): I may not need this, because I know the function bytecode length
+        scope.instructions.add(Instruction::Return); // TODO(anissen): I may not need this, because I know the function bytecode length
 
         if !self.diagnostics.has_errors() {
             Ok(self.create_bytecode(&mut scope))
@@ -784,204 +937,284 @@ impl<'a> Codegen<'a> {
         }
     }
 
-    fn create_bytecode(&mut self, scope: &mut Scope) -> Vec<u8> {
-        let mut header_builder = BytecodeBuilder::new();
-
-        let mut sorted_map = self.components.values().collect::<Vec<_>>();
-        sorted_map.sort_by(|a, b| a.id.cmp(&b.id));
-
-        header_builder.add_byte(self.components.len() as u8);
-        for component_metadata in &sorted_map {
-            header_builder.add_byte(component_metadata.id);
-            header_builder.add_byte(component_metadata.properties.len() as u8);
-            for property in component_metadata.properties.iter() {
-                header_builder.add_string(&property.name.lexeme);
-                let type_id: u8 = match property.type_ {
-                    // TODO(anissen): Implement this convertion on `Type`
-                    Type::Boolean => 0,
-                    Type::Integer => 1,
-                    Type::Float => 2,
-                    Type::String => 3,
-                    _ => panic!("Unknown type"),
-                };
-                header_builder.add_byte(type_id);
-                let size: u16 = match property.type_ {
-                    // TODO(anissen): Implement this method on `Type`
-                    Type::Boolean => 1,
-                    Type::Integer => 4,
-                    Type::Float => 4,
-                    Type::String => 1 /* length of string */ + 32, /* fixed max size of string */ // TODO(anissen): Make this size dynamic
-                    _ => panic!("Unknown/unhandled type"),
-                };
-                header_builder.add_u16(&size);
+    fn field_layout(&self, property: &PropertyDefinition) -> FieldLayout {
+        let (type_id, size, type_parameter) = match &property.type_ {
+            PropertyType::Primitive(type_) => (primitive_type_id(type_), primitive_size(type_), 0),
+            PropertyType::Tag(_) => (TAG_TYPE_ID, 4, 0), // handle to the tag name in the string heap
+            PropertyType::List(element_type) => {
+                // handle to the elements in the list heap
+                (LIST_TYPE_ID, 4, primitive_type_id(element_type) as u32)
             }
+            PropertyType::Component(name) => {
+                // the fields of the nested component are packed in place
+                let nested = self.components.get(&name.lexeme).unwrap();
+                let size = nested
+                    .properties
+                    .iter()
+                    .map(|property| self.field_layout(property).size)
+                    .sum();
+                (COMPONENT_TYPE_ID, size, nested.id)
+            }
+        };
+        FieldLayout {
+            name: property.name.lexeme.clone(),
+            type_id,
+            size,
+            type_parameter,
         }
+    }
+
+    fn create_bytecode(&mut self, scope: &mut Scope) -> Vec<u8> {
+        let mut sorted_map = self.components.iter().collect::<Vec<_>>();
+        sorted_map.sort_by_key(|(_, a)| a.id);
 
-        let mut signature_patches = Vec::new();
-        // println!("Function chunks:");
-        for ele in self.function_chunks.iter() {
-            // println!("{:?}", ele);
-            let signature_offset = header_builder
-                .add_op(ByteCode::FunctionSignature)
-                .add_string(&ele.function_name)
-                .add_byte(ele.local_count)
-                .get_patchable_i16_offset();
-            signature_patches.push(signature_offset);
+        let components = sorted_map
+            .iter()
+            .map(|(name, component_metadata)| ComponentEntry {
+                id: component_metadata.id,
+                is_child_of: name.as_str() == "ChildOf",
+                fields: component_metadata
+                    .properties
+                    .iter()
+                    .map(|property| self.field_layout(property))
+                    .collect(),
+            })
+            .collect();
+
+        let mut debug_info = DebugInfo {
+            components: sorted_map
+                .iter()
+                .map(|(name, component_metadata)| ComponentNames {
+                    id: component_metadata.id,
+                    name: name.to_string(),
+                    field_names: component_metadata
+                        .properties
+                        .iter()
+                        .map(|property| property.name.lexeme.clone())
+                        .collect(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        let mut constants = ConstantPool::default();
+        let mut code = Vec::new();
+        encode(
+            &optimize(scope.instructions.instructions.clone()),
+            &mut code,
+            &mut constants,
+            &mut debug_info,
+        );
+        let mut functions = Vec::new();
+        for chunk in &self.function_chunks {
+            functions.push(FunctionEntry {
+                name: chunk.function_name.clone(),
+                local_count: chunk.local_count,
+                position: code.len() as u32,
+            });
+            encode(
+                &optimize(chunk.instructions.clone()),
+                &mut code,
+                &mut constants,
+                &mut debug_info,
+            );
         }
 
-        {
-            let mut length = header_builder.bytes.len() + scope.bytecode.bytes.len();
-            for (index, ele) in self.function_chunks.iter().enumerate() {
-                header_builder.patch_i16_offset(signature_patches[index], length as isize);
-                length += ele.bytes.len();
-            }
+        BytecodeFile {
+            components,
+            functions,
+            foreign_functions: std::mem::take(&mut self.foreign_functions),
+            handlers: std::mem::take(&mut self.handlers),
+            resources: std::mem::take(&mut self.resources),
+            constants: constants.into_constants(),
+            code,
+            debug_info: (!self.release).then_some(debug_info),
         }
+        .to_bytes()
+    }
+}
 
-        let mut bytecode = vec![];
-        bytecode.append(&mut header_builder.bytes);
-        bytecode.append(&mut scope.bytecode.bytes);
-        for ele in self.function_chunks.iter() {
-            bytecode.append(&mut ele.bytes.clone());
+// Binds the next local to a query value. Unnamed values still take up a local, which is reserved with a name
+// that cannot clash with identifiers.
+fn bind_query_local(scope: &mut Scope, name: Option<&Token>, type_: &Token) {
+    let index = scope.locals.len() as u32;
+    match name {
+        Some(name) if !scope.locals.contains(&name.lexeme) => {
+            scope.locals.insert(name.lexeme.clone());
+        }
+        _ => {
+            scope.locals.insert(format!("<query local {index}>"));
         }
-        bytecode
+    }
+    if let Some(name) = name {
+        scope.environment.insert(name.lexeme.clone(), index);
+        scope
+            .local_component_mapping
+            .insert(name.lexeme.clone(), type_.clone());
     }
 }
 
-#[derive(Debug, Clone)]
-struct BytecodeBuilder {
-    bytes: Vec<u8>,
+// The source line where the expression starts
+fn source_line(expr: &Expr) -> Option<u32> {
+    let token = match expr {
+        Expr::Identifier { name }
+        | Expr::Context { name }
+        | Expr::ContextIdentifier { name, .. }
+        | Expr::Call { name, .. }
+        | Expr::ComponentDefinition { name, .. }
+        | Expr::ResourceDefinition { name, .. } => name,
+        Expr::Value { token, .. }
+        | Expr::Unary { token, .. }
+        | Expr::Is { token, .. }
+        | Expr::Create { token, .. }
+        | Expr::Destroy { token, .. } => token,
+        Expr::Handler { event, .. } => event,
+        Expr::AddComponent { entity, .. } | Expr::RemoveComponent { entity, .. } => {
+            return source_line(entity);
+        }
+        Expr::FieldAccess { identifier, .. } => identifier,
+        Expr::Assignment { target, .. } => return source_line(target),
+        Expr::Binary { left, .. } => return source_line(left),
+        Expr::Block { exprs } => return exprs.first().and_then(source_line),
+        Expr::Query {
+            include_components,
+            optional_components,
+            exclude_components,
+            added_components: _,
+            changed_components: _,
+            parent: _,
+            expr,
+        } => match (
+            include_components.first(),
+            optional_components.first(),
+            exclude_components.first(),
+        ) {
+            (Some(component), _, _) | (None, Some(component), _) => &component.type_,
+            (None, None, Some(component)) => component,
+            (None, None, None) => return source_line(expr),
+        },
+    };
+    Some(token.position.line as u32)
 }
 
-impl BytecodeBuilder {
-    fn new() -> Self {
-        Self { bytes: Vec::new() }
+fn resource_value(value: &ValueType) -> Value {
+    match value {
+        ValueType::Integer(value) => Value::Integer(*value),
+        ValueType::Integer64(value) => Value::Integer64(*value),
+        ValueType::Float(value) => Value::Float(*value),
+        ValueType::Float64(value) => Value::Float64(*value),
+        ValueType::String(value) => Value::String(value.clone()),
+        _ => panic!("Unsupported resource value"),
     }
+}
 
-    fn add_byte(&mut self, byte: u8) -> &mut Self {
-        self.bytes.push(byte);
-        self
+// TODO(anissen): Implement this convertion on `Type`
+fn primitive_type_id(type_: &Type) -> u8 {
+    match type_ {
+        Type::Boolean => 0,
+        Type::Integer => 1,
+        Type::Float => 2,
+        Type::String => 3,
+        Type::Integer64 => 4,
+        Type::Float64 => 5,
+        _ => panic!("Unknown type"),
     }
+}
 
-    fn add_op(&mut self, code: ByteCode) -> &mut Self {
-        self.add_byte(code.into());
-        self
+// TODO(anissen): Implement this method on `Type`
+fn primitive_size(type_: &Type) -> u16 {
+    match type_ {
+        Type::Boolean => 1,
+        Type::Integer => 4,
+        Type::Float => 4,
+        Type::Integer64 => 8,
+        Type::Float64 => 8,
+        Type::String => 4, // handle to the string in the string heap of the world
+        _ => panic!("Unknown/unhandled type"),
     }
+}
 
-    fn add_bytes<const COUNT: usize>(&mut self, value: &[u8; COUNT]) -> &mut Self {
-        self.bytes.extend_from_slice(value);
-        self
+#[derive(Debug, Clone)]
+struct InstructionBuilder {
+    instructions: Vec<Instruction>,
+    next_label: u32,
+    line: Option<u32>, // the source line of the last line marker
+}
+
+impl InstructionBuilder {
+    fn new() -> Self {
+        Self {
+            instructions: Vec::new(),
+            next_label: 0,
+            line: None,
+        }
     }
 
-    fn add_i16(&mut self, value: &i16) -> &mut Self {
-        self.add_bytes(&value.to_be_bytes())
+    fn add_line(&mut self, line: u32) -> &mut Self {
+        if self.line != Some(line) {
+            self.line = Some(line);
+            self.add(Instruction::Line(line));
+        }
+        self
     }
 
-    fn add_u16(&mut self, value: &u16) -> &mut Self {
-        self.add_bytes(&value.to_be_bytes())
+    fn add(&mut self, instruction: Instruction) -> &mut Self {
+        self.instructions.push(instruction);
+        self
     }
 
-    fn add_i32(&mut self, value: &i32) -> &mut Self {
-        self.add_bytes(&value.to_be_bytes())
+    fn new_label(&mut self) -> Label {
+        let label = Label(self.next_label);
+        self.next_label += 1;
+        label
     }
 
-    fn add_f32(&mut self, value: &f32) -> &mut Self {
-        self.add_bytes(&value.to_be_bytes())
+    fn add_label(&mut self, label: Label) -> &mut Self {
+        self.add(Instruction::Label(label))
     }
 
-    fn add_byte_array(&mut self, bytes: &[u8]) -> &mut Self {
-        self.bytes.extend(bytes);
-        self
+    fn add_jump_if_false(&mut self) -> Label {
+        let label = self.new_label();
+        self.add(Instruction::JumpIfFalse(label));
+        label
     }
 
-    fn add_string(&mut self, value: &str) -> &mut Self {
-        self.add_byte(value.len() as u8)
-            .add_byte_array(value.as_bytes())
-    }
-
-    fn add_jump_if_false(&mut self) -> usize {
-        let bytes = 0_i16.to_be_bytes();
-        self.add_op(ByteCode::JumpIfFalse)
-            .add_bytes(&bytes /* placeholder */);
-        self.bytes.len() - bytes.len()
-    }
-
-    fn add_unconditional_jump(&mut self) -> usize {
-        let bytes = 0_i16.to_be_bytes();
-        self.add_op(ByteCode::Jump)
-            .add_bytes(&bytes /* placeholder */);
-        self.bytes.len() - bytes.len()
-    }
-
-    // TODO: Create a PatchableOffset for this
-    // fn add_patchable_bytes(&mut self, bytes: u8) -> PatchableBytes {
-    //     let offset = self.bytes.len();
-    //     for byte in 0..bytes {
-    //         self.add_byte(0u8);
-    //     }
-    //     PatchableBytes {
-    //         offset,
-    //         length: bytes,
-    //     }
-    // }
-
-    // fn get_patchable_bytes(&mut self, index: u32, length: u8) -> PatchableBytes {
-    //     PatchableBytes {
-    //         index,
-    //         length,
-    //     }
-    // }
-
-    fn get_patchable_i16_offset(&mut self) -> usize {
-        let bytes = 0_i16.to_be_bytes();
-        self.add_bytes(&bytes /* placeholder */);
-        self.bytes.len() - bytes.len()
-    }
-
-    fn patch_i16_offset(&mut self, patchable_bytes: usize, new_offset: isize) {
-        // byte offset is the start of 2 bytes that indicate the jump offset
-        if new_offset < i16::MIN as isize {
-            panic!("New offset is too small");
-        } else if new_offset > i16::MAX as isize {
-            panic!("New offset is too large");
-        }
-        (new_offset as i16)
-            .to_be_bytes()
-            .swap_with_slice(&mut self.bytes[patchable_bytes..patchable_bytes + 2]);
+    fn add_unconditional_jump(&mut self) -> Label {
+        let label = self.new_label();
+        self.add(Instruction::Jump(label));
+        label
     }
 
-    fn add_set_local_value(&mut self, index: u8) -> &mut Self {
-        self.add_op(ByteCode::SetLocalValue).add_byte(index)
+    fn add_set_local_value(&mut self, index: u32) -> &mut Self {
+        self.add(Instruction::SetLocalValue { index })
     }
 
-    fn add_get_local_value(&mut self, index: u8) -> &mut Self {
-        self.add_op(ByteCode::GetLocalValue).add_byte(index)
+    fn add_get_local_value(&mut self, index: u32) -> &mut Self {
+        self.add(Instruction::GetLocalValue { index })
     }
 
-    fn add_get_field_value(&mut self, index: u8, field_index: u8) -> &mut Self {
-        self.add_op(ByteCode::GetFieldValue)
-            .add_byte(index)
-            .add_byte(field_index)
+    fn add_get_field_value(
+        &mut self,
+        index: u32,
+        component: u32,
+        field_path: Vec<u32>,
+    ) -> &mut Self {
+        self.add(Instruction::GetFieldValue {
+            index,
+            component,
+            field_path,
+        })
     }
 
     fn add_get_context_value(&mut self, name: &str) -> &mut Self {
-        self.add_op(ByteCode::GetContextValue).add_string(name)
+        self.add(Instruction::GetContextValue {
+            name: name.to_string(),
+        })
     }
 
     fn add_set_context_value(&mut self, name: &str) -> &mut Self {
-        self.add_op(ByteCode::SetContextValue).add_string(name)
-    }
-
-    fn patch_jump_to_current_byte(&mut self, byte_offset: usize) {
-        // byte offset is the start of 2 bytes that indicate the jump offset
-        let jump_instruction_bytes = 2;
-        let jump_offset = (self.bytes.len() - (byte_offset + jump_instruction_bytes)) as isize;
-        if jump_offset < i16::MIN as isize {
-            panic!("Jump offset is too small");
-        } else if jump_offset > i16::MAX as isize {
-            panic!("Jump offset is too large");
-        }
-        (jump_offset as i16)
-            .to_be_bytes()
-            .swap_with_slice(&mut self.bytes[byte_offset..byte_offset + 2]);
+        self.add(Instruction::SetContextValue {
+            name: name.to_string(),
+        })
     }
 }
@@ -1,349 +1,385 @@
+use std::collections::HashMap;
+
+use elements::{COMPONENT_TYPE_ID, FieldLayout, LIST_TYPE_ID, world::ComponentEvent};
+
 use crate::CompilationMetadata;
+use crate::bytecode_file::{ByteReader, BytecodeFile, DebugInfo, FORMAT_VERSION};
 use crate::bytecodes::ByteCode;
+use crate::instructions::{Instruction, Label};
+use crate::verifier;
+use crate::vm::Value;
 
+// Renders bytecode as text. Jump targets and function positions are shown as labels rather than byte offsets,
+// so that the output can be edited and turned back into bytecode by the assembler.
+// Names of components, fields and called functions are only shown for bytecode with debug info.
 pub struct Disassembler {
-    program: Vec<u8>,
-    program_counter: usize,
-    last_program_counter: usize,
+    position: usize,                        // of the instruction being disassembled
+    labels: HashMap<usize, String>,         // byte position => label name
+    function_names: HashMap<usize, String>, // byte position => function name
+    foreign_function_names: Vec<String>,
+    debug_info: Option<DebugInfo>,
 }
 
 pub fn disassemble(bytes: Vec<u8>, metadata: &mut CompilationMetadata) {
-    Disassembler::new(bytes).disassemble(metadata)
+    match BytecodeFile::from_bytes(&bytes).and_then(|file| verifier::verify(&file).map(|_| file)) {
+        Ok(file) => Disassembler::new(&file).disassemble(&file, metadata),
+        Err(error) => metadata.disassembled_instructions = format!("Invalid bytecode: {error}"),
+    }
 }
 
 impl Disassembler {
-    fn new(program: Vec<u8>) -> Self {
+    fn new(file: &BytecodeFile) -> Self {
+        let mut function_names = HashMap::from([(0, "main".to_string())]);
+        for function in &file.functions {
+            function_names.insert(function.position as usize, function.name.clone());
+        }
         Self {
-            program,
-            program_counter: 0,
-            last_program_counter: 0,
+            position: 0,
+            labels: HashMap::new(),
+            function_names,
+            foreign_function_names: file
+                .foreign_functions
+                .iter()
+                .map(|foreign| foreign.name.clone())
+                .collect(),
+            debug_info: file.debug_info.clone(),
         }
     }
 
-    fn read_i16(&mut self) -> i16 {
-        let bytes = [
-            self.program[self.program_counter],
-            self.program[self.program_counter + 1],
-        ];
-        self.program_counter += 2;
-        i16::from_be_bytes(bytes)
-    }
-
-    fn read_i32(&mut self) -> i32 {
-        let bytes = [
-            self.program[self.program_counter],
-            self.program[self.program_counter + 1],
-            self.program[self.program_counter + 2],
-            self.program[self.program_counter + 3],
-        ];
-        self.program_counter += 4;
-        i32::from_be_bytes(bytes)
-    }
-
-    fn read_f32(&mut self) -> f32 {
-        let bytes = [
-            self.program[self.program_counter],
-            self.program[self.program_counter + 1],
-            self.program[self.program_counter + 2],
-            self.program[self.program_counter + 3],
-        ];
-        self.program_counter += 4;
-        f32::from_be_bytes(bytes)
+    fn component_name(&self, id: u32) -> Option<&String> {
+        let debug_info = self.debug_info.as_ref()?;
+        Some(&debug_info.component(id)?.name)
     }
 
-    fn read_byte(&mut self) -> u8 {
-        let byte = self.program[self.program_counter];
-        self.program_counter += 1;
-        byte
+    // Returns the label of a byte position, creating it on first use
+    fn label(&mut self, position: usize) -> String {
+        let label_count = self.labels.len();
+        self.labels
+            .entry(position)
+            .or_insert_with(|| format!("L{label_count}"))
+            .clone()
     }
 
-    fn read_u16(&mut self) -> u16 {
-        let bytes = [
-            self.program[self.program_counter],
-            self.program[self.program_counter + 1],
-        ];
-        self.program_counter += 2;
-        u16::from_be_bytes(bytes)
+    fn query_component(&self, kind: &str, id: u32) -> String {
+        match self.component_name(id) {
+            Some(name) => format!("{kind} {id} ({name})"),
+            None => format!("{kind} {id}"),
+        }
     }
 
-    fn read_string(&mut self) -> String {
-        let string_length = self.read_byte();
-        self.read_string_bytes(string_length)
+    // Jumps are decoded with the byte position they jump to
+    fn jump_label(&mut self, target: Label) -> String {
+        self.label(target.0 as usize)
     }
 
-    fn read_string_bytes(&mut self, string_length: u8) -> String {
-        let value_bytes: Vec<u8> = self.program
-            [self.program_counter..self.program_counter + (string_length as usize)]
-            .into();
-        self.program_counter += string_length as usize;
-        String::from_utf8(value_bytes).unwrap()
+    // The field indices of a path through nested components, e.g. `1.0`
+    fn field_path(field_path: &[u32]) -> String {
+        field_path
+            .iter()
+            .map(|field| field.to_string())
+            .collect::<Vec<_>>()
+            .join(".")
     }
 
+    // Instructions that start the code of a source line are suffixed with the line
     fn print(&mut self, values: Vec<String>) -> String {
-        let formatted = format!("{} \t{}\n", self.last_program_counter, values.join(" "));
-        print!("{formatted}");
-        formatted
+        let line = self
+            .debug_info
+            .as_ref()
+            .and_then(|debug_info| debug_info.lines.get(&(self.position as u32)));
+        match line {
+            Some(line) => format!("{} \t{} (line: {line})\n", self.position, values.join(" ")),
+            None => format!("{} \t{}\n", self.position, values.join(" ")),
+        }
     }
 
-    fn read_components(&mut self) -> String {
-        let mut result = String::new();
-        let component_count = self.read_byte();
-        result.push_str(&self.print(vec![format!("Component Count: {}", component_count)]));
-        for _ in 0..component_count {
-            let component_id = self.read_byte();
-            let property_count = self.read_byte();
-            result.push_str(&self.print(vec![format!("Component ID: {}", component_id)]));
-            for _ in 0..property_count {
-                let property_name = self.read_string();
-                let property_type_id = self.read_byte();
-                let property_size = self.read_u16();
-                result.push_str(&self.print(vec![format!(
-                    "{}, type id: {}, size: {}",
-                    property_name, property_type_id, property_size
-                )]));
+    fn print_header(&mut self, file: &BytecodeFile) -> String {
+        let mut lines = vec![format!("Format Version: {FORMAT_VERSION}")];
+        lines.push(match file.debug_info {
+            Some(_) => "Debug Info: included".to_string(),
+            None => "Debug Info: stripped".to_string(),
+        });
+        lines.push(format!("Constant Count: {}", file.constants.len()));
+        lines.push(format!("Component Count: {}", file.components.len()));
+        for component in &file.components {
+            let names = file
+                .debug_info
+                .as_ref()
+                .and_then(|debug_info| debug_info.component(component.id));
+            let relationship = match component.is_child_of {
+                true => " (relationship: child of)",
+                false => "",
+            };
+            match names {
+                Some(names) => {
+                    lines.push(format!(
+                        "Component ID: {} (name: {}){relationship}",
+                        component.id, names.name
+                    ));
+                    for (field, name) in component.fields.iter().zip(&names.field_names) {
+                        lines.push(format!("{name}, {}", field_layout(field)));
+                    }
+                }
+                None => {
+                    lines.push(format!("Component ID: {}{relationship}", component.id));
+                    for field in &component.fields {
+                        lines.push(field_layout(field));
+                    }
+                }
             }
         }
-        result
+        for function in &file.functions {
+            let label = self.label(function.position as usize);
+            lines.push(format!(
+                "function signature (name: {}) (local count: {}) (function position: {label})",
+                function.name, function.local_count
+            ));
+        }
+        for foreign in &file.foreign_functions {
+            lines.push(format!(
+                "foreign function (name: {}) (arity: {})",
+                foreign.name, foreign.arity
+            ));
+        }
+        for handler in &file.handlers {
+            let event = match handler.event {
+                ComponentEvent::Add => "add",
+                ComponentEvent::Remove => "remove",
+                ComponentEvent::Destroy => "destroy",
+            };
+            lines.push(format!(
+                "handler (event: {event}) (component: {}) (function index: {})",
+                handler.component, handler.function
+            ));
+        }
+        for resource in &file.resources {
+            let (type_name, value) = match &resource.default {
+                Value::Integer(value) => ("i32", value.to_string()),
+                Value::Integer64(value) => ("i64", value.to_string()),
+                Value::Float(value) => ("f32", value.to_string()),
+                Value::Float64(value) => ("f64", value.to_string()),
+                Value::String(value) => ("str", format!("'{}'", escape(value))),
+                value => panic!("Unsupported resource value {value:?}"),
+            };
+            lines.push(format!(
+                "resource (name: {}) (type: {type_name}) (default: {value})",
+                resource.name
+            ));
+        }
+        let header = lines.join("\n") + "\n";
+        print!("{header}");
+        header
     }
 
-    pub fn disassemble(&mut self, metadata: &mut CompilationMetadata) {
+    pub fn disassemble(&mut self, file: &BytecodeFile, metadata: &mut CompilationMetadata) {
         let mut result = String::new();
 
-        result.push_str(&self.read_components());
+        result.push_str(&self.print_header(file));
 
-        while self.program_counter < self.program.len() {
-            let instruction = ByteCode::try_from(self.program[self.program_counter]).unwrap();
-            self.last_program_counter = self.program_counter;
-            self.program_counter += 1;
+        let mut lines = Vec::new();
+        let mut reader = ByteReader::new(&file.code);
+        while reader.position() < file.code.len() {
+            self.position = reader.position();
+            // The file has been verified, so every instruction decodes
+            let instruction = Instruction::decode(&mut reader, &file.constants).unwrap();
 
             let instruction_str = match instruction {
-                ByteCode::PushTrue => self.print(vec!["push_true".to_string()]),
-
-                ByteCode::PushFalse => self.print(vec!["push_false".to_string()]),
-
-                ByteCode::PushInteger => {
-                    let value = self.read_i32();
-                    self.print(vec![
-                        "push_integer".to_string(),
-                        format!("(value: {})", value),
-                    ])
+                Instruction::PushTrue => self.print(vec!["push_true".to_string()]),
+
+                Instruction::PushFalse => self.print(vec!["push_false".to_string()]),
+
+                Instruction::PushInteger(value) => self.print(vec![
+                    "push_integer".to_string(),
+                    format!("(value: {})", value),
+                ]),
+
+                Instruction::PushFloat(value) => self.print(vec![
+                    "push_float".to_string(),
+                    format!("(value: {})", value),
+                ]),
+
+                Instruction::PushInteger64(value) => self.print(vec![
+                    "push_integer64".to_string(),
+                    format!("(value: {})", value),
+                ]),
+
+                Instruction::PushFloat64(value) => self.print(vec![
+                    "push_float64".to_string(),
+                    format!("(value: {})", value),
+                ]),
+
+                Instruction::PushString(string) => self.print(vec![
+                    "push_string".to_string(),
+                    format!("(value: '{}')", escape(&string)),
+                ]),
+
+                Instruction::PushSimpleTag { name } => self.print(vec![
+                    "push_simple_tag".to_string(),
+                    format!("(value: '{}')", escape(&name)),
+                ]),
+
+                Instruction::PushTag { name } => self.print(vec![
+                    "push_tag".to_string(),
+                    format!("(value: '{}')", escape(&name)),
+                ]),
+
+                Instruction::PushList { length } => {
+                    self.print(vec!["push_list".to_string(), format!("(length: {length})")])
                 }
 
-                ByteCode::PushFloat => {
-                    let value = self.read_f32();
-                    self.print(vec![
-                        "push_float".to_string(),
-                        format!("(value: {})", value),
-                    ])
-                }
-
-                ByteCode::PushString => {
-                    let string_length = self.read_byte();
-                    let value_bytes: Vec<u8> = self.program
-                        [self.program_counter..self.program_counter + (string_length as usize)]
-                        .into();
-                    self.program_counter += string_length as usize;
-                    let string = String::from_utf8(value_bytes).unwrap();
+                Instruction::PushComponent { id, property_count } => self.print(vec![
+                    "push_component".to_string(),
+                    format!("(id: {id}, properties: {property_count})"),
+                ]),
 
-                    self.print(vec![
-                        "push_string".to_string(),
-                        format!("(value: '{}')", string),
-                    ])
-                }
-
-                ByteCode::PushSimpleTag => {
-                    let tag_name = self.read_string();
-
-                    self.print(vec![
-                        "push_simple_tag".to_string(),
-                        format!("(value: '{tag_name}')"),
-                    ])
-                }
-
-                ByteCode::PushTag => {
-                    let tag_name = self.read_string();
-
-                    self.print(vec![
-                        "push_tag".to_string(),
-                        format!("(value: '{}')", tag_name),
-                    ])
-                }
-
-                ByteCode::PushList => {
-                    let list_length = self.read_i32();
-                    self.print(vec![
-                        "push_list".to_string(),
-                        format!("(length: {list_length})"),
-                    ])
-                }
-
-                ByteCode::PushComponent => {
-                    let component_id = self.read_byte();
-                    let property_count = self.read_byte();
-                    self.print(vec![
-                        "push_component".to_string(),
-                        format!("(id: {component_id}, properties: {property_count})"),
-                    ])
-                }
-
-                ByteCode::SetNextComponentColumnOrJump => {
+                Instruction::SetNextComponentColumnOrJump => {
                     self.print(vec!["set_next_component_column_or_jump".to_string()])
                 }
 
-                ByteCode::GetTagName => self.print(vec!["get_tag_name".to_string()]),
-
-                ByteCode::GetTagPayload => self.print(vec!["get_tag_payload".to_string()]),
-
-                ByteCode::IntegerAddition => self.print(vec!["int_add".to_string()]),
-
-                ByteCode::IntegerSubtraction => self.print(vec!["int_sub".to_string()]),
-
-                ByteCode::IntegerMultiplication => self.print(vec!["int_mult".to_string()]),
-
-                ByteCode::IntegerDivision => self.print(vec!["int_div".to_string()]),
-
-                ByteCode::IntegerModulo => self.print(vec!["int_mod".to_string()]),
-
-                ByteCode::IntegerLessThan => self.print(vec!["int_lt".to_string()]),
-
-                ByteCode::IntegerLessThanEquals => self.print(vec!["int_lte".to_string()]),
-
-                ByteCode::FloatAddition => self.print(vec!["float_add".to_string()]),
-
-                ByteCode::FloatSubtraction => self.print(vec!["float_sub".to_string()]),
-
-                ByteCode::FloatMultiplication => self.print(vec!["float_mult".to_string()]),
-
-                ByteCode::FloatDivision => self.print(vec!["float_div".to_string()]),
-
-                ByteCode::FloatModulo => self.print(vec!["float_mod".to_string()]),
-
-                ByteCode::FloatLessThan => self.print(vec!["float_lt".to_string()]),
-
-                ByteCode::FloatLessThanEquals => self.print(vec!["float_lte".to_string()]),
-
-                ByteCode::StringConcat => self.print(vec!["str_concat".to_string()]),
-
-                ByteCode::BooleanAnd => self.print(vec!["and".to_string()]),
-
-                ByteCode::BooleanOr => self.print(vec!["or".to_string()]),
-
-                ByteCode::Equals => self.print(vec!["eq".to_string()]),
+                Instruction::GetTagName => self.print(vec!["get_tag_name".to_string()]),
+
+                Instruction::GetTagPayload => self.print(vec!["get_tag_payload".to_string()]),
+
+                Instruction::IntegerAddition
+                | Instruction::IntegerSubtraction
+                | Instruction::IntegerMultiplication
+                | Instruction::IntegerDivision
+                | Instruction::IntegerModulo
+                | Instruction::IntegerLessThan
+                | Instruction::IntegerLessThanEquals
+                | Instruction::FloatAddition
+                | Instruction::FloatSubtraction
+                | Instruction::FloatMultiplication
+                | Instruction::FloatDivision
+                | Instruction::FloatModulo
+                | Instruction::FloatLessThan
+                | Instruction::FloatLessThanEquals
+                | Instruction::StringConcat
+                | Instruction::BooleanAnd
+                | Instruction::BooleanOr
+                | Instruction::Equals => {
+                    let operation = instruction.opcode().unwrap();
+                    self.print(vec![binary_operation_name(operation).to_string()])
+                }
 
-                ByteCode::Negation => self.print(vec!["neg".to_string()]),
+                Instruction::Negation => self.print(vec!["neg".to_string()]),
 
-                ByteCode::Not => self.print(vec!["not".to_string()]),
+                Instruction::Not => self.print(vec!["not".to_string()]),
 
-                ByteCode::GetLocalValue => {
-                    let index = self.program[self.program_counter]; // TODO(anissen): Make helper function to read bytes and increment program counter
-                    self.program_counter += 1;
+                Instruction::GetLocalValue { index } => {
                     self.print(vec!["get_value".to_string(), format!("(index: {})", index)])
                 }
 
-                ByteCode::GetForeignValue => {
-                    let name = self.read_string();
-
-                    self.print(vec![
-                        "get_foreign_value".to_string(),
-                        format!("(name: {})", name),
-                    ])
-                }
-
-                ByteCode::SetLocalValue => {
-                    let index = self.read_byte();
+                Instruction::GetLocalValueAndOperate { index, operation } => self.print(vec![
+                    "get_value_and_operate".to_string(),
+                    format!(
+                        "(index: {}, op: {})",
+                        index,
+                        binary_operation_name(operation)
+                    ),
+                ]),
+
+                Instruction::GetForeignValue { name } => self.print(vec![
+                    "get_foreign_value".to_string(),
+                    format!("(name: {})", name),
+                ]),
+
+                Instruction::SetLocalValue { index } => {
                     self.print(vec!["set_value".to_string(), format!("(index: {})", index)])
                 }
 
-                ByteCode::GetFieldValue => {
-                    let index = self.read_byte();
-                    let field_index = self.read_byte();
-                    self.print(vec![
-                        "get_field_value".to_string(),
-                        format!("(index: {}, field_index: {})", index, field_index),
-                    ])
-                }
-
-                ByteCode::SetFieldValue => {
-                    let index = self.read_byte();
-                    let field_index = self.read_byte();
-                    self.print(vec![
-                        "set_field_value".to_string(),
-                        format!("(index: {}, field_index: {})", index, field_index),
-                    ])
-                }
-
-                ByteCode::GetContextValue => {
-                    let name = self.read_string();
-                    self.print(vec![
-                        "get_context_value".to_string(),
-                        format!("(name: {})", name),
-                    ])
-                }
-
-                ByteCode::SetContextValue => {
-                    let name = self.read_string();
-                    self.print(vec![
-                        "set_context_value".to_string(),
-                        format!("(name: {})", name),
-                    ])
-                }
-
-                ByteCode::GetListElementAtIndex => {
+                Instruction::GetFieldValue {
+                    index,
+                    component,
+                    field_path,
+                } => self.print(vec![
+                    "get_field_value".to_string(),
+                    format!(
+                        "(index: {}, component: {}, field_path: {})",
+                        index,
+                        component,
+                        Self::field_path(&field_path)
+                    ),
+                ]),
+
+                Instruction::SetFieldValue {
+                    index,
+                    component,
+                    field_path,
+                } => self.print(vec![
+                    "set_field_value".to_string(),
+                    format!(
+                        "(index: {}, component: {}, field_path: {})",
+                        index,
+                        component,
+                        Self::field_path(&field_path)
+                    ),
+                ]),
+
+                Instruction::GetContextValue { name } => self.print(vec![
+                    "get_context_value".to_string(),
+                    format!("(name: {})", name),
+                ]),
+
+                Instruction::SetContextValue { name } => self.print(vec![
+                    "set_context_value".to_string(),
+                    format!("(name: {})", name),
+                ]),
+
+                Instruction::GetListElementAtIndex => {
                     self.print(vec!["get_list_element_at_index".to_string()])
                 }
 
-                ByteCode::GetArrayLength => self.print(vec!["get_array_length".to_string()]),
-
-                ByteCode::ArrayAppend => self.print(vec!["append".to_string()]),
+                Instruction::GetArrayLength => self.print(vec!["get_array_length".to_string()]),
 
-                ByteCode::Log => self.print(vec!["log".to_string()]),
+                Instruction::ArrayAppend => self.print(vec!["append".to_string()]),
 
-                ByteCode::FunctionSignature => {
-                    let name = self.read_string();
-                    let local_count = self.read_byte();
-                    let function_position = self.read_i16();
-                    self.print(vec![
-                        format!("function signature"),
-                        format!("(name: {})", name),
-                        format!("(local count: {})", local_count),
-                        format!("(function position: {})", function_position),
-                    ])
-                }
+                Instruction::Log => self.print(vec!["log".to_string()]),
 
-                ByteCode::FunctionChunk => {
-                    let name = self.read_string();
+                Instruction::FunctionChunk => {
+                    let name = self.function_names[&self.position].clone();
                     let formatted = self.print(vec![format!("=== function chunk: {} ===", name)]);
                     format!("\n{formatted}")
                 }
 
-                ByteCode::Function => {
-                    let function_index = self.read_byte();
-                    let param_count = self.read_byte();
-                    self.print(vec![
-                        format!("function"),
-                        format!("(function index: {})", function_index),
-                        format!("(params: {})", param_count),
-                    ])
-                }
-
-                ByteCode::Return => self.print(vec!["ret".to_string()]),
-
-                ByteCode::Call => {
-                    let arg_count = self.read_byte();
-                    let is_global = self.read_byte();
-                    let index = self.read_byte();
-                    let name = self.read_string();
+                Instruction::Function {
+                    function_index,
+                    param_count,
+                } => self.print(vec![
+                    format!("function"),
+                    format!("(function index: {})", function_index),
+                    format!("(params: {})", param_count),
+                ]),
+
+                Instruction::Return => self.print(vec!["ret".to_string()]),
+
+                Instruction::Call {
+                    arg_count,
+                    is_global,
+                    index,
+                    ..
+                } => {
+                    let name = self
+                        .debug_info
+                        .as_ref()
+                        .and_then(|debug_info| debug_info.call_names.get(&(self.position as u32)));
+                    let call = match name {
+                        Some(name) => format!("call {name}"),
+                        None => "call".to_string(),
+                    };
 
                     self.print(vec![
-                        format!("call {} (is_global: {})", name, is_global),
+                        call,
+                        format!("(is_global: {})", is_global as u8),
                         format!("(arg count: {}, function index: {})", arg_count, index),
                     ])
                 }
 
-                ByteCode::CallForeign => {
-                    let foreign_index = self.read_byte();
-                    let arg_count = self.read_byte();
-                    let name = self.read_string();
+                Instruction::CallForeign {
+                    foreign_index,
+                    arg_count,
+                } => {
+                    let name = self.foreign_function_names[foreign_index as usize].clone();
 
                     self.print(vec![
                         format!("call foreign function {}", name),
@@ -354,70 +390,132 @@ impl Disassembler {
                     ])
                 }
 
-                ByteCode::Jump => {
-                    // let pc = self.program_counter;
-                    let offset = self.read_i16();
-                    self.print(vec![format!(
-                        "jump (offset: {}, to byte {})",
-                        offset,
-                        self.program_counter as i16 + offset
-                    )])
+                Instruction::Jump(target) => {
+                    let label = self.jump_label(target);
+                    self.print(vec![format!("jump (to: {label})")])
                 }
 
-                ByteCode::JumpIfTrue => {
-                    let offset = self.read_i16();
-                    self.print(vec![format!(
-                        "jump if true (offset: {}, to byte {})",
-                        offset,
-                        self.program_counter as i16 + offset
-                    )])
+                Instruction::JumpIfTrue(target) => {
+                    let label = self.jump_label(target);
+                    self.print(vec![format!("jump if true (to: {label})")])
                 }
 
-                ByteCode::JumpIfFalse => {
-                    let offset = self.read_i16();
-                    self.print(vec![format!(
-                        "jump if false (offset: {}, to byte {})",
-                        offset,
-                        self.program_counter as i16 + offset
-                    )])
+                Instruction::JumpIfFalse(target) => {
+                    let label = self.jump_label(target);
+                    self.print(vec![format!("jump if false (to: {label})")])
                 }
 
-                ByteCode::ContextQuery => {
-                    let jump_offset = self.read_i16();
-                    let include_component_count = self.read_byte();
-                    let exclude_component_count = self.read_byte();
-                    let mut include_components = Vec::new();
-                    let mut exclude_components = Vec::new();
+                Instruction::ContextQuery {
+                    end,
+                    local_index,
+                    has_parent,
+                    include_components,
+                    optional_components,
+                    exclude_components,
+                    added_components,
+                    changed_components,
+                } => {
+                    let end_label = self.jump_label(end);
                     // collect all component ids and names for printing
-                    for _ in 0..include_component_count {
-                        let component_id = self.read_byte();
-                        let component_name = self.read_string();
-                        include_components
-                            .push(format!("include {} ({})", component_id, component_name));
-                    }
-                    for _ in 0..exclude_component_count {
-                        let component_id = self.read_byte();
-                        let component_name = self.read_string();
-                        exclude_components
-                            .push(format!("exclude {} ({})", component_id, component_name));
-                    }
-                    self.print(vec![format!(
-                        "query components: +{} -{} (offset: {}, to byte {})",
-                        include_components.join(", "),
-                        exclude_components.join(", "),
-                        jump_offset,
-                        self.program_counter as i16 + jump_offset
-                    )])
+                    let names = |kind: &str, ids: &[u32]| {
+                        ids.iter()
+                            .map(|id| self.query_component(kind, *id))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    };
+                    let line = format!(
+                        "query components: +{} ?{} -{} >{} ~{} (local index: {local_index}, parent: {}, end: {end_label})",
+                        names("include", &include_components),
+                        names("optional", &optional_components),
+                        names("exclude", &exclude_components),
+                        names("added", &added_components),
+                        names("changed", &changed_components),
+                        has_parent as u8,
+                    );
+                    self.print(vec![line])
                 }
 
-                ByteCode::Create => self.print(vec![format!("create entity")]),
+                Instruction::Create => self.print(vec![format!("create entity")]),
+
+                Instruction::Destroy => self.print(vec![format!("destroy entity")]),
+
+                Instruction::AddComponent => self.print(vec![format!("add component")]),
+
+                Instruction::RemoveComponent { id } => {
+                    self.print(vec![format!("remove component (id: {id})")])
+                }
 
-                ByteCode::Destroy => self.print(vec![format!("destroy entity")]),
+                Instruction::Label(_) | Instruction::Line(_) => unreachable!(),
             };
 
-            result.push_str(&instruction_str);
+            lines.push((self.position, instruction_str));
+        }
+
+        // Labels are only known once every jump has been seen, so they are inserted afterwards
+        let mut code = String::new();
+        for (position, line) in lines {
+            match self.labels.get(&position) {
+                Some(label) => match line.strip_prefix('\n') {
+                    Some(line) => code.push_str(&format!("\n{label}:\n{line}")),
+                    None => code.push_str(&format!("{label}:\n{line}")),
+                },
+                None => code.push_str(&line),
+            }
         }
+        print!("{code}");
 
+        result.push_str(&code);
         metadata.disassembled_instructions = result;
     }
 }
+
+// The type parameter is only shown for the fields that have one
+fn field_layout(field: &FieldLayout) -> String {
+    let layout = format!("type id: {}, size: {}", field.type_id, field.size);
+    match field.type_id {
+        COMPONENT_TYPE_ID | LIST_TYPE_ID => {
+            format!("{layout}, type parameter: {}", field.type_parameter)
+        }
+        _ => layout,
+    }
+}
+
+// Quotes are escaped so that the assembler can find the end of the string
+fn escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for char in value.chars() {
+        match char {
+            '\\' => escaped.push_str("\\\\"),
+            '\'' => escaped.push_str("\\'"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            _ => escaped.push(char),
+        }
+    }
+    escaped
+}
+
+pub fn binary_operation_name(operation: ByteCode) -> &'static str {
+    match operation {
+        ByteCode::IntegerAddition => "int_add",
+        ByteCode::IntegerSubtraction => "int_sub",
+        ByteCode::IntegerMultiplication => "int_mult",
+        ByteCode::IntegerDivision => "int_div",
+        ByteCode::IntegerModulo => "int_mod",
+        ByteCode::IntegerLessThan => "int_lt",
+        ByteCode::IntegerLessThanEquals => "int_lte",
+        ByteCode::FloatAddition => "float_add",
+        ByteCode::FloatSubtraction => "float_sub",
+        ByteCode::FloatMultiplication => "float_mult",
+        ByteCode::FloatDivision => "float_div",
+        ByteCode::FloatModulo => "float_mod",
+        ByteCode::FloatLessThan => "float_lt",
+        ByteCode::FloatLessThanEquals => "float_lte",
+        ByteCode::StringConcat => "str_concat",
+        ByteCode::BooleanAnd => "and",
+        ByteCode::BooleanOr => "or",
+        ByteCode::Equals => "eq",
+        _ => panic!("Unknown binary operation: {:?}", operation),
+    }
+}
@@ -2,8 +2,10 @@ use std::fmt;
 
 use crate::tokens::Token;
 use crate::unification::UnificationType;
+use crate::vm::RuntimeError;
 
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     SyntaxError {
         description: String,
@@ -14,8 +16,8 @@ pub enum Error {
         token: Token,
     },
     TypeMismatch {
-        expected: UnificationType,
-        got: UnificationType,
+        expected: Box<UnificationType>,
+        got: Box<UnificationType>,
         declared_at: Token,
         provided_at: Token,
         mismatch_at: Option<Token>,
@@ -32,10 +34,10 @@ pub enum Error {
     FunctionNotFound {
         name: String,
     },
-    FunctionNameTooLong {
-        token: Token,
-    },
     FileErr(String),
+    BytecodeErr(String),
+    AssemblyErr(String),
+    RuntimeErr(RuntimeError),
     PropertyMissing {
         property_definition: Token,
         token: Token,
@@ -43,6 +45,12 @@ pub enum Error {
     PropertyDuplicated {
         token: Token,
     },
+    OptionalFieldAccess {
+        token: Token,
+    },
+    HandlerFieldAssignment {
+        token: Token,
+    },
     ResolutionErr(ResolutionError),
 }
 
@@ -55,6 +63,10 @@ pub enum ResolutionError {
     BuiltinComponentRedefined {
         name: Token,
     },
+    ResourceRedefined {
+        name: Token,
+        definition: Token,
+    },
     IsWithoutArms {
         token: Token,
     },
@@ -106,10 +118,10 @@ impl fmt::Display for Error {
             Error::FunctionNotFound { name } => {
                 write!(f, "Function not found: {name}")
             }
-            Error::FunctionNameTooLong { token } => {
-                write!(f, "Function name too long; at {:?}", token.position)
-            }
             Error::FileErr(error_msg) => write!(f, "File error: {error_msg}"),
+            Error::BytecodeErr(error_msg) => write!(f, "Bytecode error: {error_msg}"),
+            Error::AssemblyErr(error_msg) => write!(f, "Assembly error: {error_msg}"),
+            Error::RuntimeErr(error) => write!(f, "{error}"),
             Error::PropertyMissing {
                 property_definition,
                 token,
@@ -141,6 +153,20 @@ impl fmt::Display for Error {
                     token.position.line, token.position.column, token.lexeme
                 )
             }
+            Error::OptionalFieldAccess { token } => {
+                write!(
+                    f,
+                    "Line {}.{}: '{}' is an optional component, so it must be matched against :none before its fields can be accessed",
+                    token.position.line, token.position.column, token.lexeme
+                )
+            }
+            Error::HandlerFieldAssignment { token } => {
+                write!(
+                    f,
+                    "Line {}.{}: '{}' is the component of a handler, so its fields cannot be assigned",
+                    token.position.line, token.position.column, token.lexeme
+                )
+            }
             Error::ResolutionErr(resolution_error) => resolution_error.fmt(f),
         }
     }
@@ -167,6 +193,17 @@ impl fmt::Display for ResolutionError {
                     name.position.line, name.position.column, name.lexeme
                 )
             }
+            ResolutionError::ResourceRedefined { name, definition } => {
+                write!(
+                    f,
+                    "Line {}.{}: Resource '{}' has already been defined at line {}.{}",
+                    name.position.line,
+                    name.position.column,
+                    name.lexeme,
+                    definition.position.line,
+                    definition.position.column,
+                )
+            }
             ResolutionError::IsWithoutArms { token } => {
                 write!(
                     f,
@@ -249,13 +286,22 @@ impl ErrorDescription for Error {
             Error::FunctionNotFound { name: _ } => {
                 format!("???\n{self}")
             }
-            Error::FunctionNameTooLong { token } => {
-                let error_line = get_error_line(source, token);
-                format!("{error_line}\n{self}")
-            }
             Error::FileErr(_error_msg) => {
                 format!("???\n{self}")
             }
+            Error::BytecodeErr(_error_msg) => {
+                format!("???\n{self}")
+            }
+            Error::AssemblyErr(_error_msg) => {
+                format!("???\n{self}")
+            }
+            Error::RuntimeErr(error) => {
+                let error_line = error
+                    .line
+                    .and_then(|line| source.lines().nth((line as usize).checked_sub(1)?))
+                    .unwrap_or("???");
+                format!("{error_line}\n{self}")
+            }
             Error::TypeRedefinition { token } => {
                 let error_line = get_error_line(source, token);
                 format!("{error_line}\n{self}")
@@ -271,7 +317,9 @@ impl ErrorDescription for Error {
                 let error_line = get_error_line(source, token);
                 format!("{error_line}\n{self}")
             }
-            Error::PropertyDuplicated { token } => {
+            Error::PropertyDuplicated { token }
+            | Error::OptionalFieldAccess { token }
+            | Error::HandlerFieldAssignment { token } => {
                 let error_line = get_error_line(source, token);
                 format!("{error_line}\n{self}")
             }
@@ -283,7 +331,10 @@ impl ErrorDescription for Error {
 impl ErrorDescription for ResolutionError {
     fn print(&self, source: &str) -> String {
         match self {
-            ResolutionError::ComponentRedefined { name, definition: _ } => {
+            ResolutionError::ComponentRedefined {
+                name,
+                definition: _,
+            } => {
                 let error_line = get_error_line(source, name);
                 format!("{error_line}\n{self}")
             }
@@ -291,6 +342,13 @@ impl ErrorDescription for ResolutionError {
                 let error_line = get_error_line(source, name);
                 format!("{error_line}\n{self}")
             }
+            ResolutionError::ResourceRedefined {
+                name,
+                definition: _,
+            } => {
+                let error_line = get_error_line(source, name);
+                format!("{error_line}\n{self}")
+            }
             ResolutionError::IsWithoutArms { token } => {
                 let error_line = get_error_line(source, token);
                 format!("{error_line}\n{self}")
@@ -4,7 +4,9 @@ use crate::tokens::Token;
 pub enum ValueType {
     Boolean(bool),
     Integer(i32),
+    Integer64(i64),
     Float(f32),
+    Float64(f64),
     String(String),
     Function {
         params: Vec<Token>, // TODO(anissen): Do we also need type information here?
@@ -30,6 +32,7 @@ pub enum Expr {
         name: Token,
     },
     ContextIdentifier {
+        #[allow(dead_code)]
         context: Token,
         name: Token,
     },
@@ -72,13 +75,28 @@ pub enum Expr {
     },
     Query {
         include_components: Vec<MaybeNamedType>, // TODO(anissen): Should this be Vec<Expr> instead?
+        optional_components: Vec<MaybeNamedType>,
         exclude_components: Vec<Token>,
+        added_components: Vec<Token>, // included components that must have been added since the last run
+        changed_components: Vec<Token>, // included components that must have been changed since the last run
+        parent: Option<Box<Expr>>,      // only match the children of this entity
         expr: Box<Expr>,
     },
     ComponentDefinition {
         name: Token,
         properties: Vec<PropertyDefinition>,
     },
+    Handler {
+        event: Token,          // `add`, `remove` or `destroy`
+        entity: Option<Token>, // the name bound to the entity
+        component: MaybeNamedType,
+        expr: Box<Expr>,
+    },
+    ResourceDefinition {
+        name: Token,
+        type_: crate::unification::Type,
+        default: Box<Expr>, // a literal value of the type
+    },
     Create {
         token: Token,
         arguments: Box<Expr>,
@@ -87,9 +105,19 @@ pub enum Expr {
         token: Token,
         argument: Box<Expr>,
     },
+    AddComponent {
+        token: Token,
+        entity: Box<Expr>,
+        component: Box<Expr>,
+    },
+    RemoveComponent {
+        token: Token,
+        entity: Box<Expr>,
+        component: Token,
+    },
     FieldAccess {
         identifier: Token,
-        field_name: Token,
+        field_names: Vec<Token>, // the path to the field, e.g. `pos` and `x` in `t.pos.x`
     },
     // TODO(anissen): Add an Error and/or Todo expression?
 }
@@ -103,7 +131,15 @@ pub struct MaybeNamedType {
 #[derive(Debug, Clone)]
 pub struct PropertyDefinition {
     pub name: Token,
-    pub type_: crate::unification::Type, // TODO(anissen): Also needs to be able to represent complex types
+    pub type_: PropertyType,
+}
+
+#[derive(Debug, Clone)]
+pub enum PropertyType {
+    Primitive(crate::unification::Type), // e.g. `i32` or `str`
+    Tag(Vec<Token>),                     // one of the listed simple tags, e.g. `:idle | :walking`
+    List(crate::unification::Type),      // a list of primitives, e.g. `[f32]`
+    Component(Token),                    // a nested component, e.g. `Position`
 }
 
 #[derive(Debug)]
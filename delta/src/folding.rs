@@ -0,0 +1,370 @@
+use std::cmp::Ordering;
+use std::ops::{Add, Div, Mul, Rem, Sub};
+
+use crate::arithmetic;
+use crate::expressions::{
+    ArithmeticOperations, BinaryOperator, BooleanOperations, Comparisons, EqualityOperations, Expr,
+    IsArm, IsArmPattern, StringOperations, UnaryOperator, ValueType,
+};
+
+// Evaluates pure expressions over literals at compile time, e.g. `1 + 2 + 3` => `6`.
+// Expressions are only folded when the result is exactly what the VM would have computed.
+pub fn fold_constants(expr: &mut Expr) {
+    fold_children(expr);
+
+    let folded = match expr {
+        Expr::Unary {
+            operator,
+            token,
+            expr: operand,
+        } => fold_unary(operator, operand).map(|value| (value, token.clone())),
+        Expr::Binary {
+            left,
+            operator,
+            token,
+            right,
+        } => fold_binary(left, operator, right).map(|value| (value, token.clone())),
+        Expr::Is {
+            token: _,
+            expr: scrutinee,
+            arms,
+        } => {
+            if let Some(arm_index) = matching_arm(scrutinee, arms) {
+                let arm = arms.swap_remove(arm_index);
+                *expr = arm.block;
+            }
+            None
+        }
+        _ => None,
+    };
+
+    if let Some((value, token)) = folded {
+        *expr = Expr::Value { value, token };
+    }
+}
+
+fn fold_children(expr: &mut Expr) {
+    match expr {
+        Expr::Value { value, token: _ } => match value {
+            ValueType::Function { params: _, expr } => fold_constants(expr),
+            ValueType::Tag {
+                name: _,
+                payload: Some(payload),
+            } => fold_constants(payload),
+            ValueType::List(exprs) => exprs.iter_mut().for_each(fold_constants),
+            ValueType::Component {
+                name: _,
+                properties,
+            } => properties
+                .iter_mut()
+                .for_each(|property| fold_constants(&mut property.value)),
+            _ => (),
+        },
+        Expr::Call { name: _, args } => args.iter_mut().for_each(fold_constants),
+        Expr::Assignment {
+            target: _,
+            _operator: _,
+            expr,
+        } => fold_constants(expr),
+        Expr::Unary {
+            operator: _,
+            token: _,
+            expr,
+        } => fold_constants(expr),
+        Expr::Binary {
+            left,
+            operator: _,
+            token: _,
+            right,
+        } => {
+            fold_constants(left);
+            fold_constants(right);
+        }
+        Expr::Block { exprs } => exprs.iter_mut().for_each(fold_constants),
+        Expr::Is {
+            token: _,
+            expr,
+            arms,
+        } => {
+            fold_constants(expr);
+            for arm in arms {
+                if let IsArmPattern::Expression(pattern) = &mut arm.pattern {
+                    fold_constants(pattern);
+                }
+                if let Some(guard) = &mut arm.guard {
+                    fold_constants(&mut guard.condition);
+                }
+                fold_constants(&mut arm.block);
+            }
+        }
+        Expr::Query {
+            include_components: _,
+            optional_components: _,
+            exclude_components: _,
+            added_components: _,
+            changed_components: _,
+            parent,
+            expr,
+        } => {
+            if let Some(parent) = parent {
+                fold_constants(parent);
+            }
+            fold_constants(expr);
+        }
+        Expr::Handler {
+            event: _,
+            entity: _,
+            component: _,
+            expr,
+        } => fold_constants(expr),
+        Expr::Create {
+            token: _,
+            arguments,
+        } => fold_constants(arguments),
+        Expr::Destroy { token: _, argument } => fold_constants(argument),
+        Expr::AddComponent {
+            token: _,
+            entity,
+            component,
+        } => {
+            fold_constants(entity);
+            fold_constants(component);
+        }
+        Expr::RemoveComponent {
+            token: _,
+            entity,
+            component: _,
+        } => fold_constants(entity),
+        Expr::Identifier { .. }
+        | Expr::Context { .. }
+        | Expr::ContextIdentifier { .. }
+        | Expr::ComponentDefinition { .. }
+        | Expr::ResourceDefinition { .. }
+        | Expr::FieldAccess { .. } => (),
+    }
+}
+
+fn fold_unary(operator: &UnaryOperator, expr: &Expr) -> Option<ValueType> {
+    match (operator, literal(expr)?) {
+        (UnaryOperator::Negation, ValueType::Float(value)) => Some(ValueType::Float(-value)),
+        (UnaryOperator::Negation, ValueType::Float64(value)) => Some(ValueType::Float64(-value)),
+        (UnaryOperator::Not, ValueType::Boolean(value)) => Some(ValueType::Boolean(!value)),
+        _ => None,
+    }
+}
+
+fn fold_binary(left: &Expr, operator: &BinaryOperator, right: &Expr) -> Option<ValueType> {
+    let (left, right) = (literal(left)?, literal(right)?);
+    match (operator, left, right) {
+        (
+            BinaryOperator::IntegerOperation(operation),
+            ValueType::Integer(left),
+            ValueType::Integer(right),
+        ) => integer_operation(operation, *left, *right).map(ValueType::Integer),
+        (
+            BinaryOperator::IntegerOperation(operation),
+            ValueType::Integer64(left),
+            ValueType::Integer64(right),
+        ) => integer_operation(operation, *left, *right).map(ValueType::Integer64),
+        (
+            BinaryOperator::FloatOperation(operation),
+            ValueType::Float(left),
+            ValueType::Float(right),
+        ) => float_operation(operation, *left, *right).map(ValueType::Float),
+        (
+            BinaryOperator::FloatOperation(operation),
+            ValueType::Float64(left),
+            ValueType::Float64(right),
+        ) => float_operation(operation, *left, *right).map(ValueType::Float64),
+        (
+            BinaryOperator::BooleanOperation(operation),
+            ValueType::Boolean(left),
+            ValueType::Boolean(right),
+        ) => Some(ValueType::Boolean(match operation {
+            BooleanOperations::And => *left && *right,
+            BooleanOperations::Or => *left || *right,
+        })),
+        (
+            BinaryOperator::StringOperation(StringOperations::StringConcat),
+            ValueType::String(left),
+            right,
+        ) => Some(ValueType::String(left.clone() + &string_value(right)?)),
+        (
+            BinaryOperator::IntegerComparison(comparison),
+            ValueType::Integer(left),
+            ValueType::Integer(right),
+        ) => Some(ValueType::Boolean(compare(comparison, left, right))),
+        (
+            BinaryOperator::IntegerComparison(comparison),
+            ValueType::Integer64(left),
+            ValueType::Integer64(right),
+        ) => Some(ValueType::Boolean(compare(comparison, left, right))),
+        (
+            BinaryOperator::FloatComparison(comparison),
+            ValueType::Float(left),
+            ValueType::Float(right),
+        ) => Some(ValueType::Boolean(compare(comparison, left, right))),
+        (
+            BinaryOperator::FloatComparison(comparison),
+            ValueType::Float64(left),
+            ValueType::Float64(right),
+        ) => Some(ValueType::Boolean(compare(comparison, left, right))),
+        (BinaryOperator::Equality(equality), left, right) => {
+            let equal = literals_equal(left, right)?;
+            Some(ValueType::Boolean(match equality {
+                EqualityOperations::Equal => equal,
+                EqualityOperations::NotEqual => !equal,
+            }))
+        }
+        _ => None,
+    }
+}
+
+// Division and modulo by zero are not folded, so that the VM reports them
+fn integer_operation<T: arithmetic::WrappingInteger>(
+    operation: &ArithmeticOperations,
+    left: T,
+    right: T,
+) -> Option<T> {
+    match operation {
+        ArithmeticOperations::Addition => Some(arithmetic::integer_addition(left, right)),
+        ArithmeticOperations::Subtraction => Some(arithmetic::integer_subtraction(left, right)),
+        ArithmeticOperations::Multiplication => {
+            Some(arithmetic::integer_multiplication(left, right))
+        }
+        ArithmeticOperations::Division => arithmetic::integer_division(left, right),
+        ArithmeticOperations::Modulus => arithmetic::integer_modulo(left, right),
+    }
+}
+
+fn float_operation<T>(operation: &ArithmeticOperations, left: T, right: T) -> Option<T>
+where
+    T: Copy + PartialEq + Default + Add<Output = T> + Sub<Output = T> + Mul<Output = T>,
+    T: Div<Output = T> + Rem<Output = T>,
+{
+    match operation {
+        ArithmeticOperations::Addition => Some(left + right),
+        ArithmeticOperations::Subtraction => Some(left - right),
+        ArithmeticOperations::Multiplication => Some(left * right),
+        ArithmeticOperations::Division => arithmetic::float_division(left, right),
+        ArithmeticOperations::Modulus => arithmetic::float_modulo(left, right),
+    }
+}
+
+fn compare<T: PartialOrd>(comparison: &Comparisons, left: T, right: T) -> bool {
+    match comparison {
+        Comparisons::LessThan => left < right,
+        Comparisons::LessThanEqual => left <= right,
+        // The VM implements these as the negation of the opposite comparison
+        Comparisons::GreaterThan => !matches!(
+            left.partial_cmp(&right),
+            Some(Ordering::Less | Ordering::Equal)
+        ),
+        Comparisons::GreaterThanEqual => !matches!(left.partial_cmp(&right), Some(Ordering::Less)),
+    }
+}
+
+// Returns the index of the arm that a literal scrutinee is statically known to match
+fn matching_arm(scrutinee: &Expr, arms: &[IsArm]) -> Option<usize> {
+    let scrutinee = literal(scrutinee)?;
+    for (index, arm) in arms.iter().enumerate() {
+        if arm.guard.is_some() {
+            return None;
+        }
+        match &arm.pattern {
+            IsArmPattern::Expression(pattern) => {
+                if literals_equal(scrutinee, literal(pattern)?)? {
+                    return Some(index);
+                }
+            }
+            IsArmPattern::Default { token: _ } => return Some(index),
+            IsArmPattern::Capture { .. } | IsArmPattern::CaptureTagPayload { .. } => return None,
+        }
+    }
+    None
+}
+
+fn literal(expr: &Expr) -> Option<&ValueType> {
+    match expr {
+        Expr::Value { value, token: _ } => match value {
+            ValueType::Boolean(_)
+            | ValueType::Integer(_)
+            | ValueType::Integer64(_)
+            | ValueType::Float(_)
+            | ValueType::Float64(_)
+            | ValueType::String(_) => Some(value),
+            ValueType::Tag { name: _, payload } => match payload {
+                Some(payload) => literal(payload).map(|_| value),
+                None => Some(value),
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Mirrors how the VM compares values with `Equals`
+fn literals_equal(left: &ValueType, right: &ValueType) -> Option<bool> {
+    let equal = match (left, right) {
+        (ValueType::Boolean(left), ValueType::Boolean(right)) => left == right,
+        (ValueType::Integer(left), ValueType::Integer(right)) => left == right,
+        (ValueType::Integer64(left), ValueType::Integer64(right)) => left == right,
+        (ValueType::Float(left), ValueType::Float(right)) => left == right,
+        (ValueType::Float64(left), ValueType::Float64(right)) => left == right,
+        (ValueType::String(left), ValueType::String(right)) => left == right,
+        (
+            ValueType::Tag {
+                name: left_name,
+                payload: left_payload,
+            },
+            ValueType::Tag {
+                name: right_name,
+                payload: right_payload,
+            },
+        ) => {
+            left_name.lexeme == right_name.lexeme
+                && match (left_payload, right_payload) {
+                    (Some(left), Some(right)) => literals_equal(literal(left)?, literal(right)?)?,
+                    (None, None) => true,
+                    _ => false,
+                }
+        }
+        (
+            ValueType::Boolean(_)
+            | ValueType::Integer(_)
+            | ValueType::Integer64(_)
+            | ValueType::Float(_)
+            | ValueType::Float64(_)
+            | ValueType::String(_)
+            | ValueType::Tag { .. },
+            _,
+        ) => false,
+        _ => return None,
+    };
+    Some(equal)
+}
+
+// Mirrors how the VM converts values to strings when concatenating
+fn string_value(value: &ValueType) -> Option<String> {
+    match value {
+        ValueType::String(value) => Some(value.clone()),
+        ValueType::Integer(value) => Some(value.to_string()),
+        ValueType::Integer64(value) => Some(value.to_string()),
+        ValueType::Float(value) => Some(value.to_string()),
+        ValueType::Float64(value) => Some(value.to_string()),
+        ValueType::Boolean(value) => Some(value.to_string()),
+        ValueType::Tag {
+            name,
+            payload: None,
+        } => Some(name.lexeme.clone()),
+        ValueType::Tag {
+            name,
+            payload: Some(payload),
+        } => Some(format!(
+            "{}({})",
+            name.lexeme,
+            string_value(literal(payload)?)?
+        )),
+        _ => None,
+    }
+}
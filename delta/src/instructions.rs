@@ -0,0 +1,725 @@
+use std::collections::HashMap;
+
+use crate::bytecode_file::{ByteReader, DebugInfo};
+use crate::bytecodes::ByteCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Label(pub u32);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    IntegerAddition,
+    IntegerSubtraction,
+    IntegerDivision,
+    IntegerMultiplication,
+    IntegerModulo,
+    IntegerLessThan,
+    IntegerLessThanEquals,
+
+    FloatAddition,
+    FloatSubtraction,
+    FloatDivision,
+    FloatMultiplication,
+    FloatModulo,
+    FloatLessThan,
+    FloatLessThanEquals,
+
+    StringConcat,
+
+    BooleanAnd,
+    BooleanOr,
+
+    Equals,
+
+    Negation,
+    Not,
+
+    GetLocalValue {
+        index: u32,
+    },
+    SetLocalValue {
+        index: u32,
+    },
+    GetLocalValueAndOperate {
+        index: u32,
+        operation: ByteCode,
+    },
+
+    GetFieldValue {
+        index: u32,
+        component: u32, // the id of the component in the local, which the path starts from
+        field_path: Vec<u32>, // the field index in the component and in each nested component
+    },
+    SetFieldValue {
+        index: u32,
+        component: u32,
+        field_path: Vec<u32>,
+    },
+
+    GetContextValue {
+        name: String,
+    },
+    SetContextValue {
+        name: String,
+    },
+
+    PushTrue,
+    PushFalse,
+    PushFloat(f32),
+    PushFloat64(f64),
+    PushInteger(i32),
+    PushInteger64(i64),
+    PushString(String),
+    PushList {
+        length: u32,
+    },
+    PushComponent {
+        id: u32,
+        property_count: u32,
+    },
+
+    PushSimpleTag {
+        name: String,
+    },
+    PushTag {
+        name: String,
+    },
+    GetTagName,
+    GetTagPayload,
+
+    FunctionChunk,
+    Function {
+        function_index: u32,
+        param_count: u32,
+    },
+    Return,
+    Call {
+        arg_count: u32,
+        is_global: bool,
+        index: u32,
+        name: Option<String>, // the name of the called function, only kept in the debug info
+    },
+
+    CallForeign {
+        foreign_index: u32,
+        arg_count: u32,
+    },
+    GetForeignValue {
+        name: String,
+    },
+
+    Jump(Label),
+    JumpIfTrue(Label),
+    JumpIfFalse(Label),
+
+    GetListElementAtIndex,
+    GetArrayLength,
+    ArrayAppend,
+
+    Log,
+
+    ContextQuery {
+        end: Label,
+        local_index: u32, // the local that the entity is bound to, followed by the components
+        has_parent: bool, // only match the children of the entity popped from the stack
+        include_components: Vec<u32>,
+        optional_components: Vec<u32>,
+        exclude_components: Vec<u32>,
+        added_components: Vec<u32>, // included components that must have been added since the last run
+        changed_components: Vec<u32>, // included components that must have been changed since the last run
+    },
+    SetNextComponentColumnOrJump,
+    Create,
+    Destroy,
+    AddComponent,
+    RemoveComponent {
+        id: u32,
+    },
+
+    // Pseudo-instruction marking a jump target. Takes up no space in the encoded bytecode.
+    Label(Label),
+    // Pseudo-instruction marking the start of the code for a source line. Recorded in the debug info.
+    Line(u32),
+}
+
+impl Instruction {
+    pub fn opcode(&self) -> Option<ByteCode> {
+        let opcode = match self {
+            Instruction::IntegerAddition => ByteCode::IntegerAddition,
+            Instruction::IntegerSubtraction => ByteCode::IntegerSubtraction,
+            Instruction::IntegerDivision => ByteCode::IntegerDivision,
+            Instruction::IntegerMultiplication => ByteCode::IntegerMultiplication,
+            Instruction::IntegerModulo => ByteCode::IntegerModulo,
+            Instruction::IntegerLessThan => ByteCode::IntegerLessThan,
+            Instruction::IntegerLessThanEquals => ByteCode::IntegerLessThanEquals,
+            Instruction::FloatAddition => ByteCode::FloatAddition,
+            Instruction::FloatSubtraction => ByteCode::FloatSubtraction,
+            Instruction::FloatDivision => ByteCode::FloatDivision,
+            Instruction::FloatMultiplication => ByteCode::FloatMultiplication,
+            Instruction::FloatModulo => ByteCode::FloatModulo,
+            Instruction::FloatLessThan => ByteCode::FloatLessThan,
+            Instruction::FloatLessThanEquals => ByteCode::FloatLessThanEquals,
+            Instruction::StringConcat => ByteCode::StringConcat,
+            Instruction::BooleanAnd => ByteCode::BooleanAnd,
+            Instruction::BooleanOr => ByteCode::BooleanOr,
+            Instruction::Equals => ByteCode::Equals,
+            Instruction::Negation => ByteCode::Negation,
+            Instruction::Not => ByteCode::Not,
+            Instruction::GetLocalValue { .. } => ByteCode::GetLocalValue,
+            Instruction::SetLocalValue { .. } => ByteCode::SetLocalValue,
+            Instruction::GetLocalValueAndOperate { .. } => ByteCode::GetLocalValueAndOperate,
+            Instruction::GetFieldValue { .. } => ByteCode::GetFieldValue,
+            Instruction::SetFieldValue { .. } => ByteCode::SetFieldValue,
+            Instruction::GetContextValue { .. } => ByteCode::GetContextValue,
+            Instruction::SetContextValue { .. } => ByteCode::SetContextValue,
+            Instruction::PushTrue => ByteCode::PushTrue,
+            Instruction::PushFalse => ByteCode::PushFalse,
+            Instruction::PushFloat(_) => ByteCode::PushFloat,
+            Instruction::PushFloat64(_) => ByteCode::PushFloat64,
+            Instruction::PushInteger(_) => ByteCode::PushInteger,
+            Instruction::PushInteger64(_) => ByteCode::PushInteger64,
+            Instruction::PushString(_) => ByteCode::PushString,
+            Instruction::PushList { .. } => ByteCode::PushList,
+            Instruction::PushComponent { .. } => ByteCode::PushComponent,
+            Instruction::PushSimpleTag { .. } => ByteCode::PushSimpleTag,
+            Instruction::PushTag { .. } => ByteCode::PushTag,
+            Instruction::GetTagName => ByteCode::GetTagName,
+            Instruction::GetTagPayload => ByteCode::GetTagPayload,
+            Instruction::FunctionChunk => ByteCode::FunctionChunk,
+            Instruction::Function { .. } => ByteCode::Function,
+            Instruction::Return => ByteCode::Return,
+            Instruction::Call { .. } => ByteCode::Call,
+            Instruction::CallForeign { .. } => ByteCode::CallForeign,
+            Instruction::GetForeignValue { .. } => ByteCode::GetForeignValue,
+            Instruction::Jump(_) => ByteCode::Jump,
+            Instruction::JumpIfTrue(_) => ByteCode::JumpIfTrue,
+            Instruction::JumpIfFalse(_) => ByteCode::JumpIfFalse,
+            Instruction::GetListElementAtIndex => ByteCode::GetListElementAtIndex,
+            Instruction::GetArrayLength => ByteCode::GetArrayLength,
+            Instruction::ArrayAppend => ByteCode::ArrayAppend,
+            Instruction::Log => ByteCode::Log,
+            Instruction::ContextQuery { .. } => ByteCode::ContextQuery,
+            Instruction::SetNextComponentColumnOrJump => ByteCode::SetNextComponentColumnOrJump,
+            Instruction::Create => ByteCode::Create,
+            Instruction::Destroy => ByteCode::Destroy,
+            Instruction::AddComponent => ByteCode::AddComponent,
+            Instruction::RemoveComponent { .. } => ByteCode::RemoveComponent,
+            Instruction::Label(_) | Instruction::Line(_) => return None,
+        };
+        Some(opcode)
+    }
+
+    // Strings referenced by the instruction, in the order they are encoded
+    fn constants(&self) -> Vec<&String> {
+        match self {
+            Instruction::GetContextValue { name }
+            | Instruction::SetContextValue { name }
+            | Instruction::PushSimpleTag { name }
+            | Instruction::PushTag { name }
+            | Instruction::GetForeignValue { name } => vec![name],
+            Instruction::PushString(value) => vec![value],
+            _ => vec![],
+        }
+    }
+
+    // Number of bytes the instruction takes up when encoded, including the opcode.
+    // The strings of the instruction must already have been added to the constant pool.
+    pub fn size(&self, constants: &ConstantPool) -> usize {
+        let string_size = |s: &String| varint_size(constants.index(s));
+        let operands = match self {
+            Instruction::Label(_) | Instruction::Line(_) => return 0,
+            Instruction::GetLocalValue { index } | Instruction::SetLocalValue { index } => {
+                varint_size(*index)
+            }
+            Instruction::GetLocalValueAndOperate { index, .. } => varint_size(*index) + 1,
+            Instruction::GetFieldValue {
+                index,
+                component,
+                field_path,
+            }
+            | Instruction::SetFieldValue {
+                index,
+                component,
+                field_path,
+            } => {
+                varint_size(*index)
+                    + varint_size(*component)
+                    + varint_size(field_path.len() as u32)
+                    + field_path
+                        .iter()
+                        .map(|field| varint_size(*field))
+                        .sum::<usize>()
+            }
+            Instruction::GetContextValue { name }
+            | Instruction::SetContextValue { name }
+            | Instruction::PushSimpleTag { name }
+            | Instruction::PushTag { name }
+            | Instruction::GetForeignValue { name } => string_size(name),
+            Instruction::PushFloat(_) | Instruction::PushInteger(_) => 4,
+            Instruction::PushFloat64(_) | Instruction::PushInteger64(_) => 8,
+            Instruction::PushString(value) => string_size(value),
+            Instruction::PushList { length } | Instruction::RemoveComponent { id: length } => {
+                varint_size(*length)
+            }
+            Instruction::PushComponent { id, property_count } => {
+                varint_size(*id) + varint_size(*property_count)
+            }
+            Instruction::Function {
+                function_index,
+                param_count,
+            } => varint_size(*function_index) + varint_size(*param_count),
+            Instruction::Call {
+                arg_count, index, ..
+            } => varint_size(*arg_count) + 1 + varint_size(*index),
+            Instruction::CallForeign {
+                foreign_index,
+                arg_count,
+            } => varint_size(*foreign_index) + varint_size(*arg_count),
+            Instruction::Jump(_) | Instruction::JumpIfTrue(_) | Instruction::JumpIfFalse(_) => {
+                JUMP_OFFSET_SIZE
+            }
+            Instruction::ContextQuery {
+                local_index,
+                include_components,
+                optional_components,
+                exclude_components,
+                added_components,
+                changed_components,
+                ..
+            } => {
+                JUMP_OFFSET_SIZE
+                    + varint_size(*local_index)
+                    + 1 // has parent
+                    + varint_size(include_components.len() as u32)
+                    + varint_size(optional_components.len() as u32)
+                    + varint_size(exclude_components.len() as u32)
+                    + varint_size(added_components.len() as u32)
+                    + varint_size(changed_components.len() as u32)
+                    + include_components
+                        .iter()
+                        .chain(optional_components.iter())
+                        .chain(exclude_components.iter())
+                        .chain(added_components.iter())
+                        .chain(changed_components.iter())
+                        .map(|id| varint_size(*id))
+                        .sum::<usize>()
+            }
+            _ => 0,
+        };
+        1 + operands
+    }
+
+    // Decodes the instruction at the position of the reader. Strings are looked up in the constant pool, and jump
+    // targets are labelled with the byte position they jump to. Call names are only kept in the debug info, so they
+    // are left empty.
+    pub fn decode(reader: &mut ByteReader, constants: &[String]) -> Result<Instruction, String> {
+        let opcode = reader.read_byte()?;
+        let bytecode =
+            ByteCode::try_from(opcode).map_err(|_| format!("Invalid instruction {opcode}"))?;
+        let read_string = |reader: &mut ByteReader| -> Result<String, String> {
+            let index = reader.read_varint()?;
+            constants
+                .get(index as usize)
+                .cloned()
+                .ok_or_else(|| format!("Unknown constant {index}"))
+        };
+        let read_label = |reader: &mut ByteReader| -> Result<Label, String> {
+            // Offsets are relative to the byte following the offset
+            let offset = reader.read_u32()? as i32;
+            u32::try_from(reader.position() as i64 + offset as i64)
+                .map(Label)
+                .map_err(|_| format!("Jump offset {offset} is out of bounds"))
+        };
+        let read_ids = |reader: &mut ByteReader, count: u32| -> Result<Vec<u32>, String> {
+            (0..count).map(|_| reader.read_varint()).collect()
+        };
+
+        let instruction = match bytecode {
+            ByteCode::IntegerAddition => Instruction::IntegerAddition,
+            ByteCode::IntegerSubtraction => Instruction::IntegerSubtraction,
+            ByteCode::IntegerDivision => Instruction::IntegerDivision,
+            ByteCode::IntegerMultiplication => Instruction::IntegerMultiplication,
+            ByteCode::IntegerModulo => Instruction::IntegerModulo,
+            ByteCode::IntegerLessThan => Instruction::IntegerLessThan,
+            ByteCode::IntegerLessThanEquals => Instruction::IntegerLessThanEquals,
+            ByteCode::FloatAddition => Instruction::FloatAddition,
+            ByteCode::FloatSubtraction => Instruction::FloatSubtraction,
+            ByteCode::FloatDivision => Instruction::FloatDivision,
+            ByteCode::FloatMultiplication => Instruction::FloatMultiplication,
+            ByteCode::FloatModulo => Instruction::FloatModulo,
+            ByteCode::FloatLessThan => Instruction::FloatLessThan,
+            ByteCode::FloatLessThanEquals => Instruction::FloatLessThanEquals,
+            ByteCode::StringConcat => Instruction::StringConcat,
+            ByteCode::BooleanAnd => Instruction::BooleanAnd,
+            ByteCode::BooleanOr => Instruction::BooleanOr,
+            ByteCode::Equals => Instruction::Equals,
+            ByteCode::Negation => Instruction::Negation,
+            ByteCode::Not => Instruction::Not,
+            ByteCode::GetLocalValue => Instruction::GetLocalValue {
+                index: reader.read_varint()?,
+            },
+            ByteCode::SetLocalValue => Instruction::SetLocalValue {
+                index: reader.read_varint()?,
+            },
+            ByteCode::GetLocalValueAndOperate => {
+                let index = reader.read_varint()?;
+                let operation = reader.read_byte()?;
+                let operation = ByteCode::try_from(operation)
+                    .map_err(|_| format!("Invalid binary operation {operation}"))?;
+                Instruction::GetLocalValueAndOperate { index, operation }
+            }
+            ByteCode::GetFieldValue | ByteCode::SetFieldValue => {
+                let index = reader.read_varint()?;
+                let component = reader.read_varint()?;
+                let length = reader.read_varint()?;
+                let field_path = read_ids(reader, length)?;
+                match bytecode {
+                    ByteCode::GetFieldValue => Instruction::GetFieldValue {
+                        index,
+                        component,
+                        field_path,
+                    },
+                    _ => Instruction::SetFieldValue {
+                        index,
+                        component,
+                        field_path,
+                    },
+                }
+            }
+            ByteCode::GetContextValue => Instruction::GetContextValue {
+                name: read_string(reader)?,
+            },
+            ByteCode::SetContextValue => Instruction::SetContextValue {
+                name: read_string(reader)?,
+            },
+            ByteCode::PushTrue => Instruction::PushTrue,
+            ByteCode::PushFalse => Instruction::PushFalse,
+            ByteCode::PushFloat => Instruction::PushFloat(f32::from_bits(reader.read_u32()?)),
+            ByteCode::PushFloat64 => {
+                let bytes = reader.read_bytes(8)?.try_into().unwrap();
+                Instruction::PushFloat64(f64::from_be_bytes(bytes))
+            }
+            ByteCode::PushInteger => Instruction::PushInteger(reader.read_u32()? as i32),
+            ByteCode::PushInteger64 => {
+                let bytes = reader.read_bytes(8)?.try_into().unwrap();
+                Instruction::PushInteger64(i64::from_be_bytes(bytes))
+            }
+            ByteCode::PushString => Instruction::PushString(read_string(reader)?),
+            ByteCode::PushList => Instruction::PushList {
+                length: reader.read_varint()?,
+            },
+            ByteCode::PushComponent => Instruction::PushComponent {
+                id: reader.read_varint()?,
+                property_count: reader.read_varint()?,
+            },
+            ByteCode::PushSimpleTag => Instruction::PushSimpleTag {
+                name: read_string(reader)?,
+            },
+            ByteCode::PushTag => Instruction::PushTag {
+                name: read_string(reader)?,
+            },
+            ByteCode::GetTagName => Instruction::GetTagName,
+            ByteCode::GetTagPayload => Instruction::GetTagPayload,
+            ByteCode::FunctionChunk => Instruction::FunctionChunk,
+            ByteCode::Function => Instruction::Function {
+                function_index: reader.read_varint()?,
+                param_count: reader.read_varint()?,
+            },
+            ByteCode::Return => Instruction::Return,
+            ByteCode::Call => {
+                let arg_count = reader.read_varint()?;
+                let is_global = match reader.read_byte()? {
+                    0 => false,
+                    1 => true,
+                    flag => return Err(format!("Invalid call flag {flag}")),
+                };
+                Instruction::Call {
+                    arg_count,
+                    is_global,
+                    index: reader.read_varint()?,
+                    name: None,
+                }
+            }
+            ByteCode::CallForeign => Instruction::CallForeign {
+                foreign_index: reader.read_varint()?,
+                arg_count: reader.read_varint()?,
+            },
+            ByteCode::GetForeignValue => Instruction::GetForeignValue {
+                name: read_string(reader)?,
+            },
+            ByteCode::Jump => Instruction::Jump(read_label(reader)?),
+            ByteCode::JumpIfTrue => Instruction::JumpIfTrue(read_label(reader)?),
+            ByteCode::JumpIfFalse => Instruction::JumpIfFalse(read_label(reader)?),
+            ByteCode::GetListElementAtIndex => Instruction::GetListElementAtIndex,
+            ByteCode::GetArrayLength => Instruction::GetArrayLength,
+            ByteCode::ArrayAppend => Instruction::ArrayAppend,
+            ByteCode::Log => Instruction::Log,
+            ByteCode::ContextQuery => {
+                let end = read_label(reader)?;
+                let local_index = reader.read_varint()?;
+                let has_parent = match reader.read_byte()? {
+                    0 => false,
+                    1 => true,
+                    flag => return Err(format!("Invalid query parent flag {flag}")),
+                };
+                let mut counts = [0; 5];
+                for count in &mut counts {
+                    *count = reader.read_varint()?;
+                }
+                let [include, optional, exclude, added, changed] = counts;
+                Instruction::ContextQuery {
+                    end,
+                    local_index,
+                    has_parent,
+                    include_components: read_ids(reader, include)?,
+                    optional_components: read_ids(reader, optional)?,
+                    exclude_components: read_ids(reader, exclude)?,
+                    added_components: read_ids(reader, added)?,
+                    changed_components: read_ids(reader, changed)?,
+                }
+            }
+            ByteCode::SetNextComponentColumnOrJump => Instruction::SetNextComponentColumnOrJump,
+            ByteCode::Create => Instruction::Create,
+            ByteCode::Destroy => Instruction::Destroy,
+            ByteCode::AddComponent => Instruction::AddComponent,
+            ByteCode::RemoveComponent => Instruction::RemoveComponent {
+                id: reader.read_varint()?,
+            },
+        };
+        Ok(instruction)
+    }
+}
+
+// Jump offsets are encoded as a fixed-size i32 so that instruction sizes are known before labels are resolved
+pub const JUMP_OFFSET_SIZE: usize = 4;
+
+// Number of bytes needed to encode the value as a variable-length (LEB128) integer
+pub fn varint_size(value: u32) -> usize {
+    let mut size = 1;
+    let mut value = value >> 7;
+    while value != 0 {
+        size += 1;
+        value >>= 7;
+    }
+    size
+}
+
+// Encodes the value as a variable-length (LEB128) integer, i.e. 7 bits per byte with the high bit set on all but the last byte
+pub fn add_varint(bytes: &mut Vec<u8>, value: u32) {
+    let mut value = value;
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+// Strings are stored once in the constant pool of the bytecode file and referenced by their index
+#[derive(Debug, Default)]
+pub struct ConstantPool {
+    constants: Vec<String>,
+    indices: HashMap<String, u32>,
+}
+
+impl ConstantPool {
+    pub fn add(&mut self, value: &str) -> u32 {
+        if let Some(index) = self.indices.get(value) {
+            return *index;
+        }
+        let index = self.constants.len() as u32;
+        self.constants.push(value.to_string());
+        self.indices.insert(value.to_string(), index);
+        index
+    }
+
+    pub fn index(&self, value: &str) -> u32 {
+        self.indices[value]
+    }
+
+    pub fn into_constants(self) -> Vec<String> {
+        self.constants
+    }
+}
+
+// Encodes the instructions at the end of `code`, adding their strings to the constant pool and their names and
+// source lines to the debug info
+pub fn encode(
+    instructions: &[Instruction],
+    code: &mut Vec<u8>,
+    constants: &mut ConstantPool,
+    debug_info: &mut DebugInfo,
+) {
+    for instruction in instructions {
+        for constant in instruction.constants() {
+            constants.add(constant);
+        }
+    }
+
+    let mut label_positions = HashMap::new();
+    let mut position = code.len();
+    for instruction in instructions {
+        if let Instruction::Label(label) = instruction {
+            label_positions.insert(*label, position);
+        }
+        position += instruction.size(constants);
+    }
+
+    let mut encoder = Encoder {
+        bytes: code,
+        label_positions,
+        constants,
+        debug_info,
+    };
+    for instruction in instructions {
+        encoder.encode(instruction);
+    }
+}
+
+struct Encoder<'a> {
+    bytes: &'a mut Vec<u8>,
+    label_positions: HashMap<Label, usize>,
+    constants: &'a ConstantPool,
+    debug_info: &'a mut DebugInfo,
+}
+
+impl Encoder<'_> {
+    fn encode(&mut self, instruction: &Instruction) {
+        let position = self.bytes.len() as u32;
+        match instruction {
+            Instruction::Line(line) => {
+                self.debug_info.lines.insert(position, *line);
+            }
+            Instruction::Call {
+                name: Some(name), ..
+            } => {
+                self.debug_info.call_names.insert(position, name.clone());
+            }
+            _ => (),
+        }
+
+        let Some(opcode) = instruction.opcode() else {
+            return; // labels and lines are not part of the encoded bytecode
+        };
+        self.add_byte(opcode.into());
+
+        match instruction {
+            Instruction::GetLocalValue { index } | Instruction::SetLocalValue { index } => {
+                self.add_varint(*index)
+            }
+            Instruction::GetLocalValueAndOperate { index, operation } => {
+                self.add_varint(*index);
+                self.add_byte((*operation).into());
+            }
+            Instruction::GetFieldValue {
+                index,
+                component,
+                field_path,
+            }
+            | Instruction::SetFieldValue {
+                index,
+                component,
+                field_path,
+            } => {
+                self.add_varint(*index);
+                self.add_varint(*component);
+                self.add_varint(field_path.len() as u32);
+                field_path.iter().for_each(|field| self.add_varint(*field));
+            }
+            Instruction::GetContextValue { name }
+            | Instruction::SetContextValue { name }
+            | Instruction::PushSimpleTag { name }
+            | Instruction::PushTag { name }
+            | Instruction::GetForeignValue { name } => self.add_string(name),
+            Instruction::PushFloat(value) => self.add_bytes(&value.to_be_bytes()),
+            Instruction::PushFloat64(value) => self.add_bytes(&value.to_be_bytes()),
+            Instruction::PushInteger(value) => self.add_bytes(&value.to_be_bytes()),
+            Instruction::PushInteger64(value) => self.add_bytes(&value.to_be_bytes()),
+            Instruction::PushString(value) => self.add_string(value),
+            Instruction::PushList { length } => self.add_varint(*length),
+            Instruction::RemoveComponent { id } => self.add_varint(*id),
+            Instruction::PushComponent { id, property_count } => {
+                self.add_varint(*id);
+                self.add_varint(*property_count);
+            }
+            Instruction::Function {
+                function_index,
+                param_count,
+            } => {
+                self.add_varint(*function_index);
+                self.add_varint(*param_count);
+            }
+            Instruction::Call {
+                arg_count,
+                is_global,
+                index,
+                name: _,
+            } => {
+                self.add_varint(*arg_count);
+                self.add_byte(if *is_global { 1 } else { 0 });
+                self.add_varint(*index);
+            }
+            Instruction::CallForeign {
+                foreign_index,
+                arg_count,
+            } => {
+                self.add_varint(*foreign_index);
+                self.add_varint(*arg_count);
+            }
+            Instruction::Jump(label)
+            | Instruction::JumpIfTrue(label)
+            | Instruction::JumpIfFalse(label) => self.add_jump_offset(label),
+            Instruction::ContextQuery {
+                end,
+                local_index,
+                has_parent,
+                include_components,
+                optional_components,
+                exclude_components,
+                added_components,
+                changed_components,
+            } => {
+                self.add_jump_offset(end);
+                self.add_varint(*local_index);
+                self.add_byte(*has_parent as u8);
+                self.add_varint(include_components.len() as u32);
+                self.add_varint(optional_components.len() as u32);
+                self.add_varint(exclude_components.len() as u32);
+                self.add_varint(added_components.len() as u32);
+                self.add_varint(changed_components.len() as u32);
+                for id in include_components
+                    .iter()
+                    .chain(optional_components.iter())
+                    .chain(exclude_components.iter())
+                    .chain(added_components.iter())
+                    .chain(changed_components.iter())
+                {
+                    self.add_varint(*id);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    fn add_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn add_varint(&mut self, value: u32) {
+        add_varint(self.bytes, value);
+    }
+
+    fn add_string(&mut self, value: &str) {
+        self.add_varint(self.constants.index(value));
+    }
+
+    fn add_jump_offset(&mut self, label: &Label) {
+        // Offsets are relative to the byte following the offset itself
+        let target = *self.label_positions.get(label).expect("Unknown label") as isize;
+        let offset = target - (self.bytes.len() + JUMP_OFFSET_SIZE) as isize;
+        self.add_bytes(&(offset as i32).to_be_bytes());
+    }
+}
@@ -95,6 +95,7 @@ impl Lexer {
             '=' if self.matches('=') => TokenKind::EqualEqual,
             '=' => TokenKind::Equal,
             ',' => TokenKind::Comma,
+            '?' => TokenKind::QuestionMark,
             '#' => self.comment(),
             '|' => TokenKind::Pipe,
             '∆' => self.context(),
@@ -148,9 +149,13 @@ impl Lexer {
             _ if self.match_keyword("create") => TokenKind::KeywordCreate,
             _ if self.match_keyword("destroy") => TokenKind::KeywordDestroy,
             _ if self.match_keyword("f32") => TokenKind::KeywordF32,
+            _ if self.match_keyword("f64") => TokenKind::KeywordF64,
             _ if self.match_keyword("i32") => TokenKind::KeywordI32,
+            _ if self.match_keyword("i64") => TokenKind::KeywordI64,
             _ if self.match_keyword("str") => TokenKind::KeywordStr,
             _ if self.match_keyword("not") => TokenKind::KeywordNot,
+            _ if self.match_keyword("on") => TokenKind::KeywordOn,
+            _ if self.match_keyword("resource") => TokenKind::KeywordResource,
             // '∆' if self.matches('.') && self.is_letter(self.peek()) => self.context_value(), // TODO(anissen): Key-value pair, e.g. ∆.x = y
             // '∆' => self.identifier(), // TODO(anissen): Named context, e.g. ∆x or ∆x.y
             c if self.is_letter(c) => self.identifier(),
@@ -218,7 +223,7 @@ impl Lexer {
             } else {
                 let token_after_keyword = self.peek_at(keyword.len());
                 match token_after_keyword {
-                    '\0' | ' ' | ',' | '\n' => {
+                    '\0' | ' ' | ',' | '\n' | ')' | ']' | '}' => {
                         // TODO(anissen): This should be an allow-list (e.g. [a-z][0-1]) instead of a black-list
                         self.advance_many(keyword.len() - 1);
                         true
@@ -240,12 +245,39 @@ impl Lexer {
             while self.is_digit(self.peek()) {
                 self.advance();
             }
-            TokenKind::Float
+            if self.matches_suffix("f64") {
+                TokenKind::Float64
+            } else {
+                TokenKind::Float
+            }
+        } else if self.matches_suffix("i64") {
+            TokenKind::Integer64
+        } else if self.matches_suffix("f64") {
+            TokenKind::Float64
         } else {
             TokenKind::Integer
         }
     }
 
+    // Matches a type suffix directly following a number literal, e.g. the `i64` in `42i64`
+    fn matches_suffix(&mut self, suffix: &str) -> bool {
+        let end = self.current + suffix.len();
+        if end > self.source.len()
+            || self.source[self.current..end]
+                .iter()
+                .copied()
+                .ne(suffix.chars())
+        {
+            return false;
+        }
+        let char_after_suffix = self.source.get(end).copied().unwrap_or_default();
+        if self.is_letter(char_after_suffix) || self.is_digit(char_after_suffix) {
+            return false;
+        }
+        self.advance_many(suffix.len());
+        true
+    }
+
     fn context(&mut self) -> TokenKind {
         self.identifier();
         TokenKind::Context
@@ -263,7 +295,8 @@ impl Lexer {
             if self.is_at_end() {
                 return TokenKind::SyntaxError("Unterminated tag");
             }
-            if self.peek().is_whitespace() {
+            // Tags also end at delimiters, e.g. in `[:a, :b]` and `{ state :idle | :walking }`
+            if self.peek().is_whitespace() || matches!(self.peek(), ',' | ')' | ']' | '}') {
                 break;
             }
             self.advance();
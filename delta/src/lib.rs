@@ -1,16 +1,24 @@
+mod arithmetic;
+mod assembler;
+mod bytecode_file;
 mod bytecodes;
 mod codegen;
 pub mod diagnostics;
 mod disassembler;
 mod errors;
 mod expressions;
+mod folding;
+mod instructions;
 mod lexer;
+mod optimizer;
 mod parser;
 pub mod program;
 mod resolver;
+mod save_file;
 mod tokens;
 mod typer;
 mod unification;
+mod verifier;
 pub mod vm;
 
 use std::{fs::File, io::Read};
@@ -50,6 +58,7 @@ pub struct DeltaArguments {
     pub source_path: String,
     pub no_run: bool,
     pub debug: bool,
+    pub release: bool, // compile without debug info
 }
 
 pub fn read_file(path: &String) -> std::io::Result<String> {
@@ -60,15 +69,51 @@ pub fn read_file(path: &String) -> std::io::Result<String> {
 }
 
 pub fn run_file(args: &DeltaArguments) -> Result<ProgramResult, Diagnostics> {
-    let source = read_file(&args.source_path);
-    match source {
+    let bytes = std::fs::read(&args.source_path).map_err(file_error)?;
+    if bytecode_file::is_bytecode_file(&bytes) {
+        return run_bytecode(bytes, args);
+    }
+    match String::from_utf8(bytes) {
         Ok(source) => run(&source, args),
-        Err(err) => {
-            let mut diagnostics = Diagnostics::new();
-            diagnostics.add_error(errors::Error::FileErr(err.to_string()));
-            Err(diagnostics)
-        }
+        Err(err) => Err(file_error(err)),
+    }
+}
+
+// Compiles the source file and writes the bytecode to `output_path`, so it can be run without the source
+pub fn compile_file(args: &DeltaArguments, output_path: &str) -> Result<usize, Diagnostics> {
+    let source = read_file(&args.source_path).map_err(file_error)?;
+
+    let context = program::Context::new();
+    let mut program = Program::new(context, args);
+    if let Some(diagnostics) = program.reload(source) {
+        return Err(diagnostics);
     }
+
+    let bytecode = &program.metadata.compilation_metadata.bytecode;
+    std::fs::write(output_path, bytecode).map_err(file_error)?;
+    Ok(bytecode.len())
+}
+
+// Assembles a disassembly listing (see `assembler::assemble`) and writes the bytecode to `output_path`
+pub fn assemble_file(source_path: &String, output_path: &str) -> Result<usize, Diagnostics> {
+    let source = read_file(source_path).map_err(file_error)?;
+    let bytecode = assemble(&source)?;
+    std::fs::write(output_path, &bytecode).map_err(file_error)?;
+    Ok(bytecode.len())
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, Diagnostics> {
+    assembler::assemble(source).map_err(|error| {
+        let mut diagnostics = Diagnostics::new();
+        diagnostics.add_error(errors::Error::AssemblyErr(error));
+        diagnostics
+    })
+}
+
+fn file_error(err: impl ToString) -> Diagnostics {
+    let mut diagnostics = Diagnostics::new();
+    diagnostics.add_error(errors::Error::FileErr(err.to_string()));
+    diagnostics
 }
 
 /*
@@ -76,7 +121,7 @@ pub fn run_file(args: &DeltaArguments) -> Result<ProgramResult, Diagnostics> {
 
     E.g.
     let context = delta::context::new();
-    context.add_function("draw_circle", |call| {
+    context.add_function("draw_circle", 3, |call| {
         let x = call.get_float("x");
         let y = call.get_float("y");
         let radius = call.get_float("radius");
@@ -101,16 +146,36 @@ pub fn run(source: &str, args: &DeltaArguments) -> Result<ProgramResult, Diagnos
 
     let context = program::Context::new();
     let mut program = Program::new(context, args);
-    let result = program.reload(source.to_string());
-    match result {
-        None => {
-            println!("\n# vm =>");
-            let value = if !args.no_run { program.run() } else { None };
-            Ok(ProgramResult {
-                value,
-                metadata: program.metadata,
-            })
-        }
+    match program.reload(source.to_string()) {
+        None => run_program(program, args),
         Some(diagnostics) => Err(diagnostics),
     }
 }
+
+pub fn run_bytecode(
+    bytecode: Vec<u8>,
+    args: &DeltaArguments,
+) -> Result<ProgramResult, Diagnostics> {
+    println!("\n# bytecode (file: {}) =>", &args.source_path);
+
+    let context = program::Context::new();
+    let mut program = Program::new(context, args);
+    program.load(bytecode)?;
+    run_program(program, args)
+}
+
+fn run_program(mut program: Program, args: &DeltaArguments) -> Result<ProgramResult, Diagnostics> {
+    println!("\n# vm =>");
+    let value = match args.no_run {
+        false => program.run().map_err(|error| {
+            let mut diagnostics = Diagnostics::new();
+            diagnostics.add_error(errors::Error::RuntimeErr(error));
+            diagnostics
+        })?,
+        true => None,
+    };
+    Ok(ProgramResult {
+        value,
+        metadata: program.metadata,
+    })
+}
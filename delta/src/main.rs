@@ -8,10 +8,21 @@ fn main() {
         exit(1);
     }
 
+    if args[1] == "compile" {
+        compile(&args);
+        return;
+    }
+
+    if args[1] == "assemble" {
+        assemble(&args);
+        return;
+    }
+
     let delta_args = delta::DeltaArguments {
         source_path: args[1].clone(),
         debug: args.contains(&"--debug".to_string()),
         no_run: args.contains(&"--no-run".to_string()),
+        release: args.contains(&"--release".to_string()),
     };
     let result = delta::run_file(&delta_args);
     match result {
@@ -23,23 +34,72 @@ fn main() {
                 println!("\nResult: N/A");
             }
         },
+        Err(diagnostics) => print_diagnostics(&diagnostics, &delta_args.source_path),
+    }
+}
+
+// Usage: delta compile <source file> -o <output file> [--release]
+fn compile(args: &[String]) {
+    let (source_path, output_path) = input_and_output_paths(args);
+
+    let delta_args = delta::DeltaArguments {
+        source_path: source_path.clone(),
+        debug: args.contains(&"--debug".to_string()),
+        no_run: true,
+        release: args.contains(&"--release".to_string()),
+    };
+    match delta::compile_file(&delta_args, output_path) {
+        Ok(length) => println!("\nCompiled {source_path} to {output_path} ({length} bytes)"),
         Err(diagnostics) => {
-            println!();
-            let source = delta::read_file(&delta_args.source_path);
-            for ele in diagnostics.print(&source.unwrap()) {
-                println!("\x1b[31merror:\x1b[0m");
-                println!("{ele}");
-                println!();
-                //                 println!(
-                //                     "\x1b[31merror:\x1b[0m
-                //    ┌─ {filePath + fileName}:{line}:{column}
-                //    │
-                // {line}  │   {error_line}
-                //    │   {arrows}
-                //    │
-                // {hint}"
-                // );
-            }
+            print_diagnostics(&diagnostics, source_path);
+            exit(1);
         }
     }
 }
+
+// Usage: delta assemble <disassembly file> -o <output file>
+fn assemble(args: &[String]) {
+    let (source_path, output_path) = input_and_output_paths(args);
+    match delta::assemble_file(source_path, output_path) {
+        Ok(length) => println!("Assembled {source_path} to {output_path} ({length} bytes)"),
+        Err(diagnostics) => {
+            print_diagnostics(&diagnostics, source_path);
+            exit(1);
+        }
+    }
+}
+
+fn input_and_output_paths(args: &[String]) -> (&String, &String) {
+    let output_path = args
+        .iter()
+        .position(|arg| arg == "-o")
+        .and_then(|position| args.get(position + 1));
+    let source_path = args[2..]
+        .iter()
+        .find(|arg| !arg.starts_with('-') && Some(*arg) != output_path);
+    let (Some(source_path), Some(output_path)) = (source_path, output_path) else {
+        println!("Usage: delta {} <source file> -o <output file>", args[1]);
+        exit(1);
+    };
+    (source_path, output_path)
+}
+
+fn print_diagnostics(diagnostics: &delta::diagnostics::Diagnostics, source_path: &String) {
+    println!();
+    // Precompiled bytecode files have no source to show
+    let source = delta::read_file(source_path).unwrap_or_default();
+    for ele in diagnostics.print(&source) {
+        println!("\x1b[31merror:\x1b[0m");
+        println!("{ele}");
+        println!();
+        //                 println!(
+        //                     "\x1b[31merror:\x1b[0m
+        //    ┌─ {filePath + fileName}:{line}:{column}
+        //    │
+        // {line}  │   {error_line}
+        //    │   {arrows}
+        //    │
+        // {hint}"
+        // );
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::arithmetic;
+use crate::bytecodes::ByteCode;
+use crate::instructions::{Instruction, Label};
+
+// Runs the optimization passes on the instructions of a single function chunk until nothing changes
+pub fn optimize(mut instructions: Vec<Instruction>) -> Vec<Instruction> {
+    loop {
+        let mut changed = false;
+        changed |= fold_constants(&mut instructions);
+        changed |= remove_dead_stores(&mut instructions);
+        changed |= simplify_conditional_jumps(&mut instructions);
+        changed |= thread_jumps(&mut instructions);
+        changed |= remove_unreachable_instructions(&mut instructions);
+        if !changed {
+            break;
+        }
+    }
+    merge_local_operations(&mut instructions);
+    instructions
+}
+
+// E.g. `push_integer 1, push_integer 2, int_add` => `push_integer 3`
+fn fold_constants(instructions: &mut Vec<Instruction>) -> bool {
+    let mut changed = false;
+    let mut index = 0;
+    while index < instructions.len() {
+        if index >= 1
+            && let Some(folded) = fold_unary(&instructions[index - 1], &instructions[index])
+        {
+            instructions.splice(index - 1..=index, [folded]);
+            changed = true;
+            index -= 1;
+            continue;
+        }
+        if index >= 2
+            && let Some(folded) = fold_binary(
+                &instructions[index - 2],
+                &instructions[index - 1],
+                &instructions[index],
+            )
+        {
+            instructions.splice(index - 2..=index, [folded]);
+            changed = true;
+            index -= 2;
+            continue;
+        }
+        index += 1;
+    }
+    changed
+}
+
+fn fold_unary(value: &Instruction, operation: &Instruction) -> Option<Instruction> {
+    match (value, operation) {
+        (Instruction::PushTrue, Instruction::Not) => Some(Instruction::PushFalse),
+        (Instruction::PushFalse, Instruction::Not) => Some(Instruction::PushTrue),
+        (Instruction::PushFloat(value), Instruction::Negation) => {
+            Some(Instruction::PushFloat(-value))
+        }
+        (Instruction::PushFloat64(value), Instruction::Negation) => {
+            Some(Instruction::PushFloat64(-value))
+        }
+        _ => None,
+    }
+}
+
+fn fold_binary(
+    left: &Instruction,
+    right: &Instruction,
+    operation: &Instruction,
+) -> Option<Instruction> {
+    match (left, right) {
+        (Instruction::PushInteger(left), Instruction::PushInteger(right)) => {
+            let (left, right) = (*left, *right);
+            match operation {
+                Instruction::IntegerAddition => Some(Instruction::PushInteger(
+                    arithmetic::integer_addition(left, right),
+                )),
+                Instruction::IntegerSubtraction => Some(Instruction::PushInteger(
+                    arithmetic::integer_subtraction(left, right),
+                )),
+                Instruction::IntegerMultiplication => Some(Instruction::PushInteger(
+                    arithmetic::integer_multiplication(left, right),
+                )),
+                Instruction::IntegerDivision => {
+                    arithmetic::integer_division(left, right).map(Instruction::PushInteger)
+                }
+                Instruction::IntegerModulo => {
+                    arithmetic::integer_modulo(left, right).map(Instruction::PushInteger)
+                }
+                Instruction::IntegerLessThan => Some(push_boolean(left < right)),
+                Instruction::IntegerLessThanEquals => Some(push_boolean(left <= right)),
+                Instruction::Equals => Some(push_boolean(left == right)),
+                _ => None,
+            }
+        }
+        (Instruction::PushFloat(left), Instruction::PushFloat(right)) => {
+            let (left, right) = (*left, *right);
+            match operation {
+                Instruction::FloatAddition => Some(Instruction::PushFloat(left + right)),
+                Instruction::FloatSubtraction => Some(Instruction::PushFloat(left - right)),
+                Instruction::FloatMultiplication => Some(Instruction::PushFloat(left * right)),
+                Instruction::FloatDivision => {
+                    arithmetic::float_division(left, right).map(Instruction::PushFloat)
+                }
+                Instruction::FloatModulo => {
+                    arithmetic::float_modulo(left, right).map(Instruction::PushFloat)
+                }
+                Instruction::FloatLessThan => Some(push_boolean(left < right)),
+                Instruction::FloatLessThanEquals => Some(push_boolean(left <= right)),
+                Instruction::Equals => Some(push_boolean(left == right)),
+                _ => None,
+            }
+        }
+        (Instruction::PushInteger64(left), Instruction::PushInteger64(right)) => {
+            let (left, right) = (*left, *right);
+            match operation {
+                Instruction::IntegerAddition => Some(Instruction::PushInteger64(
+                    arithmetic::integer_addition(left, right),
+                )),
+                Instruction::IntegerSubtraction => Some(Instruction::PushInteger64(
+                    arithmetic::integer_subtraction(left, right),
+                )),
+                Instruction::IntegerMultiplication => Some(Instruction::PushInteger64(
+                    arithmetic::integer_multiplication(left, right),
+                )),
+                Instruction::IntegerDivision => {
+                    arithmetic::integer_division(left, right).map(Instruction::PushInteger64)
+                }
+                Instruction::IntegerModulo => {
+                    arithmetic::integer_modulo(left, right).map(Instruction::PushInteger64)
+                }
+                Instruction::IntegerLessThan => Some(push_boolean(left < right)),
+                Instruction::IntegerLessThanEquals => Some(push_boolean(left <= right)),
+                Instruction::Equals => Some(push_boolean(left == right)),
+                _ => None,
+            }
+        }
+        (Instruction::PushFloat64(left), Instruction::PushFloat64(right)) => {
+            let (left, right) = (*left, *right);
+            match operation {
+                Instruction::FloatAddition => Some(Instruction::PushFloat64(left + right)),
+                Instruction::FloatSubtraction => Some(Instruction::PushFloat64(left - right)),
+                Instruction::FloatMultiplication => Some(Instruction::PushFloat64(left * right)),
+                Instruction::FloatDivision => {
+                    arithmetic::float_division(left, right).map(Instruction::PushFloat64)
+                }
+                Instruction::FloatModulo => {
+                    arithmetic::float_modulo(left, right).map(Instruction::PushFloat64)
+                }
+                Instruction::FloatLessThan => Some(push_boolean(left < right)),
+                Instruction::FloatLessThanEquals => Some(push_boolean(left <= right)),
+                Instruction::Equals => Some(push_boolean(left == right)),
+                _ => None,
+            }
+        }
+        _ => {
+            let (left, right) = (as_boolean(left)?, as_boolean(right)?);
+            match operation {
+                Instruction::BooleanAnd => Some(push_boolean(left && right)),
+                Instruction::BooleanOr => Some(push_boolean(left || right)),
+                Instruction::Equals => Some(push_boolean(left == right)),
+                _ => None,
+            }
+        }
+    }
+}
+
+fn as_boolean(instruction: &Instruction) -> Option<bool> {
+    match instruction {
+        Instruction::PushTrue => Some(true),
+        Instruction::PushFalse => Some(false),
+        _ => None,
+    }
+}
+
+fn push_boolean(value: bool) -> Instruction {
+    if value {
+        Instruction::PushTrue
+    } else {
+        Instruction::PushFalse
+    }
+}
+
+// Removes stores that write a local back to itself, e.g. `get_value 2, set_value 2`
+fn remove_dead_stores(instructions: &mut Vec<Instruction>) -> bool {
+    let mut changed = false;
+    let mut index = 1;
+    while index < instructions.len() {
+        match (&instructions[index - 1], &instructions[index]) {
+            (
+                Instruction::GetLocalValue { index: get_index },
+                Instruction::SetLocalValue { index: set_index },
+            ) if get_index == set_index => {
+                instructions.drain(index - 1..=index);
+                changed = true;
+            }
+            _ => index += 1,
+        }
+    }
+    changed
+}
+
+// E.g. `push_true, jump_if_false L` => nothing, and `not, jump_if_false L` => `jump_if_true L`
+fn simplify_conditional_jumps(instructions: &mut Vec<Instruction>) -> bool {
+    let mut changed = false;
+    let mut index = 1;
+    while index < instructions.len() {
+        let simplified = match (&instructions[index - 1], &instructions[index]) {
+            (Instruction::PushTrue, Instruction::JumpIfFalse(_))
+            | (Instruction::PushFalse, Instruction::JumpIfTrue(_)) => Some(vec![]),
+            (Instruction::PushFalse, Instruction::JumpIfFalse(label))
+            | (Instruction::PushTrue, Instruction::JumpIfTrue(label)) => {
+                Some(vec![Instruction::Jump(*label)])
+            }
+            (Instruction::Not, Instruction::JumpIfFalse(label)) => {
+                Some(vec![Instruction::JumpIfTrue(*label)])
+            }
+            (Instruction::Not, Instruction::JumpIfTrue(label)) => {
+                Some(vec![Instruction::JumpIfFalse(*label)])
+            }
+            _ => None,
+        };
+        match simplified {
+            Some(replacement) => {
+                instructions.splice(index - 1..=index, replacement);
+                changed = true;
+            }
+            None => index += 1,
+        }
+    }
+    changed
+}
+
+// Retargets jumps that land on an unconditional jump and removes jumps to the next instruction
+fn thread_jumps(instructions: &mut Vec<Instruction>) -> bool {
+    let label_indices: HashMap<Label, usize> = instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, instruction)| match instruction {
+            Instruction::Label(label) => Some((*label, index)),
+            _ => None,
+        })
+        .collect();
+
+    let final_target = |label: Label| {
+        let mut target = label;
+        let mut visited = HashSet::new();
+        while visited.insert(target) {
+            let next = instructions[label_indices[&target]..]
+                .iter()
+                .find(|instruction| !is_marker(instruction));
+            match next {
+                Some(Instruction::Jump(next_target)) => target = *next_target,
+                _ => break,
+            }
+        }
+        target
+    };
+
+    let mut changed = false;
+    let retargeted = instructions
+        .iter()
+        .map(|instruction| match instruction {
+            Instruction::Jump(label) => Instruction::Jump(final_target(*label)),
+            Instruction::JumpIfTrue(label) => Instruction::JumpIfTrue(final_target(*label)),
+            Instruction::JumpIfFalse(label) => Instruction::JumpIfFalse(final_target(*label)),
+            other => other.clone(),
+        })
+        .collect::<Vec<_>>();
+    if retargeted != *instructions {
+        *instructions = retargeted;
+        changed = true;
+    }
+
+    let mut index = 0;
+    while index < instructions.len() {
+        if let Instruction::Jump(label) = instructions[index] {
+            let jumps_to_next = instructions[index + 1..]
+                .iter()
+                .take_while(|instruction| is_marker(instruction))
+                .any(|instruction| *instruction == Instruction::Label(label));
+            if jumps_to_next {
+                instructions.remove(index);
+                changed = true;
+                continue;
+            }
+        }
+        index += 1;
+    }
+    changed
+}
+
+// Labels and lines take up no space, so jumps to them land on the next actual instruction
+fn is_marker(instruction: &Instruction) -> bool {
+    matches!(instruction, Instruction::Label(_) | Instruction::Line(_))
+}
+
+// Removes instructions following an unconditional jump or return up until the next label
+fn remove_unreachable_instructions(instructions: &mut Vec<Instruction>) -> bool {
+    let mut changed = false;
+    let mut index = 0;
+    while index < instructions.len() {
+        if matches!(
+            instructions[index],
+            Instruction::Jump(_) | Instruction::Return
+        ) {
+            let unreachable = instructions[index + 1..]
+                .iter()
+                .take_while(|instruction| !matches!(instruction, Instruction::Label(_)))
+                .count();
+            if unreachable > 0 {
+                instructions.drain(index + 1..index + 1 + unreachable);
+                changed = true;
+            }
+        }
+        index += 1;
+    }
+    changed
+}
+
+// E.g. `get_value 1, int_add` => `get_value_and_operate 1 int_add`
+fn merge_local_operations(instructions: &mut Vec<Instruction>) {
+    let mut index = 1;
+    while index < instructions.len() {
+        if let Instruction::GetLocalValue { index: local_index } = instructions[index - 1]
+            && let Some(operation) = instructions[index].opcode()
+            && is_binary_operation(operation)
+        {
+            instructions.splice(
+                index - 1..=index,
+                [Instruction::GetLocalValueAndOperate {
+                    index: local_index,
+                    operation,
+                }],
+            );
+        }
+        index += 1;
+    }
+}
+
+pub fn is_binary_operation(operation: ByteCode) -> bool {
+    matches!(
+        operation,
+        ByteCode::IntegerAddition
+            | ByteCode::IntegerSubtraction
+            | ByteCode::IntegerDivision
+            | ByteCode::IntegerMultiplication
+            | ByteCode::IntegerModulo
+            | ByteCode::IntegerLessThan
+            | ByteCode::IntegerLessThanEquals
+            | ByteCode::FloatAddition
+            | ByteCode::FloatSubtraction
+            | ByteCode::FloatDivision
+            | ByteCode::FloatMultiplication
+            | ByteCode::FloatModulo
+            | ByteCode::FloatLessThan
+            | ByteCode::FloatLessThanEquals
+            | ByteCode::StringConcat
+            | ByteCode::BooleanAnd
+            | ByteCode::BooleanOr
+            | ByteCode::Equals
+    )
+}
@@ -13,6 +13,7 @@ use crate::expressions::IsGuard;
 use crate::expressions::MaybeNamedType;
 use crate::expressions::PropertyDeclaration;
 use crate::expressions::PropertyDefinition;
+use crate::expressions::PropertyType;
 use crate::expressions::StringOperations;
 use crate::expressions::UnaryOperator;
 use crate::expressions::ValueType;
@@ -116,6 +117,10 @@ impl Parser {
                 break;
             }
         }
+        // The built-in `ChildOf` component is defined (at line 0) for the programs that use it
+        if self.tokens.iter().any(|token| token.lexeme == "ChildOf") {
+            expressions.insert(0, child_of_definition());
+        }
         if !diagnostics.has_errors() {
             Ok(Expr::Block { exprs: expressions })
         } else {
@@ -136,15 +141,7 @@ impl Parser {
                     return Err("Unterminated component definition".to_string());
                 }
                 let property_name = self.consume(&Identifier)?;
-                if !self.matches_any(&[KeywordF32, KeywordI32, KeywordStr]) {
-                    return Err("Expected property type declaration".to_string());
-                }
-                let property_type = match self.previous().kind {
-                    TokenKind::KeywordF32 => unification::Type::Float,
-                    TokenKind::KeywordI32 => unification::Type::Integer,
-                    TokenKind::KeywordStr => unification::Type::String,
-                    _ => return Err("Unknown property type declaration".to_string()),
-                };
+                let property_type = self.property_type()?;
                 let property = PropertyDefinition {
                     name: property_name,
                     type_: property_type,
@@ -163,6 +160,39 @@ impl Parser {
         }))
     }
 
+    // property_type → primitive | "[" primitive "]" | TAG ( "|" TAG )* | IDENTIFIER
+    fn property_type(&mut self) -> Result<PropertyType, String> {
+        if self.matches(&LeftBracket) {
+            let element_type = self.primitive_type()?;
+            self.consume(&RightBracket)?;
+            Ok(PropertyType::List(element_type))
+        } else if self.matches(&Tag) {
+            let mut tags = vec![self.previous()];
+            while self.matches(&Pipe) {
+                tags.push(self.consume(&Tag)?);
+            }
+            Ok(PropertyType::Tag(tags))
+        } else if self.matches(&Identifier) {
+            Ok(PropertyType::Component(self.previous()))
+        } else {
+            Ok(PropertyType::Primitive(self.primitive_type()?))
+        }
+    }
+
+    fn primitive_type(&mut self) -> Result<unification::Type, String> {
+        if !self.matches_any(&[KeywordF32, KeywordF64, KeywordI32, KeywordI64, KeywordStr]) {
+            return Err("Expected property type declaration".to_string());
+        }
+        match self.previous().kind {
+            TokenKind::KeywordF32 => Ok(unification::Type::Float),
+            TokenKind::KeywordF64 => Ok(unification::Type::Float64),
+            TokenKind::KeywordI32 => Ok(unification::Type::Integer),
+            TokenKind::KeywordI64 => Ok(unification::Type::Integer64),
+            TokenKind::KeywordStr => Ok(unification::Type::String),
+            _ => Err("Unknown property type declaration".to_string()),
+        }
+    }
+
     fn create(&mut self) -> Result<Option<Expr>, String> {
         let token = self.previous();
         self.consume(&LeftBracket)?; // TODO(anissen): This ought to be part of list() ?
@@ -185,6 +215,58 @@ impl Parser {
         }))
     }
 
+    // handler → "on" ("add" | "remove" | "destroy") ("Entity" IDENTIFIER? ",")? IDENTIFIER IDENTIFIER? block
+    fn handler(&mut self) -> Result<Option<Expr>, String> {
+        let event = self.advance();
+        let is_event = match event.kind {
+            KeywordDestroy => true,
+            Identifier => event.lexeme == "add" || event.lexeme == "remove",
+            _ => false,
+        };
+        if !is_event {
+            return Err("Expected `add`, `remove` or `destroy` after `on`".to_string());
+        }
+        let mut type_ = self.consume(&Identifier)?;
+        let mut entity = None;
+        if type_.lexeme == "Entity" {
+            entity = self.optional(&Identifier);
+            self.consume(&Comma)?;
+            type_ = self.consume(&Identifier)?;
+        }
+        let name = self.optional(&Identifier);
+        let expr = self.block()?;
+        Ok(Some(Expr::Handler {
+            event,
+            entity,
+            component: MaybeNamedType { type_, name },
+            expr: Box::new(expr.unwrap()),
+        }))
+    }
+
+    // resource → "resource" IDENTIFIER primitive "=" "-"? literal
+    fn resource(&mut self) -> Result<Option<Expr>, String> {
+        let name = self.consume(&Identifier)?;
+        let type_ = self.primitive_type()?;
+        self.consume(&Equal)?;
+        let is_negated = self.matches(&Minus);
+        let Some(Expr::Value { value, token }) = self.primary()? else {
+            return Err("Expected a literal default value".to_string());
+        };
+        let value = match (value, is_negated) {
+            (value, false) => value,
+            (ValueType::Integer(value), true) => ValueType::Integer(-value),
+            (ValueType::Integer64(value), true) => ValueType::Integer64(-value),
+            (ValueType::Float(value), true) => ValueType::Float(-value),
+            (ValueType::Float64(value), true) => ValueType::Float64(-value),
+            _ => return Err("Expected a numeric default value".to_string()),
+        };
+        Ok(Some(Expr::ResourceDefinition {
+            name,
+            type_,
+            default: Box::new(Expr::Value { value, token }),
+        }))
+    }
+
     fn expression(&mut self) -> Result<Option<Expr>, String> {
         if self.matches(&KeywordComponent) {
             self.component()
@@ -192,12 +274,17 @@ impl Parser {
             self.create()
         } else if self.matches(&KeywordDestroy) {
             self.destroy()
+        } else if self.matches(&KeywordOn) {
+            self.handler()
+        } else if self.matches(&KeywordResource) {
+            self.resource()
         } else {
             self.assignment()
         }
     }
 
     // assignment → IDENTIFIER "=" logic_or
+    #[allow(clippy::unnecessary_unwrap)]
     fn assignment(&mut self) -> Result<Option<Expr>, String> {
         let expr = self.query()?;
         if expr.is_some() && self.matches(&Equal) {
@@ -210,7 +297,7 @@ impl Parser {
                 }
                 | Expr::FieldAccess {
                     identifier: _,
-                    field_name: _,
+                    field_names: _,
                 } => {
                     let operator = self.previous();
                     let value = self.assignment()?;
@@ -232,6 +319,10 @@ impl Parser {
         let expr = if self.check(&NewLine)
             || self.check(&KeywordIs)
             || self.check(&Pipe)
+            || self.check(&Comma)
+            || self.check(&RightParen)
+            || self.check(&RightBracket)
+            || self.check(&RightBrace)
             || self.is_at_end()
         {
             None
@@ -282,14 +373,21 @@ impl Parser {
 
             let mut include_components = vec![];
             let mut has_entity_component = false;
+            let mut optional_components = vec![];
             let mut exclude_components = vec![];
+            let mut added_components = vec![];
+            let mut changed_components = vec![];
+            let mut parent = None;
             // parse components
             while !self.check(&NewLine) {
                 if self.is_at_end() {
                     return Err("Unexpected end of input".to_string());
                 }
 
-                if !include_components.is_empty() {
+                if !include_components.is_empty()
+                    || !optional_components.is_empty()
+                    || !exclude_components.is_empty()
+                {
                     self.consume(&Comma)?;
                 }
 
@@ -299,8 +397,49 @@ impl Parser {
                     let type_ = self.consume(&Identifier)?;
                     exclude_components.push(type_);
                 } else {
-                    let type_ = self.consume(&Identifier)?;
+                    let mut type_ = self.consume(&Identifier)?;
+                    // `Added Enemy` and `Changed Position p` include the component if it was added or changed
+                    // since the last run of the query
+                    let filter = match type_.lexeme.as_str() {
+                        "Added" if self.check(&Identifier) => Some(&mut added_components),
+                        "Changed" if self.check(&Identifier) => Some(&mut changed_components),
+                        _ => None,
+                    };
+                    let is_filtered = filter.is_some();
+                    if let Some(filter) = filter {
+                        type_ = self.consume(&Identifier)?;
+                        if type_.lexeme == "Entity" {
+                            return Err("Entity cannot be added or changed".to_string());
+                        }
+                        filter.push(type_.clone());
+                    }
+                    // `ChildOf(parent) c` only matches the children of `parent`
+                    let has_parent = type_.lexeme == "ChildOf" && self.matches(&LeftParen);
+                    if has_parent {
+                        if parent.is_some() {
+                            return Err(
+                                "A query can only match the children of one parent".to_string()
+                            );
+                        }
+                        parent = self.expression()?.map(Box::new);
+                        self.consume(&RightParen)?;
+                    }
+                    let is_optional = self.matches(&QuestionMark);
+                    if is_optional && is_filtered {
+                        return Err("Optional components cannot be added or changed".to_string());
+                    }
+                    if is_optional && has_parent {
+                        return Err("The children of a parent cannot be optional".to_string());
+                    }
                     let name = self.optional(&Identifier);
+                    if is_optional {
+                        // optional components are bound to `:none` when the entity does not have them
+                        if type_.lexeme == "Entity" {
+                            return Err("Entity cannot be optional".to_string());
+                        }
+                        optional_components.push(MaybeNamedType { type_, name });
+                        continue;
+                    }
                     if let Some(ref name) = name
                         && name.lexeme == "Entity"
                     {
@@ -327,7 +466,11 @@ impl Parser {
                 self.decrease_indentation();
                 Ok(Some(Expr::Query {
                     include_components,
+                    optional_components,
                     exclude_components,
+                    added_components,
+                    changed_components,
+                    parent,
                     expr: Box::new(expr),
                 }))
             } else {
@@ -491,6 +634,7 @@ impl Parser {
     }
 
     // equality → comparison ( ( "!=" | "==" ) comparison )* ;
+    #[allow(clippy::unnecessary_unwrap)]
     fn equality(&mut self) -> Result<Option<Expr>, String> {
         let expr = self.comparison()?;
         if expr.is_some() && self.matches_any(&[EqualEqual, BangEqual]) {
@@ -514,6 +658,7 @@ impl Parser {
     }
 
     // comparison → term ( ( ">" | ">=" | "<" | "<=" ) term )* ;
+    #[allow(clippy::unnecessary_unwrap)]
     fn comparison(&mut self) -> Result<Option<Expr>, String> {
         let expr = self.term()?;
         if expr.is_some()
@@ -637,10 +782,18 @@ impl Parser {
         }
     }
 
-    // call_with_first_arg → IDENTIFIER primary*
+    // call_with_first_arg → ("add" | "remove") COMPONENT | IDENTIFIER primary*
     fn call_with_first_arg(&mut self, expr: Expr, _token: Token) -> Result<Option<Expr>, String> {
         self.consume(&Identifier)?;
         let previous = self.previous();
+        if self.is_component_operation(&previous) {
+            let component_expr = self.component_operation(expr, previous)?;
+            return if self.matches(&Pipe) {
+                self.call_with_first_arg(component_expr, self.previous())
+            } else {
+                Ok(Some(component_expr))
+            };
+        }
         // TODO(anissen): Check that function name exists and is a function
         let first_arg = expr;
         let mut args = vec![first_arg];
@@ -667,6 +820,33 @@ impl Parser {
         }
     }
 
+    // `e | add Position { x 1, y 2 }` and `e | remove Position`, where the component names are capitalized, which
+    // sets them apart from calls to functions named `add` or `remove`
+    fn is_component_operation(&self, name: &Token) -> bool {
+        (name.lexeme == "add" || name.lexeme == "remove")
+            && self.check(&Identifier)
+            && self.peek().lexeme.chars().take(1).any(|c| c.is_uppercase())
+    }
+
+    fn component_operation(&mut self, entity: Expr, token: Token) -> Result<Expr, String> {
+        let entity = Box::new(entity);
+        if token.lexeme == "add" {
+            let component = self.primary()?.unwrap();
+            Ok(Expr::AddComponent {
+                token,
+                entity,
+                component: Box::new(component),
+            })
+        } else {
+            let component = self.consume(&Identifier)?;
+            Ok(Expr::RemoveComponent {
+                token,
+                entity,
+                component,
+            })
+        }
+    }
+
     // function → IDENTIFIER* block
     fn function(&mut self) -> Result<Option<Expr>, String> {
         let slash = self.previous();
@@ -768,11 +948,14 @@ impl Parser {
                     token: name,
                 }))
             } else if self.matches(&Dot) {
-                // Field access
-                let field_name = self.consume(&Identifier)?;
+                // Field access, possibly of nested components
+                let mut field_names = vec![self.consume(&Identifier)?];
+                while self.matches(&Dot) {
+                    field_names.push(self.consume(&Identifier)?);
+                }
                 Ok(Some(Expr::FieldAccess {
                     identifier: name,
-                    field_name,
+                    field_names,
                 }))
             } else {
                 // Plain identifier
@@ -809,6 +992,26 @@ impl Parser {
                 })),
                 Err(err) => Err(err.to_string()),
             }
+        } else if self.matches(&Integer64) {
+            let lexeme = self.previous().lexeme;
+            let value = lexeme.trim_end_matches("i64").parse::<i64>();
+            match value {
+                Ok(value) => Ok(Some(Expr::Value {
+                    value: ValueType::Integer64(value),
+                    token: self.previous().clone(),
+                })),
+                Err(err) => Err(err.to_string()),
+            }
+        } else if self.matches(&Float64) {
+            let lexeme = self.previous().lexeme;
+            let value = lexeme.trim_end_matches("f64").parse::<f64>();
+            match value {
+                Ok(value) => Ok(Some(Expr::Value {
+                    value: ValueType::Float64(value),
+                    token: self.previous().clone(),
+                })),
+                Err(err) => Err(err.to_string()),
+            }
         } else if self.matches(&False) {
             Ok(Some(Expr::Value {
                 value: ValueType::Boolean(false),
@@ -922,3 +1125,19 @@ impl Parser {
         Ok(())
     }
 }
+
+// component ChildOf { parent i32 }
+fn child_of_definition() -> Expr {
+    let token = |lexeme: &str| Token {
+        kind: Identifier,
+        position: Position { line: 0, column: 0 },
+        lexeme: lexeme.to_string(),
+    };
+    Expr::ComponentDefinition {
+        name: token("ChildOf"),
+        properties: vec![PropertyDefinition {
+            name: token("parent"),
+            type_: PropertyType::Primitive(unification::Type::Integer),
+        }],
+    }
+}
@@ -1,19 +1,22 @@
 use std::collections::HashMap;
+use std::mem::discriminant;
 
-use elements::EntityManager;
 use elements::world::World;
 
 use crate::CompilationMetadata;
 use crate::DeltaArguments;
 use crate::ExecutionMetadata;
 use crate::ProgramMetadata;
+use crate::bytecode_file::ResourceEntry;
 use crate::codegen;
 use crate::diagnostics::Diagnostics;
 use crate::disassembler;
 use crate::errors::Error;
+use crate::folding;
 use crate::lexer;
 use crate::parser;
 use crate::resolver;
+use crate::save_file::SaveFile;
 use crate::tokens;
 use crate::typer;
 use crate::vm;
@@ -38,13 +41,14 @@ type ForeignValue<'a> = Box<dyn Fn() -> vm::Value + 'a>;
 type ForeignFn<'a> = Box<dyn Fn(&Vec<vm::Value>) -> vm::Value + 'a>;
 
 struct ForeignFunction<'a> {
-    index: u8,
+    name: String,
+    arity: u32,
     function: ForeignFn<'a>,
 }
 
 pub struct Context<'a> {
-    functions: HashMap<String, ForeignFunction<'a>>,
-    function_count: u8,
+    functions: Vec<ForeignFunction<'a>>, // called by index from the VM
+    function_indices: HashMap<String, u32>,
     values: HashMap<String, ForeignValue<'a>>,
     // pub entity_components: HashMap<i32, HashMap<String, vm::Value>>,
 }
@@ -58,8 +62,8 @@ impl Default for Context<'_> {
 impl<'a> Context<'a> {
     pub fn new() -> Self {
         Self {
-            functions: HashMap::new(),
-            function_count: 0,
+            functions: Vec::new(),
+            function_indices: HashMap::new(),
             values: HashMap::new(),
             // entity_components: HashMap::new(),
         }
@@ -88,49 +92,57 @@ impl<'a> Context<'a> {
     pub fn add_function(
         &mut self,
         name: String,
+        arity: u32,
         function: impl Fn(&Vec<vm::Value>) -> vm::Value + 'a,
     ) {
-        self.functions.insert(
-            name,
-            ForeignFunction {
-                index: self.function_count,
-                function: Box::new(function),
-            },
-        );
-        self.function_count += 1;
+        let foreign = ForeignFunction {
+            name: name.clone(),
+            arity,
+            function: Box::new(function),
+        };
+        // Registering a function again replaces it but keeps its index
+        match self.function_indices.get(&name) {
+            Some(index) => self.functions[*index as usize] = foreign,
+            None => {
+                self.function_indices
+                    .insert(name, self.functions.len() as u32);
+                self.functions.push(foreign);
+            }
+        }
     }
 
     pub fn has_function(&self, name: &String) -> bool {
-        self.functions.contains_key(name)
+        self.function_indices.contains_key(name)
+    }
+
+    pub fn get_index(&self, name: &String) -> Option<u32> {
+        self.function_indices.get(name).copied()
     }
 
-    pub fn get_index(&self, name: &String) -> u8 {
-        self.functions.get(name).unwrap().index
+    pub fn get_arity(&self, index: u32) -> u32 {
+        self.functions[index as usize].arity
     }
 
     pub fn get_function_names(&self) -> Vec<String> {
-        self.functions.keys().cloned().collect::<Vec<String>>()
+        self.functions
+            .iter()
+            .map(|foreign| foreign.name.clone())
+            .collect()
     }
 
-    pub fn call_function(&self, name: &String, stack: &Vec<vm::Value>) -> vm::Value {
-        if let Some(foreign) = self.functions.get(name) {
-            let func = &foreign.function;
-            func(stack)
-        } else {
-            vm::Value::False // TODO(anissen): Should this be an error?
-        }
+    pub fn call_function(&self, index: u32, stack: &Vec<vm::Value>) -> vm::Value {
+        let func = &self.functions[index as usize].function;
+        func(stack)
     }
 }
 
 pub struct Elements {
-    pub entity_manager: EntityManager,
     pub world: World,
 }
 
 impl Elements {
     fn new() -> Self {
         Self {
-            entity_manager: EntityManager::new(),
             world: World::new(),
         }
     }
@@ -139,6 +151,7 @@ impl Elements {
 pub struct PersistentData {
     // pub metadata: ExecutionMetadata,
     pub world_context: HashMap<String, Value>, // TODO(anissen): Find a better name
+    resources: HashMap<String, Value>,         // the declared resources and their default values
     pub elements: Elements,
 }
 
@@ -153,9 +166,76 @@ impl PersistentData {
         Self {
             // metadata: ExecutionMetadata::default(),
             world_context: HashMap::new(),
+            resources: HashMap::new(),
             elements: Elements::new(),
         }
     }
+
+    // Resources keep their values when the program is reloaded, unless they are declared with another type
+    pub fn declare_resources(&mut self, resources: Vec<ResourceEntry>) {
+        self.resources.clear();
+        for resource in resources {
+            let has_value = self
+                .world_context
+                .get(&resource.name)
+                .is_some_and(|value| discriminant(value) == discriminant(&resource.default));
+            if !has_value {
+                self.world_context
+                    .insert(resource.name.clone(), resource.default.clone());
+            }
+            self.resources.insert(resource.name, resource.default);
+        }
+    }
+
+    pub fn get_resource(&self, name: &str) -> Option<&Value> {
+        match self.resources.contains_key(name) {
+            true => self.world_context.get(name),
+            false => None,
+        }
+    }
+
+    pub fn set_resource(&mut self, name: &str, value: Value) -> Result<(), String> {
+        let default = self
+            .resources
+            .get(name)
+            .ok_or_else(|| format!("Resource '{name}' is not declared"))?;
+        if discriminant(default) != discriminant(&value) {
+            return Err(format!(
+                "Resource '{name}' has the type of {default:?} but got {value:?}"
+            ));
+        }
+        self.world_context.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    // Saves the `∆` context and the world, in the binary format of save files (see `save_file`)
+    pub fn save(&self) -> Vec<u8> {
+        SaveFile::new(&self.world_context, self.elements.world.snapshot()).to_bytes()
+    }
+
+    // Like `save`, but in the human-readable text format of save files
+    pub fn save_text(&self) -> String {
+        SaveFile::new(&self.world_context, self.elements.world.snapshot()).to_text()
+    }
+
+    // Replaces the `∆` context and the world with those of a save file in either format. The components of the save
+    // are matched to the components of the loaded program by name, and declared resources that were saved with
+    // another type are reset to their default value.
+    pub fn restore(&mut self, save: &[u8]) -> Result<(), String> {
+        let save = SaveFile::from_bytes(save)?;
+        self.elements.world.restore(&save.world)?;
+        self.world_context = save.context.into_iter().collect();
+        for (name, default) in &self.resources {
+            let has_value = self
+                .world_context
+                .get(name)
+                .is_some_and(|value| discriminant(value) == discriminant(default));
+            if !has_value {
+                self.world_context.insert(name.clone(), default.clone());
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct Program<'a> {
@@ -163,6 +243,7 @@ pub struct Program<'a> {
     // source: &'a str,
     source: String,
     debug: bool,
+    release: bool,
     pub metadata: ProgramMetadata,
     pub vm: Option<vm::VirtualMachine>,
     pub is_valid: bool,
@@ -175,6 +256,7 @@ impl<'a> Program<'a> {
             context,
             source: "".to_string(),
             debug: args.debug,
+            release: args.release,
             metadata: ProgramMetadata::default(),
             vm: None, //vm::VirtualMachine::new(Vec::new(), debug),
             is_valid: false,
@@ -226,7 +308,7 @@ impl<'a> Program<'a> {
 
         println!("\n# parsing =>");
         let start = std::time::Instant::now();
-        let ast = parser::parse(tokens)?;
+        let mut ast = parser::parse(tokens)?;
         let duration = start.elapsed();
         println!("Elapsed: {duration:?}");
         if self.debug {
@@ -252,76 +334,111 @@ impl<'a> Program<'a> {
             return Err(diagnostics);
         }
 
-        let foreign_functions = self
-            .context
-            .functions
-            .keys()
-            .cloned()
-            .collect::<Vec<String>>();
+        let foreign_functions = self.context.get_function_names();
         println!("foreign functions: {foreign_functions:?}");
 
+        println!("\n# constant folding =>");
+        let start = std::time::Instant::now();
+        folding::fold_constants(&mut ast);
+        let duration = start.elapsed();
+        println!("Elapsed: {duration:?}");
+
         println!("\n# code gen =>");
         let start = std::time::Instant::now();
-        let bytecodes = codegen::codegen(&ast, &self.context);
+        let bytecodes = codegen::codegen(&ast, &self.context, self.release);
         let duration = start.elapsed();
         println!("Elapsed: {duration:?}");
 
         self.is_valid = bytecodes.is_ok();
 
-        match bytecodes {
-            Ok(bytecodes) => {
-                if self.debug {
-                    println!("byte code length: {}", bytecodes.len());
-                    println!("byte codes: {bytecodes:?}");
-                }
-
-                let mut compilation_metadata = CompilationMetadata::default();
-                compilation_metadata.bytecode = bytecodes.clone();
-                compilation_metadata.bytecode_length = bytecodes.len();
-
-                if self.debug {
-                    println!("\n# disassembly =>");
-                    // Generate disassembled instructions and optionally print
-                    disassembler::disassemble(bytecodes.clone(), &mut compilation_metadata);
-                }
-
-                self.metadata = ProgramMetadata {
-                    compilation_metadata,
-                    execution_metadata: ExecutionMetadata::default(),
-                };
-
-                if let Some(vm) = &mut self.vm {
-                    vm.update_bytecode(bytecodes.clone(), &mut self.data);
-                } else {
-                    let vm = vm::VirtualMachine::new(bytecodes.clone(), &mut self.data, self.debug);
-                    self.vm = Some(vm);
-                }
-
-                Ok(bytecodes)
-            }
-            Err(diagnostics) => Err(diagnostics),
+        let bytecodes = bytecodes?;
+        self.load(bytecodes.clone())?;
+        Ok(bytecodes)
+    }
+
+    // Loads compiled bytecode, e.g. from `compile` or from a precompiled file
+    pub fn load(&mut self, bytecodes: Vec<u8>) -> Result<(), Diagnostics> {
+        if self.debug {
+            println!("byte code length: {}", bytecodes.len());
+            println!("byte codes: {bytecodes:?}");
+        }
+
+        let mut compilation_metadata = CompilationMetadata {
+            bytecode: bytecodes.clone(),
+            bytecode_length: bytecodes.len(),
+            ..Default::default()
+        };
+
+        if self.debug {
+            println!("\n# disassembly =>");
+            // Generate disassembled instructions and optionally print
+            disassembler::disassemble(bytecodes.clone(), &mut compilation_metadata);
         }
+
+        self.metadata = ProgramMetadata {
+            compilation_metadata,
+            execution_metadata: ExecutionMetadata::default(),
+        };
+
+        let result = match &mut self.vm {
+            Some(vm) => vm.update_bytecode(bytecodes, &mut self.data, &self.context),
+            None => vm::VirtualMachine::new(bytecodes, &mut self.data, &self.context, self.debug)
+                .map(|vm| self.vm = Some(vm)),
+        };
+        self.is_valid = result.is_ok();
+
+        result.map_err(|error| {
+            let mut diagnostics = Diagnostics::new();
+            diagnostics.add_error(Error::BytecodeErr(error));
+            diagnostics
+        })
+    }
+
+    // The value of a resource declared by the loaded program
+    pub fn get_resource(&self, name: &str) -> Option<&Value> {
+        self.data.get_resource(name)
+    }
+
+    pub fn set_resource(&mut self, name: &str, value: Value) -> Result<(), String> {
+        self.data.set_resource(name, value)
+    }
+
+    // Saves and restores the `∆` context and the world of the program (see `PersistentData::save`)
+    pub fn save(&self) -> Vec<u8> {
+        self.data.save()
+    }
+
+    pub fn save_text(&self) -> String {
+        self.data.save_text()
     }
 
-    pub fn run(&mut self) -> Option<vm::Value> {
+    pub fn restore(&mut self, save: &[u8]) -> Result<(), String> {
+        self.data.restore(save)
+    }
+
+    pub fn run(&mut self) -> Result<Option<vm::Value>, vm::RuntimeError> {
         match &mut self.vm {
             Some(vm) => {
                 let result = vm.execute(None, &self.context, &mut self.data);
                 self.metadata.execution_metadata = vm.metadata.clone();
                 result
             }
-            None => None,
+            None => Ok(None),
         }
     }
 
-    pub fn run_function(&mut self, function_name: String, args: Vec<Value>) -> Option<vm::Value> {
+    pub fn run_function(
+        &mut self,
+        function_name: String,
+        args: Vec<Value>,
+    ) -> Result<Option<vm::Value>, vm::RuntimeError> {
         match &mut self.vm {
             Some(vm) => {
                 let result = vm.execute(Some((function_name, args)), &self.context, &mut self.data);
                 self.metadata.execution_metadata = vm.metadata.clone();
                 result
             }
-            None => None,
+            None => Ok(None),
         }
     }
 }
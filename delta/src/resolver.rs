@@ -7,9 +7,11 @@ use crate::{
 };
 
 pub struct Resolver<'a> {
+    #[allow(dead_code)]
     context: &'a Context<'a>, // TODO(anissen): Check against shadowing variables and functions defined in the context
     diagnostics: &'a mut Diagnostics,
     component_names: Vec<Token>, // TODO(anissen): Component meta data needs to be a more complex structure
+    resource_names: Vec<Token>,
 }
 
 impl<'a> Resolver<'a> {
@@ -18,6 +20,7 @@ impl<'a> Resolver<'a> {
             context,
             diagnostics,
             component_names: Vec::new(),
+            resource_names: Vec::new(),
         }
     }
 
@@ -107,16 +110,24 @@ impl<'a> Resolver<'a> {
 
             Expr::Query {
                 include_components: _,
+                optional_components: _,
                 exclude_components: _,
+                added_components: _,
+                changed_components: _,
+                parent,
                 expr,
             } => {
                 // TODO(anissen): Resolve include/exclude components
 
+                if let Some(parent) = parent {
+                    self.resolve_expr(parent);
+                }
                 self.resolve_expr(expr);
             }
 
             Expr::ComponentDefinition { name, properties: _ } => {
-                if name.lexeme == "Entity" {
+                // The parser defines the built-in `ChildOf` at line 0
+                if name.lexeme == "Entity" || (name.lexeme == "ChildOf" && name.position.line > 0) {
                     self.error(ResolutionError::BuiltinComponentRedefined { name: name.clone() });
                 }
 
@@ -134,13 +145,54 @@ impl<'a> Resolver<'a> {
 
                 // TODO(anissen): Also check properties
             }
+
+            Expr::ResourceDefinition {
+                name,
+                type_: _,
+                default: _,
+            } => {
+                if let Some(definition) = self
+                    .resource_names
+                    .iter()
+                    .find(|token| name.lexeme == token.lexeme)
+                {
+                    self.error(ResolutionError::ResourceRedefined {
+                        name: name.clone(),
+                        definition: definition.clone(),
+                    });
+                }
+                self.resource_names.push(name.clone());
+            }
+
+            Expr::Handler {
+                event: _,
+                entity: _,
+                component: _,
+                expr,
+            } => self.resolve_expr(expr),
+
             Expr::Create { token: _, arguments } => self.resolve_expr(arguments),
 
             Expr::Destroy { token: _, argument } => self.resolve_expr(argument),
 
+            Expr::AddComponent {
+                token: _,
+                entity,
+                component,
+            } => {
+                self.resolve_expr(entity);
+                self.resolve_expr(component);
+            }
+
+            Expr::RemoveComponent {
+                token: _,
+                entity,
+                component: _,
+            } => self.resolve_expr(entity),
+
             Expr::FieldAccess {
                 identifier: _,
-                field_name: _,
+                field_names: _,
             } => (),
         }
     }
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+
+use elements::snapshot::{Snapshot, split_line};
+
+use crate::bytecode_file::{ByteReader, add_string};
+use crate::instructions::add_varint;
+use crate::vm::Value;
+
+// On-disk format of a saved game (a `.∆s` file):
+//
+//   magic           4 bytes: "∆s" in UTF-8
+//   format version  u16
+//   context         varint count, then the name and the value of each value of the `∆` context
+//   world           the snapshot of the world (see `elements::snapshot`), to the end of the file
+//
+// Values are stored as their kind (u8) followed by their data, where counts, lengths, function indices and
+// component ids are varints. Component values keep the component ids of the program that saved them.
+//
+// The text format has the same content, with a value per line, e.g.
+//
+//   ∆ save 1
+//   context "score" integer 42
+//   context "player" list 2 string "Ada" tag "level" integer 3
+//   world
+//   ∆ snapshot 1
+//   ...
+
+pub const MAGIC: [u8; 4] = *b"\xE2\x88\x86s";
+pub const FORMAT_VERSION: u16 = 1;
+
+const TEXT_HEADER: &str = "∆ save";
+
+#[derive(Debug)]
+pub struct SaveFile {
+    pub context: Vec<(String, Value)>, // in the order of the names
+    pub world: Snapshot,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ValueKind {
+    True = 1,
+    False = 2,
+    Integer = 3,
+    Integer64 = 4,
+    Float = 5,
+    Float64 = 6,
+    String = 7,
+    SimpleTag = 8,
+    Tag = 9,
+    List = 10,
+    Function = 11,
+    Component = 12,
+}
+
+impl ValueKind {
+    const ALL: [ValueKind; 12] = [
+        ValueKind::True,
+        ValueKind::False,
+        ValueKind::Integer,
+        ValueKind::Integer64,
+        ValueKind::Float,
+        ValueKind::Float64,
+        ValueKind::String,
+        ValueKind::SimpleTag,
+        ValueKind::Tag,
+        ValueKind::List,
+        ValueKind::Function,
+        ValueKind::Component,
+    ];
+
+    fn of(value: &Value) -> Self {
+        match value {
+            Value::True => ValueKind::True,
+            Value::False => ValueKind::False,
+            Value::Integer(_) => ValueKind::Integer,
+            Value::Integer64(_) => ValueKind::Integer64,
+            Value::Float(_) => ValueKind::Float,
+            Value::Float64(_) => ValueKind::Float64,
+            Value::String(_) => ValueKind::String,
+            Value::SimpleTag(_) => ValueKind::SimpleTag,
+            Value::Tag(..) => ValueKind::Tag,
+            Value::List(_) => ValueKind::List,
+            Value::Function(_) => ValueKind::Function,
+            Value::Component { .. } => ValueKind::Component,
+        }
+    }
+
+    // The keyword of the kind in the text format
+    fn name(self) -> &'static str {
+        match self {
+            ValueKind::True => "true",
+            ValueKind::False => "false",
+            ValueKind::Integer => "integer",
+            ValueKind::Integer64 => "integer64",
+            ValueKind::Float => "float",
+            ValueKind::Float64 => "float64",
+            ValueKind::String => "string",
+            ValueKind::SimpleTag => "simple_tag",
+            ValueKind::Tag => "tag",
+            ValueKind::List => "list",
+            ValueKind::Function => "function",
+            ValueKind::Component => "component",
+        }
+    }
+}
+
+impl SaveFile {
+    pub fn new(context: &HashMap<String, Value>, world: Snapshot) -> Self {
+        let mut context: Vec<_> = context
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        context.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Self { context, world }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        add_varint(&mut bytes, self.context.len() as u32);
+        for (name, value) in &self.context {
+            add_string(&mut bytes, name);
+            add_value(&mut bytes, value);
+        }
+        bytes.extend_from_slice(&self.world.to_bytes());
+        bytes
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{TEXT_HEADER} {FORMAT_VERSION}\n");
+        for (name, value) in &self.context {
+            let mut line = format!("context {name:?}");
+            add_value_text(&mut line, value);
+            text += &(line + "\n");
+        }
+        text + "world\n" + &self.world.to_text()
+    }
+
+    // Reads either format
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.starts_with(TEXT_HEADER.as_bytes()) {
+            let text = std::str::from_utf8(bytes).map_err(|_| "Invalid UTF-8 in save file")?;
+            return Self::from_text(text);
+        }
+        if !bytes.starts_with(&MAGIC) {
+            return Err("Not a save file (missing magic bytes)".to_string());
+        }
+
+        let mut reader = ByteReader::new(&bytes[MAGIC.len()..]);
+        let version = reader.read_u16()?;
+        if version != FORMAT_VERSION {
+            return Err(format!(
+                "Unsupported save file format version {version} (expected {FORMAT_VERSION})"
+            ));
+        }
+        let value_count = reader.read_varint()?;
+        let mut context = Vec::new();
+        for _ in 0..value_count {
+            let name = reader.read_string()?;
+            let value = read_value(&mut reader)?;
+            context.push((name, value));
+        }
+        let world = Snapshot::from_bytes(&bytes[MAGIC.len() + reader.position()..])?;
+        Ok(Self { context, world })
+    }
+
+    pub fn from_text(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, header)) if header.trim() == format!("{TEXT_HEADER} {FORMAT_VERSION}") => (),
+            _ => {
+                return Err(format!(
+                    "Not a save file (expected '{TEXT_HEADER} {FORMAT_VERSION}')"
+                ));
+            }
+        }
+
+        let mut context = Vec::new();
+        for (index, line) in lines.by_ref() {
+            let error = |message: String| format!("Line {}: {message}", index + 1);
+            let tokens = split_line(line).map_err(error)?;
+            match tokens.first().map(String::as_str) {
+                None => (),
+                Some("world") => break,
+                Some("context") if tokens.len() > 2 => {
+                    let mut values = tokens[2..].iter().map(String::as_str);
+                    let value = read_value_text(&mut values).map_err(error)?;
+                    if values.next().is_some() {
+                        return Err(error("Unexpected words after the value".to_string()));
+                    }
+                    context.push((tokens[1].clone(), value));
+                }
+                Some(_) => return Err(error(format!("Expected a context value, got '{line}'"))),
+            }
+        }
+        let world = lines.map(|(_, line)| line).collect::<Vec<_>>().join("\n");
+        Ok(Self {
+            context,
+            world: Snapshot::from_text(&world)?,
+        })
+    }
+}
+
+fn add_value(bytes: &mut Vec<u8>, value: &Value) {
+    bytes.push(ValueKind::of(value) as u8);
+    match value {
+        Value::True | Value::False => (),
+        Value::Integer(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+        Value::Integer64(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+        Value::Float(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+        Value::Float64(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+        Value::String(value) | Value::SimpleTag(value) => add_string(bytes, value),
+        Value::Tag(name, value) => {
+            add_string(bytes, name);
+            add_value(bytes, value);
+        }
+        Value::List(values) => {
+            add_varint(bytes, values.len() as u32);
+            values.iter().for_each(|value| add_value(bytes, value));
+        }
+        Value::Function(index) => add_varint(bytes, *index),
+        Value::Component { id, properties } => {
+            add_varint(bytes, *id);
+            add_varint(bytes, properties.len() as u32);
+            properties.iter().for_each(|value| add_value(bytes, value));
+        }
+    }
+}
+
+fn read_value(reader: &mut ByteReader) -> Result<Value, String> {
+    let kind = reader.read_byte()?;
+    let kind = ValueKind::ALL
+        .into_iter()
+        .find(|value_kind| *value_kind as u8 == kind)
+        .ok_or_else(|| format!("Unknown value kind {kind}"))?;
+    let read_8_bytes = |reader: &mut ByteReader| -> Result<[u8; 8], String> {
+        Ok(reader.read_bytes(8)?.try_into().unwrap())
+    };
+    Ok(match kind {
+        ValueKind::True => Value::True,
+        ValueKind::False => Value::False,
+        ValueKind::Integer => Value::Integer(reader.read_u32()? as i32),
+        ValueKind::Integer64 => Value::Integer64(i64::from_be_bytes(read_8_bytes(reader)?)),
+        ValueKind::Float => Value::Float(f32::from_bits(reader.read_u32()?)),
+        ValueKind::Float64 => Value::Float64(f64::from_be_bytes(read_8_bytes(reader)?)),
+        ValueKind::String => Value::String(reader.read_string()?),
+        ValueKind::SimpleTag => Value::SimpleTag(reader.read_string()?),
+        ValueKind::Tag => Value::Tag(reader.read_string()?, Box::new(read_value(reader)?)),
+        ValueKind::List => {
+            let count = reader.read_varint()?;
+            Value::List(
+                (0..count)
+                    .map(|_| read_value(reader))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+        ValueKind::Function => Value::Function(reader.read_varint()?),
+        ValueKind::Component => {
+            let id = reader.read_varint()?;
+            let count = reader.read_varint()?;
+            let properties = (0..count)
+                .map(|_| read_value(reader))
+                .collect::<Result<_, _>>()?;
+            Value::Component { id, properties }
+        }
+    })
+}
+
+// Values are written in prefix notation, with the number of elements of lists and components before the elements
+fn add_value_text(line: &mut String, value: &Value) {
+    *line += " ";
+    *line += ValueKind::of(value).name();
+    match value {
+        Value::True | Value::False => (),
+        Value::Integer(value) => *line += &format!(" {value}"),
+        Value::Integer64(value) => *line += &format!(" {value}"),
+        Value::Float(value) => *line += &format!(" {value:?}"), // round-trips
+        Value::Float64(value) => *line += &format!(" {value:?}"),
+        Value::String(value) | Value::SimpleTag(value) => *line += &format!(" {value:?}"),
+        Value::Tag(name, value) => {
+            *line += &format!(" {name:?}");
+            add_value_text(line, value);
+        }
+        Value::List(values) => {
+            *line += &format!(" {}", values.len());
+            values.iter().for_each(|value| add_value_text(line, value));
+        }
+        Value::Function(index) => *line += &format!(" {index}"),
+        Value::Component { id, properties } => {
+            *line += &format!(" {id} {}", properties.len());
+            properties
+                .iter()
+                .for_each(|value| add_value_text(line, value));
+        }
+    }
+}
+
+fn read_value_text<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Value, String> {
+    let kind = words.next().ok_or("Missing value")?;
+    let kind = ValueKind::ALL
+        .into_iter()
+        .find(|value_kind| value_kind.name() == kind)
+        .ok_or_else(|| format!("Unknown value kind '{kind}'"))?;
+    let mut word = || {
+        words
+            .next()
+            .ok_or(format!("Missing data of {} value", kind.name()))
+    };
+    fn parse<T: std::str::FromStr>(word: &str) -> Result<T, String> {
+        word.parse().map_err(|_| format!("Invalid number '{word}'"))
+    }
+    Ok(match kind {
+        ValueKind::True => Value::True,
+        ValueKind::False => Value::False,
+        ValueKind::Integer => Value::Integer(parse(word()?)?),
+        ValueKind::Integer64 => Value::Integer64(parse(word()?)?),
+        ValueKind::Float => Value::Float(parse(word()?)?),
+        ValueKind::Float64 => Value::Float64(parse(word()?)?),
+        ValueKind::String => Value::String(word()?.to_string()),
+        ValueKind::SimpleTag => Value::SimpleTag(word()?.to_string()),
+        ValueKind::Tag => {
+            let name = word()?.to_string();
+            Value::Tag(name, Box::new(read_value_text(words)?))
+        }
+        ValueKind::List => {
+            let count: u32 = parse(word()?)?;
+            Value::List(
+                (0..count)
+                    .map(|_| read_value_text(words))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+        ValueKind::Function => Value::Function(parse(word()?)?),
+        ValueKind::Component => {
+            let id = parse(word()?)?;
+            let count: u32 = parse(word()?)?;
+            let properties = (0..count)
+                .map(|_| read_value_text(words))
+                .collect::<Result<_, _>>()?;
+            Value::Component { id, properties }
+        }
+    })
+}
@@ -12,20 +12,26 @@ pub enum TokenKind {
     EqualEqual,
     False,
     Float,
+    Float64,
     Identifier,
     Integer,
+    Integer64,
     KeywordAnd,
     KeywordComponent,
     KeywordCreate,
     KeywordDestroy,
     KeywordF32,
+    KeywordF64,
     KeywordI32,
+    KeywordI64,
     KeywordNot,
+    KeywordOn,
     KeywordOr,
     KeywordIs,
     KeywordIf,
     KeywordStr,
     KeywordQuery,
+    KeywordResource,
     LeftBrace,
     LeftBracket,
     LeftParen,
@@ -41,6 +47,7 @@ pub enum TokenKind {
     Pipe,
     Plus,
     PlusDot,
+    QuestionMark,
     RightBrace,
     RightBracket,
     RightParen,
@@ -1,10 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::diagnostics::Diagnostics;
 use crate::errors::Error;
 use crate::expressions::{
-    BinaryOperator, Expr, IsArmPattern, IsGuard, PropertyDefinition, StringOperations,
-    UnaryOperator, ValueType,
+    BinaryOperator, Expr, IsArmPattern, IsGuard, PropertyDefinition, PropertyType,
+    StringOperations, UnaryOperator, ValueType,
 };
 use crate::program::Context;
 use crate::tokens::Token;
@@ -324,6 +324,14 @@ enum Constraint {
         right: UnificationType,
         at: Option<Token>,
     },
+    // Both operands must have the same type, which must be one of the given numeric types.
+    // An operand type that is still unresolved after solving defaults to the first option.
+    Numeric {
+        left: UnificationType,
+        right: UnificationType,
+        options: Vec<Type>,
+        at: Token,
+    },
 }
 
 struct ComponentMetadata {
@@ -335,28 +343,14 @@ struct ComponentMetadata {
 struct Environment {
     variables: HashMap<String, UnificationType>,
     components: HashMap<String /* TODO(anissen): Should be a Token */, ComponentMetadata>,
+    context_values: HashMap<String, UnificationType>, // the declared resources and the assigned `∆` values
+    handler_components: HashSet<String>, // the variables bound to a copy of the component that a handler observed
 }
 
 impl Environment {
     fn new() -> Self {
         Self::default()
     }
-
-    fn get_property_definition(
-        &self,
-        component_name: String,
-        property_name: String,
-    ) -> &PropertyDefinition {
-        let component_metadata = self.components.get(&component_name).unwrap();
-        let properties = &component_metadata.properties;
-        dbg!(&properties);
-        dbg!(&property_name);
-
-        (properties
-            .iter()
-            .find(|prop| prop.name.lexeme == property_name)
-            .unwrap()) as _
-    }
 }
 
 struct InferenceContext<'env> {
@@ -381,10 +375,87 @@ impl<'env> InferenceContext<'env> {
         self.last_type_variable_index
     }
 
+    // The type that the values of the property must have
+    fn property_type(&mut self, property: &PropertyDefinition) -> UnificationType {
+        match &property.type_ {
+            PropertyType::Primitive(type_) => {
+                make_constructor(type_.clone(), property.name.clone())
+            }
+            PropertyType::Tag(tags) => UnificationType::Union {
+                types: Box::new(
+                    tags.iter()
+                        .map(|tag| {
+                            let name = tag.lexeme.clone();
+                            make_constructor(Type::Tag { name }, tag.clone())
+                        })
+                        .collect(),
+                ),
+                has_wildcard: false,
+            },
+            PropertyType::List(element_type) => UnificationType::Constructor {
+                typ: Type::List,
+                generics: vec![make_constructor(
+                    element_type.clone(),
+                    property.name.clone(),
+                )],
+                token: property.name.clone(),
+            },
+            PropertyType::Component(name) => match self.environment.components.get(&name.lexeme) {
+                Some(component_metadata) => component_metadata.type_.clone(),
+                None => {
+                    self.diagnostics.add_error(Error::TypeNotFound {
+                        token: name.clone(),
+                    });
+                    self.type_placeholder()
+                }
+            },
+        }
+    }
+
     fn type_placeholder(&mut self) -> UnificationType {
         UnificationType::Variable(self.fresh_type_variable())
     }
 
+    // The type of the present component if the variable is bound to an optional component of a query
+    fn optional_component_type(&self, name: &str) -> Option<UnificationType> {
+        match self.environment.variables.get(name) {
+            Some(UnificationType::Union { types, .. }) => match types.as_slice() {
+                [
+                    present,
+                    UnificationType::Constructor {
+                        typ: Type::Tag { name },
+                        ..
+                    },
+                ] if name == ":none" => Some(present.clone()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Optional components may be `:none`, so their fields are only accessed once they are matched against `:none`
+    fn check_field_access(&mut self, identifier: &Token) {
+        if self.optional_component_type(&identifier.lexeme).is_some() {
+            self.diagnostics.add_error(Error::OptionalFieldAccess {
+                token: identifier.clone(),
+            });
+        }
+    }
+
+    // Handlers are passed a copy of the component, which may already be removed, so its fields are read-only
+    fn check_field_assignment(&mut self, identifier: &Token) {
+        self.check_field_access(identifier);
+        if self
+            .environment
+            .handler_components
+            .contains(&identifier.lexeme)
+        {
+            self.diagnostics.add_error(Error::HandlerFieldAssignment {
+                token: identifier.clone(),
+            });
+        }
+    }
+
     fn expects_type(&mut self, expression: &Expr, expected_type: UnificationType) {
         let actual_type = self.infer_type(expression);
         // dbg!(&actual_type);
@@ -396,6 +467,25 @@ impl<'env> InferenceContext<'env> {
         });
     }
 
+    // Both operands of an arithmetic operation or comparison must have the same numeric type
+    fn expects_numeric(
+        &mut self,
+        left: &Expr,
+        right: &Expr,
+        options: &[Type],
+        token: &Token,
+    ) -> UnificationType {
+        let left_type = self.infer_type(left);
+        let right_type = self.infer_type(right);
+        self.constraints.push(Constraint::Numeric {
+            left: left_type.clone(),
+            right: right_type,
+            options: options.to_vec(),
+            at: token.clone(),
+        });
+        left_type
+    }
+
     fn infer_type(&mut self, expression: &Expr) -> UnificationType {
         match expression {
             Expr::Identifier { name } => match self.environment.variables.get(&name.lexeme) {
@@ -408,19 +498,24 @@ impl<'env> InferenceContext<'env> {
                 }
             },
 
-            Expr::ContextIdentifier {
-                context: _,
-                name: _,
-            } => {
-                // TODO(anissen): Implement
-                self.type_placeholder()
+            Expr::ContextIdentifier { context: _, name } => {
+                match self.environment.context_values.get(&name.lexeme) {
+                    Some(value) => value.clone(),
+                    None => {
+                        self.diagnostics.add_error(Error::NameNotFound {
+                            token: name.clone(),
+                        });
+                        self.type_placeholder()
+                    }
+                }
             }
 
             Expr::FieldAccess {
-                identifier: _,
-                field_name: _,
+                identifier,
+                field_names: _,
             } => {
                 // todo!("Implement field access expression")
+                self.check_field_access(identifier);
                 self.type_placeholder()
             }
 
@@ -437,7 +532,7 @@ impl<'env> InferenceContext<'env> {
                     typ: Type::Component,
                     generics: properties
                         .iter()
-                        .map(|p| make_constructor(p.type_.clone(), p.name.clone()))
+                        .map(|property| self.property_type(property))
                         .collect(),
                     token: name.clone(),
                 };
@@ -454,10 +549,64 @@ impl<'env> InferenceContext<'env> {
                 type_
             }
 
+            Expr::ResourceDefinition {
+                name,
+                type_,
+                default,
+            } => {
+                let resource_type = make_constructor(type_.clone(), name.clone());
+                self.expects_type(default, resource_type.clone());
+                self.environment
+                    .context_values
+                    .insert(name.lexeme.clone(), resource_type.clone());
+                resource_type
+            }
+
+            Expr::Handler {
+                event,
+                entity,
+                component,
+                expr,
+            } => {
+                let entity_type = make_constructor(Type::Integer, event.clone());
+                if let Some(name) = entity {
+                    self.environment
+                        .variables
+                        .insert(name.lexeme.clone(), entity_type.clone());
+                }
+                let component_type = match self.environment.components.get(&component.type_.lexeme)
+                {
+                    Some(component_metadata) => component_metadata.type_.clone(),
+                    None => {
+                        self.diagnostics.add_error(Error::TypeNotFound {
+                            token: component.type_.clone(),
+                        });
+                        self.type_placeholder()
+                    }
+                };
+                if let Some(ref name) = component.name {
+                    self.environment
+                        .variables
+                        .insert(name.lexeme.clone(), component_type.clone());
+                    self.environment
+                        .handler_components
+                        .insert(name.lexeme.clone());
+                }
+                let value_type = self.infer_type(expr);
+                // A handler is typed as the function that it is compiled to
+                UnificationType::Constructor {
+                    typ: Type::Function,
+                    generics: vec![entity_type, component_type, value_type],
+                    token: event.clone(),
+                }
+            }
+
             Expr::Value { value, token } => match value {
                 ValueType::Boolean(_) => make_constructor(Type::Boolean, token.clone()),
                 ValueType::Integer(_) => make_constructor(Type::Integer, token.clone()),
+                ValueType::Integer64(_) => make_constructor(Type::Integer64, token.clone()),
                 ValueType::Float(_) => make_constructor(Type::Float, token.clone()),
+                ValueType::Float64(_) => make_constructor(Type::Float64, token.clone()),
                 ValueType::String(_) => make_constructor(Type::String, token.clone()),
                 ValueType::Tag { name, payload } => UnificationType::Constructor {
                     typ: Type::Tag {
@@ -521,21 +670,23 @@ impl<'env> InferenceContext<'env> {
 
                     if let Some(component_metadata) = self.environment.components.get(&name.lexeme)
                     {
+                        let UnificationType::Constructor {
+                            generics: property_types,
+                            ..
+                        } = component_metadata.type_.clone()
+                        else {
+                            unreachable!("Components have constructor types")
+                        };
                         let filled_in_properties: Vec<_> = component_metadata
                             .properties
                             .clone() // Needed to avoid repeated borrowing
                             .iter()
-                            .map(|def_prop| {
+                            .zip(property_types)
+                            .map(|(def_prop, property_type)| {
                                 let res = property_map.get(&def_prop.name.lexeme);
 
                                 if let Some(actual_prop) = res {
-                                    self.expects_type(
-                                        &actual_prop.value,
-                                        make_constructor(
-                                            def_prop.type_.clone(),
-                                            def_prop.name.clone(),
-                                        ),
-                                    );
+                                    self.expects_type(&actual_prop.value, property_type);
                                     self.infer_type(&actual_prop.value)
                                 } else {
                                     self.diagnostics.add_error(Error::PropertyMissing {
@@ -602,16 +753,19 @@ impl<'env> InferenceContext<'env> {
                         context: _,
                         ref name,
                     } => {
+                        // Resources keep their declared type and other values get the type of their first assignment
                         self.environment
-                            .variables
-                            .insert(name.lexeme.clone(), type_variable);
+                            .context_values
+                            .entry(name.lexeme.clone())
+                            .or_insert(type_variable);
                     }
                     Expr::FieldAccess {
                         ref identifier,
-                        ref field_name,
+                        ref field_names,
                     } => {
                         // let property = self.environment.get_property_definition(component_name, property_name)
-                        let name = format!("{}.{}", identifier.lexeme, field_name.lexeme);
+                        self.check_field_assignment(identifier);
+                        let name = field_path_name(identifier, field_names);
                         let t = self.environment.variables.get(&identifier.lexeme).unwrap();
                         dbg!(&name);
                         dbg!(&t);
@@ -630,16 +784,18 @@ impl<'env> InferenceContext<'env> {
                         context: _,
                         ref name,
                     } => {
-                        // TODO(anissen): This is not right for ContextIdentifier?!?
-                        self.environment
-                            .variables
-                            .insert(name.lexeme.clone(), expr_type.clone());
+                        let value_type = self.environment.context_values[&name.lexeme].clone();
+                        self.constraints.push(Constraint::Eq {
+                            left: expr_type.clone(),
+                            right: value_type,
+                            at: None,
+                        });
                     }
                     Expr::FieldAccess {
                         ref identifier,
-                        ref field_name,
+                        ref field_names,
                     } => {
-                        let name = format!("{}.{}", identifier.lexeme, field_name.lexeme);
+                        let name = field_path_name(identifier, field_names);
                         self.environment.variables.insert(name, expr_type.clone());
                     }
                     _ => panic!("Invalid assignment target"),
@@ -660,26 +816,20 @@ impl<'env> InferenceContext<'env> {
                 right,
             } => match operator {
                 BinaryOperator::IntegerOperation(_) => {
-                    self.expects_type(left, make_constructor(Type::Integer, token.clone()));
-                    self.expects_type(right, make_constructor(Type::Integer, token.clone()));
-                    make_constructor(Type::Integer, token.clone())
+                    self.expects_numeric(left, right, &[Type::Integer, Type::Integer64], token)
                 }
 
                 BinaryOperator::IntegerComparison(_) => {
-                    self.expects_type(left, make_constructor(Type::Integer, token.clone()));
-                    self.expects_type(right, make_constructor(Type::Integer, token.clone()));
+                    self.expects_numeric(left, right, &[Type::Integer, Type::Integer64], token);
                     make_constructor(Type::Boolean, token.clone())
                 }
 
                 BinaryOperator::FloatOperation(_) => {
-                    self.expects_type(left, make_constructor(Type::Float, token.clone()));
-                    self.expects_type(right, make_constructor(Type::Float, token.clone()));
-                    make_constructor(Type::Float, token.clone())
+                    self.expects_numeric(left, right, &[Type::Float, Type::Float64], token)
                 }
 
                 BinaryOperator::FloatComparison(_) => {
-                    self.expects_type(left, make_constructor(Type::Float, token.clone()));
-                    self.expects_type(right, make_constructor(Type::Float, token.clone()));
+                    self.expects_numeric(left, right, &[Type::Float, Type::Float64], token);
                     make_constructor(Type::Boolean, token.clone())
                 }
 
@@ -699,8 +849,8 @@ impl<'env> InferenceContext<'env> {
                     match string_operations {
                         StringOperations::StringConcat => {
                             self.expects_type(left, make_constructor(Type::String, token.clone()));
-                            // TODO(anissen): Implement:
-                            // self.expect_type(right, Type::Any, &_token.position, env, diagnostics); // TODO(anissen): Check types
+                            // Values of any type can be interpolated, but they must still be well-typed themselves
+                            self.infer_type(right);
                             make_constructor(Type::String, token.clone())
                         }
                     }
@@ -727,6 +877,14 @@ impl<'env> InferenceContext<'env> {
                 let mut has_wildcard = false;
                 let mut arm_expr_types = Vec::new();
                 let mut return_types = Vec::new();
+                // An optional component is narrowed to the component in the arms after it is matched against `:none`
+                let optional = match expr.as_ref() {
+                    Expr::Identifier { name } => self
+                        .optional_component_type(&name.lexeme)
+                        .map(|present| (present, self.environment.variables[&name.lexeme].clone())),
+                    _ => None,
+                };
+                let mut none_matched = false;
 
                 // TODO(anissen): Add positions here
                 for arm in arms {
@@ -737,7 +895,11 @@ impl<'env> InferenceContext<'env> {
                         }
 
                         IsArmPattern::Capture { identifier } => {
-                            let x = self.type_placeholder();
+                            let x = match &optional {
+                                Some((present, _)) if none_matched => present.clone(),
+                                Some((_, optional)) => optional.clone(),
+                                None => self.type_placeholder(),
+                            };
                             self.environment
                                 .variables
                                 .insert(identifier.lexeme.clone(), x);
@@ -750,6 +912,9 @@ impl<'env> InferenceContext<'env> {
                             identifier,
                         } => {
                             let capture = self.type_placeholder();
+                            self.environment
+                                .variables
+                                .insert(identifier.lexeme.clone(), capture.clone());
                             arm_expr_types.push(UnificationType::Constructor {
                                 typ: Type::Tag {
                                     name: tag_name.lexeme.clone(),
@@ -769,6 +934,16 @@ impl<'env> InferenceContext<'env> {
                             condition,
                             make_constructor(Type::Boolean, token.clone()),
                         );
+                    } else if let IsArmPattern::Expression(Expr::Value {
+                        value:
+                            ValueType::Tag {
+                                name,
+                                payload: None,
+                            },
+                        ..
+                    }) = &arm.pattern
+                    {
+                        none_matched |= name.lexeme == ":none";
                     }
 
                     // TODO(anissen): Check for exhaustiveness
@@ -795,9 +970,21 @@ impl<'env> InferenceContext<'env> {
 
             Expr::Query {
                 include_components,
+                optional_components,
                 exclude_components: _,
+                added_components: _,
+                changed_components: _,
+                parent,
                 expr,
             } => {
+                if let Some(parent) = parent
+                    && let Some(child_of) = include_components
+                        .iter()
+                        .find(|component| component.type_.lexeme == "ChildOf")
+                {
+                    let parent_type = make_constructor(Type::Integer, child_of.type_.clone());
+                    self.expects_type(parent, parent_type);
+                }
                 include_components.iter().for_each(|component| {
                     let component_name = component.type_.lexeme.clone();
                     // Special-case handling for 'Entity' component
@@ -815,6 +1002,7 @@ impl<'env> InferenceContext<'env> {
                             self.environment.components.get(&component_name)
                         {
                             if let Some(ref name) = component.name {
+                                self.environment.handler_components.remove(&name.lexeme);
                                 // let v = self.type_placeholder();
                                 // let prop_def = self
                                 //     .environment
@@ -832,6 +1020,43 @@ impl<'env> InferenceContext<'env> {
                         };
                     }
                 });
+                optional_components.iter().for_each(|component| {
+                    let Some(component_metadata) =
+                        self.environment.components.get(&component.type_.lexeme)
+                    else {
+                        self.diagnostics.add_error(Error::TypeNotFound {
+                            token: component.type_.clone(),
+                        });
+                        return;
+                    };
+                    if let Some(ref name) = component.name {
+                        self.environment.handler_components.remove(&name.lexeme);
+                        // Markers are bound to `:some` and components with fields to the component itself
+                        let present_type = if component_metadata.properties.is_empty() {
+                            make_constructor(
+                                Type::Tag {
+                                    name: ":some".to_string(),
+                                },
+                                component.type_.clone(),
+                            )
+                        } else {
+                            component_metadata.type_.clone()
+                        };
+                        let absent_type = make_constructor(
+                            Type::Tag {
+                                name: ":none".to_string(),
+                            },
+                            component.type_.clone(),
+                        );
+                        self.environment.variables.insert(
+                            name.lexeme.clone(),
+                            UnificationType::Union {
+                                types: Box::new(vec![present_type, absent_type]),
+                                has_wildcard: false,
+                            },
+                        );
+                    }
+                });
                 self.infer_type(expr)
             }
 
@@ -853,11 +1078,37 @@ impl<'env> InferenceContext<'env> {
                 self.expects_type(argument, make_constructor(Type::Integer, token.clone()));
                 make_constructor(Type::Boolean, token.clone()) // TODO(anissen): Function actually returns Void
             }
+
+            Expr::AddComponent {
+                token,
+                entity,
+                component,
+            } => {
+                self.expects_type(entity, make_constructor(Type::Integer, token.clone()));
+                // The component value itself is checked against the component definition
+                self.expects_type(component, make_constructor(Type::Component, token.clone()));
+                make_constructor(Type::Integer, token.clone()) // entity id, so operations can be chained
+            }
+
+            Expr::RemoveComponent {
+                token,
+                entity,
+                component,
+            } => {
+                self.expects_type(entity, make_constructor(Type::Integer, token.clone()));
+                if !self.environment.components.contains_key(&component.lexeme) {
+                    self.diagnostics.add_error(Error::TypeNotFound {
+                        token: component.clone(),
+                    });
+                }
+                make_constructor(Type::Integer, token.clone()) // entity id, so operations can be chained
+            }
         }
     }
 
     fn solve(&mut self) -> HashMap<TypeVariable, UnificationType> {
         let mut substitutions = HashMap::new();
+        let mut unresolved_numerics = Vec::new();
 
         for constraint in &self.constraints {
             match constraint {
@@ -870,9 +1121,51 @@ impl<'env> InferenceContext<'env> {
                         self.diagnostics,
                     );
                 }
+                Constraint::Numeric {
+                    left,
+                    right,
+                    options,
+                    at,
+                } => match left.substitute(&substitutions) {
+                    UnificationType::Constructor { ref typ, .. } if !options.contains(typ) => {
+                        let left = left.substitute(&substitutions);
+                        let expected = make_constructor(options[0].clone(), at.clone());
+                        unify(&left, &expected, None, &mut substitutions, self.diagnostics);
+                    }
+                    left_type => {
+                        unify(right, left, None, &mut substitutions, self.diagnostics);
+                        if let UnificationType::Variable(_) = left_type {
+                            unresolved_numerics.push((left, options, at));
+                        }
+                    }
+                },
+            }
+        }
+
+        // Operands whose types were not known when their operation was solved, e.g. function parameters
+        for (left, options, at) in unresolved_numerics {
+            let expected = make_constructor(options[0].clone(), at.clone());
+            match left.substitute(&substitutions) {
+                UnificationType::Variable(v) => {
+                    substitutions.insert(v, expected);
+                }
+                UnificationType::Constructor { ref typ, .. } if !options.contains(typ) => {
+                    let left = left.substitute(&substitutions);
+                    unify(&left, &expected, None, &mut substitutions, self.diagnostics);
+                }
+                _ => (),
             }
         }
 
         substitutions
     }
 }
+
+// The name of the field in the environment, e.g. `t.pos.x`
+fn field_path_name(identifier: &Token, field_names: &[Token]) -> String {
+    std::iter::once(identifier)
+        .chain(field_names)
+        .map(|token| token.lexeme.as_str())
+        .collect::<Vec<_>>()
+        .join(".")
+}
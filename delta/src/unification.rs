@@ -9,7 +9,9 @@ use std::iter::zip;
 pub enum Type {
     Boolean,
     Integer,
+    Integer64,
     Float,
+    Float64,
     String,
     Tag { name: String },
     // TagUnion {  }
@@ -29,6 +31,7 @@ pub enum UnificationType {
         token: Token,
     },
     Variable(TypeVariable),
+    #[allow(clippy::box_collection)]
     Union {
         types: Box<Vec<UnificationType>>,
         has_wildcard: bool,
@@ -45,7 +48,9 @@ impl fmt::Display for UnificationType {
             } => match typ {
                 Type::Boolean => "bool",
                 Type::Integer => "int",
+                Type::Integer64 => "int64",
                 Type::Float => "float",
+                Type::Float64 => "float64",
                 Type::String => "string",
                 Type::Tag { name } => {
                     let fields = generics
@@ -112,7 +117,7 @@ pub fn make_constructor(typ: Type, token: Token) -> UnificationType {
 }
 
 impl UnificationType {
-    fn substitute(
+    pub fn substitute(
         &self,
         substitutions: &HashMap<TypeVariable, UnificationType>,
     ) -> UnificationType {
@@ -243,8 +248,8 @@ pub fn unify(
 
             if name1 != name2 || generics1.len() != generics2.len() {
                 diagnostics.add_error(Error::TypeMismatch {
-                    expected: right.substitute(substitutions),
-                    got: left.substitute(substitutions),
+                    expected: Box::new(right.substitute(substitutions)),
+                    got: Box::new(left.substitute(substitutions)),
                     declared_at: token1,
                     provided_at: token2,
                     mismatch_at: at.cloned(),
@@ -335,8 +340,8 @@ pub fn unify(
             });
             if !has_match && !has_wildcard {
                 diagnostics.add_error(Error::TypeMismatch {
-                    expected: right.substitute(substitutions),
-                    got: left.substitute(substitutions),
+                    expected: Box::new(right.substitute(substitutions)),
+                    got: Box::new(left.substitute(substitutions)),
                     declared_at: token1.clone(),
                     provided_at: mismatch_token.unwrap_or(token1),
                     mismatch_at: at.cloned(),
@@ -349,10 +354,13 @@ pub fn unify(
             unify(right, left, at, substitutions, diagnostics);
         }
 
-        // Union vs Union: check that all types in left union exist in right union
+        // Union vs Union: check that all types in left union exist in right union (unless it has a wildcard)
         (
             UnificationType::Union { types: types1, .. },
-            UnificationType::Union { types: types2, .. },
+            UnificationType::Union {
+                types: types2,
+                has_wildcard,
+            },
         ) => {
             for type1 in types1.iter() {
                 if let UnificationType::Constructor {
@@ -373,10 +381,10 @@ pub fn unify(
                             panic!()
                         }
                     });
-                    if !has_match {
+                    if !has_match && !has_wildcard {
                         diagnostics.add_error(Error::TypeMismatch {
-                            expected: right.substitute(substitutions),
-                            got: type1.clone(),
+                            expected: Box::new(right.substitute(substitutions)),
+                            got: Box::new(type1.clone()),
                             declared_at: token1.clone(),
                             provided_at: token1.clone(),
                             mismatch_at: at.cloned(),
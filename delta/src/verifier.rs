@@ -0,0 +1,869 @@
+use std::collections::{BTreeMap, HashMap};
+
+use elements::{COMPONENT_TYPE_ID, LIST_TYPE_ID, STRING_TYPE_ID, TAG_TYPE_ID};
+
+use crate::bytecode_file::{ByteReader, BytecodeFile, ComponentEntry, DebugInfo};
+use crate::instructions::Instruction;
+use crate::optimizer::is_binary_operation;
+
+// Checks a bytecode file before the VM accepts it, so that compiled scripts loaded from disk or from mods
+// cannot make the VM read past the end of the program, decode invalid strings, jump into the middle of an
+// instruction or pop values that were never pushed.
+//
+// The component table is checked first, so that the VM can read every field within the bytes of its component.
+// The code is then verified in two passes:
+//   1. Every instruction is decoded, checking opcodes, operands and the function and component tables.
+//   2. Each chunk (the main chunk and every function chunk) is walked along all of its control flow paths,
+//      tracking the stack height of each call and query frame.
+// Finally, the names and source lines of the debug info must refer to the components and instructions of the file.
+pub fn verify(file: &BytecodeFile) -> Result<(), String> {
+    verify_components(file)?;
+    verify_handlers(file)?;
+    verify_resources(file)?;
+    let instructions = decode(file)?;
+
+    let mut chunk_starts: Vec<usize> = vec![0];
+    for function in &file.functions {
+        let position = function.position as usize;
+        match instructions.get(&position) {
+            Some(instruction) if position > 0 && *instruction == Instruction::FunctionChunk => {
+                chunk_starts.push(position)
+            }
+            _ => {
+                return Err(format!(
+                    "Function '{}' does not point to the start of a function chunk",
+                    function.name
+                ));
+            }
+        }
+    }
+    chunk_starts.sort();
+    if chunk_starts.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err("Several functions point to the same function chunk".to_string());
+    }
+
+    if !file.code.is_empty() {
+        verify_chunk(
+            file,
+            &instructions,
+            0,
+            chunk_end(&chunk_starts, 0, &file.code),
+            0,
+        )?;
+    }
+    for function in &file.functions {
+        let start = function.position as usize;
+        let end = chunk_end(&chunk_starts, start, &file.code);
+        verify_chunk(
+            file,
+            &instructions,
+            start,
+            end,
+            function.local_count as usize,
+        )?;
+    }
+
+    if let Some(debug_info) = &file.debug_info {
+        verify_debug_info(debug_info, file, &instructions)?;
+    }
+    Ok(())
+}
+
+// Fields hold a value of a fixed size for their type. Nested components must come earlier in the table (which rules
+// out cycles) and fit their field exactly, while fields that refer to the heaps of the world hold a 4-byte handle.
+fn verify_components(file: &BytecodeFile) -> Result<(), String> {
+    for (position, component) in file.components.iter().enumerate() {
+        for field in &component.fields {
+            let expected_size = match field.type_id {
+                0 => 1,     // bool
+                1 | 2 => 4, // i32 and f32
+                4 | 5 => 8, // i64 and f64
+                COMPONENT_TYPE_ID => {
+                    let nested = file.components[..position]
+                        .iter()
+                        .find(|nested| nested.id == field.type_parameter)
+                        .ok_or_else(|| {
+                            format!(
+                                "Component {} nests component {} that is not defined before it",
+                                component.id, field.type_parameter
+                            )
+                        })?;
+                    nested.fields.iter().map(|field| field.size as u32).sum()
+                }
+                LIST_TYPE_ID if field.type_parameter > 5 => {
+                    return Err(format!(
+                        "Unknown list element type {} in component {}",
+                        field.type_parameter, component.id
+                    ));
+                }
+                STRING_TYPE_ID | TAG_TYPE_ID | LIST_TYPE_ID => 4,
+                type_id => {
+                    return Err(format!(
+                        "Unknown field type {type_id} in component {}",
+                        component.id
+                    ));
+                }
+            };
+            if field.size as u32 != expected_size {
+                return Err(format!(
+                    "Field of component {} has size {} instead of {}",
+                    component.id, field.size, expected_size
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Handlers are called by the VM with the entity and the component as their arguments
+fn verify_handlers(file: &BytecodeFile) -> Result<(), String> {
+    for handler in &file.handlers {
+        if !file
+            .components
+            .iter()
+            .any(|component| component.id == handler.component)
+        {
+            return Err(format!(
+                "Handler of unknown component {}",
+                handler.component
+            ));
+        }
+        let function = file
+            .functions
+            .get(handler.function as usize)
+            .ok_or_else(|| format!("Handler of unknown function {}", handler.function))?;
+        if function.local_count != 2 {
+            return Err(format!(
+                "Handler '{}' takes {} parameters instead of 2",
+                function.name, function.local_count
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn verify_resources(file: &BytecodeFile) -> Result<(), String> {
+    for (index, resource) in file.resources.iter().enumerate() {
+        if file.resources[..index]
+            .iter()
+            .any(|other| other.name == resource.name)
+        {
+            return Err(format!(
+                "Resource '{}' is declared more than once",
+                resource.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+// The debug info must describe the components and instructions of the file it is part of
+fn verify_debug_info(
+    debug_info: &DebugInfo,
+    file: &BytecodeFile,
+    instructions: &BTreeMap<usize, Instruction>,
+) -> Result<(), String> {
+    for names in &debug_info.components {
+        let component = file
+            .components
+            .iter()
+            .find(|component| component.id == names.id)
+            .ok_or_else(|| format!("Debug info names unknown component {}", names.id))?;
+        if component.fields.len() != names.field_names.len() {
+            return Err(format!(
+                "Component '{}' has {} fields but {} field names",
+                names.name,
+                component.fields.len(),
+                names.field_names.len()
+            ));
+        }
+    }
+    for (position, name) in &debug_info.call_names {
+        match instructions.get(&(*position as usize)) {
+            Some(Instruction::Call { .. }) => (),
+            _ => return Err(format!("Call name '{name}' does not belong to a call")),
+        }
+    }
+    for position in debug_info.lines.keys() {
+        if !instructions.contains_key(&(*position as usize)) {
+            return Err(format!(
+                "Source line at byte {position} is not the start of an instruction"
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn decode(file: &BytecodeFile) -> Result<BTreeMap<usize, Instruction>, String> {
+    let mut instructions = BTreeMap::new();
+    let mut reader = ByteReader::new(&file.code);
+    while reader.position() < file.code.len() {
+        let position = reader.position();
+        let instruction = Instruction::decode(&mut reader, &file.constants)
+            .and_then(|instruction| verify_operands(&instruction, file).map(|_| instruction))
+            .map_err(|error| format!("{error} (at byte {position})"))?;
+        instructions.insert(position, instruction);
+    }
+    Ok(instructions)
+}
+
+// Checks the operands that refer to the function and component tables of the file
+fn verify_operands(instruction: &Instruction, file: &BytecodeFile) -> Result<(), String> {
+    match instruction {
+        Instruction::PushComponent { id, property_count } => {
+            let component = component(file, *id)?;
+            if component.fields.len() != *property_count as usize {
+                return Err(format!(
+                    "Component {id} has {} fields but {property_count} were given",
+                    component.fields.len()
+                ));
+            }
+        }
+
+        Instruction::GetLocalValueAndOperate { operation, .. }
+            if !is_binary_operation(*operation) =>
+        {
+            return Err(format!("Invalid binary operation {operation:?}"));
+        }
+
+        Instruction::Function {
+            function_index,
+            param_count,
+        } => {
+            let function = file
+                .functions
+                .get(*function_index as usize)
+                .ok_or_else(|| format!("Unknown function {function_index}"))?;
+            if function.local_count != *param_count {
+                return Err(format!(
+                    "Function '{}' takes {} parameters but is declared with {param_count}",
+                    function.name, function.local_count
+                ));
+            }
+        }
+
+        Instruction::CallForeign {
+            foreign_index,
+            arg_count,
+        } => {
+            let foreign = file
+                .foreign_functions
+                .get(*foreign_index as usize)
+                .ok_or_else(|| format!("Unknown foreign function {foreign_index}"))?;
+            if foreign.arity != *arg_count {
+                return Err(format!(
+                    "Foreign function '{}' takes {} arguments but is called with {arg_count}",
+                    foreign.name, foreign.arity
+                ));
+            }
+        }
+
+        Instruction::ContextQuery {
+            include_components,
+            optional_components,
+            exclude_components,
+            added_components,
+            changed_components,
+            ..
+        } => {
+            for id in include_components
+                .iter()
+                .chain(optional_components)
+                .chain(exclude_components)
+                .chain(added_components)
+                .chain(changed_components)
+            {
+                component(file, *id)?;
+            }
+            // The added and changed components must also be included
+            for id in added_components.iter().chain(changed_components) {
+                if !include_components.contains(id) {
+                    return Err(format!("Filtered component {id} is not included"));
+                }
+            }
+        }
+
+        Instruction::RemoveComponent { id } => {
+            component(file, *id)?;
+        }
+
+        // Every field along the path, except the last, must be a nested component
+        Instruction::GetFieldValue {
+            component: id,
+            field_path,
+            ..
+        }
+        | Instruction::SetFieldValue {
+            component: id,
+            field_path,
+            ..
+        } => {
+            if field_path.is_empty() {
+                return Err("Empty field path".to_string());
+            }
+            let mut fields = &component(file, *id)?.fields;
+            for (depth, field_index) in field_path.iter().enumerate() {
+                let field = fields.get(*field_index as usize).ok_or_else(|| {
+                    format!("Field path {field_path:?} is out of bounds of component {id}")
+                })?;
+                if depth + 1 < field_path.len() {
+                    if field.type_id != COMPONENT_TYPE_ID {
+                        return Err(format!(
+                            "Field path {field_path:?} of component {id} goes through a field that is not a component"
+                        ));
+                    }
+                    fields = &component(file, field.type_parameter)?.fields;
+                }
+            }
+        }
+
+        _ => (),
+    }
+    Ok(())
+}
+
+fn component(file: &BytecodeFile, id: u32) -> Result<&ComponentEntry, String> {
+    file.components
+        .iter()
+        .find(|component| component.id == id)
+        .ok_or_else(|| format!("Unknown component {id}"))
+}
+
+fn chunk_end(chunk_starts: &[usize], start: usize, code: &[u8]) -> usize {
+    chunk_starts
+        .iter()
+        .copied()
+        .find(|chunk_start| *chunk_start > start)
+        .unwrap_or(code.len())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Frame {
+    height: usize,
+    query: Option<Query>, // set for the frames pushed by queries
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Query {
+    end: usize,
+    local_index: usize, // where the entity and its components are bound
+    component_count: usize,
+}
+
+// Walks every path through a chunk. Paths that meet must agree on their frames, and the stack height used
+// for the remaining checks is the lowest height of the paths, e.g. query bodies leave a value behind on each
+// iteration, which is only discarded when the next iteration replaces the bindings.
+fn verify_chunk(
+    file: &BytecodeFile,
+    instructions: &BTreeMap<usize, Instruction>,
+    start: usize,
+    end: usize,
+    parameter_count: usize,
+) -> Result<(), String> {
+    let mut states: HashMap<usize, Vec<Frame>> = HashMap::new();
+    let mut pending = vec![start];
+    states.insert(
+        start,
+        vec![Frame {
+            height: parameter_count,
+            query: None,
+        }],
+    );
+
+    while let Some(position) = pending.pop() {
+        let instruction = &instructions[&position];
+        let next = instructions
+            .range(position + 1..)
+            .next()
+            .map_or(file.code.len(), |(next, _)| *next);
+        let frames = states[&position].clone();
+        let successors = stack_effect(file, instruction, next, frames, start > 0)
+            .map_err(|error| format!("{error} (at byte {position})"))?;
+
+        for (successor, frames) in successors {
+            if successor < start || successor >= end || !instructions.contains_key(&successor) {
+                return Err(format!(
+                    "Jump to byte {successor} is outside of the chunk or not at an instruction (at byte {position})"
+                ));
+            }
+            match states.get_mut(&successor) {
+                None => {
+                    states.insert(successor, frames);
+                    pending.push(successor);
+                }
+                Some(existing) => {
+                    let compatible = existing.len() == frames.len()
+                        && existing
+                            .iter()
+                            .zip(&frames)
+                            .all(|(existing, frame)| existing.query == frame.query);
+                    if !compatible {
+                        return Err(format!(
+                            "Inconsistent stack frames at byte {successor} (from byte {position})"
+                        ));
+                    }
+                    let mut lowered = false;
+                    for (existing, frame) in existing.iter_mut().zip(frames) {
+                        if frame.height < existing.height {
+                            existing.height = frame.height;
+                            lowered = true;
+                        }
+                    }
+                    if lowered {
+                        pending.push(successor);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Returns the instructions that can follow, together with the frames at that point
+fn stack_effect(
+    file: &BytecodeFile,
+    instruction: &Instruction,
+    next: usize,
+    mut frames: Vec<Frame>,
+    is_function_chunk: bool,
+) -> Result<Vec<(usize, Vec<Frame>)>, String> {
+    let frame = frames.last_mut().unwrap();
+    let height = frame.height;
+
+    match instruction {
+        Instruction::PushTrue
+        | Instruction::PushFalse
+        | Instruction::PushInteger(_)
+        | Instruction::PushInteger64(_)
+        | Instruction::PushFloat(_)
+        | Instruction::PushFloat64(_)
+        | Instruction::PushString(_)
+        | Instruction::PushSimpleTag { .. }
+        | Instruction::GetContextValue { .. }
+        | Instruction::GetForeignValue { .. }
+        | Instruction::Function { .. } => frame.height += 1,
+
+        Instruction::FunctionChunk => (),
+
+        Instruction::PushTag { .. } | Instruction::Negation | Instruction::Not => {
+            require(height, 1)?
+        }
+
+        Instruction::SetContextValue { .. } | Instruction::Log => require(height, 1)?,
+
+        Instruction::GetTagName | Instruction::GetTagPayload | Instruction::GetArrayLength => {
+            require(height, 1)?;
+            frame.height += 1;
+        }
+
+        Instruction::IntegerAddition
+        | Instruction::IntegerSubtraction
+        | Instruction::IntegerDivision
+        | Instruction::IntegerMultiplication
+        | Instruction::IntegerModulo
+        | Instruction::IntegerLessThan
+        | Instruction::IntegerLessThanEquals
+        | Instruction::FloatAddition
+        | Instruction::FloatSubtraction
+        | Instruction::FloatDivision
+        | Instruction::FloatMultiplication
+        | Instruction::FloatModulo
+        | Instruction::FloatLessThan
+        | Instruction::FloatLessThanEquals
+        | Instruction::StringConcat
+        | Instruction::BooleanAnd
+        | Instruction::BooleanOr
+        | Instruction::Equals
+        | Instruction::GetListElementAtIndex
+        | Instruction::ArrayAppend => {
+            require(height, 2)?;
+            frame.height -= 1;
+        }
+
+        Instruction::PushList { length: count }
+        | Instruction::PushComponent {
+            property_count: count,
+            ..
+        }
+        | Instruction::Call {
+            arg_count: count, ..
+        }
+        | Instruction::CallForeign {
+            arg_count: count, ..
+        } => {
+            let count = *count as usize;
+            require(height, count)?;
+            frame.height = height - count + 1;
+        }
+
+        Instruction::GetLocalValue { index } | Instruction::GetFieldValue { index, .. } => {
+            require_local(height, *index as usize)?;
+            frame.height += 1;
+        }
+
+        Instruction::GetLocalValueAndOperate { index, .. } => {
+            require_local(height, *index as usize)?;
+            require(height, 1)?;
+        }
+
+        Instruction::SetLocalValue { index } => {
+            // Assigning to the stack top keeps the value in place, anything else pops it
+            require(height, 1)?;
+            require_local(height, *index as usize)?;
+            if (*index as usize) < height - 1 {
+                frame.height -= 1;
+            }
+        }
+
+        Instruction::SetFieldValue { index, .. } => {
+            require(height, 1)?;
+            require_local(height - 1, *index as usize)?;
+            frame.height -= 1;
+        }
+
+        Instruction::Create | Instruction::Destroy => {
+            require(height, 1)?;
+            frame.height -= 1;
+        }
+
+        // The entity is left on the stack, so component operations can be chained
+        Instruction::AddComponent => {
+            require(height, 2)?;
+            frame.height -= 1;
+        }
+
+        Instruction::RemoveComponent { .. } => require(height, 1)?,
+
+        Instruction::Return => {
+            if frame.query.is_some() {
+                return Err("Return from inside a query".to_string());
+            }
+            if is_function_chunk {
+                require(height, 1)?;
+            }
+            return Ok(vec![]);
+        }
+
+        Instruction::Jump(target) => return Ok(vec![(target.0 as usize, frames)]),
+
+        Instruction::JumpIfTrue(target) | Instruction::JumpIfFalse(target) => {
+            require(height, 1)?;
+            frame.height -= 1;
+            return Ok(vec![(target.0 as usize, frames.clone()), (next, frames)]);
+        }
+
+        Instruction::ContextQuery {
+            end,
+            local_index,
+            has_parent,
+            include_components,
+            optional_components,
+            ..
+        } => {
+            // The parent of the children to match is popped first
+            let parent_count = *has_parent as usize;
+            require(height, parent_count)?;
+            frame.height -= parent_count;
+            // The bindings are stored as locals of the enclosing frame, so they cannot start above its stack
+            let local_index = *local_index as usize;
+            require(frame.height, local_index)?;
+            // Included markers are not bound, while optional components always are
+            let component_count = optional_components.len()
+                + include_components
+                    .iter()
+                    .filter(|id| !component(file, **id).unwrap().fields.is_empty())
+                    .count();
+            // Without results the query jumps straight to its end, otherwise it pushes a query frame that shares
+            // the stack of the enclosing frame from the bindings and down
+            let end = end.0 as usize;
+            let mut query_frames = frames.clone();
+            query_frames.push(Frame {
+                height: local_index,
+                query: Some(Query {
+                    end,
+                    local_index,
+                    component_count,
+                }),
+            });
+            return Ok(vec![(end, frames), (next, query_frames)]);
+        }
+
+        Instruction::SetNextComponentColumnOrJump => {
+            let Some(query) = frame.query else {
+                return Err("Query iteration outside of a query".to_string());
+            };
+            // Either the stack is truncated to the bindings, which are then pushed, or the query frame is popped
+            // leaving the last value of the query body behind in place of the bindings
+            frame.height = query.local_index + 1 + query.component_count;
+            let mut done_frames = frames.clone();
+            done_frames.pop();
+            done_frames.last_mut().unwrap().height = query.local_index + 1;
+            return Ok(vec![(next, frames), (query.end, done_frames)]);
+        }
+
+        Instruction::Label(_) | Instruction::Line(_) => unreachable!(),
+    }
+
+    Ok(vec![(next, frames)])
+}
+
+fn require(height: usize, count: usize) -> Result<(), String> {
+    if height < count {
+        return Err(format!(
+            "Stack underflow: {count} values are needed but the stack height is {height}"
+        ));
+    }
+    Ok(())
+}
+
+fn require_local(height: usize, index: usize) -> Result<(), String> {
+    if index >= height {
+        return Err(format!(
+            "Local {index} is outside of the stack frame (stack height is {height})"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bytecode_file::FORMAT_VERSION;
+
+    // A query that reads and assigns fields of a nested component
+    const PROGRAM: &str = "\
+Debug Info: included
+Component ID: 0 (name: Position)
+x, type id: 1, size: 4
+y, type id: 1, size: 4
+Component ID: 1 (name: Transform)
+pos, type id: 7, size: 8, type parameter: 0
+scale, type id: 2, size: 4
+label, type id: 3, size: 4
+=== function chunk: main ===
+push_integer (value: 1) (line: 1)
+push_integer (value: 2)
+push_component (id: 0, properties: 2)
+push_float (value: 3)
+push_string (value: 'a')
+push_component (id: 1, properties: 3)
+push_list (length: 1)
+create entity
+query components: +include 1 (Transform) ? - > ~ (local index: 0, parent: 0, end: L0) (line: 2)
+L1:
+set_next_component_column_or_jump
+get_field_value (index: 1, component: 1, field_path: 0.1) (line: 3)
+set_field_value (index: 1, component: 1, field_path: 0.0)
+jump (to: L1)
+L0:
+ret
+";
+
+    fn verify_listing(listing: &str) -> Result<(), String> {
+        let bytes = assemble(&format!("Format Version: {FORMAT_VERSION}\n{listing}"))?;
+        verify_bytes(&bytes)
+    }
+
+    fn verify_bytes(bytes: &[u8]) -> Result<(), String> {
+        BytecodeFile::from_bytes(bytes).and_then(|file| verify(&file))
+    }
+
+    #[test]
+    fn accepts_valid_program() {
+        assert_eq!(verify_listing(PROGRAM), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unknown_field_type() {
+        let listing = PROGRAM.replace("label, type id: 3,", "label, type id: 9,");
+        assert!(
+            verify_listing(&listing)
+                .unwrap_err()
+                .contains("Unknown field type 9")
+        );
+    }
+
+    #[test]
+    fn rejects_primitive_field_of_wrong_size() {
+        for (from, to) in [
+            ("x, type id: 1, size: 4", "x, type id: 1, size: 2"),
+            ("x, type id: 1, size: 4", "x, type id: 0, size: 4"),
+            ("scale, type id: 2, size: 4", "scale, type id: 5, size: 4"),
+        ] {
+            let listing = PROGRAM.replace(from, to);
+            assert!(
+                verify_listing(&listing).unwrap_err().contains("has size"),
+                "{to}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_field_path_out_of_bounds() {
+        for (from, to) in [
+            ("field_path: 0.1)", "field_path: 3)"),
+            ("field_path: 0.1)", "field_path: 0.2)"),
+            ("field_path: 0.0)", "field_path: 0.0.0)"),
+        ] {
+            let listing = PROGRAM.replace(from, to);
+            assert!(verify_listing(&listing).is_err(), "{to}");
+        }
+    }
+
+    #[test]
+    fn rejects_field_path_through_non_component() {
+        let listing = PROGRAM.replace("field_path: 0.1)", "field_path: 1.0)");
+        assert!(
+            verify_listing(&listing)
+                .unwrap_err()
+                .contains("not a component")
+        );
+    }
+
+    #[test]
+    fn rejects_field_path_of_unknown_component() {
+        let listing = PROGRAM.replace(
+            "component: 1, field_path: 0.1",
+            "component: 2, field_path: 0",
+        );
+        assert!(
+            verify_listing(&listing)
+                .unwrap_err()
+                .contains("Unknown component 2")
+        );
+    }
+
+    // A jump over an instruction of several bytes, whose operand is patched by the tests
+    const JUMP: &str = "\
+=== function chunk: main ===
+jump (to: L0)
+L0:
+push_integer (value: 1)
+ret
+";
+
+    fn verify_patched(listing: &str, patch: impl FnOnce(&mut Vec<u8>)) -> Result<(), String> {
+        let bytes = assemble(&format!("Format Version: {FORMAT_VERSION}\n{listing}"))?;
+        let mut file = BytecodeFile::from_bytes(&bytes)?;
+        patch(&mut file.code);
+        verify(&file)
+    }
+
+    // The jump follows the function chunk marker, and its offset is relative to the push at byte 6
+    fn patch_jump_offset(offset: i32) -> impl FnOnce(&mut Vec<u8>) {
+        move |code| code[2..6].copy_from_slice(&offset.to_be_bytes())
+    }
+
+    #[test]
+    fn rejects_jump_into_instruction() {
+        assert_eq!(
+            verify_patched(JUMP, patch_jump_offset(1)),
+            Err(
+                "Jump to byte 7 is outside of the chunk or not at an instruction (at byte 1)"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_jump_outside_of_chunk() {
+        assert!(
+            verify_patched(JUMP, patch_jump_offset(100))
+                .unwrap_err()
+                .starts_with("Jump to byte 106 is outside of the chunk")
+        );
+        let listing = format!(
+            "function signature (name: f) (local count: 0) (function position: L1)\n{JUMP}L1:\n=== function chunk: f ===\nret\n"
+        );
+        assert!(
+            verify_patched(&listing, patch_jump_offset(6))
+                .unwrap_err()
+                .starts_with("Jump to byte 12 is outside of the chunk")
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_opcode() {
+        assert_eq!(
+            verify_patched(JUMP, |code| code[6] = 0xff),
+            Err("Invalid instruction 255 (at byte 6)".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_stack_underflow() {
+        let listing = "=== function chunk: main ===\npush_integer (value: 1)\neq\nret\n";
+        assert_eq!(
+            verify_listing(listing),
+            Err(
+                "Stack underflow: 2 values are needed but the stack height is 1 (at byte 6)"
+                    .to_string()
+            )
+        );
+    }
+
+    // The lowest stack height of the paths is used after they merge, so the value that only one path pushed
+    // cannot be popped
+    #[test]
+    fn rejects_merged_paths_of_different_stack_heights() {
+        let listing = "\
+=== function chunk: main ===
+push_true
+jump if false (to: L0)
+push_integer (value: 1)
+L0:
+push_integer (value: 2)
+eq
+ret
+";
+        assert_eq!(
+            verify_listing(listing),
+            Err(
+                "Stack underflow: 2 values are needed but the stack height is 1 (at byte 17)"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_return_inside_query() {
+        let listing = PROGRAM.replace(
+            "set_field_value (index: 1, component: 1, field_path: 0.0)",
+            "ret",
+        );
+        assert_eq!(
+            verify_listing(&listing),
+            Err("Return from inside a query (at byte 47)".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_function_that_does_not_point_to_function_chunk() {
+        let listing = "\
+function signature (name: f) (local count: 0) (function position: L0)
+=== function chunk: main ===
+push_integer (value: 1)
+L0:
+ret
+";
+        assert_eq!(
+            verify_listing(listing),
+            Err("Function 'f' does not point to the start of a function chunk".to_string())
+        );
+    }
+
+    // Verifying corrupted files must fail with an error rather than panic
+    #[test]
+    fn survives_corrupted_bytes() {
+        let bytes = assemble(&format!("Format Version: {FORMAT_VERSION}\n{PROGRAM}")).unwrap();
+        for position in 0..bytes.len() {
+            for value in [0, 1, 2, 7, 9, 0x7f, 0x80, 0xff, bytes[position] ^ 1] {
+                let mut corrupted = bytes.clone();
+                corrupted[position] = value;
+                let _ = verify_bytes(&corrupted);
+            }
+            let _ = verify_bytes(&bytes[..position]);
+        }
+    }
+}
@@ -1,15 +1,25 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Display;
 
 use crate::ExecutionMetadata;
+use crate::arithmetic;
+use crate::bytecode_file::{BytecodeFile, DebugInfo, HandlerEntry};
 use crate::bytecodes::ByteCode;
 use crate::program::Context;
 use crate::program::PersistentData;
+use crate::verifier;
 
+use elements::COMPONENT_TYPE_ID;
+use elements::ComponentId;
 use elements::ComponentLayout;
 use elements::Entity;
-use elements::FieldLayout;
+use elements::LIST_TYPE_ID;
+use elements::STRING_TYPE_ID;
+use elements::TAG_TYPE_ID;
+use elements::Tick;
+use elements::world::ChangeFilter;
 use elements::world::QueryResult;
+use elements::world::World;
 
 // TODO(anissen): See https://github.com/brightly-salty/rox/blob/master/src/value.rs
 #[derive(Debug, Clone, PartialEq)]
@@ -17,13 +27,15 @@ pub enum Value {
     True,
     False,
     Integer(i32),
+    Integer64(i64),
     Float(f32),
+    Float64(f64),
     String(String),
     SimpleTag(String),
     Tag(String, Box<Value>),
     List(Vec<Value>),
-    Function(u8),
-    Component { id: u8, properties: Vec<Value> },
+    Function(u32),
+    Component { id: u32, properties: Vec<Value> },
 }
 
 impl Display for Value {
@@ -32,7 +44,9 @@ impl Display for Value {
             Value::True => write!(f, "true")?,
             Value::False => write!(f, "false")?,
             Value::Integer(i) => write!(f, "{i}")?,
+            Value::Integer64(i) => write!(f, "{i}")?,
             Value::Float(d) => write!(f, "{d:.2}")?,
+            Value::Float64(d) => write!(f, "{d:.2}")?,
             Value::String(s) => write!(f, "{s}")?,
             Value::SimpleTag(t) => write!(f, "{t}")?,
             Value::Tag(t, a) => write!(f, "{t}({a})")?,
@@ -62,6 +76,28 @@ impl Display for Value {
     }
 }
 
+// An error that stops the program, e.g. a division by zero. The source line of the instruction is only known when
+// the bytecode includes debug info.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub position: usize, // of the instruction in the code
+    pub line: Option<u32>,
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "Line {line}: Runtime error: {}", self.message),
+            None => write!(
+                f,
+                "Runtime error: {} (at byte {})",
+                self.message, self.position
+            ),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct FunctionObj {
     name: String,
@@ -71,16 +107,44 @@ struct FunctionObj {
 #[derive(Debug)]
 struct CallFrame {
     return_program_counter: usize,
-    stack_index: u8,
+    stack_index: usize,
+    is_handler: bool, // called by the VM, so the result is discarded
+}
+
+// A handler to call with the entity and the component of an observed event
+struct PendingHandler {
+    function: u32,
+    entity: Value,
+    component: Value,
+}
+
+// The execution state of a query. Queries can be nested, so the VM keeps a stack of them.
+struct QueryState {
+    result: QueryResult,
+    active_entity: Option<Entity>,
+    end_program_counter: usize,
+    stack_start: usize, // the entity and its components are stored from here, as locals of the enclosing frame
+    binding_count: usize,
+}
+
+// A component added to or removed from an existing entity
+enum ComponentChange {
+    Add { entity: Entity, component: Value },
+    Remove { entity: Entity, id: u32 },
 }
 
 pub struct VirtualMachine {
     program: Vec<u8>,
     program_counter: usize,
-    main_chunk_program_counter: usize,
     functions: Vec<FunctionObj>,
+    foreign_functions: Vec<u32>, // the host context index of each foreign function of the bytecode
+    constants: Vec<String>,
+    debug_info: Option<DebugInfo>, // only used for verbose output
     stack: Vec<Value>,
     call_stack: Vec<CallFrame>,
+    query_runs: HashMap<usize, Tick>, // the last run of each query with added or changed components, by position
+    handlers: Vec<HandlerEntry>,
+    pending_handlers: VecDeque<PendingHandler>, // called one at a time, in the order of the events
     verbose: bool,
     pub metadata: ExecutionMetadata,
 }
@@ -91,89 +155,126 @@ pub fn run<'a>(
     context: &'a Context<'a>,
     data: &mut PersistentData,
     verbose: bool,
-) -> Option<Value> {
-    VirtualMachine::new(bytes, data, verbose).execute(function_name, context, data)
+) -> Result<Option<Value>, String> {
+    VirtualMachine::new(bytes, data, context, verbose)?
+        .execute(function_name, context, data)
+        .map_err(|error| error.to_string())
 }
 
 static EMPTY_VALUE: Value = Value::False; // Only used when a function returns no result
 
 impl VirtualMachine {
-    pub fn new(bytes: Vec<u8>, data: &mut PersistentData, verbose: bool) -> Self {
+    pub fn new(
+        bytes: Vec<u8>,
+        data: &mut PersistentData,
+        context: &Context,
+        verbose: bool,
+    ) -> Result<Self, String> {
         let mut vm = Self {
-            program: bytes,
+            program: Vec::new(),
             program_counter: 0,
             functions: Vec::new(),
+            foreign_functions: Vec::new(),
+            constants: Vec::new(),
+            debug_info: None,
             stack: Vec::new(),
             call_stack: Vec::new(),
+            query_runs: HashMap::new(),
+            handlers: Vec::new(),
+            pending_handlers: VecDeque::new(),
             verbose,
-            main_chunk_program_counter: 0,
             metadata: ExecutionMetadata::default(),
         };
-        vm.read_header(data);
-        vm
+        vm.load(&bytes, data, context)?;
+        Ok(vm)
     }
 
-    pub fn update_bytecode(&mut self, bytes: Vec<u8>, data: &mut PersistentData) {
-        self.program = bytes;
+    pub fn update_bytecode(
+        &mut self,
+        bytes: Vec<u8>,
+        data: &mut PersistentData,
+        context: &Context,
+    ) -> Result<(), String> {
         self.functions.clear();
         self.stack.clear();
         self.call_stack.clear();
-        self.read_header(data);
+        self.query_runs.clear();
+        self.pending_handlers.clear();
+        self.load(&bytes, data, context)
     }
 
-    fn read_header(&mut self, data: &mut PersistentData) {
-        // TODO(anissen): Read bytecode header here
-
-        self.read_component_data(data);
-        self.read_functions();
-
-        self.main_chunk_program_counter = self.program_counter;
-    }
-
-    fn read_component_data(&mut self, data: &mut PersistentData) {
-        // TODO(anissen): Check that the new components matches the old
-
-        let component_count = self.read_byte();
-        for _ in 0..component_count {
-            let id = self.read_byte();
-            let field_count = self.read_byte();
-            let mut fields = Vec::with_capacity(field_count as usize);
-            for _ in 0..field_count {
-                let name = self.read_string();
-                let type_id = self.read_byte();
-                let size = self.read_u16();
-                fields.push(FieldLayout {
-                    name,
-                    type_id,
-                    size,
-                });
+    fn load(
+        &mut self,
+        bytes: &[u8],
+        data: &mut PersistentData,
+        context: &Context,
+    ) -> Result<(), String> {
+        let file = BytecodeFile::from_bytes(bytes)?;
+        verifier::verify(&file)?;
+
+        // The host must provide every foreign function that the bytecode calls
+        let mut foreign_functions = Vec::new();
+        for foreign in &file.foreign_functions {
+            let index = context.get_index(&foreign.name).ok_or_else(|| {
+                format!(
+                    "Foreign function '{}' is not provided by the host",
+                    foreign.name
+                )
+            })?;
+            let arity = context.get_arity(index);
+            if arity != foreign.arity {
+                return Err(format!(
+                    "Foreign function '{}' is called with {} arguments but the host expects {arity}",
+                    foreign.name, foreign.arity
+                ));
             }
-
-            data.elements
-                .world
-                .register_component(id as u32, ComponentLayout::new(fields));
+            foreign_functions.push(index);
         }
-    }
+        self.foreign_functions = foreign_functions;
 
-    fn get_next_bytecode(&mut self) -> Result<ByteCode, ()> {
-        if self.program_counter < self.program.len() {
-            ByteCode::try_from(self.read_byte())
-        } else {
-            Err(())
+        // TODO(anissen): Check that the new components matches the old
+        for mut component in file.components {
+            // Component and field names are only known in debug builds
+            let names = file
+                .debug_info
+                .as_ref()
+                .and_then(|debug_info| debug_info.component(component.id));
+            if let Some(names) = names {
+                for (field, name) in component.fields.iter_mut().zip(&names.field_names) {
+                    field.name = name.clone();
+                }
+            }
+            let layout = ComponentLayout::new(component.fields)
+                .with_name(names.map_or("", |names| names.name.as_str()));
+            match component.is_child_of {
+                true => data.elements.world.register_child_of(component.id, layout),
+                false => data.elements.world.register_component(component.id, layout),
+            }
         }
-    }
 
-    fn read_functions(&mut self) {
-        while let Ok(ByteCode::FunctionSignature) = self.get_next_bytecode() {
-            let name = self.read_string();
-            let _local_count = self.read_byte();
-            let function_position = self.read_i16();
-
-            self.functions.push(FunctionObj {
-                name,
-                ip: function_position as u32,
-            });
+        // The world only records the events that the handlers are interested in
+        let world = &mut data.elements.world;
+        world.clear_observers();
+        for handler in &file.handlers {
+            world.observe(handler.event, handler.component);
         }
+        self.handlers = file.handlers;
+
+        data.declare_resources(file.resources);
+
+        self.functions = file
+            .functions
+            .into_iter()
+            .map(|function| FunctionObj {
+                name: function.name,
+                ip: function.position,
+            })
+            .collect();
+
+        self.constants = file.constants;
+        self.debug_info = file.debug_info;
+        self.program = file.code;
+        Ok(())
     }
 
     pub fn execute(
@@ -181,20 +282,16 @@ impl VirtualMachine {
         function: Option<(String, Vec<Value>)>,
         context: &Context,
         data: &mut PersistentData,
-    ) -> Option<Value> {
-        self.program_counter = self.main_chunk_program_counter;
-
-        if self.program_counter >= self.program.len() {
-            return None;
+    ) -> Result<Option<Value>, RuntimeError> {
+        if self.program.is_empty() {
+            return Ok(None);
         }
 
-        let main_start = self.program_counter - 1;
-
         // Construct an initial call frame for the top-level code.
         self.program_counter = self.program.len(); // Set return IP to EOF.
 
         if let Some((function_name, args)) = function {
-            let arity = args.len() as u8;
+            let arity = args.len();
             self.stack = args;
             let function_to_execute = self
                 .functions
@@ -207,27 +304,33 @@ impl VirtualMachine {
             self.call(
                 FunctionObj {
                     name: "<main>".to_string(),
-                    ip: main_start as u32,
+                    ip: 0,
                 },
                 0,
             );
         }
 
-        let mut query_results: Option<QueryResult> = None;
-        let mut active_entity = None;
+        let mut queries: Vec<QueryState> = Vec::new();
+        let mut component_changes_asap = Vec::new();
         let mut create_components_asap = Vec::new();
         let mut destroy_entities_asap = Vec::new();
 
         while self.program_counter < self.program.len() {
+            let pc = self.program_counter;
             let next = self.read_byte();
             let instruction = ByteCode::try_from(next).unwrap();
             self.metadata.instructions_executed += 1;
             if self.verbose {
-                println!(
-                    "\n=== Instruction: {:?} === (pc: {})",
-                    instruction,
-                    self.program_counter - 1
-                );
+                match self
+                    .debug_info
+                    .as_ref()
+                    .and_then(|debug_info| debug_info.line(pc as u32))
+                {
+                    Some(line) => {
+                        println!("\n=== Instruction: {instruction:?} === (pc: {pc}, line: {line})")
+                    }
+                    None => println!("\n=== Instruction: {instruction:?} === (pc: {pc})"),
+                }
                 println!("Stack: {:?}", self.stack);
             }
             match instruction {
@@ -245,24 +348,34 @@ impl VirtualMachine {
                     self.push_float(value);
                 }
 
+                ByteCode::PushInteger64 => {
+                    let value = i64::from_be_bytes(self.read_8bytes());
+                    self.push_value(Value::Integer64(value));
+                }
+
+                ByteCode::PushFloat64 => {
+                    let value = f64::from_be_bytes(self.read_8bytes());
+                    self.push_value(Value::Float64(value));
+                }
+
                 ByteCode::PushString => {
-                    let string = self.read_string();
+                    let string = self.read_constant();
                     self.push_string(string);
                 }
 
                 ByteCode::PushSimpleTag => {
-                    let name = self.read_string();
+                    let name = self.read_constant();
                     self.push_simple_tag(name);
                 }
 
                 ByteCode::PushTag => {
-                    let name = self.read_string();
+                    let name = self.read_constant();
                     let value = self.pop_any();
                     self.push_tag(name, value);
                 }
 
                 ByteCode::PushList => {
-                    let length = self.read_i32();
+                    let length = self.read_varint();
 
                     let mut list = Vec::new();
                     for _ in 0..length {
@@ -272,9 +385,9 @@ impl VirtualMachine {
                 }
 
                 ByteCode::PushComponent => {
-                    let component_id = self.read_byte();
-                    let property_count = self.read_byte();
-                    let properties = self.pop_many(property_count);
+                    let component_id = self.read_varint();
+                    let property_count = self.read_varint();
+                    let properties = self.pop_many(property_count as usize);
                     self.push_component(component_id, properties);
                 }
 
@@ -295,125 +408,34 @@ impl VirtualMachine {
                     }
                 }
 
-                ByteCode::IntegerAddition => {
-                    let right = self.pop_integer();
-                    let left = self.pop_integer();
-                    self.push_integer(left + right);
-                }
-
-                ByteCode::IntegerSubtraction => {
-                    let right = self.pop_integer();
-                    let left = self.pop_integer();
-                    self.push_integer(left - right);
-                }
-
-                ByteCode::IntegerMultiplication => {
-                    let right = self.pop_integer();
-                    let left = self.pop_integer();
-                    self.push_integer(left * right);
-                }
-
-                ByteCode::IntegerDivision => {
-                    let right = self.pop_integer();
-                    let left = self.pop_integer();
-                    if right != 0 {
-                        self.push_integer(left / right);
-                    } else {
-                        self.push_integer(0);
-                    }
-                }
-
-                ByteCode::IntegerModulo => {
-                    let modulus = self.pop_integer();
-                    let value = self.pop_integer();
-                    self.push_integer(value % modulus);
-                }
-
-                ByteCode::IntegerLessThan => {
-                    let right = self.pop_integer();
-                    let left = self.pop_integer();
-                    self.push_boolean(left < right);
-                }
-
-                ByteCode::IntegerLessThanEquals => {
-                    let right = self.pop_integer();
-                    let left = self.pop_integer();
-                    self.push_boolean(left <= right);
-                }
-
-                ByteCode::FloatAddition => {
-                    let right = self.pop_float();
-                    let left = self.pop_float();
-                    self.push_float(left + right);
-                }
-
-                ByteCode::FloatSubtraction => {
-                    let right = self.pop_float();
-                    let left = self.pop_float();
-                    self.push_float(left - right);
-                }
-
-                ByteCode::FloatMultiplication => {
-                    let right = self.pop_float();
-                    let left = self.pop_float();
-                    self.push_float(left * right);
-                }
-
-                ByteCode::FloatDivision => {
-                    let right = self.pop_float();
-                    let left = self.pop_float();
-                    if right != 0.0 {
-                        self.push_float(left / right);
-                    } else {
-                        self.push_float(0.0);
-                    }
-                }
-
-                ByteCode::FloatModulo => {
-                    let modulus = self.pop_float();
-                    let value = self.pop_float();
-                    self.push_float(value % modulus);
-                }
-
-                ByteCode::FloatLessThan => {
-                    let right = self.pop_float();
-                    let left = self.pop_float();
-                    self.push_boolean(left < right);
-                }
-
-                ByteCode::FloatLessThanEquals => {
-                    let right = self.pop_float();
-                    let left = self.pop_float();
-                    self.push_boolean(left <= right);
-                }
-
-                ByteCode::StringConcat => {
-                    let right = self.pop_any();
-                    let left = self.pop_string();
-                    self.push_string(self.string_concat_values(left, right));
-                }
-
-                ByteCode::BooleanAnd => {
-                    let right = self.pop_boolean();
-                    let left = self.pop_boolean();
-                    self.push_boolean(left && right)
-                }
-
-                ByteCode::BooleanOr => {
-                    let right = self.pop_boolean();
-                    let left = self.pop_boolean();
-                    self.push_boolean(left || right)
-                }
-
-                ByteCode::Equals => {
-                    let right = self.pop_any();
-                    let left = self.pop_any();
-                    self.push_boolean(left == right)
-                }
+                ByteCode::IntegerAddition
+                | ByteCode::IntegerSubtraction
+                | ByteCode::IntegerMultiplication
+                | ByteCode::IntegerDivision
+                | ByteCode::IntegerModulo
+                | ByteCode::IntegerLessThan
+                | ByteCode::IntegerLessThanEquals
+                | ByteCode::FloatAddition
+                | ByteCode::FloatSubtraction
+                | ByteCode::FloatMultiplication
+                | ByteCode::FloatDivision
+                | ByteCode::FloatModulo
+                | ByteCode::FloatLessThan
+                | ByteCode::FloatLessThanEquals
+                | ByteCode::StringConcat
+                | ByteCode::BooleanAnd
+                | ByteCode::BooleanOr
+                | ByteCode::Equals => self
+                    .binary_operation(instruction)
+                    .map_err(|message| self.runtime_error(message, pc))?,
 
                 ByteCode::Negation => {
-                    let value = self.pop_float();
-                    self.push_float(-value);
+                    let value = match self.pop_any() {
+                        Value::Float(value) => Value::Float(-value),
+                        Value::Float64(value) => Value::Float64(-value),
+                        _ => panic!("expected float, encountered some other type"),
+                    };
+                    self.push_value(value);
                 }
 
                 ByteCode::Not => {
@@ -422,33 +444,53 @@ impl VirtualMachine {
                 }
 
                 ByteCode::GetLocalValue => {
-                    let index = self.read_byte();
+                    let index = self.read_varint() as usize;
+                    let stack_index = self.current_call_frame().stack_index;
+                    let value = self
+                        .stack
+                        .get(stack_index + index)
+                        .unwrap_or_else(|| {
+                            panic!(
+                                "Stack underflow: tried to access index {} but stack size is {}",
+                                stack_index + index,
+                                self.stack.len()
+                            )
+                        })
+                        .clone();
+                    self.push_value(value);
+                }
+
+                ByteCode::GetLocalValueAndOperate => {
+                    let index = self.read_varint() as usize;
+                    let operation = ByteCode::try_from(self.read_byte()).unwrap();
                     let stack_index = self.current_call_frame().stack_index;
                     let value = self
                         .stack
-                        .get((stack_index + index) as usize)
+                        .get(stack_index + index)
                         .unwrap_or_else(|| {
                             panic!(
                                 "Stack underflow: tried to access index {} but stack size is {}",
-                                (stack_index + index) as usize,
+                                stack_index + index,
                                 self.stack.len()
                             )
                         })
                         .clone();
                     self.push_value(value);
+                    self.binary_operation(operation)
+                        .map_err(|message| self.runtime_error(message, pc))?;
                 }
 
                 ByteCode::GetForeignValue => {
-                    let name = self.read_string();
+                    let name = self.read_constant();
                     let value = context.get_value(&name);
 
                     self.push_value(value);
                 }
 
                 ByteCode::SetLocalValue => {
-                    let index = self.read_byte();
+                    let index = self.read_varint() as usize;
                     let stack_index = self.current_call_frame().stack_index;
-                    let actual_index = (stack_index + index) as usize;
+                    let actual_index = stack_index + index;
 
                     let stack_top_index = self.stack.len() - 1;
 
@@ -470,82 +512,88 @@ impl VirtualMachine {
                 }
 
                 ByteCode::GetFieldValue => {
-                    let index = self.read_byte();
-                    let field_index = self.read_byte();
+                    let index = self.read_varint() as usize;
+                    let component = self.read_varint();
+                    let field_path = self.read_field_path();
                     let stack_index = self.current_call_frame().stack_index;
                     // println!(
-                    //     "Index: {}, field_index: {}, stack_index: {}",
-                    //     index, field_index, stack_index
+                    //     "Index: {}, field_path: {:?}, stack_index: {}",
+                    //     index, field_path, stack_index
                     // );
-                    let object = self
-                        .stack
-                        .get((stack_index + index) as usize)
-                        .unwrap_or_else(|| {
-                            panic!(
-                                "Stack underflow: tried to access index {} but stack size is {}",
-                                (stack_index + index) as usize,
-                                self.stack.len()
-                            )
-                        });
-                    let value = match object {
-                        Value::Component { id: _, properties } => {
-                            properties[field_index as usize].clone()
-                        }
-                        _ => {
-                            dbg!(&self.stack);
-                            panic!("Trying to get field value from non-object")
-                        }
-                    };
-                    self.push_value(value);
+                    let object = self.stack.get(stack_index + index).unwrap_or_else(|| {
+                        panic!(
+                            "Stack underflow: tried to access index {} but stack size is {}",
+                            stack_index + index,
+                            self.stack.len()
+                        )
+                    });
+                    // The verifier has checked the path against the layout of the component
+                    if !matches!(object, Value::Component { id, .. } if *id == component) {
+                        panic!("Trying to get field value from non-object");
+                    }
+                    let value =
+                        field_path
+                            .iter()
+                            .fold(object, |object, field_index| match object {
+                                Value::Component { id: _, properties } => &properties[*field_index],
+                                _ => panic!("Trying to get field value from non-object"),
+                            });
+                    self.push_value(value.clone());
                 }
 
                 ByteCode::SetFieldValue => {
-                    let index = self.read_byte();
-                    let field_index = self.read_byte();
+                    let index = self.read_varint() as usize;
+                    let component = self.read_varint();
+                    let field_path = self.read_field_path();
                     let stack_index = self.current_call_frame().stack_index;
                     let stack_size = self.stack.len();
                     let new_value = self.pop_any();
-                    let object = self
-                        .stack
-                        .get_mut((stack_index + index) as usize)
-                        .unwrap_or_else(|| {
-                            panic!(
-                                "Stack underflow: tried to access index {} but stack size is {}",
-                                (stack_index + index) as usize,
-                                stack_size
-                            )
-                        });
+                    let object = self.stack.get_mut(stack_index + index).unwrap_or_else(|| {
+                        panic!(
+                            "Stack underflow: tried to access index {} but stack size is {}",
+                            stack_index + index,
+                            stack_size
+                        )
+                    });
                     match object {
-                        Value::Component { id, properties } => {
-                            // Update the value on stack
-                            properties[field_index as usize] = new_value;
-
-                            // Update the world representation
-                            if let Some(ref mut query) = query_results {
-                                // Find the column for this component in the active query
-                                if let Some(column) =
-                                    query.columns.iter_mut().find(|c| c.id == *id as u32)
-                                {
-                                    let bytes = get_bytes_from_values(properties, &column.layout);
-                                    column.insert(active_entity.unwrap(), &bytes);
-                                }
-                            } else {
-                                panic!("Trying to update component value without active query");
+                        Value::Component { id, properties } if *id == component => {
+                            // Update the value on stack, in the nested component that the path leads to
+                            let (field_index, nested_path) = field_path.split_last().unwrap();
+                            let mut fields = &mut *properties;
+                            for nested_index in nested_path {
+                                fields = match &mut fields[*nested_index] {
+                                    Value::Component { id: _, properties } => properties,
+                                    _ => panic!("Trying to set field value of non-object"),
+                                };
                             }
+                            fields[*field_index] = new_value;
+
+                            // Update the world representation for the entity of the query that bound the component
+                            let slot = stack_index + index;
+                            let Some(query) = queries.iter().rev().find(|query| {
+                                slot > query.stack_start
+                                    && slot < query.stack_start + query.binding_count
+                            }) else {
+                                panic!("Trying to update component value without active query");
+                            };
+                            let world = &mut data.elements.world;
+                            let bytes = get_bytes_from_values(properties, *id, world);
+                            // A `ChildOf` with a parent that is no longer alive is ignored, like stale handles
+                            _ = world.insert(*id, query.active_entity.unwrap(), &bytes);
                         }
                         _ => panic!("Trying to get field value from non-object"),
                     };
                 }
 
                 ByteCode::GetContextValue => {
-                    let name = self.read_string();
+                    let name = self.read_constant();
 
                     let value = get_context_value(&data.world_context, name);
                     self.push_value(value);
                 }
 
                 ByteCode::SetContextValue => {
-                    let name = self.read_string();
+                    let name = self.read_constant();
 
                     set_context_value(&mut data.world_context, name, self.peek_top().clone());
                     // TODO(anissen): Is peek top correct here?
@@ -575,20 +623,21 @@ impl VirtualMachine {
                     println!("Log: {value}");
                 }
 
-                ByteCode::FunctionSignature => {
-                    panic!("FunctionSignature: this shouldn't happen")
-                }
-
                 ByteCode::FunctionChunk => {
-                    let name = self.read_string();
                     if self.verbose {
+                        let pc = self.program_counter as u32 - 1;
+                        let name = self
+                            .functions
+                            .iter()
+                            .find(|function| function.ip == pc)
+                            .map_or("main", |function| &function.name);
                         println!("FunctionChunk: {name}");
                     }
                 }
 
                 ByteCode::Function => {
-                    let function_index = self.read_byte();
-                    self.read_byte(); // arity
+                    let function_index = self.read_varint();
+                    self.read_varint(); // arity
 
                     self.push_value(Value::Function(function_index));
                 }
@@ -598,12 +647,18 @@ impl VirtualMachine {
                 }
 
                 ByteCode::Call => {
-                    let arity = self.read_byte();
+                    let pc = self.program_counter as u32 - 1;
+                    let arity = self.read_varint() as usize;
                     let is_global = self.read_byte() == 1;
-                    let index = self.read_byte(); // TODO(anissen): This seems off
-                    let name = self.read_string();
+                    let index = self.read_varint(); // TODO(anissen): This seems off
                     if self.verbose {
-                        println!("function name: {name}");
+                        if let Some(name) = self
+                            .debug_info
+                            .as_ref()
+                            .and_then(|debug_info| debug_info.call_names.get(&pc))
+                        {
+                            println!("function name: {name}");
+                        }
                         println!("is_global: {is_global}");
                         println!("arity: {arity}");
                         println!("index: {index}");
@@ -619,23 +674,23 @@ impl VirtualMachine {
                 }
 
                 ByteCode::CallForeign => {
-                    let _foreign_index = self.read_byte();
-                    let arity = self.read_byte();
-                    let name = self.read_string();
+                    let foreign_index = self.read_varint() as usize;
+                    let arity = self.read_varint() as usize;
 
                     let function_stack = self.pop_many(arity);
-                    let result = context.call_function(&name, &function_stack); // TODO(anissen): Should use index instead
+                    let result = context
+                        .call_function(self.foreign_functions[foreign_index], &function_stack);
 
                     self.push_value(result);
                 }
 
                 ByteCode::Jump => {
-                    let offset = self.read_i16();
+                    let offset = self.read_i32();
                     self.jump_offset(offset);
                 }
 
                 ByteCode::JumpIfTrue => {
-                    let offset = self.read_i16();
+                    let offset = self.read_i32();
 
                     let condition = self.pop_boolean();
                     if condition {
@@ -644,7 +699,7 @@ impl VirtualMachine {
                 }
 
                 ByteCode::JumpIfFalse => {
-                    let offset = self.read_i16();
+                    let offset = self.read_i32();
 
                     let condition = self.pop_boolean();
                     if !condition {
@@ -653,113 +708,170 @@ impl VirtualMachine {
                 }
 
                 ByteCode::ContextQuery => {
-                    if query_results.is_some() {
-                        println!("*** No noes, nested queries are not yet supported! ***");
-                        panic!("Nested queries are not yet supported!");
-                    }
-
-                    let jump_offset = self.read_i16();
+                    let jump_offset = self.read_i32();
                     let pc = self.program_counter;
-                    let include_component_count = self.read_byte();
-                    let exclude_component_count = self.read_byte();
+                    let local_index = self.read_varint() as usize;
+                    let has_parent = self.read_byte() == 1;
+                    let include_component_count = self.read_varint();
+                    let optional_component_count = self.read_varint();
+                    let exclude_component_count = self.read_varint();
+                    let added_component_count = self.read_varint();
+                    let changed_component_count = self.read_varint();
 
-                    // collect all component ids and names for printing
                     let mut include_component_ids = Vec::new();
                     for _ in 0..include_component_count {
-                        let component_id = self.read_byte();
-                        include_component_ids.push(component_id as u32);
-                        let _component_name = self.read_string();
+                        let component_id = self.read_varint();
+                        include_component_ids.push(component_id);
+                    }
+
+                    let mut optional_component_ids = Vec::new();
+                    for _ in 0..optional_component_count {
+                        let component_id = self.read_varint();
+                        optional_component_ids.push(component_id);
                     }
 
                     let mut exclude_component_ids = Vec::new();
                     for _ in 0..exclude_component_count {
-                        let component_id = self.read_byte();
-                        exclude_component_ids.push(component_id as u32);
-                        let _component_name = self.read_string();
+                        let component_id = self.read_varint();
+                        exclude_component_ids.push(component_id);
                     }
 
-                    // TODO(anissen): Alternatively, create a structure to encapsulate a query-execution-state, allowing component scope to be expressed for the borrow checker
-                    let end_pc = get_jump_offset(pc, jump_offset);
-
-                    // Drop any previous query_entities to release the mutable borrow
-                    query_results = None;
+                    let mut added_component_ids = Vec::new();
+                    for _ in 0..added_component_count {
+                        let component_id = self.read_varint();
+                        added_component_ids.push(component_id);
+                    }
 
-                    // Get the mutable query iterator
-                    let query_iter = data
-                        .elements
-                        .world
-                        .query(&include_component_ids, &exclude_component_ids);
+                    let mut changed_component_ids = Vec::new();
+                    for _ in 0..changed_component_count {
+                        let component_id = self.read_varint();
+                        changed_component_ids.push(component_id);
+                    }
 
-                    // Check if there are any results by checking if columns are empty
-                    // If there are columns, there should be results
-                    let has_results = !query_iter.columns.is_empty();
+                    let end_pc = get_jump_offset(pc, jump_offset);
+                    let parent = has_parent.then(|| Entity::from_bits(self.pop_integer() as u32));
+
+                    // The matching entities of the query are cached by the world, keyed by its included and
+                    // excluded components
+                    let mut result =
+                        if added_component_ids.is_empty() && changed_component_ids.is_empty() {
+                            let world = &mut data.elements.world;
+                            let query = world
+                                .register_query(&include_component_ids, &exclude_component_ids);
+                            world.query_registered(query, &optional_component_ids)
+                        } else {
+                            let (result, this_run) = data.elements.world.query_changes(
+                                &include_component_ids,
+                                &optional_component_ids,
+                                &exclude_component_ids,
+                                ChangeFilter {
+                                    added: &added_component_ids,
+                                    changed: &changed_component_ids,
+                                    last_run: self.query_runs.get(&pc).copied().unwrap_or_default(),
+                                },
+                            );
+                            self.query_runs.insert(pc, this_run);
+                            result
+                        };
+                    if let Some(parent) = parent {
+                        let world = &data.elements.world;
+                        result.retain(|entity| world.parent(*entity) == Some(parent));
+                    }
 
-                    if has_results {
-                        query_results = Some(query_iter);
-                        self.push_query_frame(end_pc);
-                    } else {
+                    if result.is_empty() {
                         self.jump(end_pc);
+                    } else {
+                        queries.push(QueryState {
+                            binding_count: 1
+                                + result.components.len()
+                                + result.optional_components.len(),
+                            result,
+                            active_entity: None,
+                            end_program_counter: end_pc,
+                            stack_start: self.current_call_frame().stack_index + local_index,
+                        });
                     }
                 }
 
                 ByteCode::SetNextComponentColumnOrJump => {
-                    if let Some(ref mut result) = query_results
-                        && let Some(entity) = result.next()
-                    {
-                        active_entity = Some(entity); // TODO(anissen): This is a hack
-                        let stack_start = self.current_call_frame().stack_index;
-                        let is_first_query_result = self.stack.len() as u8 == stack_start;
-                        let components = result.columns.iter().map(|column| {
-                            let component_id = column.id as u8;
-                            let data = column.get(entity).unwrap();
-                            let values = get_value_from_bytes(data, &column.layout);
+                    let Some(query) = queries.last_mut() else {
+                        panic!("Query iteration outside of a query");
+                    };
+                    if let Some(entity) = query.result.next() {
+                        query.active_entity = Some(entity);
+                        let world = &data.elements.world;
+                        let components = query.result.components.iter().map(|id| {
+                            let layout = world.get_component_layout(*id).unwrap();
+                            let data = world.get(*id, entity).unwrap();
 
                             Value::Component {
-                                id: component_id,
-                                properties: values,
+                                id: *id,
+                                properties: get_value_from_bytes(data, layout, world),
                             }
                         });
-
-                        if is_first_query_result {
-                            // Push components on the stack
-                            self.push_integer(entity as i32);
-                            components.for_each(|component| self.push_value(component));
-                        } else {
-                            // Replace components on the stack
-                            self.stack[stack_start as usize] = Value::Integer(entity as i32);
-                            components.enumerate().for_each(|(index, component)| {
-                                self.stack[stack_start as usize + 1 + index] = component;
+                        // Absent optional components are pushed as `:none`, present markers as `:some`
+                        let optional_components =
+                            query.result.optional_components.iter().map(|id| {
+                                let layout = world.get_component_layout(*id).unwrap();
+                                match world.get(*id, entity) {
+                                    Some(_) if layout.size == 0 => {
+                                        Value::SimpleTag(":some".to_string())
+                                    }
+                                    Some(data) => Value::Component {
+                                        id: *id,
+                                        properties: get_value_from_bytes(data, layout, world),
+                                    },
+                                    None => Value::SimpleTag(":none".to_string()),
+                                }
                             });
+                        let bindings = std::iter::once(Value::Integer(entity.to_bits() as i32))
+                            .chain(components)
+                            .chain(optional_components)
+                            .collect::<Vec<_>>();
+
+                        // Replace the bindings (and whatever the previous iteration left behind) on the stack
+                        self.stack.truncate(query.stack_start);
+                        bindings
+                            .into_iter()
+                            .for_each(|binding| self.push_value(binding));
+                    } else {
+                        // The query is done, leaving the last value of its body behind
+                        let query = queries.pop().unwrap();
+                        let result = self.stack.pop().unwrap_or(EMPTY_VALUE.clone());
+                        self.stack.truncate(query.stack_start);
+                        self.push_value(result);
+                        self.program_counter = query.end_program_counter;
+
+                        // Entities are changed, created and destroyed when the outermost query is done. The
+                        // changes go first, as they may be for entities that are destroyed by the query.
+                        if queries.is_empty() {
+                            component_changes_asap
+                                .drain(..)
+                                .for_each(|change| change_component(data, change));
+
+                            destroy_entities(data, &destroy_entities_asap);
+                            destroy_entities_asap.clear();
+
+                            create_components_asap
+                                .iter()
+                                .for_each(|components: &Vec<Value>| {
+                                    create_entity(data, components)
+                                });
+                            create_components_asap.clear();
+
+                            self.run_handlers(data);
                         }
-                    } else if query_results.is_some() {
-                        // No query is active
-                        query_results = None;
-                        active_entity = None;
-                        self.pop_query_frame();
-
-                        destroy_entities_asap
-                            .iter()
-                            .for_each(|entity| destroy_entity(data, *entity));
-                        destroy_entities_asap.clear();
-
-                        create_components_asap
-                            .iter()
-                            .for_each(|components: &Vec<Value>| create_entity(data, components));
-                        create_components_asap.clear();
                     }
                 }
 
                 ByteCode::Create => {
                     let components = self.pop_list();
-                    match query_results {
-                        Some(_) => {
-                            // Create the entity when the query goes out of scope
-                            create_components_asap.push(components)
-                        }
-                        None => {
-                            query_results = None; // Redundant but helps the borrow checker
-                            create_entity(data, &components);
-                        }
+                    if queries.is_empty() {
+                        create_entity(data, &components);
+                        self.run_handlers(data);
+                    } else {
+                        // Create the entity when the outermost query goes out of scope
+                        create_components_asap.push(components)
                     }
 
                     // self.push_integer(entity as i32);
@@ -772,18 +884,51 @@ impl VirtualMachine {
                     //     }
                     //     panic!("Expected a component")
                     // };
-                    let entity = self.pop_integer() as Entity;
-                    match query_results {
-                        Some(_) => {
-                            // Destroy the entity when the query goes out of scope
-                            destroy_entities_asap.push(entity)
-                        }
-                        None => {
-                            println!("destroy the entity immediately");
-                            dbg!(&entity);
-                            query_results = None; // Redundant but helps the borrow checker
-                            destroy_entity(data, entity);
-                        }
+                    let entity = Entity::from_bits(self.pop_integer() as u32);
+                    if queries.is_empty() {
+                        destroy_entities(data, &[entity]);
+                        self.run_handlers(data);
+                    } else if !destroy_entities_asap.contains(&entity) {
+                        // Destroy the entity when the outermost query goes out of scope
+                        destroy_entities_asap.push(entity)
+                    }
+                }
+
+                ByteCode::AddComponent => {
+                    let component = self.pop_any();
+                    let entity = self.pop_integer();
+                    let change = ComponentChange::Add {
+                        entity: Entity::from_bits(entity as u32),
+                        component,
+                    };
+                    if queries.is_empty() {
+                        change_component(data, change);
+                    } else {
+                        // Add the component when the outermost query goes out of scope
+                        component_changes_asap.push(change);
+                    }
+                    self.push_integer(entity);
+                    if queries.is_empty() {
+                        self.run_handlers(data);
+                    }
+                }
+
+                ByteCode::RemoveComponent => {
+                    let id = self.read_varint();
+                    let entity = self.pop_integer();
+                    let change = ComponentChange::Remove {
+                        entity: Entity::from_bits(entity as u32),
+                        id,
+                    };
+                    if queries.is_empty() {
+                        change_component(data, change);
+                    } else {
+                        // Remove the component when the outermost query goes out of scope
+                        component_changes_asap.push(change);
+                    }
+                    self.push_integer(entity);
+                    if queries.is_empty() {
+                        self.run_handlers(data);
                     }
 
                     // self.push_integer(entity as i32);
@@ -796,29 +941,34 @@ impl VirtualMachine {
         if self.verbose {
             println!("End stack: {:?}", self.stack);
         }
-        self.stack.pop()
+        Ok(self.stack.pop())
+    }
+
+    // Stops the program at the instruction at `position`, leaving the VM ready to run again
+    fn runtime_error(&mut self, message: String, position: usize) -> RuntimeError {
+        self.stack.clear();
+        self.call_stack.clear();
+        self.pending_handlers.clear();
+        RuntimeError {
+            message,
+            position,
+            line: self
+                .debug_info
+                .as_ref()
+                .and_then(|debug_info| debug_info.line(position as u32)),
+        }
     }
 
-    fn call(&mut self, function: FunctionObj, arity: u8) {
+    fn call(&mut self, function: FunctionObj, arity: usize) {
         let ip = function.ip;
         self.call_stack.push(CallFrame {
             return_program_counter: self.program_counter,
-            stack_index: (self.stack.len() - (arity as usize)) as u8,
+            stack_index: self.stack.len() - arity,
+            is_handler: false,
         });
         self.program_counter = ip as usize;
     }
 
-    fn push_query_frame(&mut self, return_program_counter: usize) {
-        self.call_stack.push(CallFrame {
-            return_program_counter,
-            stack_index: self.stack.len() as u8,
-        });
-    }
-
-    fn pop_query_frame(&mut self) {
-        self.pop_call_frame();
-    }
-
     fn current_call_frame(&self) -> &CallFrame {
         &self.call_stack[self.call_stack.len() - 1]
     }
@@ -827,14 +977,61 @@ impl VirtualMachine {
         let result = self.stack.pop().unwrap_or(EMPTY_VALUE.clone());
 
         // Pop the stack back to the call frame's stack index
-        self.discard(self.stack.len() as u8 - self.current_call_frame().stack_index);
+        self.discard(self.stack.len() - self.current_call_frame().stack_index);
 
-        // Push the return value
-        self.push_value(result);
+        let frame = self.call_stack.pop().unwrap();
+        self.program_counter = frame.return_program_counter;
 
-        self.program_counter = self.current_call_frame().return_program_counter;
+        if frame.is_handler {
+            self.call_next_handler();
+        } else {
+            // Push the return value
+            self.push_value(result);
+        }
+    }
+
+    // Queues the handlers of the events since the last flush. Handlers run one at a time, so the next handler is
+    // only called here if no handler is running; otherwise it is called when the running handler returns.
+    fn run_handlers(&mut self, data: &mut PersistentData) {
+        let world = &mut data.elements.world;
+        if world.has_observations() {
+            let handlers = &self.handlers;
+            let pending_handlers = &mut self.pending_handlers;
+            world.drain_observations(|world, observation| {
+                let layout = world.get_component_layout(observation.component).unwrap();
+                let component = Value::Component {
+                    id: observation.component,
+                    properties: get_value_from_bytes(&observation.data, layout, world),
+                };
+                handlers
+                    .iter()
+                    .filter(|handler| {
+                        handler.event == observation.event
+                            && handler.component == observation.component
+                    })
+                    .for_each(|handler| {
+                        pending_handlers.push_back(PendingHandler {
+                            function: handler.function,
+                            entity: Value::Integer(observation.entity.to_bits() as i32),
+                            component: component.clone(),
+                        })
+                    });
+            });
+        }
+        if !self.call_stack.iter().any(|frame| frame.is_handler) {
+            self.call_next_handler();
+        }
+    }
 
-        self.call_stack.pop();
+    fn call_next_handler(&mut self) {
+        let Some(handler) = self.pending_handlers.pop_front() else {
+            return;
+        };
+        self.push_value(handler.entity);
+        self.push_value(handler.component);
+        let function = self.functions[handler.function as usize].clone();
+        self.call(function, 2);
+        self.call_stack.last_mut().unwrap().is_handler = true;
     }
 
     // TODO(anissen): All the function below should be part of the CallFrame impl instead (see https://craftinginterpreters.com/calls-and-functions.html @ "We’ll start at the top and plow through it.")
@@ -850,15 +1047,6 @@ impl VirtualMachine {
         byte
     }
 
-    fn read_2bytes(&mut self) -> [u8; 2] {
-        let value_bytes: [u8; 2] = self.program[self.program_counter..self.program_counter + 2]
-            .try_into()
-            .unwrap();
-        self.program_counter += 2;
-        self.track_bytes_read(2);
-        value_bytes
-    }
-
     fn read_4bytes(&mut self) -> [u8; 4] {
         let value_bytes: [u8; 4] = self.program[self.program_counter..self.program_counter + 4]
             .try_into()
@@ -868,14 +1056,13 @@ impl VirtualMachine {
         value_bytes
     }
 
-    fn read_i16(&mut self) -> i16 {
-        let raw = self.read_2bytes();
-        i16::from_be_bytes(raw)
-    }
-
-    fn read_u16(&mut self) -> u16 {
-        let raw = self.read_2bytes();
-        u16::from_be_bytes(raw)
+    fn read_8bytes(&mut self) -> [u8; 8] {
+        let value_bytes: [u8; 8] = self.program[self.program_counter..self.program_counter + 8]
+            .try_into()
+            .unwrap();
+        self.program_counter += 8;
+        self.track_bytes_read(8);
+        value_bytes
     }
 
     fn read_i32(&mut self) -> i32 {
@@ -883,22 +1070,33 @@ impl VirtualMachine {
         i32::from_be_bytes(raw)
     }
 
+    fn read_varint(&mut self) -> u32 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_byte();
+            value |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    // The field index in the component and in each nested component
+    fn read_field_path(&mut self) -> Vec<usize> {
+        let length = self.read_varint();
+        (0..length).map(|_| self.read_varint() as usize).collect()
+    }
+
     fn read_f32(&mut self) -> f32 {
         let raw = u32::from_be_bytes(self.read_4bytes());
         f32::from_bits(raw)
     }
 
-    fn read_string(&mut self) -> String {
-        let length = self.read_byte();
-        self.read_string_bytes(length as usize)
-    }
-
-    fn read_string_bytes(&mut self, length: usize) -> String {
-        let bytes: Vec<u8> =
-            self.program[self.program_counter..self.program_counter + length].into();
-        self.program_counter += length;
-        self.track_bytes_read(length);
-        String::from_utf8(bytes).unwrap()
+    fn read_constant(&mut self) -> String {
+        let index = self.read_varint();
+        self.constants[index as usize].clone()
     }
 
     fn jump(&mut self, pc: usize) {
@@ -906,7 +1104,7 @@ impl VirtualMachine {
         self.metadata.jumps_performed += 1;
     }
 
-    fn jump_offset(&mut self, offset: i16) {
+    fn jump_offset(&mut self, offset: i32) {
         self.jump(get_jump_offset(self.program_counter, offset));
     }
 
@@ -922,20 +1120,18 @@ impl VirtualMachine {
         self.peek(0)
     }
 
-    fn peek(&self, distance: u8) -> &Value {
-        self.stack
-            .get(self.stack.len() - 1 - distance as usize)
-            .unwrap()
+    fn peek(&self, distance: usize) -> &Value {
+        self.stack.get(self.stack.len() - 1 - distance).unwrap()
     }
 
-    fn discard(&mut self, count: u8) {
+    fn discard(&mut self, count: usize) {
         for _ in 0..count {
             self.stack.pop();
         }
     }
 
-    fn pop_many(&mut self, count: u8) -> Vec<Value> {
-        self.stack.split_off(self.stack.len() - (count as usize))
+    fn pop_many(&mut self, count: usize) -> Vec<Value> {
+        self.stack.split_off(self.stack.len() - count)
     }
 
     fn pop_any(&mut self) -> Value {
@@ -968,13 +1164,6 @@ impl VirtualMachine {
         }
     }
 
-    fn pop_float(&mut self) -> f32 {
-        match self.stack.pop().unwrap() {
-            Value::Float(f) => f,
-            _ => panic!("expected float, encountered some other type"),
-        }
-    }
-
     // Private helper method to track stack operations in metadata
     fn track_stack_push(&mut self) {
         self.metadata.stack_allocations += 1;
@@ -1012,15 +1201,180 @@ impl VirtualMachine {
         self.push_value(Value::List(list));
     }
 
-    fn push_component(&mut self, id: u8, properties: Vec<Value>) {
+    fn push_component(&mut self, id: u32, properties: Vec<Value>) {
         self.push_value(Value::Component { id, properties });
     }
 
+    fn binary_operation(&mut self, operation: ByteCode) -> Result<(), String> {
+        match operation {
+            ByteCode::IntegerAddition => self.integer_operation(
+                |left, right| Some(arithmetic::integer_addition(left, right)),
+                |left, right| Some(arithmetic::integer_addition(left, right)),
+            )?,
+
+            ByteCode::IntegerSubtraction => self.integer_operation(
+                |left, right| Some(arithmetic::integer_subtraction(left, right)),
+                |left, right| Some(arithmetic::integer_subtraction(left, right)),
+            )?,
+
+            ByteCode::IntegerMultiplication => self.integer_operation(
+                |left, right| Some(arithmetic::integer_multiplication(left, right)),
+                |left, right| Some(arithmetic::integer_multiplication(left, right)),
+            )?,
+
+            ByteCode::IntegerDivision => {
+                self.integer_operation(arithmetic::integer_division, arithmetic::integer_division)?
+            }
+
+            ByteCode::IntegerModulo => {
+                self.integer_operation(arithmetic::integer_modulo, arithmetic::integer_modulo)?
+            }
+
+            ByteCode::IntegerLessThan => {
+                self.integer_comparison(|left, right| left < right, |left, right| left < right)
+            }
+
+            ByteCode::IntegerLessThanEquals => {
+                self.integer_comparison(|left, right| left <= right, |left, right| left <= right)
+            }
+
+            ByteCode::FloatAddition => self.float_operation(
+                |left, right| Some(left + right),
+                |left, right| Some(left + right),
+            )?,
+
+            ByteCode::FloatSubtraction => self.float_operation(
+                |left, right| Some(left - right),
+                |left, right| Some(left - right),
+            )?,
+
+            ByteCode::FloatMultiplication => self.float_operation(
+                |left, right| Some(left * right),
+                |left, right| Some(left * right),
+            )?,
+
+            ByteCode::FloatDivision => {
+                self.float_operation(arithmetic::float_division, arithmetic::float_division)?
+            }
+
+            ByteCode::FloatModulo => {
+                self.float_operation(arithmetic::float_modulo, arithmetic::float_modulo)?
+            }
+
+            ByteCode::FloatLessThan => {
+                self.float_comparison(|left, right| left < right, |left, right| left < right)
+            }
+
+            ByteCode::FloatLessThanEquals => {
+                self.float_comparison(|left, right| left <= right, |left, right| left <= right)
+            }
+
+            ByteCode::StringConcat => {
+                let right = self.pop_any();
+                let left = self.pop_string();
+                self.push_string(self.string_concat_values(left, right));
+            }
+
+            ByteCode::BooleanAnd => {
+                let right = self.pop_boolean();
+                let left = self.pop_boolean();
+                self.push_boolean(left && right)
+            }
+
+            ByteCode::BooleanOr => {
+                let right = self.pop_boolean();
+                let left = self.pop_boolean();
+                self.push_boolean(left || right)
+            }
+
+            ByteCode::Equals => {
+                let right = self.pop_any();
+                let left = self.pop_any();
+                self.push_boolean(left == right)
+            }
+            _ => panic!("Unknown binary operation: {:?}", operation),
+        }
+        Ok(())
+    }
+
+    // The integer and float opcodes operate on both 32 and 64 bit values.
+    // The typer ensures that both operands have the same width.
+    // Operations without a result (i.e. division and modulo by zero) are runtime errors.
+    fn integer_operation(
+        &mut self,
+        operation: fn(i32, i32) -> Option<i32>,
+        operation64: fn(i64, i64) -> Option<i64>,
+    ) -> Result<(), String> {
+        let right = self.pop_any();
+        let left = self.pop_any();
+        let result = match (left, right) {
+            (Value::Integer(left), Value::Integer(right)) => {
+                operation(left, right).map(Value::Integer)
+            }
+            (Value::Integer64(left), Value::Integer64(right)) => {
+                operation64(left, right).map(Value::Integer64)
+            }
+            _ => panic!("expected integers of the same width, encountered some other type"),
+        };
+        self.push_value(result.ok_or("Division by zero")?);
+        Ok(())
+    }
+
+    fn integer_comparison(
+        &mut self,
+        comparison: fn(i32, i32) -> bool,
+        comparison64: fn(i64, i64) -> bool,
+    ) {
+        let right = self.pop_any();
+        let left = self.pop_any();
+        let result = match (left, right) {
+            (Value::Integer(left), Value::Integer(right)) => comparison(left, right),
+            (Value::Integer64(left), Value::Integer64(right)) => comparison64(left, right),
+            _ => panic!("expected integers of the same width, encountered some other type"),
+        };
+        self.push_boolean(result);
+    }
+
+    fn float_operation(
+        &mut self,
+        operation: fn(f32, f32) -> Option<f32>,
+        operation64: fn(f64, f64) -> Option<f64>,
+    ) -> Result<(), String> {
+        let right = self.pop_any();
+        let left = self.pop_any();
+        let result = match (left, right) {
+            (Value::Float(left), Value::Float(right)) => operation(left, right).map(Value::Float),
+            (Value::Float64(left), Value::Float64(right)) => {
+                operation64(left, right).map(Value::Float64)
+            }
+            _ => panic!("expected floats of the same width, encountered some other type"),
+        };
+        self.push_value(result.ok_or("Division by zero")?);
+        Ok(())
+    }
+
+    fn float_comparison(
+        &mut self,
+        comparison: fn(f32, f32) -> bool,
+        comparison64: fn(f64, f64) -> bool,
+    ) {
+        let right = self.pop_any();
+        let left = self.pop_any();
+        let result = match (left, right) {
+            (Value::Float(left), Value::Float(right)) => comparison(left, right),
+            (Value::Float64(left), Value::Float64(right)) => comparison64(left, right),
+            _ => panic!("expected floats of the same width, encountered some other type"),
+        };
+        self.push_boolean(result);
+    }
+
     fn string_concat_values(&self, left: String, right: Value) -> String {
         match right {
             Value::String(right) => left + &right,
             Value::Integer(right) => left + &right.to_string(),
+            Value::Integer64(right) => left + &right.to_string(),
             Value::Float(right) => left + &right.to_string(),
+            Value::Float64(right) => left + &right.to_string(),
             Value::True => left + "true",
             Value::False => left + "false",
             Value::SimpleTag(name) => left + &name,
@@ -1031,14 +1385,15 @@ impl VirtualMachine {
 }
 
 fn create_entity(data: &mut PersistentData, components: &Vec<Value>) {
-    let entity = data.elements.entity_manager.create();
+    let entity = data.elements.world.create();
 
     for component in components {
         match component {
             Value::Component { id, properties } => {
-                if let Some(layout) = data.elements.world.get_component_layout(*id as u32) {
-                    let bytes = get_bytes_from_values(properties, layout);
-                    data.elements.world.insert(*id as u32, entity, &bytes);
+                let world = &mut data.elements.world;
+                if world.get_component_layout(*id).is_some() {
+                    let bytes = get_bytes_from_values(properties, *id, world);
+                    _ = world.insert(*id, entity, &bytes);
                 }
             }
             _ => {
@@ -1049,16 +1404,44 @@ fn create_entity(data: &mut PersistentData, components: &Vec<Value>) {
     }
 }
 
-fn destroy_entity(data: &mut PersistentData, entity: Entity) {
-    data.elements.world.destroy(entity);
+// Handles of entities that are no longer alive are ignored, like in `World::destroy`, e.g. when an entity is
+// destroyed twice or a component is added to an entity that was destroyed since its handle was bound. Likewise, a
+// `ChildOf` component with a parent that is no longer alive is not added (`World::insert` fails without changes).
+// Children are destroyed along with their parent, so they may already have been destroyed when their turn comes.
+fn destroy_entities(data: &mut PersistentData, entities: &[Entity]) {
+    for entity in entities {
+        data.elements.world.destroy(*entity);
+    }
 }
 
-fn get_jump_offset(pc: usize, offset: i16) -> usize {
+fn change_component(data: &mut PersistentData, change: ComponentChange) {
+    let world = &mut data.elements.world;
+    match change {
+        ComponentChange::Add {
+            entity,
+            component: Value::Component { id, properties },
+        } => {
+            if world.is_alive(entity) {
+                let bytes = get_bytes_from_values(&properties, id, world);
+                _ = world.insert(id, entity, &bytes);
+            }
+        }
+        ComponentChange::Add { component, .. } => {
+            panic!("Expected component type, found {:?}", component)
+        }
+        ComponentChange::Remove { entity, id } => world.remove(id, entity),
+    }
+}
+
+fn get_jump_offset(pc: usize, offset: i32) -> usize {
     pc.strict_add_signed(offset as isize)
 }
 
 fn get_context_value(world_context: &HashMap<String, Value>, name: String) -> Value {
-    world_context.get(&name).unwrap().clone()
+    match world_context.get(&name) {
+        Some(value) => value.clone(),
+        None => panic!("Context value '{name}' has not been set"),
+    }
 }
 
 fn set_context_value(world_context: &mut HashMap<String, Value>, name: String, value: Value) {
@@ -1077,14 +1460,11 @@ fn read_byte(b: &[u8]) -> u8 {
     b[0]
 }
 
-fn read_string(b: &[u8]) -> String {
-    let length = b[0] as usize;
-    let bytes: Vec<u8> = b[1..length + 1].into();
-
-    String::from_utf8(bytes).unwrap()
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes(b.try_into().unwrap())
 }
 
-fn get_value_from_bytes(data: &[u8], layout: &ComponentLayout) -> Vec<Value> {
+fn get_value_from_bytes(data: &[u8], layout: &ComponentLayout, world: &World) -> Vec<Value> {
     let mut offset = 0;
     layout
         .fields
@@ -1093,36 +1473,90 @@ fn get_value_from_bytes(data: &[u8], layout: &ComponentLayout) -> Vec<Value> {
             let size = field.size as usize;
             let bytes = &data[offset..offset + size];
             offset += size;
-            match field.type_id {
-                0 => {
-                    if read_byte(bytes) != 0 {
-                        Value::True
-                    } else {
-                        Value::False
-                    }
-                }
-                1 => Value::Integer(read_i32(bytes)),
-                2 => Value::Float(read_f32(bytes)),
-                3 => Value::String(read_string(bytes)),
-                _ => panic!("unknown type id"),
-            }
+            get_value_from_field_bytes(bytes, field.type_id, field.type_parameter, world)
         })
         .collect()
 }
 
-fn get_bytes_from_values(values: &Vec<Value>, layout: &ComponentLayout) -> Vec<u8> {
+fn get_value_from_field_bytes(
+    bytes: &[u8],
+    type_id: u8,
+    type_parameter: u32,
+    world: &World,
+) -> Value {
+    match type_id {
+        0 => {
+            if read_byte(bytes) != 0 {
+                Value::True
+            } else {
+                Value::False
+            }
+        }
+        1 => Value::Integer(read_i32(bytes)),
+        2 => Value::Float(read_f32(bytes)),
+        STRING_TYPE_ID => Value::String(world.get_string(read_u32(bytes)).to_string()),
+        4 => Value::Integer64(i64::from_be_bytes(bytes.try_into().unwrap())),
+        5 => Value::Float64(f64::from_be_bytes(bytes.try_into().unwrap())),
+        TAG_TYPE_ID => Value::SimpleTag(world.get_string(read_u32(bytes)).to_string()),
+        COMPONENT_TYPE_ID => {
+            let layout = world.get_component_layout(type_parameter).unwrap();
+            Value::Component {
+                id: type_parameter,
+                properties: get_value_from_bytes(bytes, layout, world),
+            }
+        }
+        LIST_TYPE_ID => {
+            let element_type_id = type_parameter as u8;
+            let elements = world.get_list(read_u32(bytes));
+            Value::List(
+                elements
+                    .chunks(list_element_size(element_type_id))
+                    .map(|element| get_value_from_field_bytes(element, element_type_id, 0, world))
+                    .collect(),
+            )
+        }
+        _ => panic!("unknown type id"),
+    }
+}
+
+fn list_element_size(type_id: u8) -> usize {
+    match type_id {
+        0 => 1,
+        1..=3 => 4,
+        4 | 5 => 8,
+        _ => panic!("Unsupported list element type"),
+    }
+}
+
+// Strings, tags and lists are inserted in the heaps of the world, and the bytes hold their handles
+fn get_bytes_from_values(values: &[Value], id: ComponentId, world: &mut World) -> Vec<u8> {
+    let field_types = world
+        .get_component_layout(id)
+        .unwrap()
+        .fields
+        .iter()
+        .map(|field| (field.type_id, field.type_parameter))
+        .collect::<Vec<_>>();
     let mut bytes = Vec::new();
-    layout.fields.iter().enumerate().for_each(|(index, field)| {
-        let value = &values[index];
-        let value_bytes = get_bytes_from_value(value, field);
-        bytes.extend_from_slice(&value_bytes);
-    });
+    field_types
+        .iter()
+        .enumerate()
+        .for_each(|(index, (type_id, type_parameter))| {
+            let value = &values[index];
+            let value_bytes = get_bytes_from_value(value, *type_id, *type_parameter, world);
+            bytes.extend_from_slice(&value_bytes);
+        });
     bytes
 }
 
-fn get_bytes_from_value(value: &Value, field_layout: &FieldLayout) -> Vec<u8> {
+fn get_bytes_from_value(
+    value: &Value,
+    type_id: u8,
+    type_parameter: u32,
+    world: &mut World,
+) -> Vec<u8> {
     let mut bytes = Vec::new();
-    match field_layout.type_id {
+    match type_id {
         0 => match value {
             Value::True => bytes.push(1),
             Value::False => bytes.push(0),
@@ -1136,19 +1570,44 @@ fn get_bytes_from_value(value: &Value, field_layout: &FieldLayout) -> Vec<u8> {
             Value::Float(value) => bytes.extend_from_slice(&value.to_be_bytes()),
             _ => panic!("Expected float property"),
         },
-        3 => match value {
+        STRING_TYPE_ID => match value {
             Value::String(value) => {
-                if value.len() > 32 {
-                    panic!("String too long");
-                }
-                bytes.push(value.len() as u8);
-                bytes.extend_from_slice(value.as_bytes()); // TODO(anissen): What about byte order?
-                for _ in bytes.len()..33 {
-                    bytes.push(0);
-                }
+                bytes.extend_from_slice(&world.insert_string(value).to_be_bytes())
             }
             _ => panic!("Expected string property"),
         },
+        4 => match value {
+            Value::Integer64(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+            _ => panic!("Expected 64-bit integer property"),
+        },
+        5 => match value {
+            Value::Float64(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+            _ => panic!("Expected 64-bit float property"),
+        },
+        TAG_TYPE_ID => match value {
+            Value::SimpleTag(name) => {
+                bytes.extend_from_slice(&world.insert_string(name).to_be_bytes())
+            }
+            _ => panic!("Expected tag property"),
+        },
+        COMPONENT_TYPE_ID => match value {
+            Value::Component { id, properties } if *id == type_parameter => {
+                bytes.extend_from_slice(&get_bytes_from_values(properties, *id, world))
+            }
+            _ => panic!("Expected component {type_parameter} property"),
+        },
+        LIST_TYPE_ID => match value {
+            Value::List(elements) => {
+                let element_bytes = elements
+                    .iter()
+                    .flat_map(|element| {
+                        get_bytes_from_value(element, type_parameter as u8, 0, world)
+                    })
+                    .collect();
+                bytes.extend_from_slice(&world.insert_list(element_bytes).to_be_bytes())
+            }
+            _ => panic!("Expected list property"),
+        },
         _ => panic!("Unsupported type"),
     };
     bytes.to_vec()
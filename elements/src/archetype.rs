@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+
+use crate::schedule::{Access, SplitRow};
+use crate::{ComponentId, ComponentTicks, EntityIndex, Tick};
+
+// Entities with the same set of components are stored together in an archetype table, with a contiguous column per
+// component. Queries visit whole tables and iterate their rows linearly, while adding or removing a component moves
+// the entity (and the rest of its components) to the table of its new component set.
+#[derive(Debug, Default)]
+pub struct ArchetypeStorage {
+    sizes: Vec<usize>, // the size of each component, indexed by component id
+    archetypes: Vec<Archetype>,
+    index: HashMap<Vec<ComponentId>, usize>, // the archetype of each (sorted) component set
+    locations: Vec<Option<Location>>, // indexed by entity; None if the entity has no components
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    archetype: usize,
+    row: usize,
+}
+
+#[derive(Debug)]
+struct Archetype {
+    components: Vec<ComponentId>, // sorted
+    sizes: Vec<usize>,
+    columns: Vec<Vec<u8>>, // a column per component with a row per entity
+    ticks: Vec<Vec<ComponentTicks>>, // parallel to the columns
+    entities: Vec<EntityIndex>,
+}
+
+impl Archetype {
+    fn new(components: Vec<ComponentId>, sizes: Vec<usize>) -> Self {
+        let columns = components.iter().map(|_| Vec::new()).collect();
+        let ticks = components.iter().map(|_| Vec::new()).collect();
+        Self {
+            components,
+            sizes,
+            columns,
+            ticks,
+            entities: Vec::new(),
+        }
+    }
+
+    fn column_of(&self, id: ComponentId) -> Option<usize> {
+        self.components.binary_search(&id).ok()
+    }
+
+    fn has_all(&self, ids: &[ComponentId]) -> bool {
+        ids.iter().all(|id| self.column_of(*id).is_some())
+    }
+
+    fn has_any(&self, ids: &[ComponentId]) -> bool {
+        ids.iter().any(|id| self.column_of(*id).is_some())
+    }
+
+    fn get(&self, column: usize, row: usize) -> &[u8] {
+        let size = self.sizes[column];
+        &self.columns[column][row * size..(row + 1) * size]
+    }
+
+    fn get_mut(&mut self, column: usize, row: usize) -> &mut [u8] {
+        let size = self.sizes[column];
+        &mut self.columns[column][row * size..(row + 1) * size]
+    }
+
+    // Appends a row with the data and ticks of each component and returns its index
+    fn push(&mut self, entity: EntityIndex, values: &[(Vec<u8>, ComponentTicks)]) -> usize {
+        for ((column, ticks), (value, value_ticks)) in
+            self.columns.iter_mut().zip(&mut self.ticks).zip(values)
+        {
+            column.extend_from_slice(value);
+            ticks.push(*value_ticks);
+        }
+        self.entities.push(entity);
+        self.entities.len() - 1
+    }
+
+    // Removes the row by moving the last row into its place. Returns the entity that was moved, if any.
+    fn swap_remove(&mut self, row: usize) -> Option<EntityIndex> {
+        let last = self.entities.len() - 1;
+        for (column, &size) in self.columns.iter_mut().zip(&self.sizes) {
+            if size > 0 && row < last {
+                let (left, right) = column.split_at_mut(last * size);
+                left[row * size..(row + 1) * size].copy_from_slice(&right[..size]);
+            }
+            column.truncate(last * size);
+        }
+        self.ticks
+            .iter_mut()
+            .for_each(|ticks| _ = ticks.swap_remove(row));
+        self.entities.swap_remove(row);
+        (row < last).then(|| self.entities[row])
+    }
+
+    // Visits the rows of the included components, which must all be part of the archetype
+    fn for_each_row(
+        &mut self,
+        include: &[ComponentId],
+        system: &mut dyn FnMut(EntityIndex, &mut Vec<&mut [u8]>),
+    ) {
+        let mut columns: Vec<_> = self
+            .columns
+            .iter_mut()
+            .zip(&self.sizes)
+            .zip(&self.components)
+            .filter(|(_, id)| include.contains(id))
+            .map(|((column, &size), _)| (column, size))
+            .collect();
+        for (row, &entity) in self.entities.iter().enumerate() {
+            let mut values: Vec<&mut [u8]> = columns
+                .iter_mut()
+                .map(|(column, size)| &mut column[row * *size..(row + 1) * *size])
+                .collect();
+            system(entity, &mut values);
+        }
+    }
+
+    // Appends the rows of the archetype to the rows of each access that matches it. Each column is borrowed mutably by
+    // the access that writes it, or shared by the accesses that read it.
+    fn split_rows<'a>(&'a mut self, accesses: &[Access], rows: &mut [Vec<SplitRow<'a>>]) {
+        let matches: Vec<_> = accesses
+            .iter()
+            .map(|access| {
+                let include = access.include();
+                !include.is_empty() && self.has_all(&include) && !self.has_any(access.exclude)
+            })
+            .collect();
+        let len = self.entities.len();
+        let mut reads: Vec<Vec<(&Vec<u8>, usize)>> = accesses.iter().map(|_| Vec::new()).collect();
+        let mut writes: Vec<Vec<Box<dyn Iterator<Item = &mut [u8]>>>> =
+            accesses.iter().map(|_| Vec::new()).collect();
+        for ((column, &size), id) in self
+            .columns
+            .iter_mut()
+            .zip(&self.sizes)
+            .zip(&self.components)
+        {
+            let writer = (0..accesses.len())
+                .find(|&access| matches[access] && accesses[access].writes.contains(id));
+            match writer {
+                Some(writer) if size == 0 => {
+                    writes[writer].push(Box::new(std::iter::repeat_with(|| &mut [][..]).take(len)))
+                }
+                Some(writer) => writes[writer].push(Box::new(column.chunks_mut(size))),
+                None => {
+                    let column = &*column;
+                    (0..accesses.len())
+                        .filter(|&access| matches[access] && accesses[access].reads.contains(id))
+                        .for_each(|access| reads[access].push((column, size)));
+                }
+            }
+        }
+
+        for (((rows, reads), mut writes), _) in rows
+            .iter_mut()
+            .zip(reads)
+            .zip(writes)
+            .zip(matches)
+            .filter(|(_, matches)| *matches)
+        {
+            for (row, &entity) in self.entities.iter().enumerate() {
+                let reads = reads
+                    .iter()
+                    .map(|(column, size)| &column[row * size..(row + 1) * size])
+                    .collect();
+                let writes = writes.iter_mut().flat_map(|column| column.next()).collect();
+                rows.push((entity, reads, writes));
+            }
+        }
+    }
+
+    // The rows of the included components, which must all be part of the archetype
+    fn rows_mut(&mut self, include: &[ComponentId]) -> Vec<(EntityIndex, Vec<&mut [u8]>)> {
+        let len = self.entities.len();
+        let mut columns: Vec<Box<dyn Iterator<Item = &mut [u8]>>> = self
+            .columns
+            .iter_mut()
+            .zip(&self.sizes)
+            .zip(&self.components)
+            .filter(|(_, id)| include.contains(id))
+            .map(
+                |((column, &size), _)| -> Box<dyn Iterator<Item = &mut [u8]>> {
+                    if size == 0 {
+                        Box::new(std::iter::repeat_with(|| &mut [][..]).take(len)) // marker component
+                    } else {
+                        Box::new(column.chunks_mut(size))
+                    }
+                },
+            )
+            .collect();
+        self.entities
+            .iter()
+            .map(|&entity| {
+                let row = columns
+                    .iter_mut()
+                    .flat_map(|column| column.next())
+                    .collect();
+                (entity, row)
+            })
+            .collect()
+    }
+}
+
+impl ArchetypeStorage {
+    pub fn register(&mut self, id: ComponentId, size: usize) {
+        let idx = id as usize;
+        if idx < self.sizes.len() {
+            // Registering a component again drops the existing data of the component
+            let entities: Vec<_> = self
+                .archetypes
+                .iter()
+                .filter(|archetype| archetype.column_of(id).is_some())
+                .flat_map(|archetype| archetype.entities.clone())
+                .collect();
+            entities
+                .into_iter()
+                .for_each(|entity| self.remove(id, entity));
+            for archetype in &mut self.archetypes {
+                if let Some(column) = archetype.column_of(id) {
+                    archetype.sizes[column] = size;
+                }
+            }
+            self.sizes[idx] = size;
+        } else {
+            self.sizes.push(size);
+        }
+    }
+
+    fn location(&self, entity: EntityIndex) -> Option<Location> {
+        self.locations.get(entity as usize).copied().flatten()
+    }
+
+    fn set_location(&mut self, entity: EntityIndex, location: Option<Location>) {
+        let idx = entity as usize;
+        if idx >= self.locations.len() {
+            self.locations.resize(idx + 1, None);
+        }
+        self.locations[idx] = location;
+    }
+
+    fn archetype_for(&mut self, components: Vec<ComponentId>) -> usize {
+        if let Some(&archetype) = self.index.get(&components) {
+            return archetype;
+        }
+        let sizes = components
+            .iter()
+            .map(|id| self.sizes[*id as usize])
+            .collect();
+        self.archetypes
+            .push(Archetype::new(components.clone(), sizes));
+        self.index.insert(components, self.archetypes.len() - 1);
+        self.archetypes.len() - 1
+    }
+
+    // Moves the entity to the archetype of the component set, optionally adding the data of a new component
+    fn move_entity(
+        &mut self,
+        entity: EntityIndex,
+        components: Vec<ComponentId>,
+        added: Option<(ComponentId, &[u8], Tick)>,
+    ) {
+        let from = self.location(entity);
+        if components.is_empty() {
+            if let Some(from) = from {
+                self.remove_row(from);
+            }
+            self.set_location(entity, None);
+            return;
+        }
+
+        let archetype = self.archetype_for(components);
+        let values: Vec<_> = self.archetypes[archetype]
+            .components
+            .iter()
+            .map(|&id| match (added, from) {
+                (Some((added_id, data, tick)), _) if added_id == id => (
+                    data.to_vec(),
+                    ComponentTicks {
+                        added: tick,
+                        changed: tick,
+                    },
+                ),
+                (_, Some(from)) => {
+                    let old = &self.archetypes[from.archetype];
+                    let column = old.column_of(id).unwrap();
+                    (
+                        old.get(column, from.row).to_vec(),
+                        old.ticks[column][from.row],
+                    )
+                }
+                _ => unreachable!("The component data of the entity is missing"),
+            })
+            .collect();
+        let row = self.archetypes[archetype].push(entity, &values);
+
+        if let Some(from) = from {
+            self.remove_row(from);
+        }
+        self.set_location(entity, Some(Location { archetype, row }));
+    }
+
+    fn remove_row(&mut self, location: Location) {
+        if let Some(moved) = self.archetypes[location.archetype].swap_remove(location.row) {
+            self.set_location(moved, Some(location));
+        }
+    }
+
+    pub fn insert(&mut self, id: ComponentId, entity: EntityIndex, data: &[u8], tick: Tick) {
+        let location = self.location(entity);
+        let mut components = match location {
+            Some(location) => {
+                let archetype = &mut self.archetypes[location.archetype];
+                if let Some(column) = archetype.column_of(id) {
+                    // Replace component
+                    archetype.ticks[column][location.row].changed = tick;
+                    archetype
+                        .get_mut(column, location.row)
+                        .copy_from_slice(data);
+                    return;
+                }
+                archetype.components.clone()
+            }
+            None => Vec::new(),
+        };
+        components.push(id);
+        components.sort_unstable();
+        self.move_entity(entity, components, Some((id, data, tick)));
+    }
+
+    pub fn remove(&mut self, id: ComponentId, entity: EntityIndex) {
+        let Some(location) = self.location(entity) else {
+            return;
+        };
+        let archetype = &self.archetypes[location.archetype];
+        if archetype.column_of(id).is_none() {
+            return;
+        }
+        let components = archetype
+            .components
+            .iter()
+            .copied()
+            .filter(|component| *component != id)
+            .collect();
+        self.move_entity(entity, components, None);
+    }
+
+    pub fn remove_entity(&mut self, entity: EntityIndex) {
+        if let Some(location) = self.location(entity) {
+            self.remove_row(location);
+            self.set_location(entity, None);
+        }
+    }
+
+    pub fn get(&self, id: ComponentId, entity: EntityIndex) -> Option<&[u8]> {
+        let location = self.location(entity)?;
+        let archetype = &self.archetypes[location.archetype];
+        let column = archetype.column_of(id)?;
+        Some(archetype.get(column, location.row))
+    }
+
+    pub fn ticks(&self, id: ComponentId, entity: EntityIndex) -> Option<ComponentTicks> {
+        let location = self.location(entity)?;
+        let archetype = &self.archetypes[location.archetype];
+        let column = archetype.column_of(id)?;
+        Some(archetype.ticks[column][location.row])
+    }
+
+    pub fn set_changed(&mut self, id: ComponentId, entity: EntityIndex, tick: Tick) {
+        if let Some(location) = self.location(entity) {
+            let archetype = &mut self.archetypes[location.archetype];
+            if let Some(column) = archetype.column_of(id) {
+                archetype.ticks[column][location.row].changed = tick;
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self, id: ComponentId, entity: EntityIndex) -> Option<&mut [u8]> {
+        let location = self.location(entity)?;
+        let archetype = &mut self.archetypes[location.archetype];
+        let column = archetype.column_of(id)?;
+        Some(archetype.get_mut(column, location.row))
+    }
+
+    fn matching_archetypes(
+        &mut self,
+        include: &[ComponentId],
+        exclude: &[ComponentId],
+    ) -> Vec<&mut Archetype> {
+        self.archetypes
+            .iter_mut()
+            .filter(|archetype| {
+                !include.is_empty() && archetype.has_all(include) && !archetype.has_any(exclude)
+            })
+            .collect()
+    }
+
+    // The entities that have all the included components and none of the excluded ones, in ascending order
+    pub fn matching(&self, include: &[ComponentId], exclude: &[ComponentId]) -> Vec<EntityIndex> {
+        if include.is_empty() {
+            return Vec::new();
+        }
+        let mut entities: Vec<_> = self
+            .archetypes
+            .iter()
+            .filter(|archetype| archetype.has_all(include) && !archetype.has_any(exclude))
+            .flat_map(|archetype| archetype.entities.iter().copied())
+            .collect();
+        entities.sort_unstable();
+        entities
+    }
+
+    // Visits the rows archetype by archetype, so the entities are not in ascending order
+    pub fn for_each_row(
+        &mut self,
+        include: &[ComponentId],
+        exclude: &[ComponentId],
+        system: &mut dyn FnMut(EntityIndex, &mut Vec<&mut [u8]>),
+    ) {
+        for archetype in self.matching_archetypes(include, exclude) {
+            archetype.for_each_row(include, system);
+        }
+    }
+
+    pub fn rows_mut(
+        &mut self,
+        include: &[ComponentId],
+        exclude: &[ComponentId],
+    ) -> Vec<(EntityIndex, Vec<&mut [u8]>)> {
+        self.matching_archetypes(include, exclude)
+            .into_iter()
+            .flat_map(|archetype| archetype.rows_mut(include))
+            .collect()
+    }
+
+    // The rows of each access, archetype by archetype
+    pub fn split_rows(&mut self, accesses: &[Access]) -> Vec<Vec<SplitRow<'_>>> {
+        let mut rows: Vec<_> = accesses.iter().map(|_| Vec::new()).collect();
+        for archetype in &mut self.archetypes {
+            archetype.split_rows(accesses, &mut rows);
+        }
+        rows
+    }
+}
@@ -1,40 +1,31 @@
-use crate::Entity;
+use crate::EntityIndex;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct BitSet {
     words: Vec<u64>,
 }
 impl BitSet {
     pub fn new_empty(initial_capacity: usize) -> Self {
         let mut bitset = BitSet { words: Vec::new() };
-        bitset.ensure_capacity(initial_capacity as Entity);
+        bitset.ensure_capacity(initial_capacity as EntityIndex);
         bitset
     }
 
-    pub fn new_filled(initial_capacity: usize) -> Self {
-        let mut bitset = BitSet { words: Vec::new() };
-        bitset.ensure_capacity(initial_capacity as Entity);
-        for i in 0..initial_capacity {
-            bitset.set(i as Entity);
-        }
-        bitset
-    }
-
-    fn ensure_capacity(&mut self, entity: Entity) {
+    fn ensure_capacity(&mut self, entity: EntityIndex) {
         let word_index = (entity as usize) / 64;
         if word_index >= self.words.len() {
             self.words.resize(word_index + 1, 0);
         }
     }
 
-    pub fn set(&mut self, e: Entity) {
+    pub fn set(&mut self, e: EntityIndex) {
         self.ensure_capacity(e);
         let w = (e as usize) / 64;
         let b = (e as usize) % 64;
         self.words[w] |= 1u64 << b;
     }
 
-    pub fn unset(&mut self, e: Entity) {
+    pub fn unset(&mut self, e: EntityIndex) {
         let w = (e as usize) / 64;
         if w < self.words.len() {
             let b = (e as usize) % 64;
@@ -42,14 +33,14 @@ impl BitSet {
         }
     }
 
-    // pub fn contains(&self, e: Entity) -> bool {
-    //     let w = (e as usize) / 64;
-    //     if w >= self.words.len() {
-    //         return false;
-    //     }
-    //     let b = (e as usize) % 64;
-    //     (self.words[w] >> b) & 1 != 0
-    // }
+    pub fn contains(&self, e: EntityIndex) -> bool {
+        let w = (e as usize) / 64;
+        if w >= self.words.len() {
+            return false;
+        }
+        let b = (e as usize) % 64;
+        (self.words[w] >> b) & 1 != 0
+    }
 
     pub fn intersect_with(&mut self, other: &BitSet) {
         let min_words = self.words.len().min(other.words.len());
@@ -59,9 +50,9 @@ impl BitSet {
         }
     }
 
+    // Keeps the length of self, as the ids beyond the length of other are not part of other
     pub fn disjoint_with(&mut self, other: &BitSet) {
         let min_words = self.words.len().min(other.words.len());
-        self.words.resize(min_words, 0);
         for i in 0..min_words {
             self.words[i] &= !other.words[i];
         }
@@ -79,36 +70,6 @@ impl BitSet {
             cur: 0,
         }
     }
-
-    pub fn cloned_iter_ids(&self) -> ClonedBitSetIter {
-        ClonedBitSetIter {
-            words: self.words.clone(),
-            idx: 0,
-            cur: 0,
-        }
-    }
-}
-
-pub struct ClonedBitSetIter {
-    words: Vec<u64>,
-    idx: usize,
-    cur: u64,
-}
-impl<'a> Iterator for ClonedBitSetIter {
-    type Item = Entity;
-    fn next(&mut self) -> Option<Self::Item> {
-        while self.cur == 0 {
-            if self.idx >= self.words.len() {
-                return None;
-            }
-            self.cur = self.words[self.idx];
-            self.idx += 1;
-        }
-        let tz = self.cur.trailing_zeros() as usize;
-        self.cur &= !(1u64 << tz);
-        let entity = ((self.idx - 1) * 64 + tz) as Entity;
-        Some(entity)
-    }
 }
 
 pub struct BitSetIter<'a> {
@@ -117,7 +78,7 @@ pub struct BitSetIter<'a> {
     cur: u64,
 }
 impl<'a> Iterator for BitSetIter<'a> {
-    type Item = Entity;
+    type Item = EntityIndex;
     fn next(&mut self) -> Option<Self::Item> {
         while self.cur == 0 {
             if self.idx >= self.words.len() {
@@ -128,7 +89,7 @@ impl<'a> Iterator for BitSetIter<'a> {
         }
         let tz = self.cur.trailing_zeros() as usize;
         self.cur &= !(1u64 << tz);
-        let entity = ((self.idx - 1) * 64 + tz) as Entity;
+        let entity = ((self.idx - 1) * 64 + tz) as EntityIndex;
         Some(entity)
     }
 }
@@ -1,54 +1,47 @@
-use crate::ComponentId;
-use crate::ComponentLayout;
-use crate::Entity;
 use crate::bitset::BitSet;
+use crate::{ComponentTicks, EntityIndex, Tick};
 
 #[derive(Debug)]
 pub struct Column {
-    pub id: ComponentId, // TODO(anissen): Needs to be encoded in World instead
-    pub layout: ComponentLayout,
+    size: usize, // the size of the component
     dense: Vec<u8>,
-    entities: Vec<Entity>,
+    entities: Vec<EntityIndex>,
+    ticks: Vec<ComponentTicks>, // parallel to the entities
     sparse: Vec<usize>,
     pub bitset: BitSet,
 }
 
 impl Column {
-    pub fn new(
-        component_id: ComponentId,
-        layout: ComponentLayout,
-        initial_capacity: usize,
-    ) -> Self {
-        let size = layout.size;
+    pub fn new(size: usize, initial_capacity: usize) -> Self {
         Self {
-            id: component_id,
-            layout,
+            size,
             dense: vec![0; initial_capacity * size], // allow zero-size components
             entities: Vec::with_capacity(initial_capacity),
+            ticks: Vec::with_capacity(initial_capacity),
             sparse: vec![usize::MAX; initial_capacity],
             bitset: BitSet::new_empty(initial_capacity),
         }
     }
 
-    fn ensure_entity_capacity(&mut self, entity: Entity) {
+    fn ensure_entity_capacity(&mut self, entity: EntityIndex) {
         if entity as usize >= self.sparse.len() {
             self.sparse.resize(entity as usize + 1, usize::MAX); // TODO(anissen): Use a different magic constant
         }
     }
 
     fn ensure_dense_capacity(&mut self) {
-        if self.layout.size == 0 {
+        if self.size == 0 {
             return; // marker component: no storage needed
         }
 
-        let needed = (self.entities.len() + 1) * self.layout.size;
+        let needed = (self.entities.len() + 1) * self.size;
         if needed > self.dense.len() {
-            let new_capacity = (self.dense.len().max(self.layout.size) * 2).max(needed);
+            let new_capacity = (self.dense.len().max(self.size) * 2).max(needed);
             self.dense.resize(new_capacity, 0);
         }
     }
 
-    pub fn insert(&mut self, entity: Entity, value_bytes: &[u8]) {
+    pub fn insert(&mut self, entity: EntityIndex, value_bytes: &[u8], tick: Tick) {
         self.ensure_entity_capacity(entity);
 
         let idx = self.sparse[entity as usize];
@@ -58,55 +51,85 @@ impl Column {
             self.ensure_dense_capacity();
             let new_index = self.entities.len();
             self.entities.push(entity);
+            self.ticks.push(ComponentTicks {
+                added: tick,
+                changed: tick,
+            });
             self.sparse[entity as usize] = new_index;
 
-            if self.layout.size > 0 {
-                let start = new_index * self.layout.size;
-                let end = start + self.layout.size;
+            if self.size > 0 {
+                let start = new_index * self.size;
+                let end = start + self.size;
                 self.dense[start..end].copy_from_slice(value_bytes);
             }
             self.bitset.set(entity);
         } else {
             // Replace component
-            if self.layout.size > 0 {
-                let start = idx * self.layout.size;
-                let end = start + self.layout.size;
+            self.ticks[idx].changed = tick;
+            if self.size > 0 {
+                let start = idx * self.size;
+                let end = start + self.size;
                 self.dense[start..end].copy_from_slice(value_bytes);
             }
         }
     }
 
-    // pub fn has(&self, entity: Entity) -> bool {
+    // pub fn has(&self, entity: EntityIndex) -> bool {
     //     self.bitset.contains(entity)
     // }
 
-    pub fn get(&self, entity: Entity) -> Option<&[u8]> {
+    pub fn get(&self, entity: EntityIndex) -> Option<&[u8]> {
         let idx = self.sparse.get(entity as usize)?;
         if *idx == usize::MAX {
             return None;
         }
-        if self.layout.size == 0 {
+        if self.size == 0 {
             return Some(&[]);
         }
-        let start = idx * self.layout.size;
-        let end = start + self.layout.size;
+        let start = idx * self.size;
+        let end = start + self.size;
         Some(&self.dense[start..end])
     }
 
-    pub fn get_mut(&mut self, entity: Entity) -> Option<&mut [u8]> {
+    pub fn ticks(&self, entity: EntityIndex) -> Option<ComponentTicks> {
+        let idx = self.sparse.get(entity as usize)?;
+        self.ticks.get(*idx).copied()
+    }
+
+    pub fn set_changed(&mut self, entity: EntityIndex, tick: Tick) {
+        if let Some(&idx) = self.sparse.get(entity as usize)
+            && idx != usize::MAX
+        {
+            self.ticks[idx].changed = tick;
+        }
+    }
+
+    pub fn get_mut(&mut self, entity: EntityIndex) -> Option<&mut [u8]> {
         let idx = self.sparse.get(entity as usize)?;
         if *idx == usize::MAX {
             return None;
         }
-        if self.layout.size == 0 {
+        if self.size == 0 {
             return Some(&mut []);
         }
-        let start = idx * self.layout.size;
-        let end = start + self.layout.size;
+        let start = idx * self.size;
+        let end = start + self.size;
         Some(&mut self.dense[start..end])
     }
 
-    pub fn remove(&mut self, entity: Entity) -> bool {
+    // The components of the entities, which must all have the component, in the order of the entities
+    pub fn rows_mut(&mut self, entities: &[EntityIndex]) -> Vec<&mut [u8]> {
+        if self.size == 0 {
+            return entities.iter().map(|_| &mut [][..]).collect();
+        }
+        let mut rows: Vec<_> = self.dense.chunks_mut(self.size).map(Some).collect();
+        entities
+            .iter()
+            .map(|entity| rows[self.sparse[*entity as usize]].take().unwrap())
+            .collect()
+    }
+
+    pub fn remove(&mut self, entity: EntityIndex) -> bool {
         // TODO(anissen): DRY logic around entity existence check
         // TODO(anissen): It's probably faster to use bitmap.contains
         let idx = match self.sparse.get(entity as usize) {
@@ -116,18 +139,19 @@ impl Column {
 
         let last = self.entities.len() - 1;
         self.entities.swap(idx, last);
+        self.ticks.swap_remove(idx);
 
         let moved_entity = self.entities[idx];
         self.sparse[moved_entity as usize] = idx;
         self.sparse[entity as usize] = usize::MAX;
 
-        if self.layout.size > 0 && idx < last {
-            let s0 = idx * self.layout.size;
-            let s1 = last * self.layout.size;
+        if self.size > 0 && idx < last {
+            let s0 = idx * self.size;
+            let s1 = last * self.size;
 
             let (left, right) = self.dense.split_at_mut(s1);
-            let row0 = &mut left[s0..s0 + self.layout.size];
-            let row1 = &mut right[0..self.layout.size];
+            let row0 = &mut left[s0..s0 + self.size];
+            let row1 = &mut right[0..self.size];
             row0.swap_with_slice(row1);
         }
 
@@ -135,21 +159,4 @@ impl Column {
         self.entities.pop();
         true
     }
-
-    pub fn iter(&self) -> impl Iterator<Item = (Entity, &[u8])> {
-        self.entities.iter().enumerate().map(|(i, &e)| {
-            let bytes = if self.layout.size == 0 {
-                &[]
-            } else {
-                let start = i * self.layout.size;
-                let end = start + self.layout.size;
-                &self.dense[start..end]
-            };
-            (e, bytes)
-        })
-    }
-
-    pub fn iter_entities(&self) -> impl Iterator<Item = Entity> + '_ {
-        self.entities.iter().copied()
-    }
 }
@@ -0,0 +1,123 @@
+use crate::ComponentLayout;
+use crate::HeapId;
+use crate::bitset::BitSet;
+use crate::snapshot::HeapSnapshot;
+use crate::{COMPONENT_TYPE_ID, LIST_TYPE_ID, STRING_TYPE_ID, TAG_TYPE_ID};
+
+// Values that do not fit in the fixed size of components (strings and lists) live in a heap, while the components
+// themselves only store a handle to their values.
+// Handles carry no generation, so a handle that is used after its value was removed would read (or free) the value
+// that reuses the slot. Removing a value twice is always caught, and reading a removed value in debug builds.
+#[derive(Debug, Default)]
+pub struct Heap<T> {
+    values: Vec<T>,
+    free: Vec<HeapId>, // handles of removed values, reused before new handles are added
+    removed: BitSet,   // the handles in `free`
+}
+
+impl<T: Default> Heap<T> {
+    pub fn new() -> Self {
+        Self {
+            values: Vec::new(),
+            free: Vec::new(),
+            removed: BitSet::new_empty(0),
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> HeapId {
+        match self.free.pop() {
+            Some(id) => {
+                self.removed.unset(id);
+                self.values[id as usize] = value;
+                id
+            }
+            None => {
+                self.values.push(value);
+                (self.values.len() - 1) as HeapId
+            }
+        }
+    }
+
+    pub fn get(&self, id: HeapId) -> &T {
+        debug_assert!(
+            !self.removed.contains(id),
+            "Heap value {id} has been removed"
+        );
+        &self.values[id as usize]
+    }
+
+    pub fn remove(&mut self, id: HeapId) -> T {
+        assert!(
+            !self.removed.contains(id),
+            "Heap value {id} is removed twice"
+        );
+        self.removed.set(id);
+        self.free.push(id);
+        std::mem::take(&mut self.values[id as usize])
+    }
+}
+
+impl<T: Clone> Heap<T> {
+    // The handles are part of the snapshot, as the components refer to their values by handle
+    pub fn snapshot(&self) -> HeapSnapshot<T> {
+        HeapSnapshot {
+            values: self.values.clone(),
+            free: self.free.clone(),
+        }
+    }
+
+    pub fn restore(snapshot: &HeapSnapshot<T>) -> Self {
+        let mut removed = BitSet::new_empty(0);
+        snapshot.free.iter().for_each(|id| removed.set(*id));
+        Self {
+            values: snapshot.values.clone(),
+            free: snapshot.free.clone(),
+            removed,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Heaps {
+    pub strings: Heap<String>,
+    pub lists: Heap<Vec<u8>>, // the packed elements of each list
+}
+
+impl Heaps {
+    // Removes the heap values referenced by the component data, including those of its nested components
+    pub fn remove_fields(
+        &mut self,
+        layouts: &[ComponentLayout],
+        layout: &ComponentLayout,
+        data: &[u8],
+    ) {
+        let mut offset = 0;
+        for field in &layout.fields {
+            let size = field.size as usize;
+            let bytes = &data[offset..offset + size];
+            match field.type_id {
+                STRING_TYPE_ID | TAG_TYPE_ID => {
+                    self.strings.remove(read_id(bytes));
+                }
+                LIST_TYPE_ID => {
+                    let elements = self.lists.remove(read_id(bytes));
+                    if field.type_parameter == STRING_TYPE_ID as u32 {
+                        elements
+                            .chunks(size_of::<HeapId>())
+                            .for_each(|element| _ = self.strings.remove(read_id(element)));
+                    }
+                }
+                COMPONENT_TYPE_ID => {
+                    let nested = &layouts[field.type_parameter as usize];
+                    self.remove_fields(layouts, nested, bytes);
+                }
+                _ => (),
+            }
+            offset += size;
+        }
+    }
+}
+
+pub(crate) fn read_id(bytes: &[u8]) -> HeapId {
+    HeapId::from_be_bytes(bytes.try_into().unwrap())
+}
@@ -0,0 +1,40 @@
+use std::collections::HashMap;
+
+use crate::Entity;
+
+// The parent of each child and the children of each parent, as related by the `ChildOf` component. Entities are
+// stored with their generation, so the children of a destroyed parent are never mistaken for those of the next
+// entity created in its slot.
+#[derive(Debug, Default)]
+pub struct Hierarchy {
+    parents: HashMap<Entity, Entity>,
+    children: HashMap<Entity, Vec<Entity>>, // in the order they were added to the parent
+}
+
+impl Hierarchy {
+    pub fn set_parent(&mut self, child: Entity, parent: Entity) {
+        self.remove_parent(child);
+        self.parents.insert(child, parent);
+        self.children.entry(parent).or_default().push(child);
+    }
+
+    pub fn remove_parent(&mut self, child: Entity) {
+        let Some(parent) = self.parents.remove(&child) else {
+            return;
+        };
+        if let Some(children) = self.children.get_mut(&parent) {
+            children.retain(|sibling| *sibling != child);
+            if children.is_empty() {
+                self.children.remove(&parent);
+            }
+        }
+    }
+
+    pub fn parent(&self, child: Entity) -> Option<Entity> {
+        self.parents.get(&child).copied()
+    }
+
+    pub fn children(&self, parent: Entity) -> &[Entity] {
+        self.children.get(&parent).map_or(&[], Vec::as_slice)
+    }
+}
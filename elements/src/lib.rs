@@ -1,20 +1,47 @@
+mod archetype;
 mod bitset;
 mod column;
+pub mod heap;
+mod hierarchy;
+mod query_cache;
+pub mod schedule;
+pub mod snapshot;
+mod sparse_set;
 pub mod world;
 
-pub type Entity = u32;
+use bitset::BitSet;
+use snapshot::EntitySnapshot;
+
+pub type EntityIndex = u32;
 pub type ComponentId = u32;
 pub type ComponentTypeId = u32;
+pub type HeapId = u32;
+pub type Tick = u32;
 
-#[derive(Debug)]
+// When a component was added to its entity and when it was last inserted or written, in change ticks of the world
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComponentTicks {
+    pub added: Tick,
+    pub changed: Tick,
+}
+
+// The type ids of fields that refer to other data, which the world removes along with the component
+pub const STRING_TYPE_ID: u8 = 3; // a `HeapId` (4 bytes) of the string in the string heap of the world
+pub const TAG_TYPE_ID: u8 = 6; // a `HeapId` of the name of the tag in the string heap
+pub const COMPONENT_TYPE_ID: u8 = 7; // a nested component, packed in place with the layout of that component
+pub const LIST_TYPE_ID: u8 = 8; // a `HeapId` of the packed elements in the list heap of the world
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct FieldLayout {
     pub name: String, // For debugging purposes
     pub type_id: u8,
-    pub size: u16, // Needed when we already have type_id?
+    pub size: u16,           // Needed when we already have type_id?
+    pub type_parameter: u32, // the component id of nested component fields and the element type id of list fields
 }
 
 #[derive(Debug)]
 pub struct ComponentLayout {
+    pub name: String, // identifies the component in snapshots; empty if the names are not known (release builds)
     pub fields: Vec<FieldLayout>,
     pub size: usize, // TODO(anissen): Change to u16?
 }
@@ -22,12 +49,51 @@ pub struct ComponentLayout {
 impl ComponentLayout {
     pub fn new(fields: Vec<FieldLayout>) -> Self {
         let size = fields.iter().map(|f| f.size as usize).sum();
-        ComponentLayout { fields, size }
+        ComponentLayout {
+            name: String::new(),
+            fields,
+            size,
+        }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+}
+
+// An entity is the index of its slot plus the generation of that slot. Destroying an entity frees the slot for reuse
+// and bumps its generation, so that old handles to the entity are no longer alive instead of aliasing the next entity
+// created in the slot. Generations never wrap around: a slot that reaches the last generation is retired instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Entity {
+    pub index: EntityIndex,
+    pub generation: u32,
+}
+
+const INDEX_BITS: u32 = 20; // allows ~1M entities to be alive at the same time
+const INDEX_MASK: u32 = (1 << INDEX_BITS) - 1;
+const RETIRED_GENERATION: u32 = (1 << (32 - INDEX_BITS)) - 1; // the generation of slots that are never reused
+
+impl Entity {
+    // Packs the handle into a single integer, e.g. to pass it to scripts
+    pub fn to_bits(self) -> u32 {
+        (self.generation << INDEX_BITS) | self.index
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self {
+            index: bits & INDEX_MASK,
+            generation: bits >> INDEX_BITS,
+        }
     }
 }
 
+#[derive(Debug)]
 pub struct EntityManager {
-    next_id: Entity,
+    generations: Vec<u32>, // the current generation of each slot
+    alive: BitSet,
+    free: Vec<EntityIndex>, // slots of destroyed entities, reused before new slots are added (except retired slots)
 }
 
 impl Default for EntityManager {
@@ -38,12 +104,92 @@ impl Default for EntityManager {
 
 impl EntityManager {
     pub fn new() -> Self {
-        EntityManager { next_id: 0 }
+        EntityManager {
+            generations: Vec::new(),
+            alive: BitSet::new_empty(0),
+            free: Vec::new(),
+        }
     }
 
     pub fn create(&mut self) -> Entity {
-        let id = self.next_id;
-        self.next_id += 1;
-        id
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                let index = self.generations.len() as EntityIndex;
+                assert!(index <= INDEX_MASK, "Too many entities");
+                self.generations.push(0);
+                index
+            }
+        };
+        self.alive.set(index);
+        self.entity(index)
+    }
+
+    // Returns false, and does nothing, if the entity is no longer alive
+    pub fn destroy(&mut self, entity: Entity) -> bool {
+        if !self.is_alive(entity) {
+            return false;
+        }
+        let generation = &mut self.generations[entity.index as usize];
+        *generation += 1;
+        self.alive.unset(entity.index);
+        if *generation != RETIRED_GENERATION {
+            self.free.push(entity.index);
+        }
+        true
+    }
+
+    pub fn is_alive(&self, entity: Entity) -> bool {
+        self.alive.contains(entity.index)
+            && self.generations[entity.index as usize] == entity.generation
+    }
+
+    // The slots of the alive entities, in ascending order
+    pub fn alive(&self) -> impl Iterator<Item = EntityIndex> + '_ {
+        self.alive.iter_ids()
+    }
+
+    // The handle of the entity in the slot
+    pub fn entity(&self, index: EntityIndex) -> Entity {
+        Entity {
+            index,
+            generation: self.generations[index as usize],
+        }
+    }
+
+    pub fn snapshot(&self) -> EntitySnapshot {
+        EntitySnapshot {
+            generations: self.generations.clone(),
+            free: self.free.clone(),
+        }
+    }
+
+    // The slots that are neither free nor retired are alive
+    pub fn restore(snapshot: &EntitySnapshot) -> Result<Self, String> {
+        let mut alive = BitSet::new_empty(0);
+        for (index, generation) in snapshot.generations.iter().enumerate() {
+            match *generation {
+                RETIRED_GENERATION => (),
+                generation if generation > RETIRED_GENERATION => {
+                    return Err(format!(
+                        "Entity slot {index} has an invalid generation {generation}"
+                    ));
+                }
+                _ => alive.set(index as EntityIndex),
+            }
+        }
+        for &index in &snapshot.free {
+            if !alive.contains(index) {
+                return Err(format!(
+                    "Free entity slot {index} is out of bounds, retired or repeated"
+                ));
+            }
+            alive.unset(index);
+        }
+        Ok(EntityManager {
+            generations: snapshot.generations.clone(),
+            alive,
+            free: snapshot.free.clone(),
+        })
     }
 }
@@ -1,5 +1,8 @@
+use std::time::Instant;
+
 use elements::{
-    ComponentLayout, ComponentTypeId, Entity, EntityManager, FieldLayout, world::World,
+    ComponentLayout, ComponentTypeId, Entity, FieldLayout,
+    world::{StorageKind, World},
 };
 
 fn f32_bytes(x: f32) -> [u8; 4] {
@@ -17,145 +20,99 @@ fn velocity(dx: f32, dy: f32) -> Vec<u8> {
     [f32_bytes(dx), f32_bytes(dy)].concat()
 }
 
-// ------------------------------------------------------------
-// Packed component layout description
-// ------------------------------------------------------------
-
-// #[derive(Debug)]
-// pub struct FieldLayout {
-//     pub name: String,
-//     pub offset: usize,
-//     pub size: usize,
-// }
-
-// #[derive(Debug)]
-// pub struct ComponentLayout {
-//     pub size: usize,              // packed byte size
-//     pub fields: Vec<FieldLayout>, // ordered field offsets
-// }
-
-// // ------------------------------------------------------------
-// // Helpers: fixed-size types
-// // ------------------------------------------------------------
-
-// fn primitive_size(ty: &Type) -> usize {
-//     match ty {
-//         Type::Boolean => 1,
-//         Type::Integer => 4,
-//         Type::Float => 4,
-//         Type::String => 8, // VM string handle
-//         Type::Tag => 0,
-//         Type::List => 16,    // pointer + len
-//         Type::Function => 8, // function handle
-//         Type::Component => unreachable!("Handled separately"),
-//     }
-// }
-
-// // ------------------------------------------------------------
-// // Packed layout generator (recursive, no padding)
-// // ------------------------------------------------------------
-
-// pub fn layout_component(
-//     def: &ComponentDefinition,
-//     components: &HashMap<String, ComponentDefinition>,
-// ) -> ComponentLayout {
-//     let mut fields = Vec::new();
-//     let mut offset = 0usize;
-
-//     for prop in &def.properties {
-//         let size = match &prop.type_ {
-//             Type::Component => {
-//                 // nested component → compute recursively
-//                 let nested_def = components
-//                     .get(&prop.name)
-//                     .unwrap_or_else(|| panic!("Unknown nested component {}", prop.name));
-
-//                 let nested_layout = layout_component(nested_def, components);
-//                 nested_layout.size
-//             }
-//             other => primitive_size(other),
-//         };
-
-//         fields.push(FieldLayout {
-//             name: prop.name.clone(),
-//             offset,
-//             size,
-//         });
-
-//         offset += size; // packed: no padding
-//     }
-
-//     ComponentLayout {
-//         size: offset,
-//         fields,
-//     }
-// }
-
 // TODO(anissen): Look at MemorySegment + MemoryLayout from JDK for API inspiration
 
-fn main() {
-    let mut entity_manager = EntityManager::new();
-    let mut world = World::new();
-    // Position { x: f32, y: f32 }
-    let position_id: ComponentTypeId = 0;
+// The type id of f32 fields, as in the layouts of delta components
+const FLOAT_TYPE_ID: u8 = 2;
+
+// Position { x: f32, y: f32 }
+const POSITION_ID: ComponentTypeId = 0;
+// Velocity { dx: f32, dy: f32 }
+const VELOCITY_ID: ComponentTypeId = 1;
+// Dead (no data)
+const DEAD_ID: ComponentTypeId = 2;
+
+fn create_world(storage: StorageKind) -> World {
+    let mut world = World::with_storage(storage);
+    let position_id = POSITION_ID;
     world.register_component(
         position_id,
         ComponentLayout::new(vec![
             FieldLayout {
                 name: "x".to_string(),
-                type_id: 0,
+                type_id: FLOAT_TYPE_ID,
                 size: 4,
+                type_parameter: 0,
             },
             FieldLayout {
                 name: "y".to_string(),
-                type_id: 0,
+                type_id: FLOAT_TYPE_ID,
                 size: 4,
+                type_parameter: 0,
             },
-        ]),
+        ])
+        .with_name("Position"),
     );
-    // Velocity { dx: f32, dy: f32 }
-    let velocity_id: ComponentTypeId = 1;
+    let velocity_id = VELOCITY_ID;
     world.register_component(
         velocity_id,
         ComponentLayout::new(vec![
             FieldLayout {
                 name: "dx".to_string(),
-                type_id: 0,
+                type_id: FLOAT_TYPE_ID,
                 size: 4,
+                type_parameter: 0,
             },
             FieldLayout {
                 name: "dy".to_string(),
-                type_id: 0,
+                type_id: FLOAT_TYPE_ID,
                 size: 4,
+                type_parameter: 0,
             },
-        ]),
+        ])
+        .with_name("Velocity"),
     );
-    // Dead (no data)
-    let dead_id: ComponentTypeId = 2;
-    world.register_component(dead_id, ComponentLayout::new(vec![]));
+    world.register_component(DEAD_ID, ComponentLayout::new(vec![]).with_name("Dead"));
+    world
+}
+
+// Run with `cargo run --release -p elements -- bench` to compare the storages of the world
+fn main() {
+    if std::env::args().nth(1).as_deref() == Some("bench") {
+        benchmark(StorageKind::SparseSet);
+        benchmark(StorageKind::Archetype);
+        return;
+    }
+
+    let mut world = create_world(StorageKind::SparseSet);
+    let (position_id, velocity_id, dead_id) = (POSITION_ID, VELOCITY_ID, DEAD_ID);
 
     // Create a few entities
-    let e0 = entity_manager.create();
-    let e1 = entity_manager.create();
-    let e2 = entity_manager.create();
+    let e0 = world.create();
+    let e1 = world.create();
+    let e2 = world.create();
 
     // Add components
-    world.insert(position_id, e0, &position(0.01, 0.5));
-    world.insert(velocity_id, e0, &velocity(3.3, 3.3));
-    world.insert(velocity_id, e0, &velocity(1.0, 1.0));
-    world.insert(dead_id, e0, &[]);
+    world.insert(position_id, e0, &position(0.01, 0.5)).unwrap();
+    world.insert(velocity_id, e0, &velocity(3.3, 3.3)).unwrap();
+    world.insert(velocity_id, e0, &velocity(1.0, 1.0)).unwrap();
+    world.insert(dead_id, e0, &[]).unwrap();
 
-    world.insert(position_id, e1, &position(10.0, -5.0));
-    world.insert(velocity_id, e1, &velocity(-2.0, 0.5));
+    world
+        .insert(position_id, e1, &position(10.0, -5.0))
+        .unwrap();
+    world.insert(velocity_id, e1, &velocity(-2.0, 0.5)).unwrap();
 
-    world.insert(position_id, e2, &position(3.0, 3.0));
+    world.insert(position_id, e2, &position(3.0, 3.0)).unwrap();
 
-    let e3 = entity_manager.create();
-    world.insert(position_id, e3, &position(0.0, 0.0));
-    world.insert(velocity_id, e3, &velocity(-1.0, -1.0));
+    let e3 = world.create();
+    world.insert(position_id, e3, &position(0.0, 0.0)).unwrap();
+    world
+        .insert(velocity_id, e3, &velocity(-1.0, -1.0))
+        .unwrap();
 
-    let e4 = entity_manager.create();
-    world.insert(dead_id, e4, &[]);
+    let e4 = world.create();
+    world.insert(dead_id, e4, &[]).unwrap();
 
     /*
      * frame 1:
@@ -173,38 +130,34 @@ fn main() {
      *  e3: -3, -3
      */
 
-    /*
-    Benchmark for 1_000_000 iterations without println
-
-    hyperfine './target/release/elements'
-    Benchmark 1: ./target/release/elements
-      Time (mean ± σ):     435.6 ms ±   4.0 ms    [User: 431.2 ms, System: 2.9 ms]
-      Range (min … max):   431.7 ms … 442.3 ms    10 runs
-    */
     for frame in 0..3 {
         println!("--- Frame {} ---", frame);
 
         // TODO(anissen): We probably need to get the list of entities/components out, and then iterate?!?
-        world.system(
-            &vec![position_id, velocity_id],
-            &vec![dead_id],
-            movement_system,
-        );
+        world.system(&[position_id, velocity_id], &[dead_id], movement_system);
 
         world
             .iter(dead_id)
-            .for_each(|(entity, _)| println!("Oh, no! Entity {} is dead!", entity));
+            .for_each(|(entity, _)| println!("Oh, no! Entity {} is dead!", entity.index));
 
         world.remove(dead_id, e0);
 
         world.destroy(e4);
 
-        world.insert(dead_id, e1, &[]);
-        world.insert(velocity_id, e2, &velocity(1.0, 1.0));
+        world.insert(dead_id, e1, &[]).unwrap();
+        world.insert(velocity_id, e2, &velocity(1.0, 1.0)).unwrap();
     }
 }
 
 fn movement_system(entity: Entity, components: &mut Vec<&mut [u8]>) {
+    let (new_pos_x, new_pos_y, vel_x, vel_y) = move_entity(components);
+    println!(
+        "Entity {} at ({}, {}) with velocity ({}, {})",
+        entity.index, new_pos_x, new_pos_y, vel_x, vel_y
+    );
+}
+
+fn move_entity(components: &mut [&mut [u8]]) -> (f32, f32, f32, f32) {
     let (first, rest) = components.split_at_mut(1);
     let pos = &mut first[0];
     let vel = &mut rest[0];
@@ -218,17 +171,725 @@ fn movement_system(entity: Entity, components: &mut Vec<&mut [u8]>) {
     let new_pos = [f32_bytes(new_pos_x), f32_bytes(new_pos_y)].concat();
 
     pos.copy_from_slice(&new_pos);
+    (new_pos_x, new_pos_y, vel_x, vel_y)
+}
+
+// Moves a lot of entities for a number of frames, where every tenth entity is dead and every third has no velocity
+fn benchmark(storage: StorageKind) {
+    const ENTITIES: u32 = 100_000;
+    const FRAMES: u32 = 100;
+
+    let mut world = create_world(storage);
+    let start = Instant::now();
+    for i in 0..ENTITIES {
+        let entity = world.create();
+        world
+            .insert(POSITION_ID, entity, &position(i as f32, 0.0))
+            .unwrap();
+        if i % 3 != 0 {
+            world
+                .insert(VELOCITY_ID, entity, &velocity(1.0, -1.0))
+                .unwrap();
+        }
+        if i % 10 == 0 {
+            world.insert(DEAD_ID, entity, &[]).unwrap();
+        }
+    }
+    let spawned = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        world.system(&[POSITION_ID, VELOCITY_ID], &[DEAD_ID], |_, components| {
+            move_entity(components);
+        });
+    }
+    let moved = start.elapsed();
 
     println!(
-        "Entity {} at ({}, {}) with velocity ({}, {})",
-        entity, new_pos_x, new_pos_y, vel_x, vel_y
+        "{:?}: spawned {} entities in {:?}, moved them for {} frames in {:?} ({:?} per frame)",
+        storage,
+        ENTITIES,
+        spawned,
+        FRAMES,
+        moved,
+        moved / FRAMES
     );
 }
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
+    use super::*;
+    use elements::heap::Heap;
+    use elements::schedule::{Row, Schedule, System};
+    use elements::snapshot::Snapshot;
+    use elements::world::{ChangeFilter, ComponentEvent};
+    use elements::{COMPONENT_TYPE_ID, STRING_TYPE_ID};
+
+    // Runs the frames of the example, adding and removing components between the frames, and returns the positions
+    fn run_frames(storage: StorageKind) -> Vec<(u32, Vec<u8>)> {
+        let mut world = create_world(storage);
+        let entities: Vec<_> = (0..5).map(|_| world.create()).collect();
+        for (i, &entity) in entities.iter().enumerate() {
+            world
+                .insert(POSITION_ID, entity, &position(i as f32, 0.0))
+                .unwrap();
+            if i % 2 == 0 {
+                world
+                    .insert(VELOCITY_ID, entity, &velocity(1.0, i as f32))
+                    .unwrap();
+            }
+        }
+        world.insert(DEAD_ID, entities[0], &[]).unwrap();
+
+        for frame in 0..3 {
+            world.system(&[POSITION_ID, VELOCITY_ID], &[DEAD_ID], |_, components| {
+                move_entity(components);
+            });
+            world.remove(DEAD_ID, entities[0]);
+            world.insert(DEAD_ID, entities[2], &[]).unwrap();
+            world
+                .insert(VELOCITY_ID, entities[1], &velocity(-1.0, frame as f32))
+                .unwrap();
+            world.destroy(entities[3]);
+        }
+
+        let alive: Vec<_> = world
+            .query(&[POSITION_ID], &[], &[DEAD_ID])
+            .map(|entity| entity.index)
+            .collect();
+        assert_eq!(alive, vec![0, 1, 4]);
+
+        world
+            .iter(POSITION_ID)
+            .map(|(entity, data)| (entity.index, data.to_vec()))
+            .collect()
+    }
+
+    // Returns the positions that changed and the velocities that were added since the last run of each query
+    fn run_change_filters(storage: StorageKind) -> Vec<(Vec<u32>, Vec<u32>)> {
+        let mut world = create_world(storage);
+        let entities: Vec<_> = (0..3).map(|_| world.create()).collect();
+        for &entity in &entities {
+            world
+                .insert(POSITION_ID, entity, &position(0.0, 0.0))
+                .unwrap();
+        }
+        world
+            .insert(VELOCITY_ID, entities[0], &velocity(1.0, 1.0))
+            .unwrap();
+
+        let (mut changed_run, mut added_run) = (0, 0);
+        let mut frames = Vec::new();
+        for frame in 0..3 {
+            let (changed, this_run) = world.query_changes(
+                &[POSITION_ID],
+                &[],
+                &[],
+                ChangeFilter {
+                    changed: &[POSITION_ID],
+                    last_run: changed_run,
+                    ..Default::default()
+                },
+            );
+            changed_run = this_run;
+            let (added, this_run) = world.query_changes(
+                &[VELOCITY_ID],
+                &[],
+                &[],
+                ChangeFilter {
+                    added: &[VELOCITY_ID],
+                    last_run: added_run,
+                    ..Default::default()
+                },
+            );
+            added_run = this_run;
+            frames.push((
+                changed.map(|entity| entity.index).collect(),
+                added.map(|entity| entity.index).collect(),
+            ));
+
+            // Writing changes the position, while replacing the velocity does not add it again
+            world.get_mut(POSITION_ID, entities[0]);
+            world
+                .insert(VELOCITY_ID, entities[0], &velocity(2.0, 2.0))
+                .unwrap();
+            if let Some(&entity) = entities.get(frame + 1) {
+                world
+                    .insert(VELOCITY_ID, entity, &velocity(1.0, 1.0))
+                    .unwrap();
+            }
+            if frame == 1 {
+                world
+                    .insert(POSITION_ID, entities[2], &position(5.0, 5.0))
+                    .unwrap();
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn change_filters() {
+        let frames = run_change_filters(StorageKind::SparseSet);
+        assert_eq!(
+            frames,
+            vec![
+                (vec![0, 1, 2], vec![0]),
+                (vec![0], vec![1]),
+                (vec![0, 2], vec![2]),
+            ]
+        );
+        assert_eq!(frames, run_change_filters(StorageKind::Archetype));
+    }
+
+    #[test]
+    fn observers() {
+        for storage in [StorageKind::SparseSet, StorageKind::Archetype] {
+            let mut world = create_world(storage);
+            world.observe(ComponentEvent::Add, VELOCITY_ID);
+            world.observe(ComponentEvent::Remove, DEAD_ID);
+            world.observe(ComponentEvent::Destroy, POSITION_ID);
+            let entities: Vec<_> = (0..2).map(|_| world.create()).collect();
+            world
+                .insert(POSITION_ID, entities[0], &position(1.0, 2.0))
+                .unwrap();
+            world
+                .insert(VELOCITY_ID, entities[0], &velocity(1.0, 1.0))
+                .unwrap();
+            world
+                .insert(VELOCITY_ID, entities[0], &velocity(2.0, 2.0))
+                .unwrap(); // replaced, not added
+            world.insert(DEAD_ID, entities[1], &[]).unwrap();
+            world.remove(DEAD_ID, entities[1]);
+            world.destroy(entities[1]); // has no observed components left
+            world.destroy(entities[0]);
+
+            let mut observations = Vec::new();
+            world.drain_observations(|_, observation| {
+                observations.push((
+                    observation.event,
+                    observation.component,
+                    observation.entity.index,
+                    observation.data.clone(),
+                ))
+            });
+            assert_eq!(
+                observations,
+                vec![
+                    (ComponentEvent::Add, VELOCITY_ID, 0, velocity(1.0, 1.0)),
+                    (ComponentEvent::Remove, DEAD_ID, 1, vec![]),
+                    (ComponentEvent::Destroy, POSITION_ID, 0, position(1.0, 2.0)),
+                ]
+            );
+            assert!(!world.has_observations());
+        }
+    }
+
+    #[test]
+    fn observed_strings() {
+        const NAME_ID: ComponentTypeId = 3;
+        let mut world = create_world(StorageKind::SparseSet);
+        world.register_component(
+            NAME_ID,
+            ComponentLayout::new(vec![FieldLayout {
+                name: "name".to_string(),
+                type_id: STRING_TYPE_ID,
+                size: 4,
+                type_parameter: 0,
+            }]),
+        );
+        world.observe(ComponentEvent::Add, NAME_ID);
+        let entity = world.create();
+        for name in ["removed", "replaced", "current"] {
+            let name = world.insert_string(name);
+            world.insert(NAME_ID, entity, &name.to_be_bytes()).unwrap();
+            if name == 0 {
+                world.remove(NAME_ID, entity); // the next string reuses the slot unless freeing is deferred
+            }
+        }
+
+        // The strings of the removed and replaced components are freed after the observations are drained
+        let mut names = Vec::new();
+        world.drain_observations(|world, observation| {
+            let id = u32::from_be_bytes(observation.data[..].try_into().unwrap());
+            names.push(world.get_string(id).to_string());
+        });
+        assert_eq!(names, vec!["removed", "replaced"]);
+    }
+
+    #[test]
+    fn hierarchy() {
+        const CHILD_OF_ID: ComponentTypeId = 3;
+        let child_of = |parent: Entity| parent.to_bits().to_be_bytes();
+        for storage in [StorageKind::SparseSet, StorageKind::Archetype] {
+            let mut world = create_world(storage);
+            world.register_child_of(
+                CHILD_OF_ID,
+                ComponentLayout::new(vec![FieldLayout {
+                    name: "parent".to_string(),
+                    type_id: 1,
+                    size: 4,
+                    type_parameter: 0,
+                }]),
+            );
+            let [player, weapon, scope, other] = [(); 4].map(|_| world.create());
+            for entity in [player, weapon, scope, other] {
+                world
+                    .insert(POSITION_ID, entity, &position(0.0, 0.0))
+                    .unwrap();
+            }
+            world
+                .insert(CHILD_OF_ID, weapon, &child_of(player))
+                .unwrap();
+            world.insert(CHILD_OF_ID, scope, &child_of(weapon)).unwrap();
+            world.insert(CHILD_OF_ID, other, &child_of(weapon)).unwrap();
+            world.insert(CHILD_OF_ID, other, &child_of(player)).unwrap(); // moved to another parent
+            assert_eq!(world.children(player), &[weapon, other]);
+            assert_eq!(world.parent(scope), Some(weapon));
+
+            let children: Vec<_> = world
+                .query_children(player, &[POSITION_ID], &[], &[])
+                .collect();
+            assert_eq!(children, vec![weapon, other]);
+
+            world.remove(CHILD_OF_ID, other);
+            assert_eq!(world.parent(other), None);
+            assert!(world.destroy(player));
+            assert!(!world.is_alive(weapon) && !world.is_alive(scope));
+            assert!(world.is_alive(other));
+
+            // An entity cannot be made the child of an entity that is no longer alive
+            assert!(world.insert(CHILD_OF_ID, other, &child_of(player)).is_err());
+            assert_eq!(world.parent(other), None);
+            assert!(world.get(CHILD_OF_ID, other).is_none());
+        }
+    }
 
     #[test]
-    fn it_works() {}
+    fn cached_queries() {
+        for storage in [StorageKind::SparseSet, StorageKind::Archetype] {
+            let mut world = create_world(storage);
+            let moving = world.register_query(&[VELOCITY_ID, POSITION_ID], &[DEAD_ID]);
+            assert_eq!(
+                world.register_query(&[POSITION_ID, VELOCITY_ID], &[DEAD_ID]),
+                moving
+            );
+            let entities: Vec<_> = (0..4).map(|_| world.create()).collect();
+            for &entity in &entities {
+                world
+                    .insert(POSITION_ID, entity, &position(0.0, 0.0))
+                    .unwrap();
+                world
+                    .insert(VELOCITY_ID, entity, &velocity(1.0, 1.0))
+                    .unwrap();
+            }
+            world.insert(DEAD_ID, entities[1], &[]).unwrap();
+            world.remove(VELOCITY_ID, entities[2]);
+            world.destroy(entities[3]);
+            let reused = world.create();
+            world
+                .insert(POSITION_ID, reused, &position(0.0, 0.0))
+                .unwrap();
+
+            let matching = |world: &World| -> Vec<_> {
+                world
+                    .query_registered(moving, &[])
+                    .map(|entity| entity.index)
+                    .collect()
+            };
+            assert_eq!(matching(&world), vec![0]);
+            world.remove(DEAD_ID, entities[1]);
+            world
+                .insert(VELOCITY_ID, reused, &velocity(1.0, 1.0))
+                .unwrap();
+            assert_eq!(matching(&world), vec![0, 1, 3]);
+            let uncached: Vec<_> = world
+                .query(&[POSITION_ID, VELOCITY_ID], &[], &[DEAD_ID])
+                .map(|entity| entity.index)
+                .collect();
+            assert_eq!(matching(&world), uncached);
+
+            // Registering a component again drops its data
+            world.register_component(DEAD_ID, ComponentLayout::new(vec![]));
+            world.register_component(VELOCITY_ID, ComponentLayout::new(vec![]));
+            assert!(matching(&world).is_empty());
+        }
+    }
+
+    #[test]
+    fn queries_without_included_components() {
+        for storage in [StorageKind::SparseSet, StorageKind::Archetype] {
+            let mut world = create_world(storage);
+            let all = world.register_query(&[], &[]);
+            let alive = world.register_query(&[], &[DEAD_ID]);
+            let [moving, dead, destroyed] = [(); 3].map(|_| world.create());
+            world
+                .insert(VELOCITY_ID, moving, &velocity(1.0, 1.0))
+                .unwrap();
+            world.insert(DEAD_ID, dead, &[]).unwrap();
+            world.destroy(destroyed);
+            let empty = world.create();
+
+            let matching = |world: &World, query| -> Vec<_> {
+                world.query_registered(query, &[VELOCITY_ID]).collect()
+            };
+            assert_eq!(matching(&world, all), vec![moving, dead, empty]);
+            assert_eq!(matching(&world, alive), vec![moving, empty]);
+            let uncached: Vec<_> = world.query(&[], &[VELOCITY_ID], &[DEAD_ID]).collect();
+            assert_eq!(uncached, vec![moving, empty]);
+
+            let snapshot = world.snapshot();
+            let mut restored = create_world(storage);
+            let alive = restored.register_query(&[], &[DEAD_ID]);
+            restored.restore(&snapshot).unwrap();
+            assert_eq!(matching(&restored, alive), vec![moving, empty]);
+        }
+    }
+
+    fn move_row(row: &mut Row) {
+        let velocity = row.reads[0];
+        let position = &mut row.writes[0];
+        let x = read_f32(&position[0..4]) + read_f32(&velocity[0..4]);
+        let y = read_f32(&position[4..8]) + read_f32(&velocity[4..8]);
+        position.copy_from_slice(&[f32_bytes(x), f32_bytes(y)].concat());
+    }
+
+    fn slow_down(velocity: &mut [u8]) {
+        let dx = read_f32(&velocity[0..4]) * 0.5;
+        let dy = read_f32(&velocity[4..8]) * 0.5;
+        velocity.copy_from_slice(&[f32_bytes(dx), f32_bytes(dy)].concat());
+    }
+
+    // Moves the entities, counts the dead and slows the entities down for a number of frames, either with a schedule
+    // or one system at a time. Returns the positions and the number of dead entities that were counted.
+    fn run_systems(storage: StorageKind, scheduled: bool) -> (Vec<(u32, Vec<u8>)>, u32) {
+        let mut world = create_world(storage);
+        for i in 0..100 {
+            let entity = world.create();
+            world
+                .insert(POSITION_ID, entity, &position(i as f32, 0.0))
+                .unwrap();
+            if i % 3 != 0 {
+                world
+                    .insert(VELOCITY_ID, entity, &velocity(1.0, i as f32))
+                    .unwrap();
+            }
+            if i % 10 == 0 {
+                world.insert(DEAD_ID, entity, &[]).unwrap();
+            }
+        }
+
+        let mut dead = 0;
+        for _ in 0..10 {
+            if scheduled {
+                let mut schedule = Schedule::new();
+                schedule.add(System::new(
+                    &[VELOCITY_ID],
+                    &[POSITION_ID],
+                    &[DEAD_ID],
+                    move_row,
+                ));
+                schedule.add(System::new(&[DEAD_ID], &[], &[], |_| dead += 1));
+                schedule.add(System::new(&[], &[VELOCITY_ID], &[], |row| {
+                    slow_down(row.writes[0])
+                }));
+                assert_eq!(schedule.batches(), vec![vec![0, 1], vec![2]]);
+                schedule.run(&mut world);
+            } else {
+                world.system(&[POSITION_ID, VELOCITY_ID], &[DEAD_ID], |_, components| {
+                    move_entity(components);
+                });
+                world.system(&[DEAD_ID], &[], |_, _| dead += 1);
+                world.system(&[VELOCITY_ID], &[], |_, components| {
+                    slow_down(components[0])
+                });
+            }
+        }
+
+        let positions = world
+            .iter(POSITION_ID)
+            .map(|(entity, data)| (entity.index, data.to_vec()))
+            .collect();
+        (positions, dead)
+    }
+
+    #[test]
+    fn scheduled_systems() {
+        for storage in [StorageKind::SparseSet, StorageKind::Archetype] {
+            let sequential = run_systems(storage, false);
+            assert_eq!(sequential.1, 100);
+            for _ in 0..5 {
+                assert_eq!(run_systems(storage, true), sequential);
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_system() {
+        for storage in [StorageKind::SparseSet, StorageKind::Archetype] {
+            let mut sequential = create_world(storage);
+            let mut parallel = create_world(storage);
+            for world in [&mut sequential, &mut parallel] {
+                for i in 0..1000 {
+                    let entity = world.create();
+                    world
+                        .insert(POSITION_ID, entity, &position(i as f32, 0.0))
+                        .unwrap();
+                    world
+                        .insert(VELOCITY_ID, entity, &velocity(1.0, i as f32))
+                        .unwrap();
+                }
+            }
+            for _ in 0..3 {
+                sequential.system(&[POSITION_ID, VELOCITY_ID], &[DEAD_ID], |_, components| {
+                    move_entity(components);
+                });
+                parallel.par_system(&[VELOCITY_ID], &[POSITION_ID], &[DEAD_ID], move_row);
+            }
+            let positions = |world: &World| -> Vec<_> {
+                world
+                    .iter(POSITION_ID)
+                    .map(|(entity, data)| (entity.index, data.to_vec()))
+                    .collect()
+            };
+            assert_eq!(positions(&parallel), positions(&sequential));
+        }
+    }
+
+    #[test]
+    fn conflicting_systems() {
+        let mut schedule = Schedule::new();
+        schedule.add(System::new(&[], &[POSITION_ID], &[], |_| {}));
+        schedule.add(System::new(&[VELOCITY_ID], &[], &[], |_| {}));
+        schedule.add(System::new(&[POSITION_ID], &[], &[], |_| {}));
+        schedule.add(System::new(&[], &[VELOCITY_ID], &[DEAD_ID], |_| {}));
+        schedule.add(System::new(&[DEAD_ID], &[], &[], |_| {}));
+        assert_eq!(schedule.batches(), vec![vec![0, 1, 4], vec![2, 3]]);
+    }
+
+    #[test]
+    fn snapshots() {
+        const NAME_ID: ComponentTypeId = 3;
+        const CHILD_OF_ID: ComponentTypeId = 4;
+        let field = |name: &str, type_id, size| FieldLayout {
+            name: name.to_string(),
+            type_id,
+            size,
+            type_parameter: 0,
+        };
+        let name_layout = || ComponentLayout::new(vec![field("name", STRING_TYPE_ID, 4)]);
+        let child_of_layout = || ComponentLayout::new(vec![field("parent", 1, 4)]);
+
+        let mut world = create_world(StorageKind::SparseSet);
+        world.register_component(NAME_ID, name_layout().with_name("Name"));
+        world.register_child_of(CHILD_OF_ID, child_of_layout().with_name("ChildOf"));
+        let [player, removed, weapon] = [(); 3].map(|_| world.create());
+        world.destroy(removed);
+        world
+            .insert(POSITION_ID, player, &position(1.0, 2.0))
+            .unwrap();
+        world
+            .insert(VELOCITY_ID, player, &velocity(3.0, 4.0))
+            .unwrap();
+        world
+            .insert(POSITION_ID, weapon, &position(5.0, 6.0))
+            .unwrap();
+        world.insert(DEAD_ID, weapon, &[]).unwrap();
+        let name = world.insert_string("Ada \"the\" player");
+        world.insert(NAME_ID, player, &name.to_be_bytes()).unwrap();
+        world
+            .insert(CHILD_OF_ID, weapon, &player.to_bits().to_be_bytes())
+            .unwrap();
+
+        let snapshot = world.snapshot();
+        assert_eq!(
+            Snapshot::from_bytes(&snapshot.to_bytes()),
+            Ok(snapshot.clone())
+        );
+        assert_eq!(
+            Snapshot::from_text(&snapshot.to_text()),
+            Ok(snapshot.clone())
+        );
+
+        // The world is left unchanged if a component of the snapshot is not registered
+        let mut unregistered = create_world(StorageKind::SparseSet);
+        assert_eq!(
+            unregistered.restore(&snapshot),
+            Err("Component Name of the snapshot is not registered".to_string())
+        );
+        assert_eq!(unregistered.iter(POSITION_ID).count(), 0);
+
+        // A recompiled program, with other component ids and another order of the fields of a component
+        for storage in [StorageKind::SparseSet, StorageKind::Archetype] {
+            let mut restored = World::with_storage(storage);
+            restored.register_child_of(0, child_of_layout().with_name("ChildOf"));
+            restored.register_component(1, name_layout().with_name("Name"));
+            restored.register_component(
+                2,
+                ComponentLayout::new(vec![
+                    field("dy", FLOAT_TYPE_ID, 4),
+                    field("dx", FLOAT_TYPE_ID, 4),
+                ])
+                .with_name("Velocity"),
+            );
+            restored.register_component(
+                3,
+                ComponentLayout::new(vec![
+                    field("x", FLOAT_TYPE_ID, 4),
+                    field("y", FLOAT_TYPE_ID, 4),
+                ])
+                .with_name("Position"),
+            );
+            restored.register_component(4, ComponentLayout::new(vec![]).with_name("Dead"));
+            let alive = restored.register_query(&[3], &[4]);
+            restored.create();
+            restored.restore(&snapshot).unwrap();
+
+            assert!(restored.is_alive(player) && restored.is_alive(weapon));
+            assert!(!restored.is_alive(removed));
+            assert_eq!(restored.create().index, removed.index);
+            assert_eq!(restored.get(3, weapon), Some(&position(5.0, 6.0)[..]));
+            assert_eq!(restored.get(2, player), Some(&velocity(4.0, 3.0)[..]));
+            assert!(restored.get(4, weapon).is_some());
+            let name = u32::from_be_bytes(restored.get(1, player).unwrap().try_into().unwrap());
+            assert_eq!(restored.get_string(name), "Ada \"the\" player");
+            assert_eq!(restored.children(player), &[weapon]);
+            let alive: Vec<_> = restored.query_registered(alive, &[]).collect();
+            assert_eq!(alive, vec![player]);
+        }
+
+        // Fields are matched by name and type
+        let mut changed = create_world(StorageKind::SparseSet);
+        changed.register_component(NAME_ID, name_layout().with_name("Name"));
+        changed.register_child_of(CHILD_OF_ID, child_of_layout().with_name("ChildOf"));
+        changed.register_component(
+            VELOCITY_ID,
+            ComponentLayout::new(vec![
+                field("dx", FLOAT_TYPE_ID, 4),
+                field("dz", FLOAT_TYPE_ID, 4),
+            ])
+            .with_name("Velocity"),
+        );
+        assert_eq!(
+            changed.restore(&snapshot),
+            Err("Field 'dz' of component Velocity does not match the snapshot".to_string())
+        );
+    }
+
+    // A Transform { pos Position, name str } with the fields of both components in the given order
+    fn transform_world(position_fields: [&str; 2], transform_fields: [&str; 2]) -> World {
+        let field = |name: &str| {
+            let (type_id, type_parameter) = match name {
+                "pos" => (COMPONENT_TYPE_ID, POSITION_ID),
+                "name" => (STRING_TYPE_ID, 0),
+                _ => (FLOAT_TYPE_ID, 0),
+            };
+            let size = if name == "pos" { 8 } else { 4 };
+            FieldLayout {
+                name: name.to_string(),
+                type_id,
+                size,
+                type_parameter,
+            }
+        };
+        let mut world = World::new();
+        world.register_component(
+            POSITION_ID,
+            ComponentLayout::new(position_fields.map(field).to_vec()).with_name("Position"),
+        );
+        world.register_component(
+            VELOCITY_ID,
+            ComponentLayout::new(transform_fields.map(field).to_vec()).with_name("Transform"),
+        );
+        world
+    }
+
+    #[test]
+    fn snapshot_nested_components() {
+        let mut world = transform_world(["x", "y"], ["pos", "name"]);
+        let entity = world.create();
+        let name = world.insert_string("player");
+        let transform = [position(1.0, 2.0), name.to_be_bytes().to_vec()].concat();
+        world.insert(VELOCITY_ID, entity, &transform).unwrap();
+
+        let mut restored = transform_world(["y", "x"], ["name", "pos"]);
+        restored.restore(&world.snapshot()).unwrap();
+        let expected = [name.to_be_bytes().to_vec(), position(2.0, 1.0)].concat();
+        assert_eq!(restored.get(VELOCITY_ID, entity), Some(&expected[..]));
+    }
+
+    #[test]
+    fn snapshot_handles_out_of_bounds() {
+        let mut world = transform_world(["x", "y"], ["pos", "name"]);
+        let entity = world.create();
+        let name = world.insert_string("player");
+        let transform = [position(1.0, 2.0), name.to_be_bytes().to_vec()].concat();
+        world.insert(VELOCITY_ID, entity, &transform).unwrap();
+
+        let mut damaged = world.snapshot();
+        damaged.strings.values.clear();
+        let mut restored = transform_world(["x", "y"], ["pos", "name"]);
+        assert_eq!(
+            restored.restore(&damaged),
+            Err(
+                "A string or list of component Transform of the snapshot is out of bounds"
+                    .to_string()
+            )
+        );
+        assert_eq!(restored.iter(VELOCITY_ID).count(), 0);
+    }
+
+    #[test]
+    fn retired_entity_slots() {
+        let mut world = World::with_storage(StorageKind::SparseSet);
+        let first = world.create();
+        let mut entity = first;
+        let mut destroyed = 0;
+        // The slot is reused until its generation runs out, after which it is never handed out again
+        while entity.index == first.index {
+            assert!(world.destroy(entity));
+            assert!(!world.destroy(entity));
+            destroyed += 1;
+            entity = world.create();
+        }
+        assert_eq!(destroyed, 4095);
+        assert!(!world.is_alive(first));
+
+        let mut restored = World::with_storage(StorageKind::SparseSet);
+        restored.restore(&world.snapshot()).unwrap();
+        assert!(restored.is_alive(entity));
+        let retired = Entity {
+            index: first.index,
+            generation: 4095,
+        };
+        assert!(!restored.is_alive(retired));
+        assert_ne!(restored.create().index, first.index);
+    }
+
+    #[test]
+    #[should_panic(expected = "Heap value 0 is removed twice")]
+    fn heap_double_remove() {
+        let mut heap = Heap::new();
+        let id = heap.insert("value".to_string());
+        heap.remove(id);
+        heap.remove(id);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Heap value 0 has been removed")]
+    fn heap_removed_value() {
+        let mut heap = Heap::new();
+        let id = heap.insert("value".to_string());
+        heap.remove(id);
+        heap.get(id);
+    }
+
+    #[test]
+    fn storages_agree() {
+        let sparse_set = run_frames(StorageKind::SparseSet);
+        let archetype = run_frames(StorageKind::Archetype);
+        assert_eq!(sparse_set, archetype);
+        assert_eq!(sparse_set.len(), 4);
+        assert_eq!(sparse_set[0], (0, position(2.0, 0.0)));
+    }
 }
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use crate::{ComponentId, EntityIndex, bitset::BitSet};
+
+// A query registered with the world. Its matching entities are kept up to date as components are inserted and
+// removed, so running it does not have to match the entities again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QueryHandle(usize);
+
+#[derive(Debug)]
+struct CachedQuery {
+    include: Vec<ComponentId>, // sorted
+    exclude: Vec<ComponentId>, // sorted
+    matches: BitSet,
+}
+
+impl CachedQuery {
+    fn involves(&self, id: ComponentId) -> bool {
+        self.include.contains(&id) || self.exclude.contains(&id)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct QueryCache {
+    queries: Vec<CachedQuery>,
+    handles: HashMap<(Vec<ComponentId>, Vec<ComponentId>), QueryHandle>, // by include and exclude set
+}
+
+impl QueryCache {
+    // Registering the same include and exclude set again (in any order) returns the same handle
+    pub fn register(
+        &mut self,
+        include: &[ComponentId],
+        exclude: &[ComponentId],
+        matching: impl Fn(&[ComponentId], &[ComponentId]) -> Vec<EntityIndex>,
+    ) -> QueryHandle {
+        let key = (sorted(include), sorted(exclude));
+        if let Some(&handle) = self.handles.get(&key) {
+            return handle;
+        }
+        let mut matches = BitSet::new_empty(0);
+        matching(&key.0, &key.1)
+            .into_iter()
+            .for_each(|index| matches.set(index));
+        let handle = QueryHandle(self.queries.len());
+        self.queries.push(CachedQuery {
+            include: key.0.clone(),
+            exclude: key.1.clone(),
+            matches,
+        });
+        self.handles.insert(key, handle);
+        handle
+    }
+
+    pub fn include(&self, query: QueryHandle) -> &[ComponentId] {
+        &self.queries[query.0].include
+    }
+
+    // The matching entities, in ascending order
+    pub fn matches(&self, query: QueryHandle) -> impl Iterator<Item = EntityIndex> + '_ {
+        self.queries[query.0].matches.iter_ids()
+    }
+
+    // Matches the entity again in the queries of the component, after the component was inserted or removed.
+    // `has` tells whether the entity has a component.
+    pub fn update(
+        &mut self,
+        id: ComponentId,
+        entity: EntityIndex,
+        has: impl Fn(ComponentId) -> bool,
+    ) {
+        for query in self.queries.iter_mut().filter(|query| query.involves(id)) {
+            let is_match =
+                query.include.iter().all(|id| has(*id)) && !query.exclude.iter().any(|id| has(*id));
+            match is_match {
+                true => query.matches.set(entity),
+                false => query.matches.unset(entity),
+            }
+        }
+    }
+
+    // Matches a new entity, which has no components yet, in the queries without included components
+    pub fn add_entity(&mut self, entity: EntityIndex) {
+        self.queries
+            .iter_mut()
+            .filter(|query| query.include.is_empty())
+            .for_each(|query| query.matches.set(entity));
+    }
+
+    pub fn remove_entity(&mut self, entity: EntityIndex) {
+        self.queries
+            .iter_mut()
+            .for_each(|query| query.matches.unset(entity));
+    }
+
+    // Matches all the entities again in the queries of the component (or in every query if there is no component),
+    // e.g. after its data was dropped or the components of the world were replaced
+    pub fn refresh(
+        &mut self,
+        id: Option<ComponentId>,
+        matching: impl Fn(&[ComponentId], &[ComponentId]) -> Vec<EntityIndex>,
+    ) {
+        let queries = self.queries.iter_mut();
+        for query in queries.filter(|query| id.is_none_or(|id| query.involves(id))) {
+            query.matches = BitSet::new_empty(0);
+            matching(&query.include, &query.exclude)
+                .into_iter()
+                .for_each(|index| query.matches.set(index));
+        }
+    }
+}
+
+fn sorted(ids: &[ComponentId]) -> Vec<ComponentId> {
+    let mut ids = ids.to_vec();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
@@ -0,0 +1,142 @@
+use crate::{ComponentId, Entity, EntityIndex, world::World};
+
+// The components of a matching entity of a system, in ascending order of their ids (marker components are empty)
+pub struct Row<'a> {
+    pub entity: Entity,
+    pub reads: Vec<&'a [u8]>,
+    pub writes: Vec<&'a mut [u8]>,
+}
+
+// A row as split by the storages of the world
+pub(crate) type SplitRow<'a> = (EntityIndex, Vec<&'a [u8]>, Vec<&'a mut [u8]>);
+
+// The components that a system reads and writes. The system matches the entities that have all of them, and none of
+// the excluded components.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Access<'a> {
+    pub reads: &'a [ComponentId],
+    pub writes: &'a [ComponentId],
+    pub exclude: &'a [ComponentId],
+}
+
+impl Access<'_> {
+    pub fn include(&self) -> Vec<ComponentId> {
+        let mut include = [self.reads, self.writes].concat();
+        include.sort_unstable();
+        include
+    }
+
+    // Accesses conflict if one of them writes a component that the other reads or writes
+    pub fn conflicts_with(&self, other: &Access) -> bool {
+        let writes = |a: &Access, b: &Access| {
+            a.writes
+                .iter()
+                .any(|id| b.reads.contains(id) || b.writes.contains(id))
+        };
+        writes(self, other) || writes(other, self)
+    }
+}
+
+type SystemFn<'a> = Box<dyn FnMut(&mut Row) + Send + 'a>;
+
+pub struct System<'a> {
+    reads: Vec<ComponentId>,
+    writes: Vec<ComponentId>,
+    exclude: Vec<ComponentId>,
+    run: SystemFn<'a>,
+}
+
+impl<'a> System<'a> {
+    pub fn new(
+        reads: &[ComponentId],
+        writes: &[ComponentId],
+        exclude: &[ComponentId],
+        run: impl FnMut(&mut Row) + Send + 'a,
+    ) -> Self {
+        assert!(
+            !reads.iter().any(|id| writes.contains(id)),
+            "A system cannot both read and write a component"
+        );
+        let sorted = |ids: &[ComponentId]| {
+            let mut ids = ids.to_vec();
+            ids.sort_unstable();
+            ids.dedup();
+            ids
+        };
+        Self {
+            reads: sorted(reads),
+            writes: sorted(writes),
+            exclude: exclude.to_vec(),
+            run: Box::new(run),
+        }
+    }
+
+    fn access(&self) -> Access<'_> {
+        Access {
+            reads: &self.reads,
+            writes: &self.writes,
+            exclude: &self.exclude,
+        }
+    }
+}
+
+// Systems that run together. Systems that do not conflict run in parallel, on a thread each, while a system that
+// conflicts with a system added before it runs after that system, so the result is the same as running the systems
+// one at a time in the order they were added.
+#[derive(Default)]
+pub struct Schedule<'a> {
+    systems: Vec<System<'a>>,
+}
+
+impl<'a> Schedule<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, system: System<'a>) {
+        self.systems.push(system);
+    }
+
+    // The indices of the systems that run together, batch by batch. Each system is placed in the batch after the last
+    // batch with a system that it conflicts with.
+    pub fn batches(&self) -> Vec<Vec<usize>> {
+        let mut batch_of: Vec<usize> = Vec::with_capacity(self.systems.len());
+        let mut batches: Vec<Vec<usize>> = Vec::new();
+        for (index, system) in self.systems.iter().enumerate() {
+            let batch = self.systems[..index]
+                .iter()
+                .zip(&batch_of)
+                .filter(|(earlier, _)| earlier.access().conflicts_with(&system.access()))
+                .map(|(_, batch)| batch + 1)
+                .max()
+                .unwrap_or(0);
+            if batch == batches.len() {
+                batches.push(Vec::new());
+            }
+            batches[batch].push(index);
+            batch_of.push(batch);
+        }
+        batches
+    }
+
+    pub fn run(&mut self, world: &mut World) {
+        for batch in self.batches() {
+            let accesses: Vec<_> = batch
+                .iter()
+                .map(|index| self.systems[*index].access())
+                .collect();
+            let rows = world.split_rows(&accesses);
+            let systems = self
+                .systems
+                .iter_mut()
+                .enumerate()
+                .filter(|(index, _)| batch.contains(index))
+                .map(|(_, system)| system);
+            std::thread::scope(|scope| {
+                for (system, mut rows) in systems.zip(rows) {
+                    scope.spawn(move || rows.iter_mut().for_each(|row| (system.run)(row)));
+                }
+            });
+        }
+    }
+}
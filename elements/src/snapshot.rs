@@ -0,0 +1,451 @@
+use crate::{ComponentId, EntityIndex, FieldLayout, HeapId};
+
+// A snapshot of the entities of a world, the data of their components and the strings and lists that the components
+// refer to (see `World::snapshot` and `World::restore`).
+//
+// Binary format:
+//
+//   magic           4 bytes: "∆w" in UTF-8
+//   format version  u16
+//   entities        the generation of each slot, then the free slots
+//   strings         the values of the string heap, then the free handles
+//   lists           the packed elements of each list in the list heap, then the free handles
+//   components      per component: id, name, fields (name, type id, size and type parameter),
+//                   then the entity and the data of each row
+//
+// Integers are big-endian u32s (except the u8 type ids and u16 sizes of fields), and every list, string and list of
+// elements is preceded by its length as a u32.
+//
+// Text format: a line per value, with the same content as the binary format, e.g.
+//
+//   ∆ snapshot 1
+//   generations 0 0 1
+//   free 1
+//   string "Player"
+//   free_strings
+//   free_lists
+//   component 0 "Position"
+//   field "x" 2 4 0
+//   field "y" 2 4 0
+//   row 0 3f800000 40000000
+//
+// Strings are quoted and escaped, and the data of rows and lists is written in hex (a group per field of rows).
+
+pub const MAGIC: [u8; 4] = *b"\xE2\x88\x86w";
+pub const FORMAT_VERSION: u16 = 1;
+
+const TEXT_HEADER: &str = "∆ snapshot";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    pub entities: EntitySnapshot,
+    pub strings: HeapSnapshot<String>,
+    pub lists: HeapSnapshot<Vec<u8>>,
+    pub components: Vec<ComponentSnapshot>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EntitySnapshot {
+    pub generations: Vec<u32>,  // of each slot
+    pub free: Vec<EntityIndex>, // the slots that are not alive, in the order of `EntityManager::free`
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeapSnapshot<T> {
+    pub values: Vec<T>, // indexed by handle, including the removed values
+    pub free: Vec<HeapId>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComponentSnapshot {
+    pub id: ComponentId, // in the world of the snapshot, as referred to by the nested component fields
+    pub name: String,    // empty if the world did not know the names (release builds)
+    pub fields: Vec<FieldLayout>,
+    pub rows: Vec<(EntityIndex, Vec<u8>)>, // in ascending order of the entities
+}
+
+impl ComponentSnapshot {
+    pub fn size(&self) -> usize {
+        self.fields.iter().map(|field| field.size as usize).sum()
+    }
+
+    // The name of the component in error messages
+    pub fn display_name(&self) -> String {
+        match self.name.is_empty() {
+            true => format!("#{}", self.id),
+            false => self.name.clone(),
+        }
+    }
+}
+
+pub fn is_snapshot(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+
+        add_u32s(&mut bytes, &self.entities.generations);
+        add_u32s(&mut bytes, &self.entities.free);
+
+        add_u32(&mut bytes, self.strings.values.len() as u32);
+        for string in &self.strings.values {
+            add_bytes(&mut bytes, string.as_bytes());
+        }
+        add_u32s(&mut bytes, &self.strings.free);
+
+        add_u32(&mut bytes, self.lists.values.len() as u32);
+        for list in &self.lists.values {
+            add_bytes(&mut bytes, list);
+        }
+        add_u32s(&mut bytes, &self.lists.free);
+
+        add_u32(&mut bytes, self.components.len() as u32);
+        for component in &self.components {
+            add_u32(&mut bytes, component.id);
+            add_bytes(&mut bytes, component.name.as_bytes());
+            add_u32(&mut bytes, component.fields.len() as u32);
+            for field in &component.fields {
+                add_bytes(&mut bytes, field.name.as_bytes());
+                bytes.push(field.type_id);
+                bytes.extend_from_slice(&field.size.to_be_bytes());
+                add_u32(&mut bytes, field.type_parameter);
+            }
+            add_u32(&mut bytes, component.rows.len() as u32);
+            for (entity, data) in &component.rows {
+                add_u32(&mut bytes, *entity);
+                bytes.extend_from_slice(data);
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if !is_snapshot(bytes) {
+            return Err("Not a snapshot (missing magic bytes)".to_string());
+        }
+        let mut reader = Reader {
+            bytes,
+            position: MAGIC.len(),
+        };
+        let version = u16::from_be_bytes(reader.read_bytes(2)?.try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(format!(
+                "Unsupported snapshot format version {version} (expected {FORMAT_VERSION})"
+            ));
+        }
+
+        let entities = EntitySnapshot {
+            generations: reader.read_u32s()?,
+            free: reader.read_u32s()?,
+        };
+
+        let string_count = reader.read_u32()?;
+        let mut strings = HeapSnapshot::default();
+        for _ in 0..string_count {
+            strings.values.push(reader.read_string()?);
+        }
+        strings.free = reader.read_u32s()?;
+
+        let list_count = reader.read_u32()?;
+        let mut lists = HeapSnapshot::default();
+        for _ in 0..list_count {
+            let length = reader.read_u32()? as usize;
+            lists.values.push(reader.read_bytes(length)?.to_vec());
+        }
+        lists.free = reader.read_u32s()?;
+
+        let component_count = reader.read_u32()?;
+        let mut components = Vec::new();
+        for _ in 0..component_count {
+            let id = reader.read_u32()?;
+            let name = reader.read_string()?;
+            let field_count = reader.read_u32()?;
+            let mut fields = Vec::new();
+            for _ in 0..field_count {
+                let name = reader.read_string()?;
+                let type_id = reader.read_bytes(1)?[0];
+                let size = u16::from_be_bytes(reader.read_bytes(2)?.try_into().unwrap());
+                let type_parameter = reader.read_u32()?;
+                fields.push(FieldLayout {
+                    name,
+                    type_id,
+                    size,
+                    type_parameter,
+                });
+            }
+            let mut component = ComponentSnapshot {
+                id,
+                name,
+                fields,
+                rows: Vec::new(),
+            };
+            let row_count = reader.read_u32()?;
+            for _ in 0..row_count {
+                let entity = reader.read_u32()?;
+                let data = reader.read_bytes(component.size())?.to_vec();
+                component.rows.push((entity, data));
+            }
+            components.push(component);
+        }
+
+        if reader.position != bytes.len() {
+            return Err("Unexpected bytes after the end of the snapshot".to_string());
+        }
+        Ok(Snapshot {
+            entities,
+            strings,
+            lists,
+            components,
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        let numbers = |values: &[u32]| {
+            values
+                .iter()
+                .map(|value| format!(" {value}"))
+                .collect::<String>()
+        };
+        let mut lines = vec![format!("{TEXT_HEADER} {FORMAT_VERSION}")];
+        lines.push(format!(
+            "generations{}",
+            numbers(&self.entities.generations)
+        ));
+        lines.push(format!("free{}", numbers(&self.entities.free)));
+        for string in &self.strings.values {
+            lines.push(format!("string {string:?}"));
+        }
+        lines.push(format!("free_strings{}", numbers(&self.strings.free)));
+        for list in &self.lists.values {
+            lines.push(format!("list {}", hex(list)).trim_end().to_string());
+        }
+        lines.push(format!("free_lists{}", numbers(&self.lists.free)));
+        for component in &self.components {
+            lines.push(format!("component {} {:?}", component.id, component.name));
+            for field in &component.fields {
+                lines.push(format!(
+                    "field {:?} {} {} {}",
+                    field.name, field.type_id, field.size, field.type_parameter
+                ));
+            }
+            for (entity, data) in &component.rows {
+                let mut line = format!("row {entity}");
+                let mut offset = 0;
+                for field in &component.fields {
+                    let size = field.size as usize;
+                    if size > 0 {
+                        line += &format!(" {}", hex(&data[offset..offset + size]));
+                    }
+                    offset += size;
+                }
+                lines.push(line);
+            }
+        }
+        lines.join("\n") + "\n"
+    }
+
+    pub fn from_text(text: &str) -> Result<Self, String> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty());
+        match lines.next() {
+            Some((_, header)) if header.trim() == format!("{TEXT_HEADER} {FORMAT_VERSION}") => (),
+            Some((_, header)) if header.starts_with(TEXT_HEADER) => {
+                return Err(format!(
+                    "Unsupported snapshot format version in '{header}' (expected {FORMAT_VERSION})"
+                ));
+            }
+            _ => return Err("Not a snapshot (missing header)".to_string()),
+        }
+
+        let mut snapshot = Snapshot::default();
+        for (index, line) in lines {
+            let error = |message: String| format!("Line {}: {message}", index + 1);
+            let tokens = split_line(line).map_err(error)?;
+            let (keyword, arguments) = tokens.split_first().unwrap();
+            let numbers = || -> Result<Vec<u32>, String> {
+                arguments
+                    .iter()
+                    .map(|argument| parse_number(argument))
+                    .collect()
+            };
+            let argument = |index: usize| {
+                arguments
+                    .get(index)
+                    .ok_or_else(|| format!("Missing argument {} of '{keyword}'", index + 1))
+            };
+            match keyword.as_str() {
+                "generations" => snapshot.entities.generations = numbers().map_err(error)?,
+                "free" => snapshot.entities.free = numbers().map_err(error)?,
+                "string" => snapshot
+                    .strings
+                    .values
+                    .push(argument(0).map_err(error)?.clone()),
+                "free_strings" => snapshot.strings.free = numbers().map_err(error)?,
+                "list" => snapshot
+                    .lists
+                    .values
+                    .push(parse_hex(&arguments.concat()).map_err(error)?),
+                "free_lists" => snapshot.lists.free = numbers().map_err(error)?,
+                "component" => snapshot.components.push(ComponentSnapshot {
+                    id: parse_number(argument(0).map_err(error)?).map_err(error)?,
+                    name: argument(1).map_err(error)?.clone(),
+                    fields: Vec::new(),
+                    rows: Vec::new(),
+                }),
+                "field" | "row" => {
+                    let component = snapshot
+                        .components
+                        .last_mut()
+                        .ok_or_else(|| error(format!("'{keyword}' outside of a component")))?;
+                    if keyword == "field" {
+                        let number =
+                            |index| -> Result<u32, String> { parse_number(argument(index)?) };
+                        component.fields.push(FieldLayout {
+                            name: argument(0).map_err(error)?.clone(),
+                            type_id: number(1).map_err(error)? as u8,
+                            size: number(2).map_err(error)? as u16,
+                            type_parameter: number(3).map_err(error)?,
+                        });
+                    } else {
+                        let entity = parse_number(argument(0).map_err(error)?).map_err(error)?;
+                        let data = parse_hex(&arguments[1..].concat()).map_err(error)?;
+                        if data.len() != component.size() {
+                            return Err(error(format!(
+                                "The row has {} bytes, but component {} has {} bytes",
+                                data.len(),
+                                component.display_name(),
+                                component.size()
+                            )));
+                        }
+                        component.rows.push((entity, data));
+                    }
+                }
+                _ => return Err(error(format!("Unknown keyword '{keyword}'"))),
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+fn add_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_be_bytes());
+}
+
+fn add_u32s(bytes: &mut Vec<u8>, values: &[u32]) {
+    add_u32(bytes, values.len() as u32);
+    values.iter().for_each(|value| add_u32(bytes, *value));
+}
+
+fn add_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+    add_u32(bytes, value.len() as u32);
+    bytes.extend_from_slice(value);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .position
+            .checked_add(count)
+            .and_then(|end| self.bytes.get(self.position..end))
+            .ok_or("Unexpected end of the snapshot")?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_u32s(&mut self) -> Result<Vec<u32>, String> {
+        let count = self.read_u32()?;
+        (0..count).map(|_| self.read_u32()).collect()
+    }
+
+    fn read_string(&mut self) -> Result<String, String> {
+        let length = self.read_u32()? as usize;
+        String::from_utf8(self.read_bytes(length)?.to_vec())
+            .map_err(|_| "Invalid UTF-8 in a string of the snapshot".to_string())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err(format!("Invalid hex data '{hex}'"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&hex[index..index + 2], 16)
+                .map_err(|_| format!("Invalid hex data '{hex}'"))
+        })
+        .collect()
+}
+
+fn parse_number(token: &str) -> Result<u32, String> {
+    token
+        .parse()
+        .map_err(|_| format!("Expected a number, got '{token}'"))
+}
+
+// Splits the line of a text snapshot into its words, where quoted strings (as written with `{:?}`) are single words
+// without the quotes and escapes. Formats that embed text snapshots can use it for their own lines.
+pub fn split_line(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            loop {
+                match chars.next().ok_or("Unterminated string")? {
+                    '"' => break,
+                    '\\' => token.push(unescape(&mut chars)?),
+                    c => token.push(c),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                token.push(c);
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+fn unescape(chars: &mut impl Iterator<Item = char>) -> Result<char, String> {
+    match chars.next().ok_or("Unterminated string")? {
+        'n' => Ok('\n'),
+        'r' => Ok('\r'),
+        't' => Ok('\t'),
+        '0' => Ok('\0'),
+        c @ ('\\' | '"' | '\'') => Ok(c),
+        'u' => {
+            let code: String = chars.skip(1).take_while(|c| *c != '}').collect();
+            u32::from_str_radix(&code, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| format!("Invalid unicode escape '\\u{{{code}}}'"))
+        }
+        c => Err(format!("Unknown escape '\\{c}'")),
+    }
+}
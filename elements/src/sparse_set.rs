@@ -0,0 +1,187 @@
+use crate::bitset::BitSet;
+use crate::column::Column;
+use crate::schedule::{Access, SplitRow};
+use crate::{ComponentId, ComponentTicks, EntityIndex, Tick};
+
+// Each component is stored in its own sparse set column, indexed by component id. Adding and removing components
+// is cheap, while queries look up every component of every entity. Ad-hoc queries intersect the bitsets of the
+// columns, while the registered queries of the world keep their matching entities up to date.
+#[derive(Debug, Default)]
+pub struct SparseSetStorage {
+    columns: Vec<Column>,
+}
+
+impl SparseSetStorage {
+    pub fn register(&mut self, id: ComponentId, size: usize) {
+        let column = Column::new(size, 16);
+        let idx = id as usize;
+        if idx < self.columns.len() {
+            self.columns[idx] = column;
+        } else {
+            self.columns.push(column);
+        }
+    }
+
+    pub fn insert(&mut self, id: ComponentId, entity: EntityIndex, data: &[u8], tick: Tick) {
+        self.columns[id as usize].insert(entity, data, tick);
+    }
+
+    pub fn remove(&mut self, id: ComponentId, entity: EntityIndex) {
+        self.columns[id as usize].remove(entity);
+    }
+
+    pub fn remove_entity(&mut self, entity: EntityIndex) {
+        self.columns.iter_mut().for_each(|column| {
+            column.remove(entity);
+        });
+    }
+
+    pub fn get(&self, id: ComponentId, entity: EntityIndex) -> Option<&[u8]> {
+        self.columns[id as usize].get(entity)
+    }
+
+    pub fn ticks(&self, id: ComponentId, entity: EntityIndex) -> Option<ComponentTicks> {
+        self.columns[id as usize].ticks(entity)
+    }
+
+    pub fn set_changed(&mut self, id: ComponentId, entity: EntityIndex, tick: Tick) {
+        self.columns[id as usize].set_changed(entity, tick);
+    }
+
+    pub fn get_mut(&mut self, id: ComponentId, entity: EntityIndex) -> Option<&mut [u8]> {
+        self.columns[id as usize].get_mut(entity)
+    }
+
+    // The entities that have all the included components and none of the excluded ones, in ascending order
+    pub fn matching(&self, include: &[ComponentId], exclude: &[ComponentId]) -> Vec<EntityIndex> {
+        self.matching_bitset(include, exclude)
+            .map(|bitset| bitset.iter_ids().collect())
+            .unwrap_or_default()
+    }
+
+    fn matching_bitset(&self, include: &[ComponentId], exclude: &[ComponentId]) -> Option<BitSet> {
+        let mut include_columns = self.columns_of(include);
+        let first = include_columns.next()?;
+        let mut bitset = first.bitset.clone();
+        for column in include_columns {
+            bitset.intersect_with(&column.bitset);
+        }
+        for column in self.columns_of(exclude) {
+            bitset.disjoint_with(&column.bitset);
+        }
+        Some(bitset)
+    }
+
+    fn columns_of(&self, ids: &[ComponentId]) -> impl Iterator<Item = &Column> {
+        self.columns
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| ids.contains(&(*id as ComponentId)))
+            .map(|(_, column)| column)
+    }
+
+    // Visits the rows of the matching entities, as matched by the cached query of the world
+    pub fn for_each_row(
+        &mut self,
+        include: &[ComponentId],
+        entities: impl Iterator<Item = EntityIndex>,
+        system: &mut dyn FnMut(EntityIndex, &mut Vec<&mut [u8]>),
+    ) {
+        let mut include_columns: Vec<_> = self
+            .columns
+            .iter_mut()
+            .enumerate()
+            .filter(|(id, _)| include.contains(&(*id as ComponentId)))
+            .map(|(_, column)| column)
+            .collect();
+
+        entities.for_each(|index| {
+            let mut row: Vec<_> = include_columns
+                .iter_mut()
+                .flat_map(|col| col.get_mut(index))
+                .collect();
+            system(index, &mut row);
+        });
+    }
+
+    pub fn rows_mut(
+        &mut self,
+        include: &[ComponentId],
+        entities: impl Iterator<Item = EntityIndex>,
+    ) -> Vec<(EntityIndex, Vec<&mut [u8]>)> {
+        // SAFETY: We use raw pointers here to work around the borrow checker.
+        // This is safe because:
+        // 1. Each entity accesses a unique memory location within each column
+        // 2. We never access the same memory location twice in the same iteration
+        // 3. The pointers remain valid for the lifetime of the borrow of `self`
+        let column_ptrs: Vec<*mut Column> = self
+            .columns
+            .iter_mut()
+            .enumerate()
+            .filter(|(id, _)| include.contains(&(*id as ComponentId)))
+            .map(|(_, column)| column as *mut Column)
+            .collect();
+
+        let mut result = Vec::new();
+        for index in entities {
+            let mut row = Vec::new();
+            for &col_ptr in &column_ptrs {
+                unsafe {
+                    if let Some(data) = (*col_ptr).get_mut(index) {
+                        row.push(data);
+                    }
+                }
+            }
+            result.push((index, row));
+        }
+        result
+    }
+
+    // The rows of the matching entities of each access. Each column is borrowed mutably by the access that writes it,
+    // or shared by the accesses that read it, so the accesses must not conflict.
+    pub fn split_rows(
+        &mut self,
+        accesses: &[Access],
+        matches: Vec<Vec<EntityIndex>>,
+    ) -> Vec<Vec<SplitRow<'_>>> {
+        let mut reads: Vec<Vec<&Column>> = accesses.iter().map(|_| Vec::new()).collect();
+        let mut writes: Vec<Vec<&mut Column>> = accesses.iter().map(|_| Vec::new()).collect();
+        for (id, column) in self.columns.iter_mut().enumerate() {
+            let id = id as ComponentId;
+            match accesses
+                .iter()
+                .position(|access| access.writes.contains(&id))
+            {
+                Some(writer) => writes[writer].push(column),
+                None => {
+                    let column = &*column;
+                    accesses
+                        .iter()
+                        .zip(&mut reads)
+                        .filter(|(access, _)| access.reads.contains(&id))
+                        .for_each(|(_, reads)| reads.push(column));
+                }
+            }
+        }
+
+        reads
+            .into_iter()
+            .zip(writes)
+            .zip(matches)
+            .map(|((reads, writes), entities)| {
+                let mut writes: Vec<_> = writes
+                    .into_iter()
+                    .map(|column| column.rows_mut(&entities).into_iter())
+                    .collect();
+                entities
+                    .iter()
+                    .map(|&index| {
+                        let reads = reads.iter().flat_map(|column| column.get(index)).collect();
+                        let writes = writes.iter_mut().flat_map(|rows| rows.next()).collect();
+                        (index, reads, writes)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
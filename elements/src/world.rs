@@ -1,9 +1,21 @@
+use std::collections::HashMap;
+
 use crate::{
-    ComponentId, ComponentLayout, ComponentTypeId, Entity, bitset::BitSet, column::Column,
+    COMPONENT_TYPE_ID, ComponentId, ComponentLayout, ComponentTicks, ComponentTypeId, Entity,
+    EntityIndex, EntityManager, HeapId, LIST_TYPE_ID, STRING_TYPE_ID, TAG_TYPE_ID, Tick,
+    archetype::ArchetypeStorage,
+    heap::{Heap, Heaps, read_id},
+    hierarchy::Hierarchy,
+    query_cache::QueryCache,
+    schedule::{Access, Row},
+    snapshot::{ComponentSnapshot, Snapshot},
+    sparse_set::SparseSetStorage,
 };
 
+pub use crate::query_cache::QueryHandle;
+
 pub struct QueryResultMutIter<'a> {
-    iter: std::vec::IntoIter<(u32, Vec<&'a mut [u8]>)>,
+    iter: std::vec::IntoIter<(Entity, Vec<&'a mut [u8]>)>,
 }
 
 impl<'a> QueryResultMutIter<'a> {
@@ -28,21 +40,39 @@ impl<'a> Iterator for QueryResultMutIter<'a> {
     }
 }
 
-pub struct QueryResult<'a> {
+// The matching entities of a query. The result does not borrow the world, so several queries can be iterated at
+// the same time (e.g. nested queries), while the components are read and written through the world.
+pub struct QueryResult {
     entities: std::vec::IntoIter<Entity>,
-    pub columns: Vec<&'a mut Column>,
+    pub components: Vec<ComponentId>, // the included components that have fields
+    pub optional_components: Vec<ComponentId>, // may or may not be present for each entity
 }
 
-impl<'a> QueryResult<'a> {
-    pub fn new(entities: Vec<Entity>, columns: Vec<&'a mut Column>) -> Self {
+impl QueryResult {
+    pub fn new(
+        entities: Vec<Entity>,
+        components: Vec<ComponentId>,
+        optional_components: Vec<ComponentId>,
+    ) -> Self {
         Self {
             entities: entities.into_iter(),
-            columns,
+            components,
+            optional_components,
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.len() == 0
+    }
+
+    // Keeps the matching entities for which `f` returns true
+    pub fn retain(&mut self, f: impl FnMut(&Entity) -> bool) {
+        let entities = self.entities.by_ref().filter(f).collect::<Vec<_>>();
+        self.entities = entities.into_iter();
+    }
 }
 
-impl<'a> Iterator for QueryResult<'a> {
+impl Iterator for QueryResult {
     type Item = Entity;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -50,11 +80,74 @@ impl<'a> Iterator for QueryResult<'a> {
     }
 }
 
+// Restricts a query to the entities whose `added` components were added, and whose `changed` components were
+// inserted or written, after the last run of the query
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChangeFilter<'a> {
+    pub added: &'a [ComponentId],
+    pub changed: &'a [ComponentId],
+    pub last_run: Tick, // as returned by the last run of the query, or 0 if the query has not run before
+}
+
+// The lifecycle events of a component that can be observed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComponentEvent {
+    Add,     // the component was added to an entity that did not have it
+    Remove,  // the component was removed from an entity that is still alive
+    Destroy, // the entity that had the component was destroyed
+}
+
+// An event of an observed component, along with a copy of the data of the component. The fields still refer to their
+// strings and lists in the heaps until the observation has been drained, even if the component is removed or
+// replaced in the meantime.
+#[derive(Debug, Clone)]
+pub struct Observation {
+    pub event: ComponentEvent,
+    pub component: ComponentId,
+    pub entity: Entity,
+    pub data: Vec<u8>,
+}
+
 // --------------------
 
+// How the world stores the components of its entities. Both have the same API, but different performance
+// characteristics (see `elements/src/main.rs` for a benchmark of the movement system).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageKind {
+    #[default]
+    SparseSet, // a column per component; cheap to add and remove components
+    Archetype, // a table per set of components; fast to iterate
+}
+
+#[derive(Debug)]
+enum Storage {
+    SparseSet(SparseSetStorage),
+    Archetype(ArchetypeStorage),
+}
+
+// Forwards the call to the storage of the world
+macro_rules! storage {
+    ($storage:expr, $s:ident => $call:expr) => {
+        match $storage {
+            Storage::SparseSet($s) => $call,
+            Storage::Archetype($s) => $call,
+        }
+    };
+}
+
 #[derive(Debug)]
 pub struct World {
-    components: Vec<Column>,
+    layouts: Vec<ComponentLayout>, // indexed by component id
+    storage: Storage,
+    entities: EntityManager,
+    heaps: Heaps,
+    change_tick: Tick, // recorded by the components on writes and advanced by every run of a filtered query
+    observers: Vec<(ComponentEvent, ComponentId)>,
+    observations: Vec<Observation>, // the events of observed components since the last drain
+    freed: Vec<(ComponentId, Vec<u8>)>, // components whose strings and lists are freed once observations are drained
+    child_of: Option<ComponentId>,      // the component that relates children to their parent
+    hierarchy: Hierarchy,
+    queries: QueryCache,
 }
 
 impl Default for World {
@@ -65,235 +158,751 @@ impl Default for World {
 
 impl World {
     pub fn new() -> Self {
+        Self::with_storage(StorageKind::default())
+    }
+
+    pub fn with_storage(kind: StorageKind) -> Self {
+        let storage = match kind {
+            StorageKind::SparseSet => Storage::SparseSet(SparseSetStorage::default()),
+            StorageKind::Archetype => Storage::Archetype(ArchetypeStorage::default()),
+        };
         Self {
-            components: Vec::new(),
+            layouts: Vec::new(),
+            storage,
+            entities: EntityManager::new(),
+            heaps: Heaps::default(),
+            change_tick: 1,
+            observers: Vec::new(),
+            observations: Vec::new(),
+            freed: Vec::new(),
+            child_of: None,
+            hierarchy: Hierarchy::default(),
+            queries: QueryCache::default(),
         }
     }
 
+    pub fn create(&mut self) -> Entity {
+        let entity = self.entities.create();
+        self.queries.add_entity(entity.index);
+        entity
+    }
+
+    pub fn is_alive(&self, entity: Entity) -> bool {
+        self.entities.is_alive(entity)
+    }
+
     pub fn register_component(&mut self, id: ComponentTypeId, layout: ComponentLayout) {
-        let column = Column::new(id, layout, 16);
+        storage!(&mut self.storage, s => s.register(id, layout.size));
+        // Registering a component again drops its data
+        let (storage, entities) = (&self.storage, &self.entities);
+        self.queries.refresh(Some(id), |include, exclude| {
+            matching(storage, entities, include, exclude)
+        });
         let idx = id as usize;
-        if idx < self.components.len() {
-            self.components[idx] = column;
+        if idx < self.layouts.len() {
+            self.layouts[idx] = layout;
         } else {
-            self.components.push(column);
+            self.layouts.push(layout);
         }
     }
 
+    // Registers the `ChildOf` relationship, a component with the parent of the entity as its only field (the bits of
+    // the parent entity as a big-endian `u32`). Destroying a parent also destroys its children.
+    pub fn register_child_of(&mut self, id: ComponentTypeId, layout: ComponentLayout) {
+        assert_eq!(
+            layout.size, 4,
+            "The ChildOf component holds a single entity"
+        );
+        self.register_component(id, layout);
+        self.child_of = Some(id);
+    }
+
+    pub fn parent(&self, child: Entity) -> Option<Entity> {
+        self.hierarchy.parent(child)
+    }
+
+    pub fn children(&self, parent: Entity) -> &[Entity] {
+        self.hierarchy.children(parent)
+    }
+
     pub fn get_component_layout(&self, id: ComponentTypeId) -> Option<&ComponentLayout> {
-        self.components
-            .get(id as usize)
-            .map(|column| &column.layout)
+        self.layouts.get(id as usize)
     }
 
-    pub fn insert(&mut self, id: ComponentTypeId, entity: Entity, data: &[u8]) {
-        self.components[id as usize].insert(entity, data);
+    // Inserts or replaces the component of the entity. Fails, and does nothing, if the component is the `ChildOf`
+    // component and the parent is no longer alive.
+    pub fn insert(
+        &mut self,
+        id: ComponentTypeId,
+        entity: Entity,
+        data: &[u8],
+    ) -> Result<(), String> {
+        assert!(
+            self.is_alive(entity),
+            "Cannot insert a component for an entity that is no longer alive"
+        );
+        let parent = (self.child_of == Some(id))
+            .then(|| Entity::from_bits(u32::from_be_bytes(data.try_into().unwrap())));
+        if let Some(parent) = parent
+            && !self.is_alive(parent)
+        {
+            return Err(format!(
+                "Cannot make entity {} the child of entity {}, which is no longer alive",
+                entity.index, parent.index
+            ));
+        }
+        match storage!(&self.storage, s => s.get(id, entity.index)) {
+            Some(replaced) if self.observations.is_empty() => {
+                self.heaps
+                    .remove_fields(&self.layouts, &self.layouts[id as usize], replaced);
+            }
+            Some(replaced) => self.freed.push((id, replaced.to_vec())),
+            None => self.notify(ComponentEvent::Add, id, entity, data),
+        }
+        if let Some(parent) = parent {
+            self.hierarchy.set_parent(entity, parent);
+        }
+        let tick = self.change_tick;
+        storage!(&mut self.storage, s => s.insert(id, entity.index, data, tick));
+        self.update_queries(id, entity);
+        Ok(())
     }
 
     pub fn remove(&mut self, id: ComponentTypeId, entity: Entity) {
-        self.components[id as usize].remove(entity);
+        if self.is_alive(entity) {
+            if self.child_of == Some(id) {
+                self.hierarchy.remove_parent(entity);
+            }
+            self.remove_component(ComponentEvent::Remove, id, entity);
+            storage!(&mut self.storage, s => s.remove(id, entity.index));
+            self.update_queries(id, entity);
+        }
     }
 
-    pub fn destroy(&mut self, entity: Entity) {
-        self.components.iter_mut().for_each(|column| {
-            column.remove(entity);
+    fn update_queries(&mut self, id: ComponentId, entity: Entity) {
+        let (storage, registered) = (&self.storage, self.layouts.len() as ComponentId);
+        self.queries.update(id, entity.index, |id| {
+            id < registered && storage!(storage, s => s.get(id, entity.index)).is_some()
         });
     }
 
+    // Removes the components of the entity and frees its slot for reuse, and then does the same for its children
+    // (recursively). Returns false, and does nothing, if the entity is no longer alive.
+    pub fn destroy(&mut self, entity: Entity) -> bool {
+        if !self.entities.destroy(entity) {
+            return false;
+        }
+        for id in 0..self.layouts.len() as ComponentId {
+            self.remove_component(ComponentEvent::Destroy, id, entity);
+        }
+        storage!(&mut self.storage, s => s.remove_entity(entity.index));
+        self.queries.remove_entity(entity.index);
+        self.hierarchy.remove_parent(entity);
+        for child in self.hierarchy.children(entity).to_vec() {
+            self.destroy(child);
+        }
+        true
+    }
+
+    // Frees the strings and lists of the component, unless there are observations that may still refer to them (the
+    // removal itself, or an earlier add), in which case they are freed once the observations have been drained
+    fn remove_component(&mut self, event: ComponentEvent, id: ComponentId, entity: Entity) {
+        let Some(removed) = storage!(&self.storage, s => s.get(id, entity.index)) else {
+            return;
+        };
+        if self.observers.contains(&(event, id)) {
+            self.observations.push(Observation {
+                event,
+                component: id,
+                entity,
+                data: removed.to_vec(),
+            });
+        }
+        if self.observations.is_empty() {
+            self.heaps
+                .remove_fields(&self.layouts, &self.layouts[id as usize], removed);
+        } else {
+            self.freed.push((id, removed.to_vec()));
+        }
+    }
+
+    fn notify(&mut self, event: ComponentEvent, id: ComponentId, entity: Entity, data: &[u8]) {
+        if self.observers.contains(&(event, id)) {
+            self.observations.push(Observation {
+                event,
+                component: id,
+                entity,
+                data: data.to_vec(),
+            });
+        }
+    }
+
+    // Records an observation whenever the event happens to the component, until the observers are cleared
+    pub fn observe(&mut self, event: ComponentEvent, id: ComponentId) {
+        if !self.observers.contains(&(event, id)) {
+            self.observers.push((event, id));
+        }
+    }
+
+    pub fn clear_observers(&mut self) {
+        self.observers.clear();
+    }
+
+    pub fn has_observations(&self) -> bool {
+        !self.observations.is_empty()
+    }
+
+    // Passes the observations, in the order of the events, to `observer`. The world is passed along so that the
+    // strings and lists of the components can be read, before those of removed and replaced components are freed.
+    pub fn drain_observations(&mut self, mut observer: impl FnMut(&World, &Observation)) {
+        for observation in std::mem::take(&mut self.observations) {
+            observer(self, &observation);
+        }
+        for (id, data) in std::mem::take(&mut self.freed) {
+            self.heaps
+                .remove_fields(&self.layouts, &self.layouts[id as usize], &data);
+        }
+    }
+
+    // Strings and lists are inserted before the component that refers to them, and are removed along with the component
+    pub fn insert_string(&mut self, string: &str) -> HeapId {
+        self.heaps.strings.insert(string.to_string())
+    }
+
+    pub fn get_string(&self, id: HeapId) -> &str {
+        self.heaps.strings.get(id)
+    }
+
+    pub fn insert_list(&mut self, elements: Vec<u8>) -> HeapId {
+        self.heaps.lists.insert(elements)
+    }
+
+    pub fn get_list(&self, id: HeapId) -> &[u8] {
+        self.heaps.lists.get(id)
+    }
+
     pub fn get(&self, id: ComponentTypeId, entity: Entity) -> Option<&[u8]> {
-        self.components[id as usize].get(entity)
+        if !self.is_alive(entity) {
+            return None;
+        }
+        storage!(&self.storage, s => s.get(id, entity.index))
     }
 
+    // The component is marked as changed, as it is assumed to be written
     pub fn get_mut(&mut self, id: ComponentTypeId, entity: Entity) -> Option<&mut [u8]> {
-        self.components[id as usize].get_mut(entity)
+        if !self.is_alive(entity) {
+            return None;
+        }
+        let tick = self.change_tick;
+        storage!(&mut self.storage, s => s.set_changed(id, entity.index, tick));
+        storage!(&mut self.storage, s => s.get_mut(id, entity.index))
     }
 
     pub fn iter(&self, id: ComponentTypeId) -> impl Iterator<Item = (Entity, &[u8])> + '_ {
-        self.components[id as usize].iter()
+        self.matching(&[id], &[]).into_iter().map(move |index| {
+            let data = storage!(&self.storage, s => s.get(id, index)).unwrap();
+            (self.entities.entity(index), data)
+        })
+    }
+
+    fn matching(&self, include: &[ComponentId], exclude: &[ComponentId]) -> Vec<EntityIndex> {
+        matching(&self.storage, &self.entities, include, exclude)
     }
 
+    // Registers a query, whose matching entities are then kept up to date as components are inserted and removed.
+    // Registering the same included and excluded components again returns the same handle.
+    pub fn register_query(
+        &mut self,
+        include: &[ComponentId],
+        exclude: &[ComponentId],
+    ) -> QueryHandle {
+        let (storage, entities) = (&self.storage, &self.entities);
+        self.queries.register(include, exclude, |include, exclude| {
+            matching(storage, entities, include, exclude)
+        })
+    }
+
+    // The rows contain the included components in ascending order of their ids (marker components are empty)
     pub fn system(
         &mut self,
-        include: &Vec<ComponentId>,
-        exclude: &Vec<ComponentId>,
+        include: &[ComponentId],
+        exclude: &[ComponentId],
         mut system: impl FnMut(Entity, &mut Vec<&mut [u8]>),
     ) {
-        if include.is_empty() {
-            return;
+        assert!(!include.is_empty(), "A system must include a component");
+        let query = self.register_query(include, exclude);
+        let entities = &self.entities;
+        let mut system = |index, row: &mut Vec<&mut [u8]>| system(entities.entity(index), row);
+        // The archetype storage matches whole tables, which is cheaper than visiting the cached entities one by one
+        match &mut self.storage {
+            Storage::SparseSet(s) => {
+                s.for_each_row(include, self.queries.matches(query), &mut system)
+            }
+            Storage::Archetype(s) => s.for_each_row(include, exclude, &mut system),
         }
+    }
 
-        let exclude_columns: Vec<_> = self
-            .components
-            .iter()
-            .filter(|c| exclude.contains(&c.id))
-            .collect();
-
-        // TODO(anissen): Could split in (first, rest) for optimization
-        let exclude_bitset = if let Some(first) = exclude_columns.first() {
-            let mut bitset = first.bitset.clone();
-            self.components
-                .iter()
-                .filter(|c| exclude.contains(&c.id))
-                .map(|col| &col.bitset)
-                .for_each(|other| bitset.intersect_with(other));
-            Some(bitset)
-        } else {
-            None
+    // Like `system`, but visits the rows in chunks, on a thread per chunk. The components that are read and written
+    // are declared up front, and the rows must not depend on each other.
+    pub fn par_system(
+        &mut self,
+        reads: &[ComponentId],
+        writes: &[ComponentId],
+        exclude: &[ComponentId],
+        system: impl Fn(&mut Row) + Sync,
+    ) {
+        assert!(
+            !reads.iter().any(|id| writes.contains(id)),
+            "A system cannot both read and write a component"
+        );
+        let access = Access {
+            reads,
+            writes,
+            exclude,
         };
-
-        let mut include_columns: Vec<_> = self
-            .components
-            .iter_mut()
-            .filter(|c| include.contains(&c.id))
-            .collect();
-
-        // TODO(anissen): Could split in (first, rest) for optimization
-        if let Some(first) = include_columns.first() {
-            let mut intersection = first.bitset.clone();
-            include_columns
-                .iter()
-                .map(|col| &col.bitset)
-                .for_each(|bitset| intersection.intersect_with(bitset));
-
-            if let Some(exclude_bitset) = exclude_bitset {
-                intersection.disjoint_with(&exclude_bitset);
+        let mut rows = self.split_rows(&[access]).pop().unwrap();
+        let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+        let chunk_size = rows.len().div_ceil(threads).max(1);
+        let system = &system;
+        std::thread::scope(|scope| {
+            for chunk in rows.chunks_mut(chunk_size) {
+                scope.spawn(move || chunk.iter_mut().for_each(system));
             }
+        });
+    }
 
-            intersection.iter_ids().for_each(|entity| {
-                let mut row: Vec<_> = include_columns
-                    .iter_mut()
-                    .flat_map(|col| col.get_mut(entity))
+    // The rows of each access, which must not conflict, so that they can be visited at the same time
+    pub(crate) fn split_rows(&mut self, accesses: &[Access]) -> Vec<Vec<Row<'_>>> {
+        assert!(
+            accesses
+                .iter()
+                .enumerate()
+                .all(|(index, access)| accesses[..index]
+                    .iter()
+                    .all(|earlier| !earlier.conflicts_with(access))),
+            "Systems that run at the same time cannot write the components of each other"
+        );
+        assert!(
+            accesses.iter().all(|access| !access.include().is_empty()),
+            "A system must include a component"
+        );
+        let queries: Vec<_> = accesses
+            .iter()
+            .map(|access| self.register_query(&access.include(), access.exclude))
+            .collect();
+        let rows = match &mut self.storage {
+            Storage::SparseSet(s) => {
+                let matches = queries
+                    .iter()
+                    .map(|query| self.queries.matches(*query).collect())
                     .collect();
-                system(entity, &mut row);
-            });
-        }
+                s.split_rows(accesses, matches)
+            }
+            Storage::Archetype(s) => s.split_rows(accesses),
+        };
+        let entities = &self.entities;
+        rows.into_iter()
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|(index, reads, writes)| Row {
+                        entity: entities.entity(index),
+                        reads,
+                        writes,
+                    })
+                    .collect()
+            })
+            .collect()
     }
 
     pub fn query_mut<'a>(
         &'a mut self,
-        include: &Vec<ComponentId>,
-        exclude: &Vec<ComponentId>,
+        include: &[ComponentId],
+        exclude: &[ComponentId],
     ) -> QueryResultMutIter<'a> {
-        // ) -> Vec<(Entity, Vec<&mut [u8]>)> {
-        if include.is_empty() {
+        assert!(
+            !include.is_empty(),
+            "A query of rows must include a component"
+        );
+        let query = self.register_query(include, exclude);
+        let rows = match &mut self.storage {
+            Storage::SparseSet(s) => s.rows_mut(include, self.queries.matches(query)),
+            Storage::Archetype(s) => s.rows_mut(include, exclude),
+        };
+        if rows.is_empty() {
             return QueryResultMutIter::empty();
         }
+        let entities = &self.entities;
+        QueryResultMutIter::new(
+            rows.into_iter()
+                .map(|(index, row)| (entities.entity(index), row))
+                .collect(),
+        )
+    }
 
-        let exclude_columns: Vec<_> = self
-            .components
-            .iter()
-            .filter(|c| exclude.contains(&c.id))
-            .collect();
+    // Optional components do not restrict the matching entities, but their columns are part of the result
+    pub fn query(
+        &self,
+        include: &[ComponentId],
+        optional: &[ComponentId],
+        exclude: &[ComponentId],
+    ) -> QueryResult {
+        self.query_filtered(self.matching(include, exclude), include, optional, |_| true)
+    }
 
-        // TODO(anissen): Could split in (first, rest) for optimization
-        let exclude_bitset = if let Some(first) = exclude_columns.first() {
-            let mut bitset = first.bitset.clone();
-            self.components
-                .iter()
-                .filter(|c| exclude.contains(&c.id))
-                .map(|col| &col.bitset)
-                .for_each(|other| bitset.intersect_with(other));
-            Some(bitset)
-        } else {
-            None
+    // Like `query`, but with the matching entities of a registered query
+    pub fn query_registered(&self, query: QueryHandle, optional: &[ComponentId]) -> QueryResult {
+        let include = self.queries.include(query);
+        self.query_filtered(
+            self.queries.matches(query).collect(),
+            include,
+            optional,
+            |_| true,
+        )
+    }
+
+    // Like `query`, but only matches the children of the parent
+    pub fn query_children(
+        &self,
+        parent: Entity,
+        include: &[ComponentId],
+        optional: &[ComponentId],
+        exclude: &[ComponentId],
+    ) -> QueryResult {
+        self.query_filtered(
+            self.matching(include, exclude),
+            include,
+            optional,
+            |index| self.hierarchy.parent(self.entities.entity(index)) == Some(parent),
+        )
+    }
+
+    // Like `query`, but only matches the entities that pass the change filter. Returns the result and the tick to
+    // pass as `last_run` in the next run of the query, so the next run matches the changes made from now on.
+    pub fn query_changes(
+        &mut self,
+        include: &[ComponentId],
+        optional: &[ComponentId],
+        exclude: &[ComponentId],
+        filter: ChangeFilter,
+    ) -> (QueryResult, Tick) {
+        let query = self.register_query(include, exclude);
+        let this_run = self.change_tick;
+        self.change_tick += 1;
+        let is_newer = |id: &ComponentId, index, tick: fn(ComponentTicks) -> Tick| {
+            storage!(&self.storage, s => s.ticks(*id, index))
+                .is_some_and(|ticks| tick(ticks) > filter.last_run)
         };
+        let matching = self.queries.matches(query).collect();
+        let result = self.query_filtered(matching, include, optional, |index| {
+            filter
+                .added
+                .iter()
+                .all(|id| is_newer(id, index, |ticks| ticks.added))
+                && filter
+                    .changed
+                    .iter()
+                    .all(|id| is_newer(id, index, |ticks| ticks.changed))
+        });
+        (result, this_run)
+    }
 
-        let mut include_columns: Vec<_> = self
-            .components
-            .iter_mut()
-            .filter(|c| include.contains(&c.id))
+    fn query_filtered(
+        &self,
+        matching: Vec<EntityIndex>,
+        include: &[ComponentId],
+        optional: &[ComponentId],
+        filter: impl Fn(EntityIndex) -> bool,
+    ) -> QueryResult {
+        let matching_entities = matching
+            .into_iter()
+            .filter(|index| filter(*index))
+            .map(|index| self.entities.entity(index))
             .collect();
 
-        // TODO(anissen): Could split in (first, rest) for optimization
-        if let Some(first) = include_columns.first() {
-            let mut intersection = first.bitset.clone();
-            include_columns
-                .iter()
-                .map(|col| &col.bitset)
-                .for_each(|bitset| intersection.intersect_with(bitset));
+        let registered = || 0..self.layouts.len() as ComponentId;
+        let non_marker_include_components = registered()
+            .filter(|id| include.contains(id) && self.layouts[*id as usize].size != 0)
+            .collect();
+        let optional_components = registered()
+            .filter(|id| optional.contains(id) && !include.contains(id))
+            .collect();
+        QueryResult::new(
+            matching_entities,
+            non_marker_include_components,
+            optional_components,
+        )
+    }
 
-            if let Some(exclude_bitset) = exclude_bitset {
-                intersection.disjoint_with(&exclude_bitset);
-            }
+    // The entities, the components of every registered component and the strings and lists they refer to. The
+    // observations that have not been drained are not part of the snapshot.
+    pub fn snapshot(&self) -> Snapshot {
+        let components = self
+            .layouts
+            .iter()
+            .enumerate()
+            .map(|(id, layout)| ComponentSnapshot {
+                id: id as ComponentId,
+                name: layout.name.clone(),
+                fields: layout.fields.clone(),
+                rows: self
+                    .iter(id as ComponentId)
+                    .map(|(entity, data)| (entity.index, data.to_vec()))
+                    .collect(),
+            })
+            .collect();
+        Snapshot {
+            entities: self.entities.snapshot(),
+            strings: self.heaps.strings.snapshot(),
+            lists: self.heaps.lists.snapshot(),
+            components,
+        }
+    }
 
-            let entities: Vec<_> = intersection.iter_ids().collect();
-            let mut result = Vec::new();
-
-            // SAFETY: We use raw pointers here to work around the borrow checker.
-            // This is safe because:
-            // 1. Each entity accesses a unique memory location within each column
-            // 2. We never access the same memory location twice in the same iteration
-            // 3. The pointers remain valid for the lifetime 'a (tied to &'a mut self)
-            let column_ptrs: Vec<*mut Column> = include_columns
-                .iter_mut()
-                .map(|col| *col as *mut Column)
-                .collect();
-
-            for entity in entities {
-                let mut row = Vec::new();
-                for &col_ptr in &column_ptrs {
-                    unsafe {
-                        if let Some(data) = (*col_ptr).get_mut(entity) {
-                            row.push(data);
-                        }
-                    }
+    // Replaces the entities and components of the world with those of the snapshot. The components of the snapshot
+    // are matched to the registered components by name (or by id if the snapshot has no names), and their fields
+    // by name, so the components may have other ids and their fields another order than when the snapshot was
+    // taken. The same goes for the fields of nested components. Components of the snapshot that are no longer
+    // registered are only allowed if they have no rows. The world is left unchanged if the snapshot does not match
+    // the registered components, or if its strings and lists are not where the components refer to them.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        let mut ids = HashMap::new(); // component id in the snapshot => registered component id
+        for component in &snapshot.components {
+            let id = match component.name.is_empty() {
+                true => (component.id < self.layouts.len() as ComponentId).then_some(component.id),
+                false => self
+                    .layouts
+                    .iter()
+                    .position(|layout| layout.name == component.name)
+                    .map(|id| id as ComponentId),
+            };
+            match id {
+                Some(id) => _ = ids.insert(component.id, id),
+                None if component.rows.is_empty() => (),
+                None => {
+                    return Err(format!(
+                        "Component {} of the snapshot is not registered",
+                        component.display_name()
+                    ));
                 }
-                result.push((entity, row));
             }
+        }
 
-            QueryResultMutIter::new(result)
-        } else {
-            QueryResultMutIter::empty()
+        // The offset in the saved data of each registered field
+        let mut offsets = Vec::new();
+        for component in &snapshot.components {
+            let Some(&id) = ids.get(&component.id) else {
+                continue;
+            };
+            offsets.push(self.field_offsets(snapshot, component, id, &ids)?);
         }
-    }
 
-    pub fn query<'a>(
-        &'a mut self,
-        include: &Vec<ComponentId>,
-        exclude: &Vec<ComponentId>,
-    ) -> QueryResult<'a> {
-        let exclude_columns = self
+        // The rows of the registered components, with their fields in the registered order
+        let mut rows = Vec::new();
+        let components = snapshot
             .components
             .iter()
-            .filter(|c| exclude.contains(&c.id))
-            .collect::<Vec<_>>();
+            .filter(|component| ids.contains_key(&component.id));
+        for (component, offsets) in components.zip(offsets) {
+            let id = ids[&component.id];
+            for (index, saved) in &component.rows {
+                let data: Vec<u8> = offsets
+                    .iter()
+                    .flat_map(|(offset, size)| &saved[*offset..offset + size])
+                    .copied()
+                    .collect();
+                if !self.has_valid_handles(&self.layouts[id as usize], &data, snapshot) {
+                    return Err(format!(
+                        "A string or list of component {} of the snapshot is out of bounds",
+                        component.display_name()
+                    ));
+                }
+                rows.push((id, *index, data));
+            }
+        }
 
-        let exclude_bitmap = if let Some((first, rest)) = exclude_columns.split_first() {
-            let mut bitset = first.bitset.clone();
-            for col in rest {
-                bitset.intersect_with(&col.bitset);
+        let entities = EntityManager::restore(&snapshot.entities)?;
+        for component in &snapshot.components {
+            if let Some((entity, _)) = component
+                .rows
+                .iter()
+                .find(|(index, _)| !entities.alive.contains(*index))
+            {
+                return Err(format!(
+                    "Component {} of the snapshot belongs to entity {entity}, which is not alive",
+                    component.display_name()
+                ));
             }
-            bitset
-        } else {
-            BitSet::new_empty(0)
+        }
+        let heap_error = |name: &str, free: &[HeapId], len: usize| {
+            free.iter()
+                .any(|id| *id as usize >= len)
+                .then(|| format!("A free handle of the {name} of the snapshot is out of bounds"))
         };
+        if let Some(error) = heap_error(
+            "strings",
+            &snapshot.strings.free,
+            snapshot.strings.values.len(),
+        )
+        .or_else(|| heap_error("lists", &snapshot.lists.free, snapshot.lists.values.len()))
+        {
+            return Err(error);
+        }
 
-        let include_columns = self
-            .components
-            .iter_mut()
-            .filter(|c| include.contains(&c.id))
-            .collect::<Vec<_>>();
-
-        let matching_entities = if let Some((first, rest)) = include_columns.split_first() {
-            let mut bitset = first.bitset.clone();
-            for col in rest {
-                bitset.intersect_with(&col.bitset);
+        self.entities = entities;
+        self.heaps = Heaps {
+            strings: Heap::restore(&snapshot.strings),
+            lists: Heap::restore(&snapshot.lists),
+        };
+        self.storage = match self.storage {
+            Storage::SparseSet(_) => Storage::SparseSet(SparseSetStorage::default()),
+            Storage::Archetype(_) => Storage::Archetype(ArchetypeStorage::default()),
+        };
+        for (id, layout) in self.layouts.iter().enumerate() {
+            storage!(&mut self.storage, s => s.register(id as ComponentId, layout.size));
+        }
+        self.observations.clear();
+        self.freed.clear();
+        self.hierarchy = Hierarchy::default();
+
+        let tick = self.change_tick;
+        for (id, index, data) in rows {
+            let entity = self.entities.entity(index);
+            if self.child_of == Some(id) {
+                let parent = Entity::from_bits(u32::from_be_bytes(data[..].try_into().unwrap()));
+                self.hierarchy.set_parent(entity, parent);
             }
-            bitset.disjoint_with(&exclude_bitmap);
+            storage!(&mut self.storage, s => s.insert(id, index, &data, tick));
+        }
+        let (storage, entities) = (&self.storage, &self.entities);
+        self.queries.refresh(None, |include, exclude| {
+            matching(storage, entities, include, exclude)
+        });
+        Ok(())
+    }
 
-            bitset.iter_ids().collect::<Vec<_>>()
-        } else {
-            Vec::new()
+    // The offset and size, in the data of the saved component, of each primitive field of the registered component.
+    // The fields of nested components are listed in place of the nested component.
+    fn field_offsets(
+        &self,
+        snapshot: &Snapshot,
+        component: &ComponentSnapshot,
+        id: ComponentId,
+        ids: &HashMap<ComponentId, ComponentId>,
+    ) -> Result<Vec<(usize, usize)>, String> {
+        let layout = &self.layouts[id as usize];
+        let mismatch = |field: &str| {
+            format!(
+                "Field '{field}' of component {} does not match the snapshot",
+                component.display_name()
+            )
         };
-
-        if !matching_entities.is_empty() {
-            let non_marker_include_columns = include_columns
-                .into_iter()
-                .filter(|c| c.layout.size != 0)
-                .collect();
-            QueryResult::new(matching_entities, non_marker_include_columns)
-        } else {
-            QueryResult::new(Vec::new(), Vec::new())
+        if layout.fields.len() != component.fields.len() {
+            return Err(format!(
+                "Component {} has {} fields, but {} in the snapshot",
+                component.display_name(),
+                layout.fields.len(),
+                component.fields.len()
+            ));
         }
+        let by_name = layout.fields.iter().all(|field| !field.name.is_empty())
+            && component.fields.iter().all(|field| !field.name.is_empty());
+        layout
+            .fields
+            .iter()
+            .enumerate()
+            .map(|(index, field)| {
+                let saved = match by_name {
+                    true => component
+                        .fields
+                        .iter()
+                        .position(|saved| saved.name == field.name),
+                    false => Some(index),
+                }
+                .ok_or_else(|| mismatch(&field.name))?;
+                let saved_field = &component.fields[saved];
+                let type_parameter = match field.type_id {
+                    COMPONENT_TYPE_ID => ids.get(&saved_field.type_parameter).copied(),
+                    _ => Some(saved_field.type_parameter),
+                };
+                if saved_field.type_id != field.type_id
+                    || saved_field.size != field.size
+                    || type_parameter != Some(field.type_parameter)
+                {
+                    return Err(mismatch(&field.name));
+                }
+                let offset: usize = component.fields[..saved]
+                    .iter()
+                    .map(|field| field.size as usize)
+                    .sum();
+                if field.type_id != COMPONENT_TYPE_ID {
+                    return Ok(vec![(offset, field.size as usize)]);
+                }
+                let nested = snapshot
+                    .components
+                    .iter()
+                    .find(|nested| nested.id == saved_field.type_parameter)
+                    .ok_or_else(|| mismatch(&field.name))?;
+                let nested_offsets =
+                    self.field_offsets(snapshot, nested, field.type_parameter, ids)?;
+                Ok(nested_offsets
+                    .into_iter()
+                    .map(|(nested_offset, size)| (offset + nested_offset, size))
+                    .collect())
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|offsets| offsets.concat())
+    }
+
+    // Whether the strings, tags and lists that the data of the component refers to are in the heaps of the snapshot
+    fn has_valid_handles(
+        &self,
+        layout: &ComponentLayout,
+        data: &[u8],
+        snapshot: &Snapshot,
+    ) -> bool {
+        let is_string = |id: HeapId| (id as usize) < snapshot.strings.values.len();
+        let mut offset = 0;
+        layout.fields.iter().all(|field| {
+            let bytes = &data[offset..offset + field.size as usize];
+            offset += field.size as usize;
+            match field.type_id {
+                STRING_TYPE_ID | TAG_TYPE_ID => is_string(read_id(bytes)),
+                LIST_TYPE_ID => match snapshot.lists.values.get(read_id(bytes) as usize) {
+                    Some(elements) if field.type_parameter == STRING_TYPE_ID as u32 => {
+                        elements.chunks(size_of::<HeapId>()).all(|element| {
+                            element.len() == size_of::<HeapId>() && is_string(read_id(element))
+                        })
+                    }
+                    Some(_) => true,
+                    None => false,
+                },
+                COMPONENT_TYPE_ID => {
+                    let nested = &self.layouts[field.type_parameter as usize];
+                    self.has_valid_handles(nested, bytes, snapshot)
+                }
+                _ => true,
+            }
+        })
     }
 }
+
+// The entities that have all the included components and none of the excluded ones, in ascending order. A query
+// without included components (e.g. `Entity e, Name? n` in delta) matches every alive entity.
+fn matching(
+    storage: &Storage,
+    entities: &EntityManager,
+    include: &[ComponentId],
+    exclude: &[ComponentId],
+) -> Vec<EntityIndex> {
+    if !include.is_empty() {
+        return storage!(storage, s => s.matching(include, exclude));
+    }
+    entities
+        .alive()
+        .filter(|index| {
+            !exclude
+                .iter()
+                .any(|id| storage!(storage, s => s.get(*id, *index)).is_some())
+        })
+        .collect()
+}
@@ -18,6 +18,7 @@ struct TestResult {
     path: PathBuf,
     status: ProcessStatus,
     instructions_diff: Option<InstructionsDiff>,
+    round_trip_error: Option<String>,
 }
 
 struct InstructionsDiff {
@@ -66,8 +67,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut files_processed = 0;
     let mut ignored_files = Vec::new();
     let mut instruction_changes = Vec::new();
+    let mut round_trip_failures = Vec::new();
 
     for result in results {
+        if let Some(error) = result.round_trip_error {
+            round_trip_failures.push((result.path.clone(), error));
+        }
         match result.status {
             ProcessStatus::Processed => {
                 files_processed += 1;
@@ -103,6 +108,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    // Print disassemblies that do not assemble back into the same bytecode
+    if !round_trip_failures.is_empty() {
+        println!("\nRound-trip Failures ({}):", round_trip_failures.len());
+        println!("{}", "-".repeat(80));
+        for (path, error) in &round_trip_failures {
+            let rel_path = Path::strip_prefix(path, &tests_dir)
+                .unwrap_or(path)
+                .display();
+            println!("  {}", rel_path);
+            println!("    Reason: {}", error);
+        }
+    }
+
     let ignored_file_count = ignored_files.len();
     // Print ignored files
     if !ignored_files.is_empty() {
@@ -123,8 +141,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("  Processed: {}", files_processed);
     println!("  Ignored: {}", ignored_file_count);
     println!("  Instruction changes: {}", instruction_changes.len());
+    println!("  Round-trip failures: {}", round_trip_failures.len());
     println!("{}", "=".repeat(80));
 
+    if !round_trip_failures.is_empty() {
+        return Err("Disassembly did not round-trip through the assembler".into());
+    }
+
     Ok(())
 }
 
@@ -168,6 +191,7 @@ fn process_toml_file(test_file: &TestFile) -> Result<TestResult, Box<dyn std::er
             path: test_file.path.clone(),
             status: ProcessStatus::Ignored(ignored.to_string()),
             instructions_diff: None,
+            round_trip_error: None,
         });
     }
 
@@ -192,7 +216,9 @@ fn process_toml_file(test_file: &TestFile) -> Result<TestResult, Box<dyn std::er
                             delta::vm::Value::True => "boolean".to_string(),
                             delta::vm::Value::False => "boolean".to_string(),
                             delta::vm::Value::Integer(_) => "integer".to_string(),
+                            delta::vm::Value::Integer64(_) => "integer64".to_string(),
                             delta::vm::Value::Float(_) => "float".to_string(),
+                            delta::vm::Value::Float64(_) => "float64".to_string(),
                             delta::vm::Value::String(_) => "string".to_string(),
                             delta::vm::Value::SimpleTag { .. } => "tag".to_string(),
                             delta::vm::Value::Tag { .. } => "tag".to_string(),
@@ -212,6 +238,11 @@ fn process_toml_file(test_file: &TestFile) -> Result<TestResult, Box<dyn std::er
                 let compilation_metadata = program_result.metadata.compilation_metadata;
                 let execution_metadata = program_result.metadata.execution_metadata;
 
+                let round_trip_error = check_round_trip(
+                    &compilation_metadata.disassembled_instructions,
+                    &compilation_metadata.bytecode,
+                );
+
                 // Add compiler metadata
                 let mut compiler_table = Table::new();
                 compiler_table.insert(
@@ -272,6 +303,7 @@ fn process_toml_file(test_file: &TestFile) -> Result<TestResult, Box<dyn std::er
                     path: test_file.path.clone(),
                     status: ProcessStatus::Processed,
                     instructions_diff,
+                    round_trip_error,
                 });
             }
             Err(diagnostics) => {
@@ -291,9 +323,23 @@ fn process_toml_file(test_file: &TestFile) -> Result<TestResult, Box<dyn std::er
         path: test_file.path.clone(),
         status: ProcessStatus::Processed,
         instructions_diff: None,
+        round_trip_error: None,
     })
 }
 
+// Assembling the disassembly must reproduce the exact bytecode it was disassembled from
+fn check_round_trip(disassembly: &str, bytecode: &[u8]) -> Option<String> {
+    match delta::assemble(disassembly) {
+        Ok(assembled) if assembled == bytecode => None,
+        Ok(assembled) => Some(format!(
+            "Assembled bytecode differs ({} bytes, expected {} bytes)",
+            assembled.len(),
+            bytecode.len()
+        )),
+        Err(diagnostics) => Some(diagnostics.print(disassembly).join("\n")),
+    }
+}
+
 fn run_script(
     file_name: String,
     source: &str,
@@ -304,6 +350,7 @@ fn run_script(
         source_path: file_name,
         debug,
         no_run: false,
+        release: false,
     };
     delta::run(source, &args)
 }